@@ -1,4 +1,9 @@
 #!/bin/bash
 echo $1
 pwd
+if [ -f tmp/clock/node-$1 ]; then
+    export LD_PRELOAD=/usr/lib/x86_64-linux-gnu/faketime/libfaketime.so.1
+    export FAKETIME_TIMESTAMP_FILE=tmp/clock/node-$1
+    export FAKETIME_NO_CACHE=1
+fi
 ic/target/debug/replica --replica-version 0.9.0 --config-file tmp/ic-$1.json5
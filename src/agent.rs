@@ -0,0 +1,123 @@
+use crate::topology;
+use anyhow::{anyhow, bail, Result};
+use candid::Principal;
+use ic_agent::identity::{AnonymousIdentity, BasicIdentity};
+use ic_agent::Agent;
+use ic_management_canister_types::{
+    CanisterIdRecord, CanisterInstallMode, InstallCodeArgs, Method, Payload,
+    ProvisionalCreateCanisterWithCyclesArgs,
+};
+use ic_types::{CanisterId, PrincipalId};
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+
+// ic-prep assigns each subnet a canister id range of this size, in subnet
+// index order.
+const CANISTER_IDS_PER_SUBNET: u64 = 1 << 20;
+
+pub(crate) fn block_on<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::runtime::Runtime::new()?.block_on(future)
+}
+
+pub(crate) fn read_wasm(wasm_dir: &Path, file: &str) -> Result<Vec<u8>> {
+    let path = wasm_dir.join(file);
+    if !path.is_file() {
+        bail!("missing wasm {:?}", path);
+    }
+
+    Ok(fs::read(path)?)
+}
+
+pub(crate) async fn connect(url: &str, pem: Option<&Path>) -> Result<Agent> {
+    let builder = Agent::builder().with_url(url);
+    let agent = match pem {
+        Some(pem) => builder
+            .with_identity(BasicIdentity::from_pem_file(pem)?)
+            .build()?,
+        None => builder.with_identity(AnonymousIdentity).build()?,
+    };
+    // testnet root keys are generated by ic-prep and not known to the agent
+    match topology::load().and_then(|topology| topology.root_key_der()) {
+        Ok(root_key) => agent.set_root_key(root_key),
+        Err(_) => agent.fetch_root_key().await?,
+    }
+
+    Ok(agent)
+}
+
+pub(crate) fn effective_canister_id(subnet_index: u64) -> Principal {
+    CanisterId::from_u64(subnet_index * CANISTER_IDS_PER_SUBNET)
+        .get()
+        .0
+}
+
+pub(crate) async fn create_canister(
+    agent: &Agent,
+    subnet_index: u64,
+    cycles: Option<u128>,
+) -> Result<Principal> {
+    let args = ProvisionalCreateCanisterWithCyclesArgs::new(cycles, None);
+    let bytes = agent
+        .update(
+            &Principal::management_canister(),
+            Method::ProvisionalCreateCanisterWithCycles.to_string(),
+        )
+        .with_effective_canister_id(effective_canister_id(subnet_index))
+        .with_arg(args.encode())
+        .call_and_wait()
+        .await?;
+    let record = CanisterIdRecord::decode(&bytes).map_err(|e| anyhow!("{}", e))?;
+
+    Ok(record.get_canister_id().get().0)
+}
+
+// The replica creates a canister with a specified id only when the id is
+// routed to its subnet, outside of the ranges it allocates from.
+pub(crate) async fn create_canister_with_id(
+    agent: &Agent,
+    canister_id: Principal,
+    cycles: Option<u128>,
+) -> Result<()> {
+    let mut args = ProvisionalCreateCanisterWithCyclesArgs::new(cycles, None);
+    args.specified_id = Some(PrincipalId(canister_id));
+    agent
+        .update(
+            &Principal::management_canister(),
+            Method::ProvisionalCreateCanisterWithCycles.to_string(),
+        )
+        .with_effective_canister_id(canister_id)
+        .with_arg(args.encode())
+        .call_and_wait()
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn install_code(
+    agent: &Agent,
+    canister_id: Principal,
+    mode: CanisterInstallMode,
+    wasm: Vec<u8>,
+    arg: Vec<u8>,
+) -> Result<()> {
+    let args = InstallCodeArgs::new(
+        mode,
+        CanisterId::unchecked_from_principal(PrincipalId(canister_id)),
+        wasm,
+        arg,
+        None,
+        None,
+    );
+    agent
+        .update(
+            &Principal::management_canister(),
+            Method::InstallCode.to_string(),
+        )
+        .with_effective_canister_id(canister_id)
+        .with_arg(args.encode())
+        .call_and_wait()
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use crate::backend;
+use crate::config::TestnetConfig;
+use crate::health;
+use crate::init::nns_url;
+use crate::metadata;
+use crate::plan::{Action, Plan};
+use crate::process;
+use crate::propose;
+use crate::topology::{self, Topology};
+use crate::wizard;
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Args)]
+pub(crate) struct ApplyArgs {
+    /// Edited copy of tmp/testnet.toml
+    config: PathBuf,
+    /// Apply the plan without asking
+    #[clap(long)]
+    auto_approve: bool,
+    /// Seconds to wait for each replica shutdown and health check
+    #[clap(long, default_value = "120")]
+    timeout: u64,
+}
+
+fn flag(enabled: bool) -> &'static str {
+    if enabled {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+// Rewrites the settings `plan` compares in the replica config of a node, the
+// rest of what `init` generated stays as it is.
+fn reconfigure(node: NodeIndex, subnet: Option<SubnetIndex>, config: &TestnetConfig) -> Result<()> {
+    let settings = config.subnet(subnet);
+    let mut replica = process::replica_config(node)?;
+    let hypervisor = &mut replica["hypervisor"];
+    hypervisor["canister_sandboxing_flag"] = flag(config.node(node).sandboxing).into();
+    hypervisor["deterministic_time_slicing"] = flag(settings.deterministic_time_slicing).into();
+    hypervisor["query_stats_aggregation"] = flag(settings.query_stats != Some(false)).into();
+    hypervisor["query_stats_epoch_length"] = settings.query_stats_epoch_length.unwrap_or(60).into();
+    process::write_replica_config(node, &replica)
+}
+
+fn subnet_id(topology: &Topology, subnet: SubnetIndex) -> Result<String> {
+    Ok(topology.subnet(subnet)?.subnet_id.clone())
+}
+
+fn node_id(topology: &Topology, node: NodeIndex) -> Result<String> {
+    Ok(topology.node(node)?.node_id.clone())
+}
+
+fn propose(command: Vec<String>) -> Result<()> {
+    let output = propose::submit(&nns_url(), None, "Applied by ic-testnet", command)?;
+    print!("{}", output);
+    Ok(())
+}
+
+fn move_in_topology(
+    topology: &mut Topology,
+    node: NodeIndex,
+    to: Option<SubnetIndex>,
+) -> Result<()> {
+    let lists = topology
+        .subnets
+        .iter_mut()
+        .map(|subnet| &mut subnet.nodes)
+        .chain([&mut topology.unassigned_nodes]);
+    let mut summary = None;
+    for list in lists {
+        if let Some(i) = list.iter().position(|entry| entry.index == node) {
+            summary = Some(list.remove(i));
+        }
+    }
+    let summary = summary.ok_or(anyhow!("unknown node {}", node))?;
+
+    let list = match to {
+        Some(to) => {
+            &mut topology
+                .subnets
+                .iter_mut()
+                .find(|subnet| subnet.index == to)
+                .ok_or(anyhow!("unknown subnet {}", to))?
+                .nodes
+        }
+        None => &mut topology.unassigned_nodes,
+    };
+    list.push(summary);
+    list.sort_by_key(|entry| entry.index);
+    Ok(())
+}
+
+fn restart(topology: &Topology, node: NodeIndex, fresh: bool, timeout: Duration) -> Result<()> {
+    let backend = backend::for_node(node)?;
+    if backend.running(node) {
+        backend.stop(node, timeout)?;
+    }
+    if fresh {
+        process::reset_replicated_state(node)?;
+    }
+    backend.start(node)?;
+
+    if let Some(subnet) = topology.subnet_of(node) {
+        health::wait_healthy(subnet, &[], timeout)?;
+    }
+    Ok(())
+}
+
+// Submits the registry changes first, then restarts each affected replica
+// once, one at a time so that every subnet keeps its majority.
+pub(crate) fn run(args: ApplyArgs) -> Result<()> {
+    let plan = Plan::new(TestnetConfig::load(Some(&args.config))?)?;
+    plan.print();
+    if plan.actions.is_empty() {
+        return Ok(());
+    }
+    if plan.actions.iter().any(Action::needs_reinit) {
+        bail!("the plan has changes `apply` cannot make");
+    }
+    for action in &plan.actions {
+        if let Action::UpdateSubnetRecord { subnet, changes } = action {
+            if let Some(change) = changes.iter().find(|change| change.to == "default") {
+                bail!(
+                    "subnet {} cannot go back to the default {}, set the value instead",
+                    subnet,
+                    change.setting
+                );
+            }
+        }
+    }
+    println!();
+    if !args.auto_approve && !wizard::yes("Apply these changes", false)? {
+        bail!("apply cancelled");
+    }
+
+    let timeout = Duration::from_secs(args.timeout);
+    let mut topology = topology::load()?;
+    let mut restarts: BTreeSet<NodeIndex> = BTreeSet::new();
+    // nodes changing subnets start over from the registry
+    let mut fresh: BTreeSet<NodeIndex> = BTreeSet::new();
+
+    for action in &plan.actions {
+        match action {
+            Action::RemoveNode { node, subnet } => {
+                if subnet.is_some() {
+                    propose(vec![
+                        "propose-to-remove-nodes-from-subnet".to_string(),
+                        node_id(&topology, *node)?,
+                    ])?;
+                }
+                let backend = backend::for_node(*node)?;
+                if backend.running(*node) {
+                    backend.stop(*node, timeout)?;
+                }
+                for subnet in &mut topology.subnets {
+                    subnet.nodes.retain(|summary| summary.index != *node);
+                }
+                topology
+                    .unassigned_nodes
+                    .retain(|summary| summary.index != *node);
+                println!("removed node {}", node);
+            }
+            Action::MoveNode { node, from, to } => {
+                if from.is_some() {
+                    propose(vec![
+                        "propose-to-remove-nodes-from-subnet".to_string(),
+                        node_id(&topology, *node)?,
+                    ])?;
+                }
+                if let Some(to) = to {
+                    propose(vec![
+                        "propose-to-add-nodes-to-subnet".to_string(),
+                        "--subnet".to_string(),
+                        subnet_id(&topology, *to)?,
+                        node_id(&topology, *node)?,
+                    ])?;
+                }
+                move_in_topology(&mut topology, *node, *to)?;
+                restarts.insert(*node);
+                fresh.insert(*node);
+            }
+            Action::UpdateSubnetRecord { subnet, changes } => {
+                let mut command = vec![
+                    "propose-to-update-subnet".to_string(),
+                    "--subnet".to_string(),
+                    subnet_id(&topology, *subnet)?,
+                ];
+                for change in changes {
+                    command.push(format!("--{}", change.setting.replace('_', "-")));
+                    command.push(change.to.clone());
+                }
+                propose(command)?;
+            }
+            Action::ReconfigureSubnet { nodes, .. } => restarts.extend(nodes),
+            Action::ReconfigureNode { node, changes } => {
+                // once the config is saved the new backend does not see the
+                // replica the old one started
+                if changes.iter().any(|change| change.setting == "backend") {
+                    let backend = backend::for_node(*node)?;
+                    if backend.running(*node) {
+                        println!("stopping node {} on the {} backend", node, backend.name());
+                        backend.stop(*node, timeout)?;
+                    }
+                }
+                restarts.insert(*node);
+            }
+            // saved with the config below
+            Action::Relabel { .. } => {}
+            Action::AddNode { .. } | Action::Reinit { .. } => unreachable!(),
+        }
+    }
+
+    // replicas read their limits from the saved config when they start
+    plan.target.save()?;
+    topology.save()?;
+    metadata::touch(&topology)?;
+
+    for node in &restarts {
+        let subnet = topology.subnet_of(*node).map(|subnet| subnet.index);
+        reconfigure(*node, subnet, &plan.target)?;
+        println!("restarting node {}", node);
+        restart(&topology, *node, fresh.contains(node), timeout)?;
+    }
+    println!(
+        "applied {} changes, restarted nodes {:?}",
+        plan.actions.len(),
+        restarts
+    );
+
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use crate::config::{Backend, DockerSettings, SshSettings, TestnetConfig};
+use crate::init::output_dir;
+use crate::process::{self, Signal};
+use crate::topology;
+#[cfg(feature = "run")]
+use crate::vm;
+use anyhow::{bail, Context, Result};
+use ic_prep_lib::node::NodeIndex;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Where the replica of a node executes. The lifecycle commands go through it,
+// so they work the same for local processes, containers, VMs and remote hosts.
+pub(crate) trait NodeBackend: Send {
+    fn name(&self) -> &'static str;
+    // Starts the replica of a node. Local processes come back as the child to
+    // reap, the others keep running on their own.
+    fn start(&self, index: NodeIndex) -> Result<Option<Child>>;
+    // Starts another replica binary than the one `init` recorded, e.g. the
+    // release an upgrade test moves to.
+    fn start_with(
+        &self,
+        index: NodeIndex,
+        _replica: &Path,
+        _version: &str,
+    ) -> Result<Option<Child>> {
+        bail!(
+            "the {} backend of node {} only runs the replica binary it was set up with",
+            self.name(),
+            index
+        )
+    }
+    fn running(&self, index: NodeIndex) -> bool;
+    // Stops the replica gracefully and kills it after the timeout, returning
+    // whether it stopped by itself.
+    fn stop(&self, index: NodeIndex, timeout: Duration) -> Result<bool>;
+    fn kill(&self, index: NodeIndex) -> Result<()>;
+}
+
+// Whether the backend of a node runs its replica, for commands that must not
+// touch the state of a live one.
+pub(crate) fn is_running(index: NodeIndex) -> Result<bool> {
+    Ok(for_node(index)?.running(index))
+}
+
+pub(crate) fn for_node(index: NodeIndex) -> Result<Box<dyn NodeBackend>> {
+    let config = TestnetConfig::saved()?;
+    Ok(match config.node(index).backend {
+        Backend::Process => Box::new(ProcessBackend),
+        Backend::Docker => Box::new(DockerBackend(config.docker.unwrap_or_default())),
+        Backend::Ssh => Box::new(SshBackend(config.ssh.unwrap_or_default())),
+        #[cfg(feature = "run")]
+        Backend::Vm => Box::new(vm::VmBackend),
+        #[cfg(not(feature = "run"))]
+        Backend::Vm => bail!("node {} runs in a VM, which needs the run feature", index),
+    })
+}
+
+fn succeeds(command: &mut Command) -> bool {
+    command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |status| status.success())
+}
+
+fn tool(command: &mut Command) -> Result<String> {
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {:?}", command.get_program()))?;
+    if !output.status.success() {
+        bail!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Waits for a backend to report the replica gone.
+fn wait_stopped(backend: &dyn NodeBackend, index: NodeIndex, deadline: Instant) -> bool {
+    while backend.running(index) {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    true
+}
+
+pub(crate) struct ProcessBackend;
+
+impl NodeBackend for ProcessBackend {
+    fn name(&self) -> &'static str {
+        "process"
+    }
+
+    fn start(&self, index: NodeIndex) -> Result<Option<Child>> {
+        Ok(Some(process::start_node(index)?))
+    }
+
+    fn start_with(&self, index: NodeIndex, replica: &Path, version: &str) -> Result<Option<Child>> {
+        Ok(Some(process::start_node_with(index, replica, version)?))
+    }
+
+    fn running(&self, index: NodeIndex) -> bool {
+        process::replica_pid(index).is_some()
+    }
+
+    fn stop(&self, index: NodeIndex, timeout: Duration) -> Result<bool> {
+        process::stop_node(index, timeout)
+    }
+
+    fn kill(&self, index: NodeIndex) -> Result<()> {
+        process::signal_node(index, Signal::SIGKILL)
+    }
+}
+
+// Containers share the network of the host and mount the node state and the
+// configs at their paths on the host, which the replica config refers to.
+pub(crate) struct DockerBackend(DockerSettings);
+
+fn container(index: NodeIndex) -> String {
+    format!("ic-testnet-node-{}", index)
+}
+
+const CONTAINER_REPLICA: &str = "/opt/ic-testnet/bin/replica";
+
+impl DockerBackend {
+    fn run_container(&self, index: NodeIndex, replica: Option<&Path>, version: &str) -> Result<()> {
+        if self.running(index) {
+            bail!("the container of node {} is already running", index);
+        }
+        let state_dir = process::seed_state_dir(index)?;
+        let output_dir = output_dir()?;
+        let settings = TestnetConfig::saved()?.node(index);
+        // a container of an earlier run keeps the name
+        succeeds(Command::new("docker").args(["rm", "--force", &container(index)]));
+
+        let mut command = Command::new("docker");
+        command
+            .args(["run", "--detach", "--network", "host", "--name"])
+            .arg(container(index))
+            .arg("--volume")
+            .arg(format!("{0}:{0}", state_dir.display()))
+            .arg("--volume")
+            .arg(format!("{0}:{0}", output_dir.display()));
+        if let Some(replica) = replica {
+            let replica = replica
+                .canonicalize()
+                .with_context(|| format!("no replica binary at {:?}", replica))?;
+            command
+                .arg("--volume")
+                .arg(format!("{}:{}:ro", replica.display(), CONTAINER_REPLICA));
+        }
+        for (key, value) in &settings.env {
+            command.arg("--env").arg(format!("{}={}", key, value));
+        }
+        command
+            .arg(&self.0.image)
+            .arg(CONTAINER_REPLICA)
+            .arg("--replica-version")
+            .arg(version)
+            .arg("--config-file")
+            .arg(output_dir.join(process::config_file(index)))
+            .args(&settings.args);
+        tool(&mut command)?;
+
+        Ok(())
+    }
+}
+
+impl NodeBackend for DockerBackend {
+    fn name(&self) -> &'static str {
+        "docker"
+    }
+
+    fn start(&self, index: NodeIndex) -> Result<Option<Child>> {
+        self.run_container(index, None, &process::replica_version())?;
+        Ok(None)
+    }
+
+    // The binary is mounted over the one of the image.
+    fn start_with(&self, index: NodeIndex, replica: &Path, version: &str) -> Result<Option<Child>> {
+        self.run_container(index, Some(replica), version)?;
+        Ok(None)
+    }
+
+    fn running(&self, index: NodeIndex) -> bool {
+        Command::new("docker")
+            .args([
+                "inspect",
+                "--format",
+                "{{.State.Running}}",
+                &container(index),
+            ])
+            .output()
+            .map_or(false, |output| output.stdout.starts_with(b"true"))
+    }
+
+    // docker sends SIGTERM and kills the container itself after --time
+    // seconds, which then exits with 137.
+    fn stop(&self, index: NodeIndex, timeout: Duration) -> Result<bool> {
+        tool(Command::new("docker").args([
+            "stop",
+            "--time",
+            &timeout.as_secs().to_string(),
+            &container(index),
+        ]))?;
+        let exit_code = tool(Command::new("docker").args([
+            "inspect",
+            "--format",
+            "{{.State.ExitCode}}",
+            &container(index),
+        ]))?;
+        Ok(exit_code != "137")
+    }
+
+    fn kill(&self, index: NodeIndex) -> Result<()> {
+        tool(Command::new("docker").args(["kill", &container(index)]))?;
+        Ok(())
+    }
+}
+
+// The ic-replica systemd service of the host of a node, installed by the
+// playbook of `generate ansible`.
+pub(crate) struct SshBackend(SshSettings);
+
+impl SshBackend {
+    fn host(&self, index: NodeIndex) -> Result<IpAddr> {
+        Ok(topology::load()?.node(index)?.addr()?.ip())
+    }
+
+    fn ssh(&self, index: NodeIndex, remote: &str) -> Result<Command> {
+        let host = self.host(index)?.to_string();
+        let mut command = Command::new("ssh");
+        command.args(["-o", "BatchMode=yes"]);
+        if let Some(identity) = &self.0.identity {
+            command.arg("-i").arg(identity);
+        }
+        match &self.0.user {
+            Some(user) => command.arg(format!("{}@{}", user, host)),
+            None => command.arg(host),
+        };
+        command.arg(remote);
+        Ok(command)
+    }
+}
+
+impl NodeBackend for SshBackend {
+    fn name(&self) -> &'static str {
+        "ssh"
+    }
+
+    fn start(&self, index: NodeIndex) -> Result<Option<Child>> {
+        tool(&mut self.ssh(index, "sudo systemctl start ic-replica")?)
+            .with_context(|| format!("failed to start the replica of node {}", index))?;
+        Ok(None)
+    }
+
+    fn running(&self, index: NodeIndex) -> bool {
+        self.ssh(index, "systemctl is-active --quiet ic-replica")
+            .map_or(false, |mut command| succeeds(&mut command))
+    }
+
+    // `systemctl stop` does not trigger Restart=on-failure, unlike a signal
+    // sent to the replica.
+    fn stop(&self, index: NodeIndex, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut stop = self
+            .ssh(index, "sudo systemctl stop ic-replica")?
+            .stdout(Stdio::null())
+            .spawn()?;
+        while stop.try_wait()?.is_none() {
+            if Instant::now() > deadline {
+                self.kill(index)?;
+                let _ = stop.wait();
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        Ok(wait_stopped(self, index, deadline))
+    }
+
+    fn kill(&self, index: NodeIndex) -> Result<()> {
+        tool(&mut self.ssh(
+            index,
+            "sudo systemctl kill --signal=SIGKILL ic-replica && sudo systemctl stop ic-replica",
+        )?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,556 @@
+use crate::agent::{block_on, connect};
+use crate::init::output_dir;
+use crate::metrics::{self, finalized_height};
+use crate::process;
+use crate::topology::{self, SubnetSummary};
+use crate::workload;
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_universal_canister::wasm;
+#[cfg(unix)]
+use nix::unistd::{sysconf, SysconfVar};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Subcommand)]
+pub(crate) enum BenchCommand {
+    /// Put a subnet under load and write a report of its performance
+    Run(BenchRunArgs),
+    /// Compare two reports and fail when the new one regressed
+    Compare(BenchCompareArgs),
+}
+
+#[derive(Args)]
+pub(crate) struct BenchRunArgs {
+    /// How long the load runs, e.g. 300s or 5m
+    #[clap(long, default_value = "300s")]
+    duration: String,
+    #[clap(long, default_value = "0")]
+    subnet: SubnetIndex,
+    /// Callers each making one update call after the other
+    #[clap(long, default_value = "16")]
+    concurrency: usize,
+    /// Name of the configuration under test, recorded in the report
+    #[clap(long, default_value = "")]
+    label: String,
+    /// Report files without extension, tmp/bench/<start time> by default
+    #[clap(long)]
+    out: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub(crate) struct BenchCompareArgs {
+    old: PathBuf,
+    new: PathBuf,
+    /// Percent the finalization rate and ingress TPS may drop
+    #[clap(long, default_value = "10")]
+    max_throughput_drop: f64,
+    /// Percent the update latencies may grow
+    #[clap(long, default_value = "10")]
+    max_latency_increase: f64,
+    /// Percent the mean CPU of the replicas may grow
+    #[clap(long, default_value = "20")]
+    max_cpu_increase: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Latency {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Report {
+    pub label: String,
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub subnet: SubnetIndex,
+    pub nodes: usize,
+    pub concurrency: usize,
+    /// Finalized blocks per second
+    pub finalization_rate: f64,
+    /// Completed update calls per second
+    pub ingress_tps: f64,
+    pub calls: u64,
+    pub errors: u64,
+    pub update_latency: Latency,
+    /// CPU of each replica, 100 per busy core
+    pub cpu_percent: BTreeMap<NodeIndex, f64>,
+}
+
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let (number, unit) = duration.split_at(
+        duration
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(duration.len()),
+    );
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid duration {}, expected e.g. 300s or 5m", duration))?;
+    let secs = match unit {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 3600,
+        _ => bail!("invalid duration {}, expected e.g. 300s or 5m", duration),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+// Highest height any node of the subnet finalized.
+fn subnet_height(subnet: &SubnetSummary) -> Option<u64> {
+    subnet
+        .nodes
+        .iter()
+        .filter_map(|node| finalized_height(&node.metrics).ok())
+        .max()
+}
+
+// CPU seconds a replica used so far, from its metrics or else from /proc.
+fn cpu_seconds(index: NodeIndex, metrics_url: &str) -> Option<f64> {
+    if let Some(secs) = metrics::scrape(metrics_url)
+        .ok()
+        .and_then(|metrics| metrics::value(&metrics, "process_cpu_seconds_total", &[]))
+    {
+        return Some(secs);
+    }
+    #[cfg(unix)]
+    let ticks_per_sec = sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100) as f64;
+    #[cfg(not(unix))]
+    let ticks_per_sec = 100.0;
+    let (ticks, _) = process::resource_usage(process::replica_pid(index)?)?;
+    Some(ticks as f64 / ticks_per_sec)
+}
+
+fn cpu_sample(subnet: &SubnetSummary) -> BTreeMap<NodeIndex, f64> {
+    subnet
+        .nodes
+        .iter()
+        .filter_map(|node| Some((node.index, cpu_seconds(node.index, &node.metrics)?)))
+        .collect()
+}
+
+fn millis(latency: Duration) -> f64 {
+    latency.as_secs_f64() * 1000.0
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    millis(sorted[(sorted.len() * p / 100).min(sorted.len() - 1)])
+}
+
+// Closed-loop load: every caller writes its counter to one universal
+// canister, one update call after the other, until the deadline.
+async fn drive(
+    url: &str,
+    subnet: SubnetIndex,
+    concurrency: usize,
+    duration: Duration,
+) -> Result<(Vec<Duration>, u64)> {
+    let agent = connect(url, None).await?;
+    let canister_id = workload::deploy(&agent, subnet).await?;
+    workload::update(&agent, canister_id, wasm().stable_grow(1).reply().build()).await?;
+
+    let deadline = Instant::now() + duration;
+    let callers: Vec<_> = (0..concurrency)
+        .map(|caller| {
+            let agent = agent.clone();
+            tokio::spawn(async move {
+                let mut latencies = vec![];
+                let mut errors = 0;
+                let mut value = 0u64;
+                while Instant::now() < deadline {
+                    value += 1;
+                    // each caller keeps its own counter
+                    let payload = wasm()
+                        .stable_write(caller as u32 * 8, &value.to_le_bytes())
+                        .reply()
+                        .build();
+                    let start = Instant::now();
+                    match workload::update(&agent, canister_id, payload).await {
+                        Ok(_) => latencies.push(start.elapsed()),
+                        Err(_) => errors += 1,
+                    }
+                }
+                (latencies, errors)
+            })
+        })
+        .collect();
+
+    let mut latencies = vec![];
+    let mut errors = 0;
+    for caller in callers {
+        let (caller_latencies, caller_errors) = caller.await?;
+        latencies.extend(caller_latencies);
+        errors += caller_errors;
+    }
+    Ok((latencies, errors))
+}
+
+fn markdown(report: &Report) -> String {
+    let mut out = vec![
+        format!(
+            "# Benchmark {}",
+            if report.label.is_empty() {
+                report.started_at.to_string()
+            } else {
+                report.label.clone()
+            }
+        ),
+        String::new(),
+        format!(
+            "Subnet {} with {} nodes, {} concurrent callers for {:.0} s.",
+            report.subnet, report.nodes, report.concurrency, report.duration_secs
+        ),
+        String::new(),
+        "| Metric | Value |".to_string(),
+        "|--------|-------|".to_string(),
+        format!(
+            "| Finalization rate | {:.2} blocks/s |",
+            report.finalization_rate
+        ),
+        format!("| Ingress TPS | {:.1} |", report.ingress_tps),
+        format!(
+            "| Update calls | {} ({} failed) |",
+            report.calls, report.errors
+        ),
+        format!(
+            "| Update latency p50 | {:.0} ms |",
+            report.update_latency.p50_ms
+        ),
+        format!(
+            "| Update latency p95 | {:.0} ms |",
+            report.update_latency.p95_ms
+        ),
+        format!(
+            "| Update latency p99 | {:.0} ms |",
+            report.update_latency.p99_ms
+        ),
+    ];
+    for (node, cpu) in &report.cpu_percent {
+        out.push(format!("| CPU node {} | {:.0}% |", node, cpu));
+    }
+    out.push(String::new());
+    out.join("\n")
+}
+
+fn run_bench(args: BenchRunArgs) -> Result<()> {
+    let duration = parse_duration(&args.duration)?;
+    if args.concurrency == 0 {
+        bail!("--concurrency has to be at least 1");
+    }
+    let topology = topology::load()?;
+    let subnet = topology.subnet(args.subnet)?;
+    let url = topology.subnet_url(args.subnet)?;
+
+    let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let height_before = subnet_height(subnet).ok_or(anyhow!(
+        "no node of subnet {} reports a height",
+        args.subnet
+    ))?;
+    let cpu_before = cpu_sample(subnet);
+    let start = Instant::now();
+    println!(
+        "running {} callers on subnet {} for {:?}",
+        args.concurrency, args.subnet, duration
+    );
+
+    let (mut latencies, errors) = block_on(drive(&url, args.subnet, args.concurrency, duration))?;
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let height_after = subnet_height(subnet).unwrap_or(height_before);
+    let cpu_after = cpu_sample(subnet);
+    latencies.sort();
+
+    let report = Report {
+        label: args.label,
+        started_at,
+        duration_secs: elapsed,
+        subnet: args.subnet,
+        nodes: subnet.nodes.len(),
+        concurrency: args.concurrency,
+        finalization_rate: height_after.saturating_sub(height_before) as f64 / elapsed,
+        ingress_tps: latencies.len() as f64 / elapsed,
+        calls: latencies.len() as u64,
+        errors,
+        update_latency: Latency {
+            p50_ms: percentile(&latencies, 50),
+            p95_ms: percentile(&latencies, 95),
+            p99_ms: percentile(&latencies, 99),
+            max_ms: latencies.last().copied().map_or(0.0, millis),
+        },
+        cpu_percent: cpu_after
+            .iter()
+            .filter_map(|(node, after)| {
+                let before = cpu_before.get(node)?;
+                Some((*node, (after - before) / elapsed * 100.0))
+            })
+            .collect(),
+    };
+
+    let out = match args.out {
+        Some(out) => out,
+        None => output_dir()?.join("bench").join(started_at.to_string()),
+    };
+    if let Some(dir) = out.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let json = out.with_extension("json");
+    let md = out.with_extension("md");
+    fs::write(&json, serde_json::to_string_pretty(&report)?)?;
+    fs::write(&md, markdown(&report))?;
+
+    print!("{}", markdown(&report));
+    println!("report written to {:?} and {:?}", json, md);
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Report> {
+    let json = fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read the report {:?}: {}", path, e))?;
+    serde_json::from_str(&json).map_err(|e| anyhow!("invalid report {:?}: {}", path, e))
+}
+
+fn mean_cpu(report: &Report) -> f64 {
+    if report.cpu_percent.is_empty() {
+        return 0.0;
+    }
+    report.cpu_percent.values().sum::<f64>() / report.cpu_percent.len() as f64
+}
+
+// A metric of two reports and the percent it may change for the worse.
+struct Metric {
+    name: &'static str,
+    old: f64,
+    new: f64,
+    higher_is_better: bool,
+    allowed: f64,
+}
+
+impl Metric {
+    // Change in percent of the old value, positive when the value grew, None
+    // when a baseline of 0 leaves nothing to compare with.
+    fn change(&self) -> Option<f64> {
+        if self.old == 0.0 {
+            return (self.new == 0.0).then_some(0.0);
+        }
+        Some((self.new - self.old) / self.old * 100.0)
+    }
+
+    fn regressed(&self) -> bool {
+        match self.change() {
+            Some(change) if self.higher_is_better => -change > self.allowed,
+            Some(change) => change > self.allowed,
+            None => true,
+        }
+    }
+}
+
+fn metrics(old: &Report, new: &Report, args: &BenchCompareArgs) -> Vec<Metric> {
+    let metric = |name, old, new, higher_is_better, allowed| Metric {
+        name,
+        old,
+        new,
+        higher_is_better,
+        allowed,
+    };
+    vec![
+        metric(
+            "finalization rate",
+            old.finalization_rate,
+            new.finalization_rate,
+            true,
+            args.max_throughput_drop,
+        ),
+        metric(
+            "ingress TPS",
+            old.ingress_tps,
+            new.ingress_tps,
+            true,
+            args.max_throughput_drop,
+        ),
+        metric(
+            "update latency p50",
+            old.update_latency.p50_ms,
+            new.update_latency.p50_ms,
+            false,
+            args.max_latency_increase,
+        ),
+        metric(
+            "update latency p95",
+            old.update_latency.p95_ms,
+            new.update_latency.p95_ms,
+            false,
+            args.max_latency_increase,
+        ),
+        metric(
+            "update latency p99",
+            old.update_latency.p99_ms,
+            new.update_latency.p99_ms,
+            false,
+            args.max_latency_increase,
+        ),
+        metric(
+            "mean CPU",
+            mean_cpu(old),
+            mean_cpu(new),
+            false,
+            args.max_cpu_increase,
+        ),
+    ]
+}
+
+// The names of the metrics that regressed, printing all of them.
+fn regressions(metrics: &[Metric]) -> Vec<&'static str> {
+    println!(
+        "{:<20} {:>12} {:>12} {:>9}",
+        "metric", "old", "new", "change"
+    );
+    let mut regressions = vec![];
+    for metric in metrics {
+        let regressed = metric.regressed();
+        let change = match metric.change() {
+            Some(change) => format!("{:>+8.1}%", change),
+            None => format!("{:>9}", "no base"),
+        };
+        println!(
+            "{:<20} {:>12.2} {:>12.2} {}{}",
+            metric.name,
+            metric.old,
+            metric.new,
+            change,
+            if regressed { "  REGRESSION" } else { "" }
+        );
+        if regressed {
+            regressions.push(metric.name);
+        }
+    }
+    regressions
+}
+
+fn compare(args: BenchCompareArgs) -> Result<()> {
+    let old = load(&args.old)?;
+    let new = load(&args.new)?;
+    if old.subnet != new.subnet || old.nodes != new.nodes || old.concurrency != new.concurrency {
+        println!(
+            "warning: the reports differ in subnet, nodes or concurrency ({}/{}/{} vs {}/{}/{})",
+            old.subnet, old.nodes, old.concurrency, new.subnet, new.nodes, new.concurrency
+        );
+    }
+
+    let regressions = regressions(&metrics(&old, &new, &args));
+    if new.errors > old.errors {
+        println!("failed calls grew from {} to {}", old.errors, new.errors);
+    }
+
+    if !regressions.is_empty() {
+        bail!("regressed: {}", regressions.join(", "));
+    }
+    Ok(())
+}
+
+pub(crate) fn run(cmd: BenchCommand) -> Result<()> {
+    match cmd {
+        BenchCommand::Run(args) => run_bench(args),
+        BenchCommand::Compare(args) => compare(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(rate: f64, tps: f64, p50_ms: f64, cpu: f64) -> Report {
+        Report {
+            label: String::new(),
+            started_at: 0,
+            duration_secs: 300.0,
+            subnet: 0,
+            nodes: 4,
+            concurrency: 16,
+            finalization_rate: rate,
+            ingress_tps: tps,
+            calls: 0,
+            errors: 0,
+            update_latency: Latency {
+                p50_ms,
+                p95_ms: p50_ms,
+                p99_ms: p50_ms,
+                max_ms: p50_ms,
+            },
+            cpu_percent: BTreeMap::from([(100, cpu)]),
+        }
+    }
+
+    fn args(max_throughput_drop: f64) -> BenchCompareArgs {
+        BenchCompareArgs {
+            old: PathBuf::new(),
+            new: PathBuf::new(),
+            max_throughput_drop,
+            max_latency_increase: 10.0,
+            max_cpu_increase: 20.0,
+        }
+    }
+
+    fn regressed(old: &Report, new: &Report, args: &BenchCompareArgs) -> Vec<&'static str> {
+        metrics(old, new, args)
+            .into_iter()
+            .filter(Metric::regressed)
+            .map(|metric| metric.name)
+            .collect()
+    }
+
+    #[test]
+    fn zero_drop_allows_gains_and_flags_drops() {
+        let old = report(1.0, 100.0, 500.0, 50.0);
+        let gain = report(1.2, 120.0, 500.0, 50.0);
+        let drop = report(0.9, 99.0, 500.0, 50.0);
+        assert!(regressed(&old, &gain, &args(0.0)).is_empty());
+        assert_eq!(
+            regressed(&old, &drop, &args(0.0)),
+            vec!["finalization rate", "ingress TPS"]
+        );
+    }
+
+    #[test]
+    fn drops_within_the_limit_pass() {
+        let old = report(1.0, 100.0, 500.0, 50.0);
+        let new = report(0.95, 91.0, 500.0, 50.0);
+        assert!(regressed(&old, &new, &args(10.0)).is_empty());
+    }
+
+    #[test]
+    fn latency_and_cpu_regress_when_they_grow() {
+        let old = report(1.0, 100.0, 500.0, 50.0);
+        let slower = report(1.0, 100.0, 560.0, 61.0);
+        let faster = report(1.0, 100.0, 300.0, 20.0);
+        assert_eq!(
+            regressed(&old, &slower, &args(10.0)),
+            vec![
+                "update latency p50",
+                "update latency p95",
+                "update latency p99",
+                "mean CPU"
+            ]
+        );
+        assert!(regressed(&old, &faster, &args(10.0)).is_empty());
+    }
+
+    #[test]
+    fn zero_baseline_regresses_unless_unchanged() {
+        let old = report(0.0, 100.0, 500.0, 50.0);
+        assert_eq!(
+            regressed(&old, &report(1.0, 100.0, 500.0, 50.0), &args(10.0)),
+            vec!["finalization rate"]
+        );
+        assert!(regressed(&old, &old, &args(10.0)).is_empty());
+    }
+}
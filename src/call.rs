@@ -0,0 +1,63 @@
+use crate::agent::{block_on, connect};
+use crate::identities;
+use crate::topology;
+use anyhow::Result;
+use candid::{Encode, IDLArgs, Principal};
+use clap::Args;
+
+#[derive(Args)]
+pub(crate) struct CallArgs {
+    canister: String,
+    method: String,
+    /// Candid encoded argument as hex, defaults to `()`
+    #[clap(long)]
+    arg_hex: Option<String>,
+    /// Send the call as a generated identity instead of anonymously
+    #[clap(long)]
+    identity: Option<String>,
+    /// Node URL, defaults to a node of the subnet hosting the canister
+    #[clap(long)]
+    url: Option<String>,
+}
+
+pub(crate) fn run(args: CallArgs, query: bool) -> Result<()> {
+    let canister_id = Principal::from_text(&args.canister)?;
+    let url = match args.url {
+        Some(url) => url,
+        None => topology::load()?.canister_url(&canister_id)?,
+    };
+    let pem = match args.identity {
+        Some(name) => Some(identities::find(&name)?.pem),
+        None => None,
+    };
+    let arg = match args.arg_hex {
+        Some(arg) => hex::decode(arg)?,
+        None => Encode!()?,
+    };
+
+    let reply = block_on(async {
+        let agent = connect(&url, pem.as_deref()).await?;
+        let reply = if query {
+            agent
+                .query(&canister_id, &args.method)
+                .with_arg(arg)
+                .call()
+                .await?
+        } else {
+            agent
+                .update(&canister_id, &args.method)
+                .with_arg(arg)
+                .call_and_wait()
+                .await?
+        };
+
+        Ok(reply)
+    })?;
+
+    match IDLArgs::from_bytes(&reply) {
+        Ok(args) => println!("{}", args),
+        Err(_) => println!("{}", hex::encode(reply)),
+    }
+
+    Ok(())
+}
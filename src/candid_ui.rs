@@ -0,0 +1,78 @@
+use crate::agent::{block_on, connect, create_canister, install_code};
+use crate::init::gateway_port;
+use crate::topology;
+use anyhow::{anyhow, Result};
+use candid::Encode;
+use clap::Args;
+use ic_management_canister_types::CanisterInstallMode;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub(crate) struct CandidUiArgs {
+    /// URL of a node on the target subnet
+    #[clap(long)]
+    url: Option<String>,
+    /// Subnet to install the canister on, the first application subnet by
+    /// default
+    #[clap(long)]
+    subnet: Option<SubnetIndex>,
+    /// Candid UI wasm (didjs)
+    #[clap(
+        long,
+        env = "CANDID_UI_WASM",
+        default_value = "ic/artifacts/canisters/didjs.wasm.gz"
+    )]
+    wasm: PathBuf,
+    /// Gateway serving canister http requests, `ic-testnet gateway` on its
+    /// default port by default
+    #[clap(long)]
+    gateway: Option<String>,
+}
+
+pub(crate) fn run(args: CandidUiArgs) -> Result<()> {
+    let wasm = fs::read(&args.wasm)?;
+    let topology = topology::load()?;
+    let subnet = match args.subnet {
+        Some(subnet) => subnet,
+        None => {
+            topology
+                .subnets
+                .iter()
+                .find(|subnet| subnet.index != topology.nns_subnet)
+                .ok_or(anyhow!(
+                    "the testnet has no application subnet, pass --subnet"
+                ))?
+                .index
+        }
+    };
+    let url = match args.url {
+        Some(url) => url,
+        None => topology.subnet_url(subnet)?,
+    };
+    let gateway = match args.gateway {
+        Some(gateway) => gateway,
+        None => format!("http://127.0.0.1:{}", gateway_port()?),
+    };
+
+    let canister_id = block_on(async {
+        let agent = connect(&url, None).await?;
+        let canister_id = create_canister(&agent, subnet, None).await?;
+        install_code(
+            &agent,
+            canister_id,
+            CanisterInstallMode::Install,
+            wasm,
+            Encode!()?,
+        )
+        .await?;
+
+        Ok(canister_id)
+    })?;
+
+    println!("Candid UI {}", canister_id);
+    println!("{}/?canisterId={}", gateway, canister_id);
+
+    Ok(())
+}
@@ -0,0 +1,963 @@
+use crate::agent::{block_on, connect, create_canister_with_id};
+use crate::identities;
+use crate::ledgers::ICP_LEDGER_CANISTER_ID;
+use crate::topology;
+use anyhow::{anyhow, bail, Context, Result};
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use clap::Subcommand;
+use ic_agent::Agent;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::de::DeserializeOwned;
+use serde::Deserializer;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// Upload calls stay below the 2 MiB ingress message limit.
+const UPLOAD_CHUNK_SIZE: usize = 1_900_000;
+const CYCLES_MINTING_CANISTER_ID: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+// Memo of ledger transfers the cycles minting canister turns into a top-up.
+const MEMO_TOP_UP: u64 = 0x50555054;
+const ICP_TRANSFER_FEE_E8S: u64 = 10_000;
+
+#[derive(Subcommand)]
+pub(crate) enum CanisterCommand {
+    /// Replace the state of a canister with a snapshot downloaded elsewhere,
+    /// e.g. from mainnet or PocketIC
+    ImportState {
+        canister: String,
+        /// Directory with metadata.json, wasm_module.bin, wasm_memory.bin,
+        /// stable_memory.bin and wasm_chunk_store/
+        dir: PathBuf,
+        /// Controller of the canister, anonymous by default
+        #[clap(long)]
+        identity: Option<String>,
+    },
+    /// Take, restore and list snapshots of a canister
+    #[clap(subcommand)]
+    Snapshot(CanisterSnapshotCommand),
+    /// Create a canister with a given id, e.g. the mainnet id of a canister
+    /// that code depends on
+    Create {
+        #[clap(long)]
+        id: String,
+        /// Subnet to create the canister on, by default the subnet whose
+        /// range holds the id or else the NNS subnet
+        #[clap(long)]
+        subnet: Option<SubnetIndex>,
+        #[clap(long)]
+        cycles: Option<u128>,
+    },
+    /// Change the controllers, allocations and freezing threshold of a
+    /// canister, settings left out keep their value
+    UpdateSettings {
+        canister: String,
+        /// Principal of a controller, all of them replace the current ones
+        #[clap(long = "controller")]
+        controllers: Vec<String>,
+        /// Percent of an execution core reserved for the canister
+        #[clap(long)]
+        compute_allocation: Option<u64>,
+        /// Bytes of memory reserved for the canister, 0 for best effort
+        #[clap(long)]
+        memory_allocation: Option<u64>,
+        /// Seconds of idle cycles burn the canister keeps in reserve
+        #[clap(long)]
+        freezing_threshold: Option<u64>,
+        /// A controller of the canister, anonymous by default
+        #[clap(long)]
+        identity: Option<String>,
+    },
+    /// Upload a directory to an asset canister, replacing its assets
+    UploadAssets {
+        dir: PathBuf,
+        #[clap(long)]
+        canister: String,
+        /// Identity with the commit permission, e.g. a controller
+        #[clap(long)]
+        identity: Option<String>,
+    },
+    /// Add cycles to a canister
+    TopUp {
+        canister: String,
+        #[clap(long)]
+        cycles: u128,
+        /// Identity paying ICP to the cycles minting canister when the
+        /// testnet refuses provisional top-ups, the first generated identity
+        /// by default
+        #[clap(long)]
+        identity: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub(crate) enum CanisterSnapshotCommand {
+    /// Snapshot the state of a canister
+    Take {
+        canister: String,
+        /// Hex id of a snapshot to replace, canisters keep a single snapshot
+        /// by default
+        #[clap(long)]
+        replace: Option<String>,
+        #[clap(long)]
+        identity: Option<String>,
+    },
+    /// Roll a canister back to a snapshot
+    Restore {
+        canister: String,
+        /// Hex id of the snapshot
+        snapshot: String,
+        #[clap(long)]
+        identity: Option<String>,
+    },
+    /// List the snapshots of a canister
+    List {
+        canister: String,
+        #[clap(long)]
+        identity: Option<String>,
+    },
+}
+
+#[derive(CandidType)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Global {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    V128(candid::Nat),
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GlobalTimer {
+    Inactive,
+    Active(u64),
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LowWasmMemoryHookStatus {
+    ConditionNotSatisfied,
+    Ready,
+    Executed,
+}
+
+// metadata.json holds the reply of `read_canister_snapshot_metadata`.
+#[derive(Deserialize)]
+struct SnapshotMetadata {
+    #[serde(default)]
+    globals: Vec<Global>,
+    #[serde(deserialize_with = "hex_bytes", default)]
+    certified_data: Vec<u8>,
+    global_timer: Option<GlobalTimer>,
+    on_low_wasm_memory_hook_status: Option<LowWasmMemoryHookStatus>,
+}
+
+fn hex_bytes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(d)?;
+    hex::decode(hex).map_err(serde::de::Error::custom)
+}
+
+#[derive(CandidType)]
+struct UploadMetadataArgs {
+    canister_id: Principal,
+    replace_snapshot: Option<Vec<u8>>,
+    wasm_module_size: u64,
+    globals: Vec<Global>,
+    wasm_memory_size: u64,
+    stable_memory_size: u64,
+    certified_data: Vec<u8>,
+    global_timer: Option<GlobalTimer>,
+    on_low_wasm_memory_hook_status: Option<LowWasmMemoryHookStatus>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SnapshotIdRecord {
+    snapshot_id: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct TakeSnapshotArgs {
+    canister_id: Principal,
+    replace_snapshot: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Snapshot {
+    id: Vec<u8>,
+    taken_at_timestamp: u64,
+    total_size: u64,
+}
+
+#[derive(CandidType)]
+struct Offset {
+    offset: u64,
+}
+
+#[derive(CandidType)]
+#[allow(non_camel_case_types)]
+enum SnapshotDataKind {
+    wasm_module(Offset),
+    wasm_memory(Offset),
+    stable_memory(Offset),
+    wasm_chunk,
+}
+
+#[derive(CandidType)]
+struct UploadDataArgs {
+    canister_id: Principal,
+    snapshot_id: Vec<u8>,
+    kind: SnapshotDataKind,
+    chunk: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct LoadSnapshotArgs {
+    canister_id: Principal,
+    snapshot_id: Vec<u8>,
+    sender_canister_version: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct CanisterSettings {
+    controllers: Option<Vec<Principal>>,
+    compute_allocation: Option<Nat>,
+    memory_allocation: Option<Nat>,
+    freezing_threshold: Option<Nat>,
+}
+
+#[derive(CandidType)]
+struct UpdateSettingsArgs {
+    canister_id: Principal,
+    settings: CanisterSettings,
+}
+
+// The settings in the reply of `canister_status`, which has them all set.
+#[derive(CandidType, Deserialize)]
+struct DefiniteCanisterSettings {
+    controllers: Vec<Principal>,
+    compute_allocation: Nat,
+    memory_allocation: Nat,
+    freezing_threshold: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterStatus {
+    settings: DefiniteCanisterSettings,
+}
+
+#[derive(CandidType, Deserialize)]
+struct BatchId {
+    batch_id: Nat,
+}
+
+#[derive(CandidType)]
+struct CreateChunkArgs {
+    batch_id: Nat,
+    content: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ChunkId {
+    chunk_id: Nat,
+}
+
+#[derive(CandidType)]
+struct CreateAssetArgs {
+    key: String,
+    content_type: String,
+    max_age: Option<u64>,
+    headers: Option<Vec<(String, String)>>,
+    enable_aliasing: Option<bool>,
+    allow_raw_access: Option<bool>,
+}
+
+#[derive(CandidType)]
+struct SetAssetContentArgs {
+    key: String,
+    content_encoding: String,
+    chunk_ids: Vec<Nat>,
+    sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+struct DeleteAssetArgs {
+    key: String,
+}
+
+#[derive(CandidType)]
+enum BatchOperation {
+    CreateAsset(CreateAssetArgs),
+    SetAssetContent(SetAssetContentArgs),
+    DeleteAsset(DeleteAssetArgs),
+}
+
+#[derive(CandidType)]
+struct CommitBatchArgs {
+    batch_id: Nat,
+    operations: Vec<BatchOperation>,
+}
+
+#[derive(CandidType)]
+struct EmptyArgs {}
+
+#[derive(CandidType, Deserialize)]
+struct AssetDetails {
+    key: String,
+}
+
+#[derive(CandidType)]
+struct TopUpArgs {
+    canister_id: Principal,
+    amount: Nat,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+struct Tokens {
+    e8s: u64,
+}
+
+#[derive(CandidType)]
+struct TransferArgs {
+    memo: u64,
+    amount: Tokens,
+    fee: Tokens,
+    from_subaccount: Option<Vec<u8>>,
+    to: Vec<u8>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+#[derive(CandidType)]
+struct NotifyTopUpArgs {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpXdrConversionRate {
+    xdr_permyriad_per_icp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpXdrConversionRateResponse {
+    data: IcpXdrConversionRate,
+}
+
+async fn management_call<R: CandidType + DeserializeOwned>(
+    agent: &Agent,
+    method: &str,
+    canister_id: Principal,
+    arg: Vec<u8>,
+) -> Result<R> {
+    let reply = agent
+        .update(&Principal::management_canister(), method)
+        .with_effective_canister_id(canister_id)
+        .with_arg(arg)
+        .call_and_wait()
+        .await
+        .with_context(|| format!("{} failed", method))?;
+    Ok(Decode!(&reply, R)?)
+}
+
+fn read(dir: &Path, file: &str) -> Result<Vec<u8>> {
+    let path = dir.join(file);
+    fs::read(&path).with_context(|| format!("failed to read {:?}", path))
+}
+
+async fn upload(
+    agent: &Agent,
+    canister_id: Principal,
+    snapshot_id: &[u8],
+    data: &[u8],
+    kind: impl Fn(u64) -> SnapshotDataKind,
+) -> Result<()> {
+    for (i, chunk) in data.chunks(UPLOAD_CHUNK_SIZE).enumerate() {
+        let args = UploadDataArgs {
+            canister_id,
+            snapshot_id: snapshot_id.to_vec(),
+            kind: kind((i * UPLOAD_CHUNK_SIZE) as u64),
+            chunk: chunk.to_vec(),
+        };
+        management_call::<()>(
+            agent,
+            "upload_canister_snapshot_data",
+            canister_id,
+            Encode!(&args)?,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn import_state(agent: &Agent, canister_id: Principal, dir: &Path) -> Result<()> {
+    let metadata: SnapshotMetadata =
+        serde_json::from_slice(&read(dir, "metadata.json")?).context("invalid metadata.json")?;
+    let wasm_module = read(dir, "wasm_module.bin")?;
+    let wasm_memory = read(dir, "wasm_memory.bin")?;
+    let stable_memory = read(dir, "stable_memory.bin")?;
+    let mut chunks = vec![];
+    if let Ok(entries) = fs::read_dir(dir.join("wasm_chunk_store")) {
+        for entry in entries {
+            chunks.push(fs::read(entry?.path())?);
+        }
+    }
+
+    let args = UploadMetadataArgs {
+        canister_id,
+        replace_snapshot: None,
+        wasm_module_size: wasm_module.len() as u64,
+        globals: metadata.globals,
+        wasm_memory_size: wasm_memory.len() as u64,
+        stable_memory_size: stable_memory.len() as u64,
+        certified_data: metadata.certified_data,
+        global_timer: metadata.global_timer,
+        on_low_wasm_memory_hook_status: metadata.on_low_wasm_memory_hook_status,
+    };
+    let snapshot_id = management_call::<SnapshotIdRecord>(
+        agent,
+        "upload_canister_snapshot_metadata",
+        canister_id,
+        Encode!(&args)?,
+    )
+    .await
+    .map_err(|e| {
+        anyhow!(
+            "{:#}, is the replica recent enough to accept snapshot uploads?",
+            e
+        )
+    })?
+    .snapshot_id;
+
+    upload(agent, canister_id, &snapshot_id, &wasm_module, |offset| {
+        SnapshotDataKind::wasm_module(Offset { offset })
+    })
+    .await?;
+    upload(agent, canister_id, &snapshot_id, &wasm_memory, |offset| {
+        SnapshotDataKind::wasm_memory(Offset { offset })
+    })
+    .await?;
+    upload(agent, canister_id, &snapshot_id, &stable_memory, |offset| {
+        SnapshotDataKind::stable_memory(Offset { offset })
+    })
+    .await?;
+    for chunk in &chunks {
+        upload(agent, canister_id, &snapshot_id, chunk, |_| {
+            SnapshotDataKind::wasm_chunk
+        })
+        .await?;
+    }
+
+    load_snapshot(agent, canister_id, snapshot_id).await
+}
+
+// A snapshot replaces the state of a stopped canister only.
+async fn load_snapshot(agent: &Agent, canister_id: Principal, snapshot_id: Vec<u8>) -> Result<()> {
+    let record = Encode!(&CanisterIdRecord { canister_id })?;
+    management_call::<()>(agent, "stop_canister", canister_id, record.clone()).await?;
+    let args = LoadSnapshotArgs {
+        canister_id,
+        snapshot_id,
+        sender_canister_version: None,
+    };
+    let loaded = management_call::<()>(
+        agent,
+        "load_canister_snapshot",
+        canister_id,
+        Encode!(&args)?,
+    )
+    .await;
+    // restart the canister either way, it ran before
+    management_call::<()>(agent, "start_canister", canister_id, record).await?;
+
+    loaded
+}
+
+async fn snapshot(
+    agent: &Agent,
+    canister_id: Principal,
+    cmd: CanisterSnapshotCommand,
+) -> Result<()> {
+    match cmd {
+        CanisterSnapshotCommand::Take { replace, .. } => {
+            let args = TakeSnapshotArgs {
+                canister_id,
+                replace_snapshot: replace.map(hex::decode).transpose()?,
+            };
+            let snapshot = management_call::<Snapshot>(
+                agent,
+                "take_canister_snapshot",
+                canister_id,
+                Encode!(&args)?,
+            )
+            .await?;
+            println!(
+                "took snapshot {} of {} ({} bytes)",
+                hex::encode(&snapshot.id),
+                canister_id,
+                snapshot.total_size
+            );
+        }
+        CanisterSnapshotCommand::Restore { snapshot, .. } => {
+            load_snapshot(agent, canister_id, hex::decode(&snapshot)?).await?;
+            println!("restored {} from snapshot {}", canister_id, snapshot);
+        }
+        CanisterSnapshotCommand::List { .. } => {
+            let snapshots = management_call::<Vec<Snapshot>>(
+                agent,
+                "list_canister_snapshots",
+                canister_id,
+                Encode!(&CanisterIdRecord { canister_id })?,
+            )
+            .await?;
+            for snapshot in &snapshots {
+                println!(
+                    "{} taken at {} (unix seconds) {} bytes",
+                    hex::encode(&snapshot.id),
+                    snapshot.taken_at_timestamp / 1_000_000_000,
+                    snapshot.total_size
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn update_settings(
+    agent: &Agent,
+    canister_id: Principal,
+    settings: CanisterSettings,
+) -> Result<()> {
+    let args = UpdateSettingsArgs {
+        canister_id,
+        settings,
+    };
+    management_call::<()>(agent, "update_settings", canister_id, Encode!(&args)?).await?;
+
+    let status = management_call::<CanisterStatus>(
+        agent,
+        "canister_status",
+        canister_id,
+        Encode!(&CanisterIdRecord { canister_id })?,
+    )
+    .await?;
+    let settings = status.settings;
+    let controllers: Vec<String> = settings
+        .controllers
+        .iter()
+        .map(|controller| controller.to_string())
+        .collect();
+    println!("controllers: {}", controllers.join(", "));
+    println!("compute allocation: {}%", settings.compute_allocation);
+    println!("memory allocation: {} bytes", settings.memory_allocation);
+    println!(
+        "freezing threshold: {} seconds",
+        settings.freezing_threshold
+    );
+
+    Ok(())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" | "map" => "application/json",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+// Files below a directory with their asset key, the path below the directory
+// with a leading slash.
+fn asset_files(dir: &Path, key: &str, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let key = format!("{}/{}", key, name);
+        if path.is_dir() {
+            asset_files(&path, &key, files)?;
+        } else {
+            files.push((key, path));
+        }
+    }
+    Ok(())
+}
+
+async fn asset_call<R: CandidType + DeserializeOwned>(
+    agent: &Agent,
+    canister_id: Principal,
+    method: &str,
+    arg: Vec<u8>,
+) -> Result<R> {
+    let reply = agent
+        .update(&canister_id, method)
+        .with_arg(arg)
+        .call_and_wait()
+        .await
+        .with_context(|| format!("{} failed", method))?;
+    Ok(Decode!(&reply, R)?)
+}
+
+// The chunked upload of asset canisters: chunks go into a batch, which one
+// commit turns into the new assets, so visitors never see half a site.
+async fn upload_assets(agent: &Agent, canister_id: Principal, dir: &Path) -> Result<usize> {
+    let mut files = vec![];
+    asset_files(dir, "", &mut files)?;
+
+    let reply = agent
+        .query(&canister_id, "list")
+        .with_arg(Encode!(&EmptyArgs {})?)
+        .call()
+        .await
+        .context("list failed, is it an asset canister?")?;
+    let existing = Decode!(&reply, Vec<AssetDetails>)?;
+
+    let batch_id =
+        asset_call::<BatchId>(agent, canister_id, "create_batch", Encode!(&EmptyArgs {})?)
+            .await?
+            .batch_id;
+    let mut operations: Vec<BatchOperation> = existing
+        .into_iter()
+        .map(|asset| BatchOperation::DeleteAsset(DeleteAssetArgs { key: asset.key }))
+        .collect();
+    for (key, path) in &files {
+        let content = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        // empty files still get a chunk
+        let chunks: Vec<&[u8]> = if content.is_empty() {
+            vec![&[]]
+        } else {
+            content.chunks(UPLOAD_CHUNK_SIZE).collect()
+        };
+        let mut chunk_ids = vec![];
+        for chunk in chunks {
+            let args = CreateChunkArgs {
+                batch_id: batch_id.clone(),
+                content: chunk.to_vec(),
+            };
+            let chunk_id =
+                asset_call::<ChunkId>(agent, canister_id, "create_chunk", Encode!(&args)?)
+                    .await?
+                    .chunk_id;
+            chunk_ids.push(chunk_id);
+        }
+        println!(
+            "{} ({} bytes, {} chunks)",
+            key,
+            content.len(),
+            chunk_ids.len()
+        );
+
+        operations.push(BatchOperation::CreateAsset(CreateAssetArgs {
+            key: key.clone(),
+            content_type: content_type(path).to_string(),
+            max_age: None,
+            headers: None,
+            enable_aliasing: None,
+            allow_raw_access: None,
+        }));
+        operations.push(BatchOperation::SetAssetContent(SetAssetContentArgs {
+            key: key.clone(),
+            content_encoding: "identity".to_string(),
+            chunk_ids,
+            sha256: Some(Sha256::digest(&content).to_vec()),
+        }));
+    }
+
+    let args = CommitBatchArgs {
+        batch_id,
+        operations,
+    };
+    asset_call::<()>(agent, canister_id, "commit_batch", Encode!(&args)?).await?;
+
+    Ok(files.len())
+}
+
+// The testnets `init` creates whitelist every principal for the provisional
+// API, which creates cycles out of thin air.
+async fn provisional_top_up(agent: &Agent, canister_id: Principal, cycles: u128) -> Result<()> {
+    let args = TopUpArgs {
+        canister_id,
+        amount: Nat::from(cycles),
+    };
+    management_call::<()>(
+        agent,
+        "provisional_top_up_canister",
+        canister_id,
+        Encode!(&args)?,
+    )
+    .await
+}
+
+// Cycles bought the way mainnet does: a ledger transfer to the subaccount of
+// the canister at the cycles minting canister, which then mints the cycles.
+async fn cmc_top_up(agent: &Agent, canister_id: Principal, cycles: u128) -> Result<Nat> {
+    let cmc = Principal::from_text(CYCLES_MINTING_CANISTER_ID)?;
+    let reply = agent
+        .query(&cmc, "get_icp_xdr_conversion_rate")
+        .with_arg(Encode!()?)
+        .call()
+        .await
+        .context("the cycles minting canister is not installed")?;
+    // an ICP is worth rate / 10_000 XDR of 10^12 cycles each, so an e8s
+    // buys `rate` cycles
+    let rate = Decode!(&reply, IcpXdrConversionRateResponse)?
+        .data
+        .xdr_permyriad_per_icp as u128;
+    if rate == 0 {
+        bail!("the cycles minting canister has no ICP/XDR conversion rate");
+    }
+    let e8s = cycles.div_ceil(rate) as u64;
+
+    let mut subaccount = [0u8; 32];
+    let id = canister_id.as_slice();
+    subaccount[0] = id.len() as u8;
+    subaccount[1..=id.len()].copy_from_slice(id);
+    let args = TransferArgs {
+        memo: MEMO_TOP_UP,
+        amount: Tokens { e8s },
+        fee: Tokens {
+            e8s: ICP_TRANSFER_FEE_E8S,
+        },
+        from_subaccount: None,
+        to: identities::account_id_bytes(cmc.as_slice(), subaccount),
+        created_at_time: None,
+    };
+    let reply = agent
+        .update(&Principal::from_text(ICP_LEDGER_CANISTER_ID)?, "transfer")
+        .with_arg(Encode!(&args)?)
+        .call_and_wait()
+        .await
+        .context("transfer failed")?;
+    let block_index = Decode!(&reply, Result<u64, TransferError>)?
+        .map_err(|e| anyhow!("transfer of {} e8s failed: {:?}", e8s, e))?;
+
+    let args = NotifyTopUpArgs {
+        block_index,
+        canister_id,
+    };
+    loop {
+        let reply = agent
+            .update(&cmc, "notify_top_up")
+            .with_arg(Encode!(&args)?)
+            .call_and_wait()
+            .await
+            .context("notify_top_up failed")?;
+        match Decode!(&reply, Result<Nat, NotifyError>)? {
+            Ok(minted) => return Ok(minted),
+            Err(NotifyError::Processing) => thread::sleep(Duration::from_secs(1)),
+            Err(e) => bail!("notify_top_up of block {} failed: {:?}", block_index, e),
+        }
+    }
+}
+
+async fn top_up(canister_id: Principal, cycles: u128, identity: Option<String>) -> Result<String> {
+    let topology = topology::load()?;
+    let agent = connect(&topology.canister_url(&canister_id)?, None).await?;
+    let refused = match provisional_top_up(&agent, canister_id, cycles).await {
+        Ok(()) => return Ok(format!("topped up {} with {} cycles", canister_id, cycles)),
+        Err(e) => e,
+    };
+
+    let identity = match identity {
+        Some(name) => identities::find(&name)?,
+        None => identities::load()?
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("no generated identities"))?,
+    };
+    let cmc = Principal::from_text(CYCLES_MINTING_CANISTER_ID)?;
+    let agent = connect(&topology.canister_url(&cmc)?, Some(&identity.pem)).await?;
+    let minted = cmc_top_up(&agent, canister_id, cycles).await.map_err(|e| {
+        anyhow!(
+            "{:#}, after the provisional top-up failed: {:#}",
+            e,
+            refused
+        )
+    })?;
+    Ok(format!(
+        "topped up {} with {} cycles bought by {}",
+        canister_id, minted, identity.name
+    ))
+}
+
+fn agent_for(
+    canister: &str,
+    identity: Option<String>,
+) -> Result<(Principal, String, Option<PathBuf>)> {
+    let canister_id = Principal::from_text(canister)?;
+    let url = topology::load()?.canister_url(&canister_id)?;
+    let pem = match identity {
+        Some(name) => Some(identities::find(&name)?.pem),
+        None => None,
+    };
+
+    Ok((canister_id, url, pem))
+}
+
+pub(crate) fn run(cmd: CanisterCommand) -> Result<()> {
+    match cmd {
+        CanisterCommand::ImportState {
+            canister,
+            dir,
+            identity,
+        } => {
+            if !dir.is_dir() {
+                bail!("{:?} is not a directory", dir);
+            }
+            let (canister_id, url, pem) = agent_for(&canister, identity)?;
+            block_on(async {
+                let agent = connect(&url, pem.as_deref()).await?;
+                import_state(&agent, canister_id, &dir).await
+            })?;
+            println!("imported the state in {:?} into {}", dir, canister_id);
+        }
+        CanisterCommand::Snapshot(cmd) => {
+            let (canister, identity) = match &cmd {
+                CanisterSnapshotCommand::Take {
+                    canister, identity, ..
+                }
+                | CanisterSnapshotCommand::Restore {
+                    canister, identity, ..
+                }
+                | CanisterSnapshotCommand::List { canister, identity } => {
+                    (canister.clone(), identity.clone())
+                }
+            };
+            let (canister_id, url, pem) = agent_for(&canister, identity)?;
+            block_on(async {
+                let agent = connect(&url, pem.as_deref()).await?;
+                snapshot(&agent, canister_id, cmd).await
+            })?;
+        }
+        CanisterCommand::Create { id, subnet, cycles } => {
+            let canister_id = Principal::from_text(&id)?;
+            let topology = topology::load()?;
+            let url = match subnet {
+                Some(subnet) => topology.subnet_url(subnet)?,
+                None => topology
+                    .canister_url(&canister_id)
+                    .or_else(|_| topology.subnet_url(topology.nns_subnet))?,
+            };
+            block_on(async {
+                let agent = connect(&url, None).await?;
+                create_canister_with_id(&agent, canister_id, cycles)
+                    .await
+                    .map_err(|e| {
+                        anyhow!(
+                            "{:#}, was the testnet initialized with --use-specified-ids-allocation-range?",
+                            e
+                        )
+                    })
+            })?;
+            println!("created {}", canister_id);
+        }
+        CanisterCommand::UpdateSettings {
+            canister,
+            controllers,
+            compute_allocation,
+            memory_allocation,
+            freezing_threshold,
+            identity,
+        } => {
+            let settings = CanisterSettings {
+                controllers: if controllers.is_empty() {
+                    None
+                } else {
+                    Some(
+                        controllers
+                            .iter()
+                            .map(Principal::from_text)
+                            .collect::<Result<_, _>>()?,
+                    )
+                },
+                compute_allocation: compute_allocation.map(Nat::from),
+                memory_allocation: memory_allocation.map(Nat::from),
+                freezing_threshold: freezing_threshold.map(Nat::from),
+            };
+            let (canister_id, url, pem) = agent_for(&canister, identity)?;
+            block_on(async {
+                let agent = connect(&url, pem.as_deref()).await?;
+                update_settings(&agent, canister_id, settings).await
+            })?;
+        }
+        CanisterCommand::UploadAssets {
+            dir,
+            canister,
+            identity,
+        } => {
+            if !dir.is_dir() {
+                bail!("{:?} is not a directory", dir);
+            }
+            let (canister_id, url, pem) = agent_for(&canister, identity)?;
+            let uploaded = block_on(async {
+                let agent = connect(&url, pem.as_deref()).await?;
+                upload_assets(&agent, canister_id, &dir).await
+            })?;
+            println!("uploaded {} assets to {}", uploaded, canister_id);
+        }
+        CanisterCommand::TopUp {
+            canister,
+            cycles,
+            identity,
+        } => {
+            let canister_id = Principal::from_text(&canister)?;
+            println!("{}", block_on(top_up(canister_id, cycles, identity))?);
+        }
+    }
+
+    Ok(())
+}
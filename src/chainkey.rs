@@ -0,0 +1,242 @@
+use crate::agent::{block_on, connect, create_canister, install_code};
+use crate::init::{nns_url, output_dir};
+use crate::routing::latest_record;
+use crate::topology;
+use anyhow::{anyhow, bail, Result};
+use candid::Principal;
+use clap::Subcommand;
+use ic_agent::Agent;
+use ic_management_canister_types::{
+    CanisterInstallMode, DerivationPath, ECDSAPublicKeyArgs, ECDSAPublicKeyResponse,
+    MasterPublicKeyId, Method, Payload, SchnorrAlgorithm, SchnorrPublicKeyArgs,
+    SchnorrPublicKeyResponse, SignWithECDSAArgs, SignWithECDSAReply, SignWithSchnorrArgs,
+    SignWithSchnorrReply, IC_00,
+};
+use ic_protobuf::registry::subnet::v1::SubnetRecord;
+use ic_registry_keys::make_subnet_record_key;
+use ic_registry_subnet_features::ChainKeyConfig;
+use ic_types::{Cycles, PrincipalId, SubnetId};
+use ic_universal_canister::{call_args, wasm, UNIVERSAL_CANISTER_WASM};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use std::time::Instant;
+
+// Generous upper bound for the signing fee on any subnet size.
+const SIGNING_CYCLES: u128 = 100_000_000_000;
+
+#[derive(Subcommand)]
+pub(crate) enum ChainKeyCommand {
+    /// Request a threshold signature through a test canister and verify it
+    TestSign {
+        /// Master key id, e.g. ecdsa:Secp256k1:key_1 or schnorr:Ed25519:key_1
+        #[clap(long)]
+        key_id: String,
+        /// Subnet to deploy the signing canister on
+        #[clap(long, default_value = "0")]
+        subnet: u64,
+        #[clap(long)]
+        url: Option<String>,
+    },
+}
+
+// The universal canister forwards the call to the management canister and
+// replies with its response.
+async fn call_through(
+    agent: &Agent,
+    canister_id: Principal,
+    method: Method,
+    args: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let payload = wasm()
+        .call_with_cycles(
+            IC_00.get().as_slice(),
+            method.to_string(),
+            call_args().other_side(args),
+            Cycles::new(SIGNING_CYCLES),
+        )
+        .build();
+
+    Ok(agent
+        .update(&canister_id, "update")
+        .with_arg(payload)
+        .call_and_wait()
+        .await?)
+}
+
+fn decode<'a, T: Payload<'a>>(bytes: &'a [u8]) -> Result<T> {
+    T::decode(bytes).map_err(|e| anyhow!("{}", e))
+}
+
+fn verify(
+    key_id: &MasterPublicKeyId,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    match key_id {
+        MasterPublicKeyId::Ecdsa(_) => {
+            use k256::ecdsa::signature::hazmat::PrehashVerifier;
+            let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)?;
+            key.verify_prehash(message, &k256::ecdsa::Signature::from_slice(signature)?)?;
+        }
+        MasterPublicKeyId::Schnorr(key) => match key.algorithm {
+            SchnorrAlgorithm::Ed25519 => {
+                use ed25519_dalek::Verifier;
+                let key = ed25519_dalek::VerifyingKey::from_bytes(public_key.try_into()?)?;
+                key.verify(message, &ed25519_dalek::Signature::from_slice(signature)?)?;
+            }
+            SchnorrAlgorithm::Bip340Secp256k1 => {
+                // the public key is SEC1 compressed, BIP340 uses the x coordinate
+                let key = k256::schnorr::VerifyingKey::from_bytes(&public_key[1..])?;
+                key.verify_raw(message, &k256::schnorr::Signature::try_from(signature)?)?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+// The subnets whose record in the registry of `init` lists the key, where
+// the management canister routes its requests.
+fn key_subnets(key_id: &MasterPublicKeyId) -> Result<Vec<u64>> {
+    let topology = topology::load()?;
+    let store = output_dir()?.join("state").join("ic_registry_local_store");
+    let mut subnets = vec![];
+    for subnet in &topology.subnets {
+        let subnet_id = SubnetId::from(
+            PrincipalId::from_str(&subnet.subnet_id)
+                .map_err(|e| anyhow!("invalid subnet id {}: {}", subnet.subnet_id, e))?,
+        );
+        let (_, record) = latest_record(&store, &make_subnet_record_key(subnet_id))?;
+        let Some(record) = record else {
+            continue;
+        };
+        let Some(config) = SubnetRecord::decode(record.as_slice())?.chain_key_config else {
+            continue;
+        };
+        let config = ChainKeyConfig::try_from(config).map_err(|e| {
+            anyhow!(
+                "invalid chain key config of subnet {}: {:?}",
+                subnet.index,
+                e
+            )
+        })?;
+        if config.key_configs.iter().any(|key| key.key_id == *key_id) {
+            subnets.push(subnet.index);
+        }
+    }
+    Ok(subnets)
+}
+
+async fn test_sign(url: String, subnet: u64, key_id: MasterPublicKeyId) -> Result<()> {
+    let agent = connect(&url, None).await?;
+    let canister_id = create_canister(&agent, subnet, None).await?;
+    install_code(
+        &agent,
+        canister_id,
+        CanisterInstallMode::Install,
+        UNIVERSAL_CANISTER_WASM.to_vec(),
+        vec![],
+    )
+    .await?;
+    println!("signing canister {}", canister_id);
+
+    let message: [u8; 32] = Sha256::digest(b"ic-testnet test-sign").into();
+    let derivation_path = DerivationPath::new(vec![]);
+
+    let (public_key_call, sign_call) = match &key_id {
+        MasterPublicKeyId::Ecdsa(key_id) => (
+            (
+                Method::ECDSAPublicKey,
+                ECDSAPublicKeyArgs {
+                    canister_id: None,
+                    derivation_path: derivation_path.clone(),
+                    key_id: key_id.clone(),
+                }
+                .encode(),
+            ),
+            (
+                Method::SignWithECDSA,
+                SignWithECDSAArgs {
+                    message_hash: message,
+                    derivation_path,
+                    key_id: key_id.clone(),
+                }
+                .encode(),
+            ),
+        ),
+        MasterPublicKeyId::Schnorr(key_id) => (
+            (
+                Method::SchnorrPublicKey,
+                SchnorrPublicKeyArgs {
+                    canister_id: None,
+                    derivation_path: derivation_path.clone(),
+                    key_id: key_id.clone(),
+                }
+                .encode(),
+            ),
+            (
+                Method::SignWithSchnorr,
+                SignWithSchnorrArgs {
+                    message: message.to_vec(),
+                    derivation_path,
+                    key_id: key_id.clone(),
+                }
+                .encode(),
+            ),
+        ),
+    };
+
+    let reply = call_through(&agent, canister_id, public_key_call.0, public_key_call.1).await?;
+    let public_key = match &key_id {
+        MasterPublicKeyId::Ecdsa(_) => decode::<ECDSAPublicKeyResponse>(&reply)?.public_key,
+        MasterPublicKeyId::Schnorr(_) => decode::<SchnorrPublicKeyResponse>(&reply)?.public_key,
+    };
+
+    let start = Instant::now();
+    let reply = call_through(&agent, canister_id, sign_call.0, sign_call.1).await?;
+    let latency = start.elapsed();
+    let signature = match &key_id {
+        MasterPublicKeyId::Ecdsa(_) => decode::<SignWithECDSAReply>(&reply)?.signature,
+        MasterPublicKeyId::Schnorr(_) => decode::<SignWithSchnorrReply>(&reply)?.signature,
+    };
+
+    verify(&key_id, &public_key, &message, &signature)?;
+
+    println!("public key {}", hex::encode(&public_key));
+    println!("signature {}", hex::encode(&signature));
+    println!("verified, signing took {:?}", latency);
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: ChainKeyCommand) -> Result<()> {
+    match cmd {
+        ChainKeyCommand::TestSign {
+            key_id,
+            subnet,
+            url,
+        } => {
+            let key_id = MasterPublicKeyId::from_str(&key_id).map_err(|e| anyhow!("{}", e))?;
+            // the management canister would only reject the request
+            let holders = key_subnets(&key_id)?;
+            if holders.is_empty() {
+                bail!(
+                    "key {} is not configured on subnet {}, generate it with `init --chain-key {}={}`",
+                    key_id,
+                    subnet,
+                    subnet,
+                    key_id
+                );
+            }
+            if !holders.contains(&subnet) {
+                println!(
+                    "key {} is held by subnet {}, signing goes through xnet",
+                    key_id, holders[0]
+                );
+            }
+            block_on(test_sign(url.unwrap_or(nns_url()), subnet, key_id))
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use crate::backend;
+use crate::config::Selector;
+use crate::init::output_dir;
+use crate::metrics::finalized_height;
+use crate::process;
+use crate::topology::{self, Topology};
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+// Tags every rule added by ic-testnet so that `heal` removes exactly those.
+const RULE_COMMENT: &str = "ic-testnet-chaos";
+
+#[derive(Subcommand)]
+pub(crate) enum ChaosCommand {
+    /// Change the clock offset of a node, effective immediately
+    ClockSkew {
+        #[clap(long, required_unless_present = "select")]
+        node: Option<NodeIndex>,
+        /// Nodes by label instead, e.g. zone=b
+        #[clap(long, conflicts_with = "node")]
+        select: Option<Selector>,
+        /// libfaketime offset such as +2s or -1m
+        #[clap(long, allow_hyphen_values = true)]
+        offset: String,
+    },
+    /// Delay every transport packet to and from a node, needs a testnet
+    /// initialized with --transport-proxy
+    Latency {
+        #[clap(long, required_unless_present = "select")]
+        node: Option<NodeIndex>,
+        #[clap(long, conflicts_with = "node")]
+        select: Option<Selector>,
+        /// One-way delay in milliseconds, 0 removes it
+        #[clap(long)]
+        ms: u64,
+    },
+    /// Drop all traffic between nodes of different groups
+    Partition {
+        /// Comma separated nodes of a group, e.g. --group 100,101 --group 102
+        #[clap(long = "group", required = true)]
+        groups: Vec<String>,
+    },
+    /// Remove all partitions and latencies
+    Heal,
+    /// Kill a node's replica as soon as it finalizes the given height
+    KillAtHeight {
+        #[clap(long, required_unless_present = "select")]
+        node: Option<NodeIndex>,
+        #[clap(long, conflicts_with = "node")]
+        select: Option<Selector>,
+        #[clap(long)]
+        height: u64,
+        /// Milliseconds between height checks
+        #[clap(long, default_value = "20")]
+        poll_ms: u64,
+    },
+}
+
+// Latencies and partitions the transport proxies of `run` apply, re-read
+// while they run.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Shaping {
+    #[serde(default)]
+    pub latency_ms: BTreeMap<NodeIndex, u64>,
+    #[serde(default)]
+    pub groups: Vec<Vec<NodeIndex>>,
+}
+
+impl Shaping {
+    // One-way delay of a packet between a node and a peer, None when a
+    // partition separates them. Peers that are no node of the testnet are
+    // only delayed by the latency of the node.
+    pub(crate) fn delay(&self, node: NodeIndex, peer: Option<NodeIndex>) -> Option<Duration> {
+        let group = |node: NodeIndex| self.groups.iter().position(|group| group.contains(&node));
+        if let Some(peer) = peer {
+            if let (Some(a), Some(b)) = (group(node), group(peer)) {
+                if a != b {
+                    return None;
+                }
+            }
+        }
+        let latency = |node: NodeIndex| self.latency_ms.get(&node).copied().unwrap_or(0);
+        let ms = latency(node) + peer.map_or(0, latency);
+        Some(Duration::from_millis(ms))
+    }
+}
+
+fn shaping_file() -> Result<PathBuf> {
+    Ok(output_dir()?.join("chaos").join("shaping.json"))
+}
+
+pub(crate) fn load_shaping() -> Result<Shaping> {
+    let path = shaping_file()?;
+    if !path.is_file() {
+        return Ok(Shaping::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save_shaping(shaping: &Shaping) -> Result<()> {
+    let path = shaping_file()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // the proxies re-read the file at any time, so it is replaced at once
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, serde_json::to_string_pretty(shaping)?)?;
+    fs::rename(partial, path)?;
+    Ok(())
+}
+
+// Testnets initialized with --transport-proxy are shaped by the proxies,
+// all others with iptables.
+fn proxied(topology: &Topology) -> bool {
+    topology
+        .nodes()
+        .any(|node| matches!(process::proxied_transport(node.index), Ok(Some(_))))
+}
+
+pub(crate) fn set_latency(topology: &Topology, index: NodeIndex, ms: u64) -> Result<()> {
+    topology.node(index)?;
+    if !proxied(topology) {
+        bail!("latencies need a testnet initialized with --transport-proxy");
+    }
+    let mut shaping = load_shaping()?;
+    if ms == 0 {
+        shaping.latency_ms.remove(&index);
+    } else {
+        shaping.latency_ms.insert(index, ms);
+    }
+    save_shaping(&shaping)
+}
+
+fn iptables(args: &[&str]) -> Result<String> {
+    if !cfg!(target_os = "linux") {
+        bail!(
+            "network partitions use iptables and are not supported on {}",
+            std::env::consts::OS
+        );
+    }
+    let output = Command::new("iptables").args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "iptables {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Drops all traffic between nodes of different groups.
+pub(crate) fn partition(topology: &Topology, groups: &[Vec<NodeIndex>]) -> Result<()> {
+    if proxied(topology) {
+        for node in groups.iter().flatten() {
+            topology.node(*node)?;
+        }
+        let mut shaping = load_shaping()?;
+        shaping.groups = groups.to_vec();
+        return save_shaping(&shaping);
+    }
+    for (i, group) in groups.iter().enumerate() {
+        for other in groups.iter().skip(i + 1) {
+            for a in group {
+                for b in other {
+                    let a = topology.node(*a)?.addr()?.ip().to_string();
+                    let b = topology.node(*b)?.addr()?.ip().to_string();
+                    for (src, dst) in [(&a, &b), (&b, &a)] {
+                        iptables(&[
+                            "-I",
+                            "INPUT",
+                            "-s",
+                            src,
+                            "-d",
+                            dst,
+                            "-j",
+                            "DROP",
+                            "-m",
+                            "comment",
+                            "--comment",
+                            RULE_COMMENT,
+                        ])?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn heal(topology: &Topology) -> Result<()> {
+    if proxied(topology) {
+        return save_shaping(&Shaping::default());
+    }
+    let rules = iptables(&["-S", "INPUT"])?;
+    for rule in rules.lines().filter(|rule| rule.contains(RULE_COMMENT)) {
+        // `-A INPUT ...` becomes `-D INPUT ...`
+        let args: Vec<&str> = rule
+            .split_whitespace()
+            .map(|arg| if arg == "-A" { "-D" } else { arg })
+            .collect();
+        iptables(&args)?;
+    }
+
+    Ok(())
+}
+
+// Replicas of nodes with a clock file run under libfaketime, which re-reads
+// the offset from the file on every time query.
+pub(crate) fn clock_file(index: NodeIndex) -> Result<PathBuf> {
+    Ok(output_dir()?.join("clock").join(format!("node-{}", index)))
+}
+
+pub(crate) fn set_clock_skew(index: NodeIndex, offset: &str) -> Result<()> {
+    let (sign, rest) = offset.split_at(offset.len().min(1));
+    let number = rest.trim_end_matches(|c| "smhdy".contains(c));
+    if !(sign == "+" || sign == "-") || number.parse::<f64>().is_err() {
+        bail!("invalid clock offset {}, expected e.g. +2s or -1m", offset);
+    }
+
+    let file = clock_file(index)?;
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(file, format!("{}\n", offset))?;
+    Ok(())
+}
+
+// Seconds of a libfaketime offset such as +2s, -1m or +30d.
+pub(crate) fn offset_secs(offset: &str) -> Result<f64> {
+    let offset = offset.trim();
+    let unit = match offset.chars().last() {
+        Some('m') => 60.0,
+        Some('h') => 3600.0,
+        Some('d') => 86400.0,
+        Some('y') => 365.0 * 86400.0,
+        _ => 1.0,
+    };
+    let number = offset.trim_end_matches(|c| "smhdy".contains(c));
+    number
+        .parse::<f64>()
+        .map(|n| n * unit)
+        .map_err(|_| anyhow!("invalid clock offset {}", offset))
+}
+
+pub(crate) fn clock_offset(index: NodeIndex) -> Result<f64> {
+    match fs::read_to_string(clock_file(index)?) {
+        Ok(offset) => offset_secs(&offset),
+        Err(_) => Ok(0.0),
+    }
+}
+
+// Replicas only follow their clock file when they were started with one.
+pub(crate) fn runs_faketime(pid: i32) -> bool {
+    fs::read(format!("/proc/{}/environ", pid)).map_or(false, |environ| {
+        environ
+            .split(|b| *b == 0)
+            .any(|var| var.starts_with(b"FAKETIME_TIMESTAMP_FILE="))
+    })
+}
+
+pub(crate) fn kill_at_height(
+    topology: &Topology,
+    index: NodeIndex,
+    height: u64,
+    poll: Duration,
+) -> Result<u64> {
+    let killed = kill_all_at_height(topology, &[index], height, poll)?;
+    Ok(killed[0].1)
+}
+
+// Polls all nodes in the same loop, so that each one is killed as soon as it
+// reaches the height rather than after the ones before it. Returns the
+// height each node was killed at, in the order they were killed.
+pub(crate) fn kill_all_at_height(
+    topology: &Topology,
+    nodes: &[NodeIndex],
+    height: u64,
+    poll: Duration,
+) -> Result<Vec<(NodeIndex, u64)>> {
+    let mut waiting = vec![];
+    for index in nodes {
+        let node = topology.node(*index)?;
+        let backend = backend::for_node(*index)?;
+        if !backend.running(*index) {
+            bail!("node {} is not running", index);
+        }
+        waiting.push((*index, node.metrics.clone(), backend));
+    }
+
+    let mut killed = vec![];
+    while !waiting.is_empty() {
+        let mut i = 0;
+        while i < waiting.len() {
+            let (index, metrics, backend) = &waiting[i];
+            // the node may be restarting, keep polling until it reports again
+            match finalized_height(metrics) {
+                Ok(current) if current >= height => {
+                    backend.kill(*index)?;
+                    killed.push((*index, current));
+                    waiting.remove(i);
+                }
+                _ => i += 1,
+            }
+        }
+        if !waiting.is_empty() {
+            thread::sleep(poll);
+        }
+    }
+    Ok(killed)
+}
+
+// Nodes given by index or else by a selector, clap requires one of them.
+fn targets(
+    topology: &Topology,
+    node: Option<NodeIndex>,
+    select: Option<Selector>,
+) -> Result<Vec<NodeIndex>> {
+    match (node, select) {
+        (Some(node), _) => Ok(vec![topology.node(node)?.index]),
+        (None, Some(select)) => select.select(topology),
+        (None, None) => bail!("no node given"),
+    }
+}
+
+pub(crate) fn run(cmd: ChaosCommand) -> Result<()> {
+    match cmd {
+        ChaosCommand::ClockSkew {
+            node,
+            select,
+            offset,
+        } => {
+            for node in targets(&topology::load()?, node, select)? {
+                set_clock_skew(node, &offset)?;
+                println!("node {} clock offset {}", node, offset);
+            }
+        }
+        ChaosCommand::Latency { node, select, ms } => {
+            let topology = topology::load()?;
+            for node in targets(&topology, node, select)? {
+                set_latency(&topology, node, ms)?;
+                println!("node {} latency {} ms", node, ms);
+            }
+        }
+        ChaosCommand::Partition { groups } => {
+            let groups = groups
+                .iter()
+                .map(|group| {
+                    group
+                        .split(',')
+                        .map(|node| {
+                            node.trim()
+                                .parse()
+                                .map_err(|_| anyhow!("invalid node {} in group {}", node, group))
+                        })
+                        .collect::<Result<Vec<NodeIndex>>>()
+                })
+                .collect::<Result<Vec<_>>>()?;
+            partition(&topology::load()?, &groups)?;
+            println!("partitioned {:?}", groups);
+        }
+        ChaosCommand::Heal => {
+            heal(&topology::load()?)?;
+            println!("healed");
+        }
+        ChaosCommand::KillAtHeight {
+            node,
+            select,
+            height,
+            poll_ms,
+        } => {
+            let topology = topology::load()?;
+            // nodes that finalized the height already are killed right away
+            let targets = targets(&topology, node, select)?;
+            for (node, killed_at) in
+                kill_all_at_height(&topology, &targets, height, Duration::from_millis(poll_ms))?
+            {
+                println!("killed node {} at height {}", node, killed_at);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaping() -> Shaping {
+        Shaping {
+            latency_ms: BTreeMap::from([(100, 50), (101, 20)]),
+            groups: vec![vec![100, 101], vec![102]],
+        }
+    }
+
+    #[test]
+    fn adds_the_latencies_of_both_ends() {
+        let shaping = shaping();
+        assert_eq!(
+            shaping.delay(100, Some(101)),
+            Some(Duration::from_millis(70))
+        );
+        assert_eq!(
+            shaping.delay(101, Some(100)),
+            Some(Duration::from_millis(70))
+        );
+        // node 103 has no latency and is in no group
+        assert_eq!(
+            shaping.delay(103, Some(100)),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(shaping.delay(100, None), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn drops_packets_across_partitions() {
+        let shaping = shaping();
+        assert_eq!(shaping.delay(100, Some(102)), None);
+        assert_eq!(shaping.delay(102, Some(101)), None);
+        assert_eq!(shaping.delay(102, Some(103)), Some(Duration::ZERO));
+        assert_eq!(shaping.delay(102, None), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn delays_nothing_by_default() {
+        assert_eq!(
+            Shaping::default().delay(100, Some(101)),
+            Some(Duration::ZERO)
+        );
+    }
+}
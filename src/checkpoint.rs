@@ -0,0 +1,278 @@
+use crate::config::TestnetConfig;
+use crate::init::{node_state_dir, output_dir};
+use crate::process::{self, replica_version};
+use crate::routing::latest_record;
+use crate::tools::run_ic_binary;
+use crate::topology::{self, Topology};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_protobuf::registry::subnet::v1::CatchUpPackageContents;
+use ic_registry_keys::make_catch_up_package_contents_key;
+use ic_registry_local_store::{KeyMutation, LocalStoreImpl, LocalStoreWriter};
+use ic_types::{PrincipalId, SubnetId};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Subcommand)]
+pub(crate) enum CheckpointCommand {
+    /// Copy a checkpoint of a node to a directory, with its height and
+    /// state hash
+    Export {
+        #[clap(long)]
+        node: NodeIndex,
+        /// Height of the checkpoint, the newest one when omitted
+        #[clap(long)]
+        height: Option<u64>,
+        dir: String,
+    },
+    /// Start a subnet of a fresh testnet from an exported checkpoint, before
+    /// its nodes first run
+    Import {
+        dir: String,
+        /// Subnet to seed, the subnet of the exported node when omitted
+        #[clap(long)]
+        subnet: Option<SubnetIndex>,
+    },
+}
+
+// What `export` writes next to the checkpoint, as checkpoint.json.
+#[derive(Serialize, Deserialize)]
+struct ExportedCheckpoint {
+    height: u64,
+    subnet: SubnetIndex,
+    subnet_id: String,
+    /// Root hash of the manifest of the checkpoint, hex encoded
+    state_hash: String,
+    replica_version: String,
+}
+
+// The state hash a CUP refers to is the root hash of the manifest of the
+// checkpoint, computed by state-tool like the state manager does.
+fn state_hash(checkpoint: &Path) -> Result<String> {
+    let manifest = run_ic_binary(
+        "state-tool",
+        &[
+            "manifest".to_string(),
+            "--state".to_string(),
+            checkpoint.display().to_string(),
+        ],
+    )?;
+    manifest
+        .lines()
+        .find_map(|line| {
+            let (key, hash) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case("root hash")
+                .then(|| hash.trim().trim_start_matches("0x").to_string())
+        })
+        .ok_or(anyhow!(
+            "state-tool printed no root hash for {:?}",
+            checkpoint
+        ))
+}
+
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    let status = Command::new("cp").arg("-a").arg(from).arg(to).status()?;
+    if !status.success() {
+        bail!("failed to copy {:?} to {:?}", from, to);
+    }
+    Ok(())
+}
+
+// Checkpoints are not modified once written, so the node may keep running
+// unless it deletes the checkpoint meanwhile.
+pub(crate) fn export(
+    topology: &Topology,
+    node: NodeIndex,
+    height: Option<u64>,
+    dir: &Path,
+) -> Result<u64> {
+    let subnet = topology
+        .subnet_of(node)
+        .ok_or(anyhow!("node {} is not assigned to a subnet", node))?
+        .index;
+    let height = match height {
+        Some(height) => height,
+        None => process::latest_checkpoint(node)
+            .ok_or(anyhow!("node {} has no checkpoints yet", node))?,
+    };
+    let checkpoint = process::checkpoint_dir(node, height)?;
+    if !checkpoint.is_dir() {
+        bail!(
+            "node {} has no checkpoint at height {}, it has {:?}",
+            node,
+            height,
+            process::checkpoints(node)
+        );
+    }
+    if dir.exists() {
+        bail!("{:?} already exists", dir);
+    }
+    fs::create_dir_all(dir)?;
+
+    copy(&checkpoint, &dir.join("checkpoint"))?;
+    let exported = ExportedCheckpoint {
+        height,
+        subnet,
+        subnet_id: topology.subnet(subnet)?.subnet_id.clone(),
+        state_hash: state_hash(&dir.join("checkpoint"))?,
+        replica_version: replica_version(),
+    };
+    fs::write(
+        dir.join("checkpoint.json"),
+        serde_json::to_string_pretty(&exported)?,
+    )?;
+
+    Ok(height)
+}
+
+// Records a catch-up package at another height in the registry of the
+// testnet, on top of the versions ic-prep wrote, and with the hash of a state
+// at that height, so the subnet resumes from it like a recovered subnet.
+fn write_catch_up_package(
+    topology: &Topology,
+    subnet: SubnetIndex,
+    height: u64,
+    state_hash: Option<&str>,
+) -> Result<()> {
+    let subnet_id = &topology.subnet(subnet)?.subnet_id;
+    let subnet_id = SubnetId::from(
+        PrincipalId::from_str(subnet_id)
+            .map_err(|e| anyhow!("invalid subnet id {}: {}", subnet_id, e))?,
+    );
+    let key = make_catch_up_package_contents_key(subnet_id);
+    let store = output_dir()?.join("state").join("ic_registry_local_store");
+    let (version, record) = latest_record(&store, &key)?;
+    let record = record.ok_or(anyhow!(
+        "the registry has no catch-up package of subnet {}",
+        subnet
+    ))?;
+
+    let mut contents = CatchUpPackageContents::decode(record.as_slice())?;
+    if contents.height == height && state_hash.is_none() {
+        return Ok(());
+    }
+    contents.height = height;
+    if let Some(state_hash) = state_hash {
+        contents.state_hash = hex::decode(state_hash)?;
+        contents.time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+    }
+
+    LocalStoreImpl::new(store).store(
+        version.increment(),
+        vec![KeyMutation {
+            key,
+            value: Some(contents.encode_to_vec()),
+        }],
+    )?;
+    Ok(())
+}
+
+// Subnets with an initial_height start from a catch-up package at that height
+// instead of genesis, written here unless ic-prep put it there already.
+pub(crate) fn record_initial_heights(topology: &Topology, config: &TestnetConfig) -> Result<()> {
+    for subnet in &config.subnets {
+        if let Some(height) = subnet.initial_height.filter(|height| *height > 0) {
+            write_catch_up_package(topology, subnet.index, height, None)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn import(
+    topology: &Topology,
+    dir: &Path,
+    subnet: Option<SubnetIndex>,
+) -> Result<SubnetIndex> {
+    let exported: ExportedCheckpoint = serde_json::from_slice(
+        &fs::read(dir.join("checkpoint.json"))
+            .with_context(|| format!("{:?} is no exported checkpoint", dir))?,
+    )?;
+    let subnet = subnet.unwrap_or(exported.subnet);
+    let nodes: Vec<NodeIndex> = topology
+        .subnet(subnet)?
+        .nodes
+        .iter()
+        .map(|node| node.index)
+        .collect();
+    // nodes that ran have a CUP and state of their own, and the NNS would not
+    // know the catch-up package of the local registry
+    for node in &nodes {
+        if node_state_dir(*node)?.is_dir() {
+            bail!(
+                "node {} has run already, import into a fresh testnet (`init --force-reinit`)",
+                node
+            );
+        }
+    }
+    if exported.subnet != subnet {
+        eprintln!(
+            "warning: the checkpoint of subnet {} keeps its canister ids, which subnet {} does not allocate",
+            exported.subnet, subnet
+        );
+    }
+    if exported.replica_version != replica_version() {
+        eprintln!(
+            "warning: the checkpoint was written by replica version {}, the testnet runs {}",
+            exported.replica_version,
+            replica_version()
+        );
+    }
+
+    // an initial height puts the checkpoint there, the state hash does not
+    // depend on the height
+    let height = TestnetConfig::saved()?
+        .subnet(Some(subnet))
+        .initial_height
+        .filter(|height| *height > 0)
+        .unwrap_or(exported.height);
+    write_catch_up_package(topology, subnet, height, Some(&exported.state_hash))?;
+    let checkpoint = dir.join("checkpoint").display().to_string();
+    for node in nodes {
+        process::seed_state_dir(node)?;
+        run_ic_binary(
+            "state-tool",
+            &[
+                "import_state".to_string(),
+                "--state".to_string(),
+                checkpoint.clone(),
+                "--config".to_string(),
+                output_dir()?
+                    .join(process::config_file(node))
+                    .display()
+                    .to_string(),
+                "--height".to_string(),
+                height.to_string(),
+            ],
+        )
+        .with_context(|| format!("failed to import the checkpoint into node {}", node))?;
+    }
+
+    Ok(subnet)
+}
+
+pub(crate) fn run(cmd: CheckpointCommand) -> Result<()> {
+    let topology = topology::load()?;
+    match cmd {
+        CheckpointCommand::Export { node, height, dir } => {
+            let height = export(&topology, node, height, Path::new(&dir))?;
+            println!(
+                "exported the checkpoint of node {} at height {} to {}",
+                node, height, dir
+            );
+        }
+        CheckpointCommand::Import { dir, subnet } => {
+            let subnet = import(&topology, Path::new(&dir), subnet)?;
+            println!("subnet {} starts from {}", subnet, dir);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use crate::agent::{
+    block_on, connect, create_canister, create_canister_with_id, install_code, read_wasm,
+};
+use crate::config::TestnetConfig;
+use crate::icrc::{ledger_arg, IndexArg, IndexInitArg};
+use crate::init::{output_dir, BITCOIN_CANISTER_ID};
+use crate::topology;
+use anyhow::{anyhow, bail, Result};
+use candid::{CandidType, Encode, Nat, Principal};
+use clap::Subcommand;
+use ic_management_canister_types::CanisterInstallMode;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Init config of the bitcoin canister, whose fields are all optional.
+#[derive(CandidType)]
+#[allow(non_camel_case_types)]
+enum Network {
+    regtest,
+}
+
+#[derive(CandidType)]
+#[allow(non_camel_case_types)]
+enum Flag {
+    enabled,
+    disabled,
+}
+
+#[derive(CandidType)]
+struct BitcoinInitConfig {
+    network: Option<Network>,
+    stability_threshold: Option<Nat>,
+    syncing: Option<Flag>,
+    api_access: Option<Flag>,
+    // regtest has no peers to be fully synced with
+    disable_api_if_not_fully_synced: Option<Flag>,
+    watchdog_canister: Option<Option<Principal>>,
+}
+
+#[derive(CandidType)]
+enum BtcNetwork {
+    Regtest,
+}
+
+#[derive(CandidType)]
+enum Mode {
+    GeneralAvailability,
+}
+
+#[derive(CandidType)]
+struct MinterInitArgs {
+    btc_network: BtcNetwork,
+    ledger_id: Principal,
+    ecdsa_key_name: String,
+    retrieve_btc_min_amount: u64,
+    max_time_in_queue_nanos: u64,
+    min_confirmations: Option<u32>,
+    mode: Mode,
+}
+
+#[derive(CandidType)]
+enum MinterArg {
+    Init(MinterInitArgs),
+}
+
+#[derive(Serialize, Deserialize)]
+struct CkBtcCanisters {
+    bitcoin: Principal,
+    ledger: Principal,
+    minter: Principal,
+    index: Principal,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum CkBtcCommand {
+    /// Install the bitcoin canister on the subnet of `init --ckbtc-subnet`
+    /// and the ckBTC ledger, minter and index canisters
+    Install {
+        /// URL of a node, one of the subnet of each canister by default
+        #[clap(long)]
+        url: Option<String>,
+        /// Subnet to create the ckBTC canisters on, the subnet of the bitcoin
+        /// canister by default
+        #[clap(long)]
+        subnet: Option<SubnetIndex>,
+        /// Directory containing the bitcoin and ckBTC canister wasms
+        #[clap(long, env = "CKBTC_WASM_DIR", default_value = "ic/artifacts/canisters")]
+        wasm_dir: PathBuf,
+        /// Threshold ECDSA key used by the minter
+        #[clap(long, default_value = "dfx_test_key")]
+        ecdsa_key_name: String,
+    },
+    /// Mine regtest blocks through the bitcoind RPC interface
+    Mine {
+        #[clap(long, default_value = "1")]
+        blocks: u64,
+        /// Address receiving the block rewards
+        #[clap(long)]
+        address: String,
+        #[clap(long, default_value = "http://127.0.0.1:18443")]
+        bitcoind_url: String,
+        #[clap(long, default_value = "ic-btc-integration")]
+        rpc_user: String,
+        #[clap(long, default_value = "ic-btc-integration")]
+        rpc_password: String,
+    },
+}
+
+async fn install_bitcoin_canister(url: &str, wasm_dir: &Path) -> Result<Principal> {
+    let agent = connect(url, None).await?;
+    let bitcoin = Principal::from_text(BITCOIN_CANISTER_ID)?;
+    create_canister_with_id(&agent, bitcoin, None).await?;
+    let arg = BitcoinInitConfig {
+        network: Some(Network::regtest),
+        stability_threshold: Some(Nat::from(0u32)),
+        syncing: Some(Flag::enabled),
+        api_access: Some(Flag::enabled),
+        disable_api_if_not_fully_synced: Some(Flag::disabled),
+        watchdog_canister: Some(None),
+    };
+    install_code(
+        &agent,
+        bitcoin,
+        CanisterInstallMode::Install,
+        read_wasm(wasm_dir, "ic-btc-canister.wasm.gz")?,
+        Encode!(&arg)?,
+    )
+    .await?;
+    Ok(bitcoin)
+}
+
+async fn install(
+    bitcoin_url: String,
+    url: String,
+    subnet: SubnetIndex,
+    wasm_dir: PathBuf,
+    ecdsa_key_name: String,
+) -> Result<()> {
+    let bitcoin = install_bitcoin_canister(&bitcoin_url, &wasm_dir).await?;
+    let agent = connect(&url, None).await?;
+
+    let ledger = create_canister(&agent, subnet, None).await?;
+    let minter = create_canister(&agent, subnet, None).await?;
+    let index = create_canister(&agent, subnet, None).await?;
+
+    let arg = ledger_arg("ckBTC", "ckBTC", minter, 10, vec![]);
+    install_code(
+        &agent,
+        ledger,
+        CanisterInstallMode::Install,
+        read_wasm(&wasm_dir, "ic-icrc1-ledger.wasm.gz")?,
+        Encode!(&arg)?,
+    )
+    .await?;
+
+    let arg = MinterArg::Init(MinterInitArgs {
+        btc_network: BtcNetwork::Regtest,
+        ledger_id: ledger,
+        ecdsa_key_name,
+        retrieve_btc_min_amount: 10_000,
+        max_time_in_queue_nanos: 10_000_000_000,
+        min_confirmations: Some(1),
+        mode: Mode::GeneralAvailability,
+    });
+    install_code(
+        &agent,
+        minter,
+        CanisterInstallMode::Install,
+        read_wasm(&wasm_dir, "ic-ckbtc-minter.wasm.gz")?,
+        Encode!(&arg)?,
+    )
+    .await?;
+
+    let arg = IndexArg::Init(IndexInitArg { ledger_id: ledger });
+    install_code(
+        &agent,
+        index,
+        CanisterInstallMode::Install,
+        read_wasm(&wasm_dir, "ic-icrc1-index-ng.wasm.gz")?,
+        Encode!(&arg)?,
+    )
+    .await?;
+
+    println!(
+        "bitcoin {}\nledger {}\nminter {}\nindex {}",
+        bitcoin, ledger, minter, index
+    );
+    let canisters = CkBtcCanisters {
+        bitcoin,
+        ledger,
+        minter,
+        index,
+    };
+    fs::write(
+        output_dir()?.join("ckbtc.json"),
+        serde_json::to_string_pretty(&canisters)?,
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: CkBtcCommand) -> Result<()> {
+    match cmd {
+        CkBtcCommand::Install {
+            url,
+            subnet,
+            wasm_dir,
+            ecdsa_key_name,
+        } => {
+            let topology = topology::load()?;
+            // the replicas of this subnet run the bitcoin adapters
+            let bitcoin_subnet = TestnetConfig::saved()?
+                .subnets
+                .iter()
+                .find(|subnet| subnet.bitcoind.is_some())
+                .ok_or(anyhow!(
+                    "the testnet has no bitcoin adapters, initialize it with `init --ckbtc-subnet <subnet>`"
+                ))?
+                .index;
+            let subnet = subnet.unwrap_or(bitcoin_subnet);
+            let (bitcoin_url, url) = match url {
+                Some(url) => (url.clone(), url),
+                None => (
+                    topology.subnet_url(bitcoin_subnet)?,
+                    topology.subnet_url(subnet)?,
+                ),
+            };
+            block_on(install(bitcoin_url, url, subnet, wasm_dir, ecdsa_key_name))?
+        }
+        CkBtcCommand::Mine {
+            blocks,
+            address,
+            bitcoind_url,
+            rpc_user,
+            rpc_password,
+        } => {
+            let response: serde_json::Value = reqwest::blocking::Client::new()
+                .post(bitcoind_url)
+                .basic_auth(rpc_user, Some(rpc_password))
+                .json(&json!({
+                    "jsonrpc": "1.0",
+                    "id": "ic-testnet",
+                    "method": "generatetoaddress",
+                    "params": [blocks, address],
+                }))
+                .send()?
+                .json()?;
+
+            if !response["error"].is_null() {
+                bail!("bitcoind error: {}", response["error"]);
+            }
+            println!("{}", response["result"]);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use crate::init::output_dir;
+use crate::topology::Topology;
+use anyhow::{bail, Context, Result};
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Settings not covered by flags, read from the TOML file passed to
+// `init --config`, e.g.
+//
+//     unassigned_nodes = ["10.5.0.20"]
+//     replica_release = "2024-06-12"
+//
+//     [[subnet]]
+//     index = 0
+//     nodes = ["10.5.0.10", "10.5.0.11", "10.5.0.12", "10.5.0.13"]
+//     query_stats = true
+//     query_stats_epoch_length = 60
+//     max_instructions_per_message = 5_000_000_000
+//     dkg_interval_length = 99
+//     unit_delay_millis = 500
+//     initial_height = 1000
+//     deterministic_time_slicing = true
+//     chain_keys = ["ecdsa:Secp256k1:dfx_test_key", "schnorr:Ed25519:dfx_test_key"]
+//     bitcoind = "127.0.0.1:18444"
+//     specified_id_ranges = [
+//         { start = "uc7f6-kaaaa-aaaaq-qaaaa-cai", end = "ijz7v-ziaaa-aaaaq-7777q-cai" },
+//     ]
+//
+//     [[node]]
+//     index = 101
+//     sandboxing = true
+//     cpus = 0.5
+//     memory_mib = 4096
+//     advertise = "203.0.113.7:14100"
+//     labels = { role = "malicious", zone = "b" }
+//     args = ["--some-experimental-flag"]
+//     env = { RUST_BACKTRACE = "1" }
+//     backend = "docker"
+//
+//     [vm]
+//     image = "guestos/disk.img"
+//     bridge = "br-ic-testnet"
+//     gateway = "fd00:1::1"
+//
+// Subnets listing their nodes replace NODES, which otherwise puts all nodes
+// into subnet 0. `init` keeps a copy in tmp/testnet.toml for the settings applied when a
+// replica starts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TestnetConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unassigned_nodes: Vec<String>,
+    #[serde(default, rename = "subnet")]
+    pub subnets: Vec<SubnetSettings>,
+    #[serde(default, rename = "node")]
+    pub nodes: Vec<NodeSettings>,
+    /// IC-OS virtual machines of `ic-testnet vm`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm: Option<VmSettings>,
+    /// Containers of nodes with the docker backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docker: Option<DockerSettings>,
+    /// Remote hosts of nodes with the ssh backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshSettings>,
+    /// Replica release the replica configs are written for, the release of
+    /// the ic submodule when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replica_release: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubnetSettings {
+    pub index: SubnetIndex,
+    /// Addresses of the nodes of the subnet, as in NODES
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<String>,
+    /// Aggregate query statistics, on by default
+    pub query_stats: Option<bool>,
+    /// Rounds after which query statistics are aggregated, 60 by default
+    pub query_stats_epoch_length: Option<u64>,
+    // Instruction limits recorded in the subnet record, ic-prep's defaults
+    // when unset.
+    pub max_instructions_per_message: Option<u64>,
+    pub max_instructions_per_round: Option<u64>,
+    pub max_instructions_per_install_code: Option<u64>,
+    /// Heights between DKG summary blocks, after which the subnet reshares
+    /// its threshold keys, ic-prep's default when unset
+    pub dkg_interval_length: Option<u64>,
+    /// Delays of the block makers and of notarization, ic-prep's defaults
+    /// or those of --fast when unset
+    pub unit_delay_millis: Option<u64>,
+    pub initial_notary_delay_millis: Option<u64>,
+    /// Height of the first catch-up package of the subnet, 0 by default
+    pub initial_height: Option<u64>,
+    /// Split long messages into slices executed over several rounds, off by
+    /// default
+    #[serde(default)]
+    pub deterministic_time_slicing: bool,
+    /// Master key ids the subnet generates and signs with, e.g.
+    /// ecdsa:Secp256k1:dfx_test_key or schnorr:Bip340Secp256k1:dfx_test_key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chain_keys: Vec<String>,
+    /// Regtest bitcoind the bitcoin adapters of the nodes connect to, e.g.
+    /// 127.0.0.1:18444. `init --ckbtc-subnet` also routes the id of the
+    /// bitcoin canister to the subnet and generates dfx_test_key there
+    pub bitcoind: Option<String>,
+    /// Canister ids routed to the subnet besides the range it allocates
+    /// from, for `canister create --id`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub specified_id_ranges: Vec<IdRange>,
+}
+
+// First and last canister id of a range, e.g. mainnet ids.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IdRange {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NodeSettings {
+    pub index: NodeIndex,
+    /// Execute canisters in sandbox processes, off by default
+    #[serde(default)]
+    pub sandboxing: bool,
+    /// CPU cores the replica may use, e.g. 0.5 for half a core
+    pub cpus: Option<f64>,
+    /// Memory limit of the replica and its sandboxes
+    pub memory_mib: Option<u64>,
+    /// Throttle CPU and disk operations to simulate a straggler
+    #[serde(default)]
+    pub slow: bool,
+    /// Public API address registered for the node when it differs from the
+    /// address it binds to, e.g. the host port forwarded into a container
+    pub advertise: Option<String>,
+    /// Registered xnet address, the advertised IP with the bound xnet port by
+    /// default
+    pub advertise_xnet: Option<String>,
+    /// Free-form tags that `--select` matches, e.g. role = "malicious"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Extra command line arguments of the replica, after the ones
+    /// ic-testnet passes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Extra environment variables of the replica, e.g. RUST_BACKTRACE
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Where the replica runs, a local process by default
+    #[serde(default, skip_serializing_if = "Backend::is_process")]
+    pub backend: Backend,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    /// A child process of ic-testnet
+    #[default]
+    Process,
+    /// A container on this host, from the image of [docker]
+    Docker,
+    /// The ic-replica service that `generate ansible` installs on the node
+    /// host
+    Ssh,
+    /// An IC-OS virtual machine of `ic-testnet vm`
+    Vm,
+}
+
+impl Backend {
+    fn is_process(&self) -> bool {
+        *self == Backend::Process
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DockerSettings {
+    /// Image with the replica at /opt/ic-testnet/bin/replica
+    #[serde(default = "default_docker_image")]
+    pub image: String,
+}
+
+impl Default for DockerSettings {
+    fn default() -> DockerSettings {
+        DockerSettings {
+            image: default_docker_image(),
+        }
+    }
+}
+
+fn default_docker_image() -> String {
+    "ic-testnet-replica:latest".to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SshSettings {
+    /// Remote user, who may run systemctl with sudo
+    pub user: Option<String>,
+    /// Private key to log in with
+    pub identity: Option<PathBuf>,
+}
+
+// How `ic-testnet vm` boots nodes as qemu/KVM virtual machines from a GuestOS
+// disk image. The VMs bind the GuestOS ports, so their nodes have to advertise
+// 8080 (public API) and 2497 (xnet).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VmSettings {
+    /// Raw GuestOS disk image, each VM boots a copy-on-write overlay of it
+    pub image: PathBuf,
+    /// Host bridge the VMs are attached to, through qemu-bridge-helper
+    #[serde(default = "default_bridge")]
+    pub bridge: String,
+    /// Gateway of the node addresses on the bridge
+    pub gateway: Option<String>,
+    /// Prefix length of the node addresses, 64 for IPv6 and 24 for IPv4 by
+    /// default
+    pub prefix_length: Option<u8>,
+    /// Public keys accepted for the admin account of the VMs
+    pub ssh_authorized_keys: Option<PathBuf>,
+}
+
+fn default_bridge() -> String {
+    "br-ic-testnet".to_string()
+}
+
+// Label selector such as `zone=b,role=malicious`, matching the nodes that have
+// all of the labels. Every node also has the label `subnet`, its subnet index
+// or `unassigned`.
+#[derive(Debug, Clone)]
+pub(crate) struct Selector(Vec<(String, String)>);
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Selector, String> {
+        s.split(',')
+            .map(|term| match term.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    Ok((key.trim().to_string(), value.trim().to_string()))
+                }
+                _ => Err(format!("invalid selector {}, expected e.g. zone=b", term)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Selector)
+    }
+}
+
+impl Selector {
+    pub(crate) fn select(&self, topology: &Topology) -> Result<Vec<NodeIndex>> {
+        let config = TestnetConfig::saved()?;
+        let assigned = topology.subnets.iter().flat_map(|subnet| {
+            subnet
+                .nodes
+                .iter()
+                .map(move |node| (node.index, subnet.index.to_string()))
+        });
+        let unassigned = topology
+            .unassigned_nodes
+            .iter()
+            .map(|node| (node.index, "unassigned".to_string()));
+
+        let mut nodes: Vec<NodeIndex> = assigned
+            .chain(unassigned)
+            .filter(|(node, subnet)| {
+                let mut labels = config.node(*node).labels;
+                labels.insert("subnet".to_string(), subnet.clone());
+                self.0
+                    .iter()
+                    .all(|(key, value)| labels.get(key) == Some(value))
+            })
+            .map(|(node, _)| node)
+            .collect();
+        nodes.sort();
+        if nodes.is_empty() {
+            bail!("no node matches {}", self);
+        }
+
+        Ok(nodes)
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let terms: Vec<String> = self
+            .0
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+impl NodeSettings {
+    pub(crate) fn advertised_api(&self, bind: SocketAddr) -> Result<SocketAddr> {
+        match &self.advertise {
+            Some(addr) => addr.parse().with_context(|| {
+                format!("invalid advertised address {} of node {}", addr, self.index)
+            }),
+            None => Ok(bind),
+        }
+    }
+
+    pub(crate) fn advertised_xnet(&self, api: SocketAddr, bind: SocketAddr) -> Result<SocketAddr> {
+        match &self.advertise_xnet {
+            Some(addr) => addr.parse().with_context(|| {
+                format!(
+                    "invalid advertised xnet address {} of node {}",
+                    addr, self.index
+                )
+            }),
+            None => Ok(SocketAddr::new(api.ip(), bind.port())),
+        }
+    }
+}
+
+fn saved_path() -> Result<PathBuf> {
+    Ok(output_dir()?.join("testnet.toml"))
+}
+
+impl TestnetConfig {
+    pub(crate) fn load(path: Option<&Path>) -> Result<TestnetConfig> {
+        let Some(path) = path else {
+            return Ok(TestnetConfig::default());
+        };
+        let toml =
+            fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+        toml::from_str(&toml).with_context(|| format!("invalid config {:?}", path))
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let path = saved_path()?;
+        fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("failed to write {:?}", path))
+    }
+
+    // The config of the last `init`, the defaults for testnets initialized
+    // before it was kept.
+    pub(crate) fn saved() -> Result<TestnetConfig> {
+        let path = saved_path()?;
+        if !path.is_file() {
+            return Ok(TestnetConfig::default());
+        }
+        TestnetConfig::load(Some(&path))
+    }
+
+    // Node addresses with their subnet in node index order, None when NODES
+    // defines the nodes.
+    pub(crate) fn node_addresses(&self) -> Option<Vec<(String, Option<SubnetIndex>)>> {
+        if self.unassigned_nodes.is_empty() && self.subnets.iter().all(|s| s.nodes.is_empty()) {
+            return None;
+        }
+        let mut subnets: Vec<&SubnetSettings> = self.subnets.iter().collect();
+        subnets.sort_by_key(|subnet| subnet.index);
+        let assigned = subnets.into_iter().flat_map(|subnet| {
+            subnet
+                .nodes
+                .iter()
+                .map(move |node| (node.clone(), Some(subnet.index)))
+        });
+        let unassigned = self
+            .unassigned_nodes
+            .iter()
+            .map(|node| (node.clone(), None));
+
+        Some(assigned.chain(unassigned).collect())
+    }
+
+    // Subnets without a section get the defaults.
+    pub(crate) fn subnet(&self, index: Option<SubnetIndex>) -> SubnetSettings {
+        index
+            .and_then(|index| self.subnets.iter().find(|subnet| subnet.index == index))
+            .cloned()
+            .unwrap_or(SubnetSettings {
+                index: index.unwrap_or_default(),
+                ..SubnetSettings::default()
+            })
+    }
+
+    pub(crate) fn node(&self, index: NodeIndex) -> NodeSettings {
+        self.nodes
+            .iter()
+            .find(|node| node.index == index)
+            .cloned()
+            .unwrap_or(NodeSettings {
+                index,
+                ..NodeSettings::default()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_selectors() {
+        let selector: Selector = "zone=b, role = malicious".parse().unwrap();
+        assert_eq!(
+            selector.0,
+            vec![
+                ("zone".to_string(), "b".to_string()),
+                ("role".to_string(), "malicious".to_string()),
+            ]
+        );
+        assert_eq!(selector.to_string(), "zone=b,role=malicious");
+
+        let selector: Selector = "subnet=unassigned".parse().unwrap();
+        assert_eq!(selector.to_string(), "subnet=unassigned");
+    }
+
+    #[test]
+    fn rejects_terms_without_a_key() {
+        assert!("zone".parse::<Selector>().is_err());
+        assert!("=b".parse::<Selector>().is_err());
+        assert!("zone=b,".parse::<Selector>().is_err());
+        assert!("".parse::<Selector>().is_err());
+    }
+}
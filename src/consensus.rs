@@ -0,0 +1,142 @@
+use crate::pool;
+use crate::topology;
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use ic_artifact_pool::lmdb_pool::PersistentHeightIndexedPool;
+use ic_interfaces::consensus_pool::{HeightIndexedPool, PoolSection};
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_types::consensus::{ConsensusMessage, ConsensusMessageHashable, HasHeight};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Upper bounds of the histogram buckets, the last one catches the rest.
+const BUCKETS_MS: [u64; 10] = [50, 100, 200, 300, 500, 750, 1000, 2000, 5000, 10000];
+const BAR_WIDTH: usize = 40;
+
+#[derive(Subcommand)]
+pub(crate) enum ConsensusCommand {
+    /// Print histograms of the notarization and finalization latencies of
+    /// the rounds in the consensus pool of a node of a subnet
+    Latency {
+        #[clap(long)]
+        subnet: SubnetIndex,
+        /// Node whose pool is read, the first node of the subnet by default
+        #[clap(long)]
+        node: Option<NodeIndex>,
+    },
+}
+
+// When a node added the first artifact of each height to its validated pool,
+// in nanoseconds since the epoch.
+fn first_timestamps<T: ConsensusMessageHashable + HasHeight>(
+    section: &dyn HeightIndexedPool<T>,
+    pool: &PersistentHeightIndexedPool<ConsensusMessage>,
+) -> BTreeMap<u64, u64> {
+    let mut first: BTreeMap<u64, u64> = BTreeMap::new();
+    for artifact in section.get_all() {
+        let height = artifact.height().get();
+        let Some(timestamp) = pool.get_timestamp(&artifact.get_id()) else {
+            continue;
+        };
+        let timestamp = timestamp.as_nanos_since_unix_epoch();
+        first
+            .entry(height)
+            .and_modify(|first| *first = (*first).min(timestamp))
+            .or_insert(timestamp);
+    }
+    first
+}
+
+// Latencies of the rounds since the round before, which starts once the
+// previous height is notarized.
+fn round_latencies(previous: &BTreeMap<u64, u64>, reached: &BTreeMap<u64, u64>) -> Vec<Duration> {
+    let mut latencies: Vec<Duration> = reached
+        .iter()
+        .filter_map(|(height, reached)| {
+            let start = previous.get(&height.checked_sub(1)?)?;
+            Some(Duration::from_nanos(reached.checked_sub(*start)?))
+        })
+        .collect();
+    latencies.sort();
+    latencies
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() * p / 100).min(sorted.len() - 1)]
+}
+
+fn print_histogram(title: &str, latencies: &[Duration]) {
+    if latencies.is_empty() {
+        println!("{}: no rounds", title);
+        return;
+    }
+    println!(
+        "{}: {} rounds, p50 {:?} p90 {:?} p99 {:?} max {:?}",
+        title,
+        latencies.len(),
+        percentile(latencies, 50),
+        percentile(latencies, 90),
+        percentile(latencies, 99),
+        latencies[latencies.len() - 1]
+    );
+
+    let mut counts = [0; BUCKETS_MS.len() + 1];
+    for latency in latencies {
+        let millis = latency.as_millis() as u64;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(BUCKETS_MS.len());
+        counts[bucket] += 1;
+    }
+    let most = counts.iter().copied().max().unwrap_or(1).max(1);
+    for (bucket, count) in counts.iter().enumerate() {
+        let label = match BUCKETS_MS.get(bucket) {
+            Some(bound) => format!("<= {}ms", bound),
+            None => format!("> {}ms", BUCKETS_MS[BUCKETS_MS.len() - 1]),
+        };
+        println!(
+            "  {:>10} {:>6} {}",
+            label,
+            count,
+            "#".repeat(count * BAR_WIDTH / most)
+        );
+    }
+}
+
+fn latency(subnet: SubnetIndex, node: Option<NodeIndex>) -> Result<()> {
+    let topology = topology::load()?;
+    let members = &topology.subnet(subnet)?.nodes;
+    let node = match node {
+        Some(node) if members.iter().any(|member| member.index == node) => node,
+        Some(node) => bail!("node {} is not in subnet {}", node, subnet),
+        None => {
+            members
+                .first()
+                .ok_or(anyhow!("subnet {} has no nodes", subnet))?
+                .index
+        }
+    };
+
+    let pool = pool::open(node)?;
+    let notarized = first_timestamps(pool.notarization(), &pool);
+    let finalized = first_timestamps(pool.finalization(), &pool);
+    println!(
+        "subnet {} node {}, heights {} to {} in the pool",
+        subnet,
+        node,
+        notarized.keys().next().copied().unwrap_or_default(),
+        notarized.keys().last().copied().unwrap_or_default()
+    );
+    print_histogram("notarization", &round_latencies(&notarized, &notarized));
+    print_histogram("finalization", &round_latencies(&notarized, &finalized));
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: ConsensusCommand) -> Result<()> {
+    match cmd {
+        ConsensusCommand::Latency { subnet, node } => latency(subnet, node),
+    }
+}
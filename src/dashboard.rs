@@ -0,0 +1,245 @@
+use crate::metrics::finalized_height;
+use crate::process;
+use crate::topology::{self, NodeSummary, Topology};
+use anyhow::Result;
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use nix::unistd::{sysconf, SysconfVar};
+use ratatui::crossterm::event::{self, Event, KeyCode};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Only the tail of each log is searched for errors.
+const LOG_TAIL_BYTES: u64 = 64 * 1024;
+const LOG_ERRORS_SHOWN: usize = 20;
+
+#[derive(Args)]
+pub(crate) struct DashboardArgs {
+    /// Seconds between refreshes
+    #[clap(long, default_value = "2")]
+    interval: u64,
+}
+
+struct NodeStatus {
+    index: NodeIndex,
+    subnet: String,
+    pid: Option<i32>,
+    height: Option<u64>,
+    cpu_percent: Option<f64>,
+    rss_kib: u64,
+}
+
+struct Sampler {
+    ticks_per_sec: f64,
+    // CPU ticks of every replica at the previous sample
+    cpu: BTreeMap<i32, (u64, Instant)>,
+}
+
+impl Sampler {
+    fn new() -> Sampler {
+        let ticks_per_sec = sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100) as f64;
+        Sampler {
+            ticks_per_sec,
+            cpu: BTreeMap::new(),
+        }
+    }
+
+    fn sample(&mut self, topology: &Topology) -> Vec<NodeStatus> {
+        let nodes: Vec<(String, &NodeSummary)> = topology
+            .subnets
+            .iter()
+            .flat_map(|subnet| {
+                subnet
+                    .nodes
+                    .iter()
+                    .map(move |node| (subnet.index.to_string(), node))
+            })
+            .chain(
+                topology
+                    .unassigned_nodes
+                    .iter()
+                    .map(|node| ("-".to_string(), node)),
+            )
+            .collect();
+
+        // scrape all nodes at once so that unresponsive ones don't stall the view
+        let heights: Vec<Option<u64>> = thread::scope(|scope| {
+            let handles: Vec<_> = nodes
+                .iter()
+                .map(|(_, node)| scope.spawn(|| finalized_height(&node.metrics).ok()))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().ok().flatten())
+                .collect()
+        });
+
+        let now = Instant::now();
+        nodes
+            .into_iter()
+            .zip(heights)
+            .map(|((subnet, node), height)| {
+                let pid = process::replica_pid(node.index);
+                let usage = pid.and_then(process::resource_usage);
+                let cpu_percent = match (pid, usage) {
+                    (Some(pid), Some((ticks, _))) => {
+                        let previous = self.cpu.insert(pid, (ticks, now));
+                        previous.map(|(previous, at)| {
+                            let secs = now.duration_since(at).as_secs_f64();
+                            ticks.saturating_sub(previous) as f64 / self.ticks_per_sec / secs
+                                * 100.0
+                        })
+                    }
+                    _ => None,
+                };
+
+                NodeStatus {
+                    index: node.index,
+                    subnet,
+                    pid,
+                    height,
+                    cpu_percent,
+                    rss_kib: usage.map_or(0, |(_, rss)| rss),
+                }
+            })
+            .collect()
+    }
+}
+
+fn log_errors(index: NodeIndex) -> Vec<String> {
+    let read_tail = || -> Result<String> {
+        let mut file = File::open(process::log_file(index)?)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(LOG_TAIL_BYTES)))?;
+        let mut tail = vec![];
+        file.read_to_end(&mut tail)?;
+        Ok(String::from_utf8_lossy(&tail).to_string())
+    };
+
+    read_tail()
+        .map(|tail| {
+            tail.lines()
+                .filter(|line| line.contains(" ERRO ") || line.contains(" CRIT "))
+                .map(|line| format!("node {}: {}", index, line))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn draw(frame: &mut Frame, nodes: &[NodeStatus], errors: &[String]) {
+    let [table_area, errors_area] = Layout::vertical([
+        Constraint::Length(nodes.len() as u16 + 3),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+
+    let max_height: BTreeMap<&str, u64> = nodes.iter().fold(BTreeMap::new(), |mut max, node| {
+        let entry = max.entry(node.subnet.as_str()).or_insert(0);
+        *entry = (*entry).max(node.height.unwrap_or(0));
+        max
+    });
+    let rows = nodes.iter().map(|node| {
+        let (status, color) = match (node.pid, node.height) {
+            (None, _) => ("stopped", Color::Red),
+            (Some(_), None) => ("unresponsive", Color::Yellow),
+            (Some(_), Some(height)) if height < max_height[node.subnet.as_str()] => {
+                ("behind", Color::Yellow)
+            }
+            _ => ("ok", Color::Green),
+        };
+        Row::new(vec![
+            node.index.to_string(),
+            node.subnet.clone(),
+            status.to_string(),
+            node.pid.map_or("-".to_string(), |pid| pid.to_string()),
+            node.height
+                .map_or("-".to_string(), |height| height.to_string()),
+            node.cpu_percent
+                .map_or("-".to_string(), |cpu| format!("{:.1}%", cpu)),
+            format!("{} MiB", node.rss_kib / 1024),
+        ])
+        .style(Style::default().fg(color))
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(6),
+            Constraint::Length(7),
+            Constraint::Length(13),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(10),
+        ],
+    )
+    .header(
+        Row::new(vec![
+            "node", "subnet", "status", "pid", "height", "cpu", "memory",
+        ])
+        .style(Style::default().fg(Color::Cyan)),
+    )
+    .block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("ic-testnet (q to quit)"),
+    );
+    frame.render_widget(table, table_area);
+
+    let items: Vec<ListItem> = errors
+        .iter()
+        .map(|error| ListItem::new(error.as_str()))
+        .collect();
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("recent log errors"),
+    );
+    frame.render_widget(list, errors_area);
+}
+
+fn refresh(terminal: &mut DefaultTerminal, args: &DashboardArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let mut sampler = Sampler::new();
+    let interval = Duration::from_secs(args.interval);
+
+    loop {
+        let nodes = sampler.sample(&topology);
+        let mut errors: Vec<String> = nodes
+            .iter()
+            .flat_map(|node| log_errors(node.index))
+            .collect();
+        // log lines start with their timestamp
+        errors.sort_by(|a, b| {
+            let time = |line: &str| line.split_once(": ").map(|(_, rest)| rest.to_string());
+            time(a).cmp(&time(b))
+        });
+        let errors = &errors[errors.len().saturating_sub(LOG_ERRORS_SHOWN)..];
+
+        terminal.draw(|frame| draw(frame, &nodes, errors))?;
+
+        let deadline = Instant::now() + interval;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn run(args: DashboardArgs) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = refresh(&mut terminal, &args);
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Address `dfx start` serves the local network on.
+pub(crate) const DFX_ADDR: &str = "127.0.0.1:4943";
+
+fn shared_network_dir() -> Option<PathBuf> {
+    let data_dir = if cfg!(target_os = "macos") {
+        PathBuf::from(env::var("HOME").ok()?)
+            .join("Library")
+            .join("Application Support")
+            .join("org.dfinity.dfx")
+    } else {
+        match env::var("XDG_DATA_HOME") {
+            Ok(dir) => PathBuf::from(dir).join("dfx"),
+            Err(_) => PathBuf::from(env::var("HOME").ok()?)
+                .join(".local")
+                .join("share")
+                .join("dfx"),
+        }
+    };
+    Some(data_dir.join("network").join("local"))
+}
+
+// dfx finds a running local network through the port files in its network
+// data directory, both the shared one and the one of the project in the
+// current directory.
+pub(crate) fn write_network_files() -> Result<()> {
+    let port = DFX_ADDR.rsplit_once(':').map_or("4943", |(_, port)| port);
+    let dirs = [
+        Some(env::current_dir()?.join(".dfx/network/local")),
+        shared_network_dir(),
+    ];
+
+    for dir in dirs.into_iter().flatten() {
+        fs::create_dir_all(dir.join("replica-configuration"))?;
+        fs::write(dir.join("webserver-port"), port)?;
+        fs::write(
+            dir.join("replica-configuration").join("replica-1.port"),
+            port,
+        )?;
+        println!("wrote dfx network files to {:?}", dir);
+    }
+
+    Ok(())
+}
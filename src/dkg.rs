@@ -0,0 +1,111 @@
+use crate::init::{node_state_dir, output_dir};
+use crate::pool;
+use crate::routing::latest_record;
+use crate::topology;
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use ic_interfaces::consensus_pool::{HeightIndexedPool, PoolSection};
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_protobuf::registry::subnet::v1::SubnetRecord;
+use ic_registry_keys::make_subnet_record_key;
+use ic_types::consensus::HasHeight;
+use ic_types::crypto::threshold_sig::ni_dkg::{NiDkgTag, NiDkgTranscript};
+use ic_types::{PrincipalId, SubnetId};
+use prost::Message;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+#[derive(Subcommand)]
+pub(crate) enum DkgCommand {
+    /// Print the DKG interval of a subnet and the NiDKG transcripts of the
+    /// latest catch-up package of a node
+    Show {
+        #[clap(long)]
+        subnet: SubnetIndex,
+        /// Node whose pool is read, the first node of the subnet by default
+        #[clap(long)]
+        node: Option<NodeIndex>,
+    },
+}
+
+fn print_transcripts(kind: &str, transcripts: &BTreeMap<NiDkgTag, NiDkgTranscript>) {
+    for (tag, transcript) in transcripts {
+        println!(
+            "  {} {:?}: dealt at height {}, registry version {}, threshold {} of {}",
+            kind,
+            tag,
+            transcript.dkg_id.start_block_height.get(),
+            transcript.registry_version.get(),
+            transcript.threshold.get().get(),
+            transcript.committee.get().len()
+        );
+    }
+}
+
+fn show(subnet: SubnetIndex, node: Option<NodeIndex>) -> Result<()> {
+    let topology = topology::load()?;
+    let summary = topology.subnet(subnet)?;
+    let node = match node {
+        Some(node) if topology.subnet_of(node).map(|of| of.index) == Some(subnet) => node,
+        Some(node) => bail!("node {} is not in subnet {}", node, subnet),
+        None => {
+            summary
+                .nodes
+                .first()
+                .ok_or(anyhow!("subnet {} has no nodes", subnet))?
+                .index
+        }
+    };
+
+    // the registry of the node has the versions it fetched from the NNS
+    let mut store = node_state_dir(node)?.join("ic_registry_local_store");
+    if !store.is_dir() {
+        store = output_dir()?.join("state").join("ic_registry_local_store");
+    }
+    let subnet_id = SubnetId::from(
+        PrincipalId::from_str(&summary.subnet_id)
+            .map_err(|e| anyhow!("invalid subnet id {}: {}", summary.subnet_id, e))?,
+    );
+    let (version, record) = latest_record(&store, &make_subnet_record_key(subnet_id))?;
+    let record = SubnetRecord::decode(
+        record
+            .ok_or(anyhow!("the registry has no record of subnet {}", subnet))?
+            .as_slice(),
+    )?;
+    println!(
+        "subnet {} dkg interval length {} in registry version {}",
+        subnet,
+        record.dkg_interval_length,
+        version.get()
+    );
+
+    let pool = pool::open(node)?;
+    let Ok(cup) = pool.catch_up_package().get_highest() else {
+        println!("node {} has no catch-up package in its pool yet", node);
+        return Ok(());
+    };
+    let block = cup.content.block.as_ref();
+    let dkg = &block.payload.as_ref().as_summary().dkg;
+    println!(
+        "node {} catch-up package at height {}, registry version {}",
+        node,
+        cup.height().get(),
+        dkg.registry_version.get()
+    );
+    println!(
+        "  interval length {}, next interval length {}",
+        dkg.interval_length.get(),
+        dkg.next_interval_length.get()
+    );
+    print_transcripts("current", dkg.current_transcripts());
+    print_transcripts("next", dkg.next_transcripts());
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: DkgCommand) -> Result<()> {
+    match cmd {
+        DkgCommand::Show { subnet, node } => show(subnet, node),
+    }
+}
@@ -0,0 +1,64 @@
+use crate::init::output_dir;
+use crate::topology::{NodeSummary, Topology};
+use anyhow::Result;
+use std::fs;
+
+fn node_vars(vars: &mut Vec<(String, String)>, node: &NodeSummary) {
+    vars.push((format!("IC_URL_NODE_{}", node.index), node.url.clone()));
+    vars.push((format!("NODE_ID_{}", node.index), node.node_id.clone()));
+    vars.push((format!("XNET_ADDR_NODE_{}", node.index), node.xnet.clone()));
+    vars.push((
+        format!("METRICS_URL_NODE_{}", node.index),
+        node.metrics.clone(),
+    ));
+}
+
+// Writes the endpoints of the topology as shell variables, for CI steps that
+// `source tmp/testnet.env` instead of parsing topology.json.
+pub(crate) fn write(topology: &Topology) -> Result<()> {
+    let dir = output_dir()?;
+    let mut vars: Vec<(String, String)> = vec![];
+
+    let nns = topology.subnet(topology.nns_subnet)?;
+    vars.push(("NNS_SUBNET_ID".to_string(), nns.subnet_id.clone()));
+    vars.push(("NNS_URL".to_string(), topology.subnet_url(nns.index)?));
+    vars.push((
+        "ROOT_KEY_PATH".to_string(),
+        dir.join("root_key.pem").display().to_string(),
+    ));
+    vars.push((
+        "ROOT_KEY_DER_PATH".to_string(),
+        dir.join("root_key.der").display().to_string(),
+    ));
+    vars.push((
+        "ROOT_KEY_HEX".to_string(),
+        hex::encode(topology.root_key_der()?),
+    ));
+
+    for subnet in &topology.subnets {
+        vars.push((
+            format!("SUBNET_ID_{}", subnet.index),
+            subnet.subnet_id.clone(),
+        ));
+        vars.push((
+            format!("IC_URL_SUBNET_{}", subnet.index),
+            topology.subnet_url(subnet.index)?,
+        ));
+        let nodes: Vec<String> = subnet
+            .nodes
+            .iter()
+            .map(|node| node.index.to_string())
+            .collect();
+        vars.push((format!("SUBNET_NODES_{}", subnet.index), nodes.join(" ")));
+    }
+    for node in topology.nodes() {
+        node_vars(&mut vars, node);
+    }
+
+    let env: String = vars
+        .iter()
+        .map(|(name, value)| format!("{}='{}'\n", name, value.replace('\'', r"'\''")))
+        .collect();
+    fs::write(dir.join("testnet.env"), env)?;
+    Ok(())
+}
@@ -0,0 +1,544 @@
+use crate::http_gateway::{self, CanisterRequest};
+use crate::init::{gateway_port, node_state_dir, output_dir};
+use crate::routing::read_routing_table;
+use crate::topology::{self, SubnetSummary, Topology};
+use anyhow::{anyhow, Context, Result};
+use candid::Principal;
+use clap::Args;
+use ic_registry_routing_table::RoutingTable;
+use ic_types::{PrincipalId, RegistryVersion};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig};
+
+// How often the gateway picks up a routing table the replicas received from
+// the registry canister, e.g. after a canister migration.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Args)]
+pub(crate) struct GatewayArgs {
+    /// Address the gateway listens on, 127.0.0.1:4950 plus the port offset of
+    /// a named testnet by default
+    #[clap(long)]
+    listen: Option<String>,
+    /// Answer repeated identical queries from a cache for this many seconds
+    #[clap(long)]
+    query_cache_secs: Option<u64>,
+    /// Requests per second each client IP may make, more get a 429
+    #[clap(long)]
+    rate_limit: Option<f64>,
+    /// Refuse requests with a larger body with a 413
+    #[clap(long)]
+    max_request_bytes: Option<usize>,
+    /// Serve a canister under a host name, e.g. app.localhost=<canister id>
+    #[clap(long = "domain")]
+    domains: Vec<String>,
+    /// Origin allowed to make cross-origin requests, any origin by default
+    #[clap(long = "cors-origin")]
+    cors_origins: Vec<String>,
+    /// PEM certificate chain to serve HTTPS with
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+}
+
+// Boundary node behavior clients see at the edge, all of it off by default.
+struct Edge {
+    query_cache: Option<Duration>,
+    rate_limit: Option<f64>,
+    max_request_bytes: Option<usize>,
+}
+
+#[derive(Clone)]
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn error(status: u16, message: String) -> Reply {
+        Reply {
+            status,
+            headers: vec![],
+            body: message.into_bytes(),
+        }
+    }
+}
+
+// Tokens of a client, refilled at the rate limit up to one second's worth.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// The local store ic-prep wrote and the copies the replicas keep up to date.
+fn local_stores(topology: &Topology) -> Result<Vec<PathBuf>> {
+    let mut stores = vec![output_dir()?.join("state").join("ic_registry_local_store")];
+    for node in topology.nodes() {
+        stores.push(node_state_dir(node.index)?.join("ic_registry_local_store"));
+    }
+    Ok(stores)
+}
+
+fn latest_routing_table(topology: &Topology) -> Result<(RegistryVersion, RoutingTable)> {
+    local_stores(topology)?
+        .iter()
+        .filter(|store| store.is_dir())
+        .filter_map(|store| read_routing_table(store).ok())
+        .max_by_key(|(version, _)| *version)
+        .ok_or(anyhow!("no registry local store has a routing table"))
+}
+
+struct Gateway {
+    topology: Topology,
+    routing_table: RwLock<(RegistryVersion, RoutingTable)>,
+    client: reqwest::blocking::Client,
+    // spreads requests over the nodes of a subnet
+    next: AtomicUsize,
+    edge: Edge,
+    domains: HashMap<String, Principal>,
+    cors_origins: Vec<String>,
+    cache: Mutex<HashMap<(String, Vec<u8>), (Instant, Reply)>>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl Gateway {
+    fn reload(&self) -> Result<()> {
+        let (version, routing_table) = latest_routing_table(&self.topology)?;
+        let mut current = self
+            .routing_table
+            .write()
+            .map_err(|_| anyhow!("routing table lock poisoned"))?;
+        if version > current.0 {
+            println!("routing table of registry version {}", version);
+            *current = (version, routing_table);
+        }
+        Ok(())
+    }
+
+    fn subnet_by_id(&self, subnet_id: &str) -> Option<&SubnetSummary> {
+        self.topology
+            .subnets
+            .iter()
+            .find(|subnet| subnet.subnet_id == subnet_id)
+    }
+
+    fn subnet_of_canister(&self, canister_id: Principal) -> Result<&SubnetSummary, String> {
+        let routing_table = self
+            .routing_table
+            .read()
+            .map_err(|_| "routing table lock poisoned".to_string())?;
+        let subnet_id = routing_table
+            .1
+            .route(PrincipalId(canister_id))
+            .ok_or(format!(
+                "canister {} is not in the routing table",
+                canister_id
+            ))?;
+        self.subnet_by_id(&subnet_id.get().to_string())
+            .ok_or(format!("subnet {} is not in the topology", subnet_id))
+    }
+
+    // The subnet serving a request, by the canister or subnet id in its path
+    // as boundary nodes do, None for paths outside of the API. The status
+    // comes from the NNS subnet.
+    fn subnet(&self, path: &str) -> Result<Option<&SubnetSummary>, String> {
+        let segments: Vec<&str> = path
+            .split('?')
+            .next()
+            .unwrap_or("")
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let subnet = match segments.as_slice() {
+            ["api", "v2", "status"] => self
+                .topology
+                .subnet(self.topology.nns_subnet)
+                .map_err(|e| e.to_string())?,
+            ["api", _, "canister", id, _] => {
+                let canister_id = Principal::from_text(id)
+                    .map_err(|e| format!("invalid canister id {}: {}", id, e))?;
+                self.subnet_of_canister(canister_id)?
+            }
+            ["api", _, "subnet", id, _] => self
+                .subnet_by_id(id)
+                .ok_or(format!("unknown subnet {}", id))?,
+            _ => return Ok(None),
+        };
+        Ok(Some(subnet))
+    }
+
+    // Tries the nodes of the subnet in turn until one answers.
+    fn forward(
+        &self,
+        subnet: &SubnetSummary,
+        method: &Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<Reply> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = anyhow!("subnet {} has no nodes", subnet.index);
+        for i in 0..subnet.nodes.len() {
+            let node = &subnet.nodes[(start + i) % subnet.nodes.len()];
+            let url = format!("{}{}", node.url, path);
+            let request = match method {
+                Method::Get => self.client.get(&url),
+                _ => self
+                    .client
+                    .post(&url)
+                    .header("Content-Type", "application/cbor")
+                    .body(body.clone()),
+            };
+            match request.send() {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    let headers = response
+                        .headers()
+                        .get("Content-Type")
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| ("Content-Type".to_string(), value.to_string()))
+                        .into_iter()
+                        .collect();
+                    return Ok(Reply {
+                        status,
+                        headers,
+                        body: response.bytes()?.to_vec(),
+                    });
+                }
+                Err(e) => last_error = anyhow!("node {}: {}", node.index, e),
+            }
+        }
+        Err(last_error)
+    }
+
+    // How long a client has to wait for its next request, None when it may
+    // make it now.
+    fn throttle(&self, client: Option<IpAddr>) -> Option<Duration> {
+        let (Some(rate), Some(client)) = (self.edge.rate_limit, client) else {
+            return None;
+        };
+        let mut buckets = self.buckets.lock().ok()?;
+        let now = Instant::now();
+        let burst = rate.max(1.0);
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = bucket.tokens + (now - bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = refilled.min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+
+    // Replicas answer a query with the same bytes for a while, so the cache
+    // is keyed by the signed request itself.
+    fn cached_query(
+        &self,
+        subnet: &SubnetSummary,
+        method: &Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<Reply> {
+        let ttl = match self.edge.query_cache {
+            Some(ttl) if path.ends_with("/query") => ttl,
+            _ => return self.forward(subnet, method, path, body),
+        };
+        let key = (path.to_string(), Sha256::digest(&body).to_vec());
+        let now = Instant::now();
+        if let Ok(cache) = self.cache.lock() {
+            if let Some((stored, reply)) = cache.get(&key) {
+                if now - *stored < ttl {
+                    let mut reply = reply.clone();
+                    reply
+                        .headers
+                        .push(("X-Cache-Status".to_string(), "HIT".to_string()));
+                    return Ok(reply);
+                }
+            }
+        }
+
+        let mut reply = self.forward(subnet, method, path, body)?;
+        if reply.status == 200 {
+            if let Ok(mut cache) = self.cache.lock() {
+                cache.retain(|_, (stored, _)| now - *stored < ttl);
+                cache.insert(key, (now, reply.clone()));
+            }
+        }
+        reply
+            .headers
+            .push(("X-Cache-Status".to_string(), "MISS".to_string()));
+        Ok(reply)
+    }
+
+    // The canister a request for a frontend goes to: by a custom domain, by
+    // a host name starting with the canister id as in <id>.localhost, or by
+    // a canisterId parameter.
+    fn frontend_canister(&self, request: &Request) -> Option<Principal> {
+        let host = header(request, "Host").unwrap_or_default();
+        let host = host.split(':').next().unwrap_or_default();
+        if let Some(canister_id) = self.domains.get(host) {
+            return Some(*canister_id);
+        }
+        if let Some(canister_id) = host
+            .split('.')
+            .next()
+            .and_then(|label| Principal::from_text(label).ok())
+        {
+            return Some(canister_id);
+        }
+        let query = request.url().split_once('?')?.1;
+        query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| *name == "canisterId")
+            .and_then(|(_, id)| Principal::from_text(id).ok())
+    }
+
+    fn frontend(&self, request: &Request, canister_id: Principal, body: Vec<u8>) -> Reply {
+        let subnet = match self.subnet_of_canister(canister_id) {
+            Ok(subnet) => subnet,
+            Err(e) => return Reply::error(400, e),
+        };
+        let Some(node) = subnet
+            .nodes
+            .get(self.next.fetch_add(1, Ordering::Relaxed) % subnet.nodes.len().max(1))
+        else {
+            return Reply::error(502, format!("subnet {} has no nodes", subnet.index));
+        };
+        let canister_request = CanisterRequest {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|header| (header.field.to_string(), header.value.to_string()))
+                .collect(),
+            body,
+        };
+        match http_gateway::serve(&node.url, canister_id, canister_request) {
+            Ok(response) => Reply {
+                status: response.status,
+                headers: response.headers,
+                body: response.body,
+            },
+            Err(e) => Reply::error(502, format!("{:#}", e)),
+        }
+    }
+
+    // Headers boundary nodes add for browsers, the origin is echoed back
+    // when only some are allowed.
+    fn cors_headers(&self, request: &Request) -> Vec<(String, String)> {
+        let origin = header(request, "Origin");
+        let allowed = match (&origin, self.cors_origins.is_empty()) {
+            (_, true) => "*".to_string(),
+            (Some(origin), false) if self.cors_origins.contains(origin) => origin.clone(),
+            _ => return vec![],
+        };
+        let mut headers = vec![
+            ("Access-Control-Allow-Origin", allowed),
+            (
+                "Access-Control-Allow-Methods",
+                "GET, POST, HEAD, OPTIONS".to_string(),
+            ),
+            (
+                "Access-Control-Allow-Headers",
+                "DNT, User-Agent, X-Requested-With, If-Modified-Since, Cache-Control, Content-Type, Range, Cookie"
+                    .to_string(),
+            ),
+            (
+                "Access-Control-Expose-Headers",
+                "Content-Length, Content-Range".to_string(),
+            ),
+            ("Access-Control-Max-Age", "600".to_string()),
+        ];
+        if !self.cors_origins.is_empty() {
+            headers.push(("Vary", "Origin".to_string()));
+        }
+        headers
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    fn reply(&self, request: &mut Request, path: &str) -> Result<Reply> {
+        if *request.method() == Method::Options {
+            return Ok(Reply {
+                status: 204,
+                headers: vec![],
+                body: vec![],
+            });
+        }
+        if let Some(wait) = self.throttle(request.remote_addr().map(|addr| addr.ip())) {
+            let mut reply = Reply::error(429, "rate limit exceeded".to_string());
+            reply.headers.push((
+                "Retry-After".to_string(),
+                wait.as_secs_f64().ceil().to_string(),
+            ));
+            return Ok(reply);
+        }
+        let subnet = match self.subnet(path) {
+            Ok(subnet) => subnet,
+            Err(e) => return Ok(Reply::error(400, e)),
+        };
+        if subnet.is_some() {
+            // the status is read with GET, everything else of the API is a
+            // CBOR POST
+            let allowed = if path.contains("/api/v2/status") {
+                Method::Get
+            } else {
+                Method::Post
+            };
+            if *request.method() != allowed {
+                let mut reply = Reply::error(
+                    405,
+                    format!("{} is not allowed for {}", request.method(), path),
+                );
+                reply
+                    .headers
+                    .push(("Allow".to_string(), allowed.to_string()));
+                return Ok(reply);
+            }
+        }
+        let Some(body) = self.read_body(request)? else {
+            return Ok(Reply::error(
+                413,
+                format!(
+                    "request body larger than {} bytes",
+                    self.edge.max_request_bytes.unwrap_or_default()
+                ),
+            ));
+        };
+
+        Ok(match subnet {
+            Some(subnet) => self
+                .cached_query(subnet, request.method(), path, body)
+                .unwrap_or_else(|e| Reply::error(502, format!("{:#}", e))),
+            None => match self.frontend_canister(request) {
+                Some(canister_id) => self.frontend(request, canister_id, body),
+                None => Reply::error(404, "not found".to_string()),
+            },
+        })
+    }
+
+    // The body of a request, None when it is larger than --max-request-bytes.
+    // Chunked requests have no length up front, so the body is read up to one
+    // byte past the limit.
+    fn read_body(&self, request: &mut Request) -> Result<Option<Vec<u8>>> {
+        let mut body = vec![];
+        let Some(max) = self.edge.max_request_bytes else {
+            request.as_reader().read_to_end(&mut body)?;
+            return Ok(Some(body));
+        };
+        if request.body_length().unwrap_or(0) > max {
+            return Ok(None);
+        }
+        request
+            .as_reader()
+            .take(max as u64 + 1)
+            .read_to_end(&mut body)?;
+        Ok((body.len() <= max).then_some(body))
+    }
+
+    fn respond(&self, mut request: Request) -> Result<()> {
+        let path = request.url().to_string();
+        let mut reply = self.reply(&mut request, &path)?;
+        println!("{} {} {}", request.method(), path, reply.status);
+        reply.headers.extend(self.cors_headers(&request));
+
+        let mut response = Response::from_data(reply.body).with_status_code(reply.status);
+        for (name, value) in &reply.headers {
+            let header = Header::from_bytes(name.as_bytes(), value.as_bytes())
+                .map_err(|_| anyhow!("invalid header {}", name))?;
+            response = response.with_header(header);
+        }
+        request.respond(response)?;
+        Ok(())
+    }
+}
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.to_string())
+}
+
+pub(crate) fn run(args: GatewayArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let routing_table = RwLock::new(latest_routing_table(&topology)?);
+    let mut domains = HashMap::new();
+    for domain in &args.domains {
+        let (host, canister) = domain.split_once('=').ok_or(anyhow!(
+            "invalid domain {}, expected <host>=<canister id>",
+            domain
+        ))?;
+        domains.insert(host.to_string(), Principal::from_text(canister)?);
+    }
+    let gateway = Arc::new(Gateway {
+        topology,
+        routing_table,
+        client: reqwest::blocking::Client::new(),
+        next: AtomicUsize::new(0),
+        edge: Edge {
+            query_cache: args.query_cache_secs.map(Duration::from_secs),
+            rate_limit: args.rate_limit,
+            max_request_bytes: args.max_request_bytes,
+        },
+        domains,
+        cors_origins: args.cors_origins,
+        cache: Mutex::new(HashMap::new()),
+        buckets: Mutex::new(HashMap::new()),
+    });
+    let listen = match args.listen {
+        Some(listen) => listen,
+        None => format!("127.0.0.1:{}", gateway_port()?),
+    };
+    let (server, scheme) = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let config = SslConfig {
+                certificate: fs::read(cert)
+                    .with_context(|| format!("failed to read {:?}", cert))?,
+                private_key: fs::read(key).with_context(|| format!("failed to read {:?}", key))?,
+            };
+            (Server::https(&listen, config), "https")
+        }
+        _ => (Server::http(&listen), "http"),
+    };
+    let server = server.map_err(|e| anyhow!("{}: {}", listen, e))?;
+    println!("gateway listening on {}://{}", scheme, listen);
+
+    let reloading = Arc::clone(&gateway);
+    thread::spawn(move || loop {
+        thread::sleep(RELOAD_INTERVAL);
+        if let Err(e) = reloading.reload() {
+            eprintln!("failed to reload the routing table: {:#}", e);
+        }
+    });
+
+    for request in server.incoming_requests() {
+        let gateway = Arc::clone(&gateway);
+        thread::spawn(move || {
+            if let Err(e) = gateway.respond(request) {
+                eprintln!("failed to respond: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use crate::init::{node_state_dir, output_dir};
+use crate::process::replica_version;
+use crate::tools::ic_binary;
+use crate::topology::{self, NodeSummary, Topology};
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub(crate) enum GenerateCommand {
+    /// Ansible inventory grouping the hosts by subnet and a playbook running
+    /// the replicas as a systemd service
+    Ansible {
+        /// Defaults to tmp/ansible
+        #[clap(long)]
+        out: Option<PathBuf>,
+        /// Remote user to connect as
+        #[clap(long)]
+        user: Option<String>,
+    },
+    /// Terraform module whose variables and outputs describe the node layout
+    Terraform {
+        /// Defaults to tmp/terraform
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+fn host(node: &NodeSummary) -> Result<String> {
+    Ok(format!(
+        "node-{} ansible_host={} node_index={}",
+        node.index,
+        node.addr()?.ip(),
+        node.index
+    ))
+}
+
+fn inventory(topology: &Topology, user: Option<&str>) -> Result<String> {
+    let mut inventory = String::new();
+    let mut groups = vec![];
+
+    for subnet in &topology.subnets {
+        let group = format!("subnet_{}", subnet.index);
+        inventory.push_str(&format!("[{}]\n", group));
+        for node in &subnet.nodes {
+            inventory.push_str(&host(node)?);
+            inventory.push('\n');
+        }
+        inventory.push('\n');
+        groups.push(group);
+    }
+    if !topology.unassigned_nodes.is_empty() {
+        inventory.push_str("[unassigned]\n");
+        for node in &topology.unassigned_nodes {
+            inventory.push_str(&host(node)?);
+            inventory.push('\n');
+        }
+        inventory.push('\n');
+        groups.push("unassigned".to_string());
+    }
+
+    inventory.push_str("[replicas:children]\n");
+    for group in groups {
+        inventory.push_str(&group);
+        inventory.push('\n');
+    }
+
+    if let Some(user) = user {
+        inventory.push_str(&format!("\n[replicas:vars]\nansible_user={}\n", user));
+    }
+    Ok(inventory)
+}
+
+// The generated replica configs refer to absolute paths, so the hosts get the
+// same layout as the machine they were generated on.
+fn playbook(output_dir: &Path, base_dir: &Path, replica: &Path) -> String {
+    format!(
+        r#"- hosts: replicas
+  become: true
+  vars:
+    output_dir: "{output_dir}"
+    base_dir: "{base_dir}"
+    replica_binary: "{replica}"
+    replica_version: "{version}"
+  tasks:
+    - name: Create directories
+      file:
+        path: "{{{{ item }}}}"
+        state: directory
+      loop:
+        - /opt/ic-testnet/bin
+        - "{{{{ output_dir }}}}"
+        - "{{{{ base_dir }}}}"
+
+    - name: Install the replica
+      copy:
+        src: "{{{{ replica_binary }}}}"
+        dest: /opt/ic-testnet/bin/replica
+        mode: "0755"
+      notify: restart replica
+
+    - name: Copy the node config
+      copy:
+        src: "{{{{ output_dir }}}}/ic-{{{{ node_index }}}}.json5"
+        dest: "{{{{ output_dir }}}}/ic-{{{{ node_index }}}}.json5"
+      notify: restart replica
+
+    - name: Check for existing node state
+      stat:
+        path: "{{{{ base_dir }}}}/state-{{{{ node_index }}}}"
+      register: node_state
+
+    - name: Copy the initial state
+      copy:
+        src: "{{{{ output_dir }}}}/state/"
+        dest: "{{{{ base_dir }}}}/state-{{{{ node_index }}}}"
+      when: not node_state.stat.exists
+
+    - name: Install the replica service
+      copy:
+        dest: /etc/systemd/system/ic-replica.service
+        content: |
+          [Unit]
+          Description=IC testnet replica {{{{ node_index }}}}
+          After=network-online.target
+
+          [Service]
+          ExecStart=/opt/ic-testnet/bin/replica --replica-version {{{{ replica_version }}}} --config-file {{{{ output_dir }}}}/ic-{{{{ node_index }}}}.json5
+          Restart=on-failure
+          KillSignal=SIGTERM
+          TimeoutStopSec=60
+
+          [Install]
+          WantedBy=multi-user.target
+      notify: restart replica
+
+    - name: Run the replica
+      systemd:
+        name: ic-replica
+        enabled: true
+        state: started
+        daemon_reload: true
+
+  handlers:
+    - name: restart replica
+      systemd:
+        name: ic-replica
+        state: restarted
+        daemon_reload: true
+"#,
+        output_dir = output_dir.display(),
+        base_dir = base_dir.display(),
+        replica = replica.display(),
+        version = replica_version(),
+    )
+}
+
+fn terraform_node(node: &NodeSummary, subnet: Option<SubnetIndex>) -> Result<Value> {
+    let addr = node.addr()?;
+    let xnet: SocketAddr = node.xnet.parse()?;
+    let metrics = node
+        .metrics
+        .trim_start_matches("http://")
+        .parse::<SocketAddr>()?;
+    Ok(json!({
+        "node_id": node.node_id,
+        "ip": addr.ip().to_string(),
+        "http_port": addr.port(),
+        "xnet_port": xnet.port(),
+        "metrics_port": metrics.port(),
+        "subnet": subnet,
+    }))
+}
+
+// Written as Terraform JSON syntax so that no HCL has to be generated.
+fn terraform(topology: &Topology) -> Result<(Value, Value)> {
+    let mut nodes = serde_json::Map::new();
+    let mut subnets = serde_json::Map::new();
+    for subnet in &topology.subnets {
+        for node in &subnet.nodes {
+            nodes.insert(
+                node.index.to_string(),
+                terraform_node(node, Some(subnet.index))?,
+            );
+        }
+        let members: Vec<String> = subnet
+            .nodes
+            .iter()
+            .map(|node| node.index.to_string())
+            .collect();
+        subnets.insert(
+            subnet.index.to_string(),
+            json!({
+                "subnet_id": subnet.subnet_id,
+                "nns": subnet.index == topology.nns_subnet,
+                "nodes": members,
+            }),
+        );
+    }
+    for node in &topology.unassigned_nodes {
+        nodes.insert(node.index.to_string(), terraform_node(node, None)?);
+    }
+
+    let node_type = "map(object({node_id = string, ip = string, http_port = number, xnet_port = number, metrics_port = number, subnet = number}))";
+    let subnet_type = "map(object({subnet_id = string, nns = bool, nodes = list(string)}))";
+    let variables = json!({
+        "variable": {
+            "nodes": {
+                "description": "Nodes by index with their address, ports and subnet (null when unassigned)",
+                "type": node_type,
+                "default": nodes,
+            },
+            "subnets": {
+                "description": "Subnets by index with their member node indices",
+                "type": subnet_type,
+                "default": subnets,
+            },
+        }
+    });
+    let outputs = json!({
+        "output": {
+            "nodes": { "value": "${var.nodes}" },
+            "subnets": { "value": "${var.subnets}" },
+            "node_ips": {
+                "value": "${{ for index, node in var.nodes : index => node.ip }}",
+            },
+            "subnet_ips": {
+                "value": "${{ for index, subnet in var.subnets : index => [for node in subnet.nodes : var.nodes[node].ip] }}",
+            },
+        }
+    });
+
+    Ok((variables, outputs))
+}
+
+pub(crate) fn run(cmd: GenerateCommand) -> Result<()> {
+    let topology = topology::load()?;
+    match cmd {
+        GenerateCommand::Ansible { out, user } => {
+            let out = match out {
+                Some(out) => out,
+                None => output_dir()?.join("ansible"),
+            };
+            fs::create_dir_all(&out)?;
+
+            let base_dir = node_state_dir(0)?
+                .parent()
+                .map(Path::to_path_buf)
+                .ok_or(anyhow!("node state directories have no parent"))?;
+            let replica = env::current_dir()?.join(ic_binary("replica"));
+
+            fs::write(
+                out.join("inventory.ini"),
+                inventory(&topology, user.as_deref())?,
+            )?;
+            fs::write(
+                out.join("playbook.yml"),
+                playbook(&output_dir()?, &base_dir, &replica),
+            )?;
+            println!(
+                "ansible-playbook -i {:?} {:?}",
+                out.join("inventory.ini"),
+                out.join("playbook.yml")
+            );
+        }
+        GenerateCommand::Terraform { out } => {
+            let out = match out {
+                Some(out) => out,
+                None => output_dir()?.join("terraform"),
+            };
+            fs::create_dir_all(&out)?;
+
+            let (variables, outputs) = terraform(&topology)?;
+            fs::write(
+                out.join("variables.tf.json"),
+                serde_json::to_string_pretty(&variables)?,
+            )?;
+            fs::write(
+                out.join("outputs.tf.json"),
+                serde_json::to_string_pretty(&outputs)?,
+            )?;
+            println!("terraform module written to {:?}", out);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use crate::config::TestnetConfig;
+use crate::init::{self, NODE_INDEX};
+use crate::topology;
+use anyhow::Result;
+use clap::{ArgEnum, Args, Subcommand};
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Subcommand)]
+pub(crate) enum TopologyCommand {
+    /// Print the subnets, their nodes and the xnet links between subnets as a
+    /// Graphviz or mermaid diagram
+    Graph(GraphArgs),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+pub(crate) enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+#[derive(Args)]
+pub(crate) struct GraphArgs {
+    #[clap(long, arg_enum, default_value = "dot")]
+    format: GraphFormat,
+    /// Draw the topology a config file describes instead of the initialized
+    /// one, e.g. to review it before `init`
+    #[clap(long)]
+    config: Option<PathBuf>,
+}
+
+struct Node {
+    index: NodeIndex,
+    addr: String,
+    labels: BTreeMap<String, String>,
+}
+
+struct Subnet {
+    index: SubnetIndex,
+    nodes: Vec<Node>,
+}
+
+// Subnets in index order and the unassigned nodes.
+struct Graph {
+    nns_subnet: SubnetIndex,
+    subnets: Vec<Subnet>,
+    unassigned: Vec<Node>,
+}
+
+impl Graph {
+    fn initialized() -> Result<Graph> {
+        let topology = topology::load()?;
+        let config = TestnetConfig::saved()?;
+        let node = |node: &topology::NodeSummary| Node {
+            index: node.index,
+            addr: node.url.trim_start_matches("http://").to_string(),
+            labels: config.node(node.index).labels,
+        };
+        Ok(Graph {
+            nns_subnet: topology.nns_subnet,
+            subnets: topology
+                .subnets
+                .iter()
+                .map(|subnet| Subnet {
+                    index: subnet.index,
+                    nodes: subnet.nodes.iter().map(node).collect(),
+                })
+                .collect(),
+            unassigned: topology.unassigned_nodes.iter().map(node).collect(),
+        })
+    }
+
+    // Nodes get their indices in the order `init` assigns them.
+    fn from_config(config: &TestnetConfig) -> Graph {
+        let members = match config.node_addresses() {
+            Some(members) => members,
+            None => init::nodes()
+                .into_iter()
+                .map(|node| (node, Some(0)))
+                .collect(),
+        };
+        let mut graph = Graph {
+            nns_subnet: 0,
+            subnets: vec![],
+            unassigned: vec![],
+        };
+        for (i, (addr, subnet)) in members.into_iter().enumerate() {
+            let index = NODE_INDEX + i as NodeIndex;
+            let node = Node {
+                index,
+                addr,
+                labels: config.node(index).labels,
+            };
+            match subnet {
+                Some(subnet) => match graph.subnets.iter_mut().find(|s| s.index == subnet) {
+                    Some(existing) => existing.nodes.push(node),
+                    None => graph.subnets.push(Subnet {
+                        index: subnet,
+                        nodes: vec![node],
+                    }),
+                },
+                None => graph.unassigned.push(node),
+            }
+        }
+        graph.subnets.sort_by_key(|subnet| subnet.index);
+        graph
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.subnets
+            .iter()
+            .flat_map(|subnet| subnet.nodes.iter())
+            .chain(self.unassigned.iter())
+    }
+
+    // Addresses given to more than one node, a copy and paste mistake the
+    // diagram marks.
+    fn duplicate(&self, node: &Node) -> bool {
+        self.nodes().filter(|other| other.addr == node.addr).count() > 1
+    }
+
+    fn subnet_label(&self, subnet: &Subnet) -> String {
+        let n = subnet.nodes.len();
+        let nns = if subnet.index == self.nns_subnet {
+            " NNS"
+        } else {
+            ""
+        };
+        // consensus tolerates f faulty nodes out of 3f + 1
+        format!(
+            "subnet {}{}, {} nodes, tolerates {} faulty",
+            subnet.index,
+            nns,
+            n,
+            n.saturating_sub(1) / 3
+        )
+    }
+
+    fn node_label(&self, node: &Node) -> String {
+        let mut lines = vec![node.index.to_string(), node.addr.clone()];
+        if !node.labels.is_empty() {
+            let labels: Vec<String> = node
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            lines.push(labels.join(","));
+        }
+        if self.duplicate(node) {
+            lines.push("duplicate address".to_string());
+        }
+        lines.join("\\n")
+    }
+
+    // Every subnet exchanges xnet streams with every other subnet.
+    fn xnet_links(&self) -> Vec<(&Subnet, &Subnet)> {
+        let mut links = vec![];
+        for (i, a) in self.subnets.iter().enumerate() {
+            for b in self.subnets.iter().skip(i + 1) {
+                links.push((a, b));
+            }
+        }
+        links
+    }
+
+    fn dot(&self) -> String {
+        let mut out = vec![
+            "graph testnet {".to_string(),
+            "  compound=true;".to_string(),
+            "  node [shape=box];".to_string(),
+        ];
+        let node = |node: &Node| {
+            let color = if self.duplicate(node) {
+                ", color=red"
+            } else {
+                ""
+            };
+            format!(
+                "    n{} [label=\"{}\"{}];",
+                node.index,
+                self.node_label(node),
+                color
+            )
+        };
+        for subnet in &self.subnets {
+            out.push(format!("  subgraph cluster_subnet_{} {{", subnet.index));
+            out.push(format!("    label=\"{}\";", self.subnet_label(subnet)));
+            out.extend(subnet.nodes.iter().map(node));
+            out.push("  }".to_string());
+        }
+        if !self.unassigned.is_empty() {
+            out.push("  subgraph cluster_unassigned {".to_string());
+            out.push("    label=\"unassigned\";".to_string());
+            out.push("    style=dashed;".to_string());
+            out.extend(self.unassigned.iter().map(node));
+            out.push("  }".to_string());
+        }
+        // edges between clusters are drawn between one node of each
+        for (a, b) in self.xnet_links() {
+            let (Some(from), Some(to)) = (a.nodes.first(), b.nodes.first()) else {
+                continue;
+            };
+            out.push(format!(
+                "  n{} -- n{} [label=\"xnet\", style=dashed, ltail=cluster_subnet_{}, lhead=cluster_subnet_{}];",
+                from.index, to.index, a.index, b.index
+            ));
+        }
+        out.push("}".to_string());
+        out.join("\n")
+    }
+
+    fn mermaid(&self) -> String {
+        let mut out = vec!["flowchart LR".to_string()];
+        let node = |node: &Node| {
+            format!(
+                "    n{}[\"{}\"]",
+                node.index,
+                self.node_label(node).replace("\\n", "<br>")
+            )
+        };
+        for subnet in &self.subnets {
+            out.push(format!(
+                "  subgraph subnet{}[\"{}\"]",
+                subnet.index,
+                self.subnet_label(subnet)
+            ));
+            out.extend(subnet.nodes.iter().map(node));
+            out.push("  end".to_string());
+        }
+        if !self.unassigned.is_empty() {
+            out.push("  subgraph unassigned[\"unassigned\"]".to_string());
+            out.extend(self.unassigned.iter().map(node));
+            out.push("  end".to_string());
+        }
+        for (a, b) in self.xnet_links() {
+            out.push(format!("  subnet{} <-.->|xnet| subnet{}", a.index, b.index));
+        }
+        let duplicates: Vec<String> = self
+            .nodes()
+            .filter(|node| self.duplicate(node))
+            .map(|node| format!("n{}", node.index))
+            .collect();
+        if !duplicates.is_empty() {
+            out.push("  classDef duplicate stroke:#f00".to_string());
+            out.push(format!("  class {} duplicate", duplicates.join(",")));
+        }
+        out.join("\n")
+    }
+}
+
+pub(crate) fn run(cmd: TopologyCommand) -> Result<()> {
+    match cmd {
+        TopologyCommand::Graph(args) => {
+            let graph = match &args.config {
+                Some(path) => Graph::from_config(&TestnetConfig::load(Some(path))?),
+                None => Graph::initialized()?,
+            };
+            match args.format {
+                GraphFormat::Dot => println!("{}", graph.dot()),
+                GraphFormat::Mermaid => println!("{}", graph.mermaid()),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use crate::agent::{block_on, connect, create_canister, install_code};
+use crate::hooks::{self, Event};
+use crate::init::{self, output_dir, InitArgs};
+use crate::nns::{self, NnsCommand};
+use crate::tools::ic_binary;
+use crate::topology;
+use crate::{backend, health, propose};
+use anyhow::{bail, Result};
+use candid::Principal;
+use clap::{Args, Subcommand};
+use ic_agent::Agent;
+use ic_management_canister_types::CanisterInstallMode;
+use ic_prep_lib::node::NodeIndex;
+use ic_universal_canister::{wasm, UNIVERSAL_CANISTER_WASM};
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const WORKLOAD_WRITES: u64 = 10;
+
+#[derive(Subcommand)]
+pub(crate) enum TestCommand {
+    /// Upgrade (or downgrade) a fresh testnet between two replica versions
+    Upgrade(UpgradeArgs),
+}
+
+#[derive(Args)]
+pub(crate) struct UpgradeArgs {
+    #[clap(long)]
+    from: String,
+    #[clap(long)]
+    to: String,
+    /// Replica binary of the starting version, defaults to the ic build
+    #[clap(long)]
+    from_bin: Option<PathBuf>,
+    /// Replica binary of the target version
+    #[clap(long)]
+    to_bin: PathBuf,
+    /// Release package recorded when electing the target version
+    #[clap(
+        long,
+        default_value = "https://download.dfinity.systems/unused.tar.zst"
+    )]
+    release_package_url: String,
+    #[clap(
+        long,
+        default_value = "0000000000000000000000000000000000000000000000000000000000000000"
+    )]
+    release_package_sha256_hex: String,
+    /// Directory containing the NNS canister wasms
+    #[clap(long, env = "NNS_WASM_DIR", default_value = "ic/artifacts/canisters")]
+    wasm_dir: PathBuf,
+    /// Seconds to wait for each phase
+    #[clap(long, default_value = "600")]
+    timeout: u64,
+}
+
+// The workload keeps a counter in the stable memory of a universal canister.
+async fn write_counter(agent: &Agent, canister_id: Principal, value: u64) -> Result<()> {
+    let payload = wasm().stable_write(0, &value.to_le_bytes()).reply().build();
+    agent
+        .update(&canister_id, "update")
+        .with_arg(payload)
+        .call_and_wait()
+        .await?;
+    Ok(())
+}
+
+async fn read_counter(agent: &Agent, canister_id: Principal) -> Result<u64> {
+    let payload = wasm().stable_read(0, 8).append_and_reply().build();
+    let bytes = agent
+        .query(&canister_id, "query")
+        .with_arg(payload)
+        .call()
+        .await?;
+    Ok(u64::from_le_bytes(bytes[..8].try_into()?))
+}
+
+async fn start_workload(url: &str) -> Result<Principal> {
+    let agent = connect(url, None).await?;
+    let canister_id = create_canister(&agent, 0, None).await?;
+    install_code(
+        &agent,
+        canister_id,
+        CanisterInstallMode::Install,
+        UNIVERSAL_CANISTER_WASM.to_vec(),
+        vec![],
+    )
+    .await?;
+    agent
+        .update(&canister_id, "update")
+        .with_arg(wasm().stable_grow(1).reply().build())
+        .call_and_wait()
+        .await?;
+
+    for value in 1..=WORKLOAD_WRITES {
+        write_counter(&agent, canister_id, value).await?;
+    }
+
+    Ok(canister_id)
+}
+
+async fn verify_workload(url: &str, canister_id: Principal) -> Result<()> {
+    let agent = connect(url, None).await?;
+
+    let value = read_counter(&agent, canister_id).await?;
+    if value != WORKLOAD_WRITES {
+        bail!(
+            "workload state lost, counter is {} instead of {}",
+            value,
+            WORKLOAD_WRITES
+        );
+    }
+
+    write_counter(&agent, canister_id, value + 1).await?;
+    let value = read_counter(&agent, canister_id).await?;
+    if value != WORKLOAD_WRITES + 1 {
+        bail!("workload did not continue, counter is {}", value);
+    }
+
+    Ok(())
+}
+
+fn upgrade(args: UpgradeArgs) -> Result<()> {
+    if output_dir()?.join("topology.json").exists() {
+        bail!(
+            "{:?} already contains a testnet, run `make clean` first",
+            output_dir()?
+        );
+    }
+    let timeout = Duration::from_secs(args.timeout);
+    let from_bin = args.from_bin.unwrap_or(ic_binary("replica"));
+
+    println!("bringing up the testnet on {}", args.from);
+    init::run(InitArgs {
+        replica_version: Some(args.from.clone()),
+        ..InitArgs::default()
+    })?;
+    let topology = topology::load()?;
+    let nodes: Vec<NodeIndex> = topology.nodes().map(|node| node.index).collect();
+    for node in &nodes {
+        backend::for_node(*node)?.start_with(*node, &from_bin, &args.from)?;
+    }
+    for subnet in &topology.subnets {
+        health::wait_healthy(subnet, &[], timeout)?;
+    }
+
+    nns::run(NnsCommand::Install {
+        nns_url: None,
+        wasm_dir: args.wasm_dir,
+        sns_subnet: None,
+        icp_index: false,
+        icrc_ledgers: vec![],
+    })?;
+
+    let url = topology.subnet_url(0)?;
+    let canister_id = block_on(start_workload(&url))?;
+    println!("workload canister {}", canister_id);
+
+    println!("upgrading to {}", args.to);
+    let nns_url = topology.subnet_url(topology.nns_subnet)?;
+    propose::submit(
+        &nns_url,
+        None,
+        &format!("Elect replica version {}", args.to),
+        vec![
+            "propose-to-revise-elected-guestos-versions".to_string(),
+            "--replica-version-to-elect".to_string(),
+            args.to.clone(),
+            "--release-package-urls".to_string(),
+            args.release_package_url,
+            "--release-package-sha256-hex".to_string(),
+            args.release_package_sha256_hex,
+        ],
+    )?;
+    for subnet in &topology.subnets {
+        propose::submit(
+            &nns_url,
+            None,
+            &format!("Upgrade subnet {} to {}", subnet.index, args.to),
+            vec![
+                "propose-to-deploy-guestos-to-all-subnet-nodes".to_string(),
+                subnet.subnet_id.clone(),
+                args.to.clone(),
+            ],
+        )?;
+    }
+
+    // Without an orchestrator the replicas halt at the upgrade CUP and are
+    // restarted on the new binary by hand.
+    for subnet in &topology.subnets {
+        let height = health::wait_halted(subnet, Duration::from_secs(30), timeout)?;
+        println!("subnet {} halted at height {}", subnet.index, height);
+    }
+    for node in &nodes {
+        let backend = backend::for_node(*node)?;
+        backend.stop(*node, Duration::from_secs(30))?;
+        backend.start_with(*node, &args.to_bin, &args.to)?;
+    }
+    for subnet in &topology.subnets {
+        health::wait_healthy(subnet, &[], timeout)?;
+    }
+
+    block_on(verify_workload(&url, canister_id))?;
+
+    let message = format!("upgrade from {} to {} succeeded", args.from, args.to);
+    println!("{}", message);
+    hooks::emit(
+        Event::UpgradeComplete,
+        &message,
+        json!({ "from": args.from, "to": args.to }),
+    );
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: TestCommand) -> Result<()> {
+    match cmd {
+        TestCommand::Upgrade(args) => upgrade(args),
+    }
+}
@@ -0,0 +1,104 @@
+use crate::metrics::finalized_height;
+use crate::topology::SubnetSummary;
+use anyhow::{bail, Result};
+use ic_prep_lib::node::NodeIndex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) fn heights(subnet: &SubnetSummary) -> Vec<(NodeIndex, Option<u64>)> {
+    subnet
+        .nodes
+        .iter()
+        .map(|node| (node.index, finalized_height(&node.metrics).ok()))
+        .collect()
+}
+
+fn max_height(subnet: &SubnetSummary) -> Option<u64> {
+    heights(subnet)
+        .into_iter()
+        .filter_map(|(_, height)| height)
+        .max()
+}
+
+pub(crate) fn wait_height(subnet: &SubnetSummary, height: u64, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let current = max_height(subnet);
+        if current >= Some(height) {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "subnet {} did not reach height {} within {:?}, at {:?}",
+                subnet.index,
+                height,
+                timeout,
+                current
+            );
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// A subnet is healthy when every node (except the skipped ones) reports its
+// height and the subnet keeps finalizing blocks.
+pub(crate) fn wait_healthy(
+    subnet: &SubnetSummary,
+    skip: &[NodeIndex],
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let start = max_height(subnet);
+
+    loop {
+        let heights = heights(subnet);
+        let missing: Vec<NodeIndex> = heights
+            .iter()
+            .filter(|(node, height)| height.is_none() && !skip.contains(node))
+            .map(|(node, _)| *node)
+            .collect();
+        let max = heights.iter().filter_map(|(_, height)| *height).max();
+
+        if missing.is_empty() && max.is_some() && max > start {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "subnet {} is not healthy after {:?}: unresponsive nodes {:?}, height {:?}",
+                subnet.index,
+                timeout,
+                missing,
+                max
+            );
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Waits until the subnet stops finalizing blocks for `quiet`, e.g. because it
+// halted at an upgrade CUP, and returns the height it stopped at.
+pub(crate) fn wait_halted(
+    subnet: &SubnetSummary,
+    quiet: Duration,
+    timeout: Duration,
+) -> Result<u64> {
+    let deadline = Instant::now() + timeout;
+    let mut last = max_height(subnet);
+    let mut since = Instant::now();
+
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let current = max_height(subnet);
+        if current != last {
+            last = current;
+            since = Instant::now();
+        } else if since.elapsed() >= quiet {
+            return Ok(last.unwrap_or(0));
+        }
+        if Instant::now() > deadline {
+            bail!("subnet {} did not halt within {:?}", subnet.index, timeout);
+        }
+    }
+}
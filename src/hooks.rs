@@ -0,0 +1,130 @@
+use crate::init::output_dir;
+use anyhow::Result;
+use clap::{ArgEnum, Subcommand};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ArgEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Event {
+    NodeCrash,
+    SubnetStall,
+    UpgradeComplete,
+    SnapshotRestore,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Hook {
+    url: String,
+    // empty means every event
+    events: Vec<Event>,
+    // post `{"text": ...}` payloads understood by Slack incoming webhooks
+    slack: bool,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum HooksCommand {
+    /// Register a webhook
+    Add {
+        url: String,
+        /// Events to notify about, all when omitted
+        #[clap(long = "event", arg_enum)]
+        events: Vec<Event>,
+        #[clap(long)]
+        slack: bool,
+    },
+    /// Remove a webhook
+    Remove { url: String },
+    /// List the registered webhooks
+    List,
+    /// Send a test notification
+    Test {
+        #[clap(arg_enum, default_value = "node-crash")]
+        event: Event,
+    },
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(output_dir()?.join("hooks.json"))
+}
+
+fn load() -> Result<Vec<Hook>> {
+    let path = path()?;
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save(hooks: &[Hook]) -> Result<()> {
+    fs::write(path()?, serde_json::to_string_pretty(hooks)?)?;
+    Ok(())
+}
+
+// Notifies every hook subscribed to the event and returns whether there was
+// one. Delivery failures are reported but never interrupt the caller.
+pub(crate) fn emit(event: Event, message: &str, details: serde_json::Value) -> bool {
+    let hooks: Vec<Hook> = load()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|hook| hook.events.is_empty() || hook.events.contains(&event))
+        .collect();
+
+    for hook in &hooks {
+        let body = if hook.slack {
+            json!({ "text": message })
+        } else {
+            json!({ "event": event, "message": message, "details": details })
+        };
+        if let Err(e) = reqwest::blocking::Client::new()
+            .post(&hook.url)
+            .json(&body)
+            .send()
+            .and_then(|response| response.error_for_status())
+        {
+            eprintln!("failed to notify {}: {}", hook.url, e);
+        }
+    }
+
+    !hooks.is_empty()
+}
+
+pub(crate) fn run(cmd: HooksCommand) -> Result<()> {
+    match cmd {
+        HooksCommand::Add { url, events, slack } => {
+            let mut hooks = load()?;
+            hooks.retain(|hook| hook.url != url);
+            hooks.push(Hook { url, events, slack });
+            save(&hooks)?;
+        }
+        HooksCommand::Remove { url } => {
+            let mut hooks = load()?;
+            hooks.retain(|hook| hook.url != url);
+            save(&hooks)?;
+        }
+        HooksCommand::List => {
+            for hook in load()? {
+                println!(
+                    "{} {:?}{}",
+                    hook.url,
+                    hook.events,
+                    if hook.slack { " (slack)" } else { "" }
+                );
+            }
+        }
+        HooksCommand::Test { event } => {
+            if !emit(
+                event,
+                &format!("ic-testnet test notification for {:?}", event),
+                json!({}),
+            ) {
+                println!("no hooks registered for {:?}", event);
+            }
+        }
+    }
+
+    Ok(())
+}
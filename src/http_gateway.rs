@@ -0,0 +1,124 @@
+use crate::agent::{block_on, connect};
+use anyhow::{anyhow, bail, Result};
+use candid::types::value::IDLValue;
+use candid::{CandidType, Decode, Deserialize, Encode, Func, IDLArgs, Principal};
+use ic_agent::Agent;
+
+// Longest chain of streaming callbacks followed for one response.
+const MAX_CHUNKS: usize = 10_000;
+
+#[derive(CandidType)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CallbackStrategy {
+    callback: Func,
+    token: IDLValue,
+}
+
+#[derive(CandidType, Deserialize)]
+enum StreamingStrategy {
+    Callback(CallbackStrategy),
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+    upgrade: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<IDLValue>,
+}
+
+pub(crate) struct CanisterRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+pub(crate) struct CanisterResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// The rest of a streamed body, from the callbacks the canister names.
+async fn stream(agent: &Agent, strategy: StreamingStrategy, body: &mut Vec<u8>) -> Result<()> {
+    let StreamingStrategy::Callback(CallbackStrategy {
+        callback,
+        mut token,
+    }) = strategy;
+    for _ in 0..MAX_CHUNKS {
+        let reply = agent
+            .query(&callback.principal, &callback.method)
+            .with_arg(IDLArgs::new(&[token]).to_bytes()?)
+            .call()
+            .await?;
+        let chunk = Decode!(&reply, StreamingCallbackHttpResponse)?;
+        body.extend_from_slice(&chunk.body);
+        match chunk.token {
+            Some(next) => token = next,
+            None => return Ok(()),
+        }
+    }
+    bail!("the body streams more than {} chunks", MAX_CHUNKS)
+}
+
+// Serves a request the way the HTTP gateway protocol does: an http_request
+// query, upgraded to an http_request_update call when the canister asks for
+// it. Responses are not verified against their certification.
+pub(crate) fn serve(
+    url: &str,
+    canister_id: Principal,
+    request: CanisterRequest,
+) -> Result<CanisterResponse> {
+    let arg = HttpRequest {
+        method: request.method,
+        url: request.url,
+        headers: request.headers,
+        body: request.body,
+        certificate_version: Some(2),
+    };
+    block_on(async {
+        let agent = connect(url, None).await?;
+        let reply = agent
+            .query(&canister_id, "http_request")
+            .with_arg(Encode!(&arg)?)
+            .call()
+            .await
+            .map_err(|e| anyhow!("http_request of {} failed: {}", canister_id, e))?;
+        let mut response = Decode!(&reply, HttpResponse)?;
+        if response.upgrade == Some(true) {
+            let reply = agent
+                .update(&canister_id, "http_request_update")
+                .with_arg(Encode!(&arg)?)
+                .call_and_wait()
+                .await
+                .map_err(|e| anyhow!("http_request_update of {} failed: {}", canister_id, e))?;
+            response = Decode!(&reply, HttpResponse)?;
+        }
+
+        let mut body = response.body;
+        if let Some(strategy) = response.streaming_strategy {
+            stream(&agent, strategy, &mut body).await?;
+        }
+        Ok(CanisterResponse {
+            status: response.status_code,
+            headers: response.headers,
+            body,
+        })
+    })
+}
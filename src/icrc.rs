@@ -0,0 +1,74 @@
+use candid::{CandidType, Nat, Principal};
+
+// Subsets of the ICRC ledger and index init arguments. Optional fields that
+// are left out decode as `null` on the canister side.
+
+#[derive(CandidType, Clone)]
+pub(crate) struct Account {
+    pub owner: Principal,
+}
+
+#[derive(CandidType)]
+pub(crate) enum MetadataValue {
+    Text(String),
+}
+
+#[derive(CandidType)]
+pub(crate) struct ArchiveOptions {
+    pub num_blocks_to_archive: u64,
+    pub trigger_threshold: u64,
+    pub controller_id: Principal,
+}
+
+#[derive(CandidType)]
+pub(crate) struct LedgerInitArgs {
+    pub minting_account: Account,
+    pub transfer_fee: Nat,
+    pub token_symbol: String,
+    pub token_name: String,
+    pub metadata: Vec<(String, MetadataValue)>,
+    pub initial_balances: Vec<(Account, Nat)>,
+    pub archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType)]
+pub(crate) enum LedgerArg {
+    Init(LedgerInitArgs),
+}
+
+#[derive(CandidType)]
+pub(crate) struct IndexInitArg {
+    pub ledger_id: Principal,
+}
+
+#[derive(CandidType)]
+pub(crate) enum IndexArg {
+    Init(IndexInitArg),
+}
+
+pub(crate) fn ledger_arg(
+    symbol: &str,
+    name: &str,
+    minting_account: Principal,
+    transfer_fee: u64,
+    initial_balances: Vec<(Principal, u64)>,
+) -> LedgerArg {
+    LedgerArg::Init(LedgerInitArgs {
+        minting_account: Account {
+            owner: minting_account,
+        },
+        transfer_fee: Nat::from(transfer_fee),
+        token_symbol: symbol.to_string(),
+        token_name: name.to_string(),
+        metadata: vec![],
+        initial_balances: initial_balances
+            .into_iter()
+            .map(|(owner, amount)| (Account { owner }, Nat::from(amount)))
+            .collect(),
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 1000,
+            trigger_threshold: 2000,
+            controller_id: minting_account,
+        },
+    })
+}
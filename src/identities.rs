@@ -0,0 +1,147 @@
+use crate::init::output_dir;
+use crate::secrets;
+use anyhow::{anyhow, bail, Result};
+use ic_types::PrincipalId;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha224};
+use std::fs;
+use std::path::PathBuf;
+
+const NEURON_ID_BASE: u64 = 1_000;
+const EIGHT_YEARS_NS: u64 = 8 * 365 * 24 * 3600 * 1_000_000_000;
+// DER prefix of an Ed25519 SubjectPublicKeyInfo
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Identity {
+    pub name: String,
+    pub principal: String,
+    pub account_id: String,
+    pub neuron_id: u64,
+    pub pem: PathBuf,
+}
+
+pub(crate) fn identities_dir() -> Result<PathBuf> {
+    Ok(output_dir()?.join("identities"))
+}
+
+pub(crate) fn load() -> Result<Vec<Identity>> {
+    let dir = identities_dir()?;
+    if secrets::is_sealed(&dir) {
+        bail!("the identities are sealed, run `ic-testnet secrets unseal`");
+    }
+    let manifest = dir.join("identities.json");
+    let json = fs::read_to_string(&manifest)
+        .map_err(|e| anyhow!("failed to read {:?}: {}", manifest, e))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+// The identity of the neuron holding the majority of the voting power, None
+// when no identities were generated and the test neuron of ic-nns-init has it.
+pub(crate) fn majority() -> Result<Option<Identity>> {
+    let dir = identities_dir()?;
+    if !dir.join("identities.json").is_file() && !secrets::is_sealed(&dir) {
+        return Ok(None);
+    }
+    Ok(load()?
+        .into_iter()
+        .min_by_key(|identity| identity.neuron_id))
+}
+
+pub(crate) fn find(name: &str) -> Result<Identity> {
+    load()?
+        .into_iter()
+        .find(|identity| identity.name == name)
+        .ok_or(anyhow!("unknown identity {}", name))
+}
+
+// A dfx config root, for DFX_CONFIG_ROOT, whose selected identity is this
+// one, so that tools built on dfx act as it without touching the identities
+// of the user.
+pub(crate) fn dfx_config_root(identity: &Identity) -> Result<PathBuf> {
+    let root = output_dir()?.join("dfx-config");
+    let dfx_dir = root.join(".config").join("dfx");
+    let identity_dir = dfx_dir.join("identity").join(&identity.name);
+    fs::create_dir_all(&identity_dir)?;
+    fs::copy(&identity.pem, identity_dir.join("identity.pem"))?;
+    fs::write(
+        dfx_dir.join("identity.json"),
+        serde_json::to_string_pretty(&json!({ "default": identity.name }))?,
+    )?;
+    Ok(root)
+}
+
+pub(crate) fn account_identifier(principal: &PrincipalId) -> String {
+    hex::encode(account_id_bytes(principal.as_slice(), [0u8; 32]))
+}
+
+// The 32 byte ICP ledger account of a principal and subaccount.
+pub(crate) fn account_id_bytes(principal: &[u8], subaccount: [u8; 32]) -> Vec<u8> {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(principal);
+    hasher.update(subaccount);
+    let hash = hasher.finalize();
+    let checksum = crc32fast::hash(&hash).to_be_bytes();
+
+    [&checksum[..], &hash[..]].concat()
+}
+
+pub(crate) fn run(count: usize) -> Result<()> {
+    let dir = identities_dir()?;
+    fs::create_dir_all(&dir)?;
+
+    let rng = SystemRandom::new();
+    let mut identities = vec![];
+    let mut neurons = vec![
+        "neuron_id;owner_id;created_ts_ns;duration_to_dissolution_ns;staked_icpt;earnings;follows;not_for_profit;memo;maturity_e8s_equivalent;kyc_verified".to_string(),
+    ];
+
+    for i in 0..count {
+        let name = format!("identity-{}", i);
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| anyhow!("failed to generate key for {}", name))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|_| anyhow!("failed to parse generated key for {}", name))?;
+
+        let pem_path = dir.join(format!("{}.pem", name));
+        fs::write(
+            &pem_path,
+            pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref().to_vec())),
+        )?;
+
+        let der = [&ED25519_DER_PREFIX[..], key_pair.public_key().as_ref()].concat();
+        let principal = PrincipalId::new_self_authenticating(&der);
+        let neuron_id = NEURON_ID_BASE + i as u64;
+
+        // The first neuron holds the majority of the voting power so that its
+        // proposals are adopted right away.
+        let staked_icpt = if i == 0 { 1_000_000_000 } else { 1_000 };
+        neurons.push(format!(
+            "{};{};0;{};{};C;;false;{};0;true",
+            neuron_id, principal, EIGHT_YEARS_NS, staked_icpt, i
+        ));
+
+        println!("{} {} {}", name, principal, neuron_id);
+        identities.push(Identity {
+            name,
+            principal: principal.to_string(),
+            account_id: account_identifier(&principal),
+            neuron_id,
+            pem: pem_path,
+        });
+    }
+
+    fs::write(dir.join("initial-neurons.csv"), neurons.join("\n") + "\n")?;
+    fs::write(
+        dir.join("identities.json"),
+        serde_json::to_string_pretty(&identities)?,
+    )?;
+
+    Ok(())
+}
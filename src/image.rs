@@ -0,0 +1,175 @@
+use crate::init::output_dir;
+use crate::metadata::PINNED_BINARIES;
+use crate::process::{self, replica_version};
+use crate::tools::ic_binary;
+use crate::topology;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Libraries of glibc, which every base image has in a matching version.
+const GLIBC_LIBRARIES: [&str; 7] = [
+    "linux-vdso",
+    "ld-linux",
+    "libc.so",
+    "libm.so",
+    "libdl.so",
+    "libpthread.so",
+    "librt.so",
+];
+
+#[derive(Args)]
+pub(crate) struct BuildImageArgs {
+    /// Nodes whose replica configs go into the image, all nodes when omitted
+    #[clap(long = "node")]
+    nodes: Vec<NodeIndex>,
+    /// Registry the image is pushed to
+    #[clap(long, default_value = "localhost:5000")]
+    registry: String,
+    #[clap(long, default_value = "ic-testnet-replica")]
+    name: String,
+    #[clap(long, default_value = "latest")]
+    tag: String,
+    /// Image the binaries are added to, it needs a glibc as new as the one of
+    /// the build host
+    #[clap(long, default_value = "debian:bookworm-slim")]
+    base: String,
+    /// Only build and tag the image locally
+    #[clap(long)]
+    no_push: bool,
+}
+
+fn docker(args: &[&str]) -> Result<()> {
+    let status = Command::new("docker")
+        .args(args)
+        .status()
+        .context("failed to run docker")?;
+    if !status.success() {
+        bail!("docker {} failed", args.join(" "));
+    }
+    Ok(())
+}
+
+// Shared libraries of a binary besides glibc, from ldd. Static binaries have
+// none.
+fn shared_libraries(binary: &Path) -> Result<Vec<PathBuf>> {
+    let output = Command::new("ldd")
+        .arg(binary)
+        .output()
+        .context("failed to run ldd")?;
+    if !output.status.success() {
+        return Ok(vec![]);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !GLIBC_LIBRARIES.iter().any(|lib| line.contains(lib)))
+        .filter_map(|line| {
+            let (_, path) = line.split_once("=>")?;
+            let path = path.trim().split(" (").next()?;
+            path.starts_with('/').then(|| PathBuf::from(path))
+        })
+        .collect())
+}
+
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    let status = Command::new("cp").arg("-a").arg(from).arg(to).status()?;
+    if !status.success() {
+        bail!("failed to copy {:?} to {:?}", from, to);
+    }
+    Ok(())
+}
+
+// The build context: the binaries and their libraries under /opt/ic-testnet,
+// and the replica configs and initial state at the paths they have on this
+// host, which the configs refer to.
+fn write_context(context: &Path, nodes: &[NodeIndex], base: &str) -> Result<Vec<String>> {
+    if context.is_dir() {
+        fs::remove_dir_all(context)?;
+    }
+    let bin = context.join("opt/ic-testnet/bin");
+    let lib = context.join("opt/ic-testnet/lib");
+    fs::create_dir_all(&bin)?;
+    fs::create_dir_all(&lib)?;
+
+    let mut binaries = vec![];
+    for name in PINNED_BINARIES {
+        let binary = ic_binary(name);
+        if !binary.is_file() {
+            if name == "replica" {
+                bail!("there is no replica at {:?}", binary);
+            }
+            continue;
+        }
+        fs::copy(&binary, bin.join(name))?;
+        for library in shared_libraries(&binary)? {
+            let file_name = library.file_name().unwrap_or_default();
+            if !lib.join(file_name).is_file() {
+                fs::copy(&library, lib.join(file_name))
+                    .with_context(|| format!("failed to copy {:?}", library))?;
+            }
+        }
+        binaries.push(name.to_string());
+    }
+
+    let output_dir = output_dir()?;
+    let rootfs = context.join("rootfs").join(output_dir.strip_prefix("/")?);
+    fs::create_dir_all(&rootfs)?;
+    for node in nodes {
+        let config = process::config_file(*node);
+        fs::copy(output_dir.join(&config), rootfs.join(&config))
+            .with_context(|| format!("failed to copy the replica config of node {}", node))?;
+    }
+    copy_tree(&output_dir.join("state"), &rootfs.join("state"))?;
+
+    fs::write(
+        context.join("Dockerfile"),
+        format!(
+            r#"FROM {base}
+COPY opt /opt
+COPY rootfs /
+ENV LD_LIBRARY_PATH=/opt/ic-testnet/lib
+LABEL org.opencontainers.image.title="ic-testnet replica"
+LABEL org.opencontainers.image.version="{version}"
+CMD ["/opt/ic-testnet/bin/replica", "--help"]
+"#,
+            version = replica_version(),
+        ),
+    )?;
+
+    Ok(binaries)
+}
+
+pub(crate) fn run(args: BuildImageArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let mut nodes = args.nodes.clone();
+    if nodes.is_empty() {
+        nodes = topology.nodes().map(|node| node.index).collect();
+    }
+    for node in &nodes {
+        topology.node(*node)?;
+    }
+
+    let context = output_dir()?.join("image");
+    let binaries = write_context(&context, &nodes, &args.base)?;
+    println!(
+        "build context {:?} with {} and the configs of nodes {:?}",
+        context,
+        binaries.join(", "),
+        nodes
+    );
+
+    let local = format!("{}:{}", args.name, args.tag);
+    let remote = format!("{}/{}", args.registry, local);
+    let context = context.to_string_lossy();
+    docker(&["build", "--tag", &local, "--tag", &remote, &context])?;
+    if !args.no_push {
+        docker(&["push", &remote])?;
+    }
+    println!("{}", if args.no_push { &local } else { &remote });
+
+    Ok(())
+}
@@ -0,0 +1,930 @@
+use crate::config::{IdRange, NodeSettings, SubnetSettings, TestnetConfig};
+use crate::schema::{self, SCHEMA_VERSION};
+use crate::{
+    backend, chaos, checkpoint, dfx, env_file, logging, metadata, namespace, process,
+    registry_export, replica_config, root_key, routing, topology, wizard,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use ic_config::embedders::FeatureFlags;
+use ic_config::flag_status::FlagStatus;
+use ic_config::logger::Level;
+use ic_config::{
+    adapters::AdaptersConfig,
+    artifact_pool::ArtifactPoolTomlConfig,
+    crypto::CryptoConfig,
+    http_handler::Config as HttpHandlerConfig,
+    logger::Config as LoggerConfig,
+    metrics::{Config as MetricsConfig, Exporter},
+    registry_client::Config as RegistryClientConfig,
+    state_manager::Config as StateManagerConfig,
+    tracing::Config as TracingConfig,
+    transport::TransportConfig,
+    ConfigOptional as ReplicaConfig,
+};
+use ic_config::{
+    embedders::Config as EmbeddersConfig, execution_environment::Config as HypervisorConfig,
+};
+use ic_logger::{info, new_replica_logger_from_config};
+use ic_management_canister_types::MasterPublicKeyId;
+use ic_prep_lib::subnet_configuration::{constants, SubnetIndex};
+use ic_prep_lib::{
+    internet_computer::{IcConfig, TopologyConfig},
+    node::{NodeConfiguration, NodeIndex},
+    subnet_configuration::{SubnetConfig, SubnetRunningState},
+};
+use ic_registry_provisional_whitelist::ProvisionalWhitelist;
+use ic_registry_subnet_features::{
+    ChainKeyConfig, KeyConfig, SubnetFeatures, DEFAULT_ECDSA_MAX_QUEUE_SIZE,
+};
+use ic_registry_subnet_type::SubnetType;
+use ic_types::{Cycles, Height, ReplicaVersion};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs};
+use std::{io, str::FromStr};
+
+pub(crate) const NODE_INDEX: NodeIndex = 100;
+const HTTP_PORT: u16 = 4100;
+const METRICS_PORT: u16 = 9090;
+const GATEWAY_PORT: u16 = 4950;
+// The id the replicas route the bitcoin API of testnet and regtest to, and the
+// key the ckBTC minter signs with.
+pub(crate) const BITCOIN_CANISTER_ID: &str = "g4xu7-jiaaa-aaaan-aaaaq-cai";
+const CKBTC_KEY_ID: &str = "ecdsa:Secp256k1:dfx_test_key";
+// Port the transport of a replica binds behind the proxy of `run`, peers keep
+// dialing the usual transport port where the proxy listens.
+const PROXIED_TRANSPORT_PORT: u16 = 34100;
+
+// Block making delays of `--fast`, for testnets on a single host where
+// messages between nodes arrive without delay.
+const FAST_UNIT_DELAY: Duration = Duration::from_millis(100);
+const FAST_INITIAL_NOTARY_DELAY: Duration = Duration::from_millis(0);
+
+#[derive(Args, Default)]
+pub(crate) struct InitArgs {
+    /// OpenTelemetry collector receiving replica traces, e.g. http://10.5.0.5:4317
+    #[clap(long)]
+    pub(crate) jaeger_addr: Option<String>,
+    /// Export traces only from these nodes, all nodes when omitted
+    #[clap(long = "trace-node")]
+    pub(crate) trace_nodes: Vec<NodeIndex>,
+    /// Run a node with a skewed clock, e.g. 102=+2s (requires libfaketime)
+    #[clap(long = "clock-skew", allow_hyphen_values = true)]
+    pub(crate) clock_skews: Vec<String>,
+    /// Replica version recorded in the registry
+    #[clap(long)]
+    pub(crate) replica_version: Option<String>,
+    /// Write replica configs in the shape of an older replica release, e.g.
+    /// 2024-06-12 or rc--2024-06-12_23-01
+    #[clap(long)]
+    pub(crate) replica_release: Option<String>,
+    /// JSON5 file with replica config fields merged onto the generated config
+    /// of every node, and of single nodes under "nodes"
+    #[clap(long)]
+    pub(crate) replica_overrides: Option<PathBuf>,
+    /// Delete the state of a previous run before generating a new one
+    #[clap(long)]
+    pub(crate) force_reinit: bool,
+    /// Serve the first node on 127.0.0.1:4943 and register it as the local
+    /// network of dfx
+    #[clap(long)]
+    pub(crate) dfx: bool,
+    /// TOML file with the topology and per-subnet and per-node settings
+    #[clap(long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Register the IPv6 address of nodes given by host name when they have
+    /// one
+    #[clap(long)]
+    pub(crate) prefer_ipv6: bool,
+    /// Make blocks as fast as possible, with minimal unit and notary delays
+    /// on all subnets
+    #[clap(long)]
+    pub(crate) fast: bool,
+    /// Ask for the topology and settings and write them to a config file
+    /// first
+    #[clap(long, conflicts_with = "config")]
+    pub(crate) interactive: bool,
+    /// Bind the transport of every replica behind a proxy of `run`, so that
+    /// `chaos latency` and `chaos partition` need neither root nor iptables
+    #[clap(long)]
+    pub(crate) transport_proxy: bool,
+    /// Route the canister ids reserved for specified ids to the NNS subnet,
+    /// so that `canister create --id` can create canisters with mainnet ids
+    #[clap(long)]
+    pub(crate) use_specified_ids_allocation_range: bool,
+    /// Generate a threshold key on a subnet, e.g. 1=ecdsa:Secp256k1:dfx_test_key
+    /// or 1=schnorr:Ed25519:dfx_test_key, in addition to the chain_keys of
+    /// the config
+    #[clap(long = "chain-key")]
+    pub(crate) chain_keys: Vec<String>,
+    /// Prepare a subnet for `ckbtc install`: bitcoin adapters connected to
+    /// --bitcoind on its nodes, the ECDSA key dfx_test_key and the id of the
+    /// bitcoin canister
+    #[clap(long)]
+    pub(crate) ckbtc_subnet: Option<SubnetIndex>,
+    /// Regtest bitcoind of the bitcoin adapters of --ckbtc-subnet
+    #[clap(long, default_value = "127.0.0.1:18444")]
+    pub(crate) bitcoind: String,
+}
+
+// Node addresses, from NODES at run time or else at build time.
+pub(crate) fn nodes() -> Vec<String> {
+    env::var("NODES")
+        .ok()
+        .or(option_env!("NODES").map(|nodes| nodes.to_string()))
+        .unwrap_or("10.5.0.10 10.5.0.11 10.5.0.12 10.5.0.13".to_string())
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+// A NODES entry is the IP or host name of a node, optionally with the port of
+// its HTTP endpoint, e.g. 10.5.0.10, [fd00::10]:4100 or replica-0:4100. Host
+// names are resolved when the registry is generated. Xnet listens on the port
+// after the HTTP endpoint.
+pub(crate) fn node_endpoint(node: &str, prefer_ipv6: bool) -> Result<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, http_port()?));
+    }
+
+    let (host, port) = match node.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .with_context(|| format!("invalid port in node address {}", node))?,
+        ),
+        None => (node, http_port()?),
+    };
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve node address {}", node))?
+        .collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() == prefer_ipv6)
+        .or(addrs.first())
+        .copied()
+        .ok_or(anyhow!("{} resolves to no address", host))
+}
+
+// Named testnets keep their files in tmp/<name>.
+pub(crate) fn output_dir() -> io::Result<PathBuf> {
+    let dir = env::current_dir()?.join("tmp");
+    Ok(match namespace::name() {
+        Some(name) => dir.join(name),
+        None => dir,
+    })
+}
+
+// Default ports, moved up by the port offset of a named testnet.
+pub(crate) fn http_port() -> Result<u16> {
+    Ok(HTTP_PORT + namespace::port_offset()?)
+}
+
+pub(crate) fn metrics_port() -> Result<u16> {
+    Ok(METRICS_PORT + namespace::port_offset()?)
+}
+
+pub(crate) fn gateway_port() -> Result<u16> {
+    Ok(GATEWAY_PORT + namespace::port_offset()?)
+}
+
+// The transport of a replica listens on the default HTTP port, over UDP.
+pub(crate) fn transport_port() -> Result<u16> {
+    Ok(HTTP_PORT + namespace::port_offset()?)
+}
+
+pub(crate) fn proxied_transport_port() -> Result<u16> {
+    Ok(PROXIED_TRANSPORT_PORT + namespace::port_offset()?)
+}
+
+// Directory the replica of a node runs from, seeded with a copy of the
+// initial `state` written by ic-prep.
+pub(crate) fn node_state_dir(node_index: NodeIndex) -> io::Result<PathBuf> {
+    let base_dir = match env::var("BASE_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        _ => env::current_dir()?,
+    };
+    Ok(match namespace::name() {
+        Some(name) => base_dir.join(format!("state-{}-{}", name, node_index)),
+        None => base_dir.join(format!("state-{}", node_index)),
+    })
+}
+
+// The first node of the NNS subnet, from the topology of the last `init` when
+// there is one.
+pub(crate) fn nns_url() -> String {
+    if let Ok(url) = topology::load().and_then(|topology| topology.subnet_url(topology.nns_subnet))
+    {
+        return url;
+    }
+    let node = &nodes()[0];
+    match node_endpoint(node, false) {
+        Ok(addr) => format!("http://{}", addr),
+        Err(_) => format!("http://{}", node),
+    }
+}
+
+// Adds the keys of `--chain-key` to the chain_keys of their subnets.
+fn add_chain_keys(config: &mut TestnetConfig, chain_keys: &[String]) -> Result<()> {
+    for chain_key in chain_keys {
+        let (subnet, key_id) = chain_key.split_once('=').ok_or(anyhow!(
+            "invalid chain key {}, expected <subnet>=<key id>",
+            chain_key
+        ))?;
+        let subnet: SubnetIndex = subnet
+            .parse()
+            .with_context(|| format!("invalid subnet in chain key {}", chain_key))?;
+        if !config.subnets.iter().any(|s| s.index == subnet) {
+            config.subnets.push(SubnetSettings {
+                index: subnet,
+                ..SubnetSettings::default()
+            });
+        }
+        let settings = config
+            .subnets
+            .iter_mut()
+            .find(|s| s.index == subnet)
+            .unwrap();
+        if !settings.chain_keys.iter().any(|key| key == key_id) {
+            settings.chain_keys.push(key_id.to_string());
+        }
+    }
+    Ok(())
+}
+
+// `--ckbtc-subnet` as subnet settings. The bitcoin canister gets the id the
+// replicas route the bitcoin API to, which `ckbtc install` creates it with.
+fn add_ckbtc(config: &mut TestnetConfig, subnet: SubnetIndex, bitcoind: &str) -> Result<()> {
+    bitcoind
+        .parse::<SocketAddr>()
+        .with_context(|| format!("invalid bitcoind address {}", bitcoind))?;
+    add_chain_keys(config, &[format!("{}={}", subnet, CKBTC_KEY_ID)])?;
+    let settings = config
+        .subnets
+        .iter_mut()
+        .find(|s| s.index == subnet)
+        .unwrap();
+    settings.bitcoind = Some(bitcoind.to_string());
+    if !settings
+        .specified_id_ranges
+        .iter()
+        .any(|range| range.start == BITCOIN_CANISTER_ID && range.end == BITCOIN_CANISTER_ID)
+    {
+        settings.specified_id_ranges.push(IdRange {
+            start: BITCOIN_CANISTER_ID.to_string(),
+            end: BITCOIN_CANISTER_ID.to_string(),
+        });
+    }
+    Ok(())
+}
+
+// The config of the ic-btc-adapter that `run` starts next to the replica of a
+// node, serving the socket of the replica config. Regtest goes through the
+// testnet socket of the replica.
+fn write_bitcoin_adapter_config(
+    node_index: NodeIndex,
+    subnet: &SubnetSettings,
+    uds_path: Option<&Path>,
+) -> Result<()> {
+    let path = output_dir()?.join(process::bitcoin_adapter_config_file(node_index));
+    let (Some(bitcoind), Some(uds_path)) = (&subnet.bitcoind, uds_path) else {
+        if path.is_file() {
+            fs::remove_file(&path)?;
+        }
+        return Ok(());
+    };
+    let config = serde_json::json!({
+        "network": "regtest",
+        "nodes": [bitcoind],
+        "incoming_source": { "Path": uds_path },
+    });
+    fs::write(&path, serde_json::to_string_pretty(&config)?)
+        .with_context(|| format!("failed to write {:?}", path))
+}
+
+// The subnet generates its keys in the first DKG summary block and the
+// registry lists it as the signing subnet of each key, as ic-starter does.
+fn chain_key_config(subnet: &SubnetSettings) -> Result<Option<ChainKeyConfig>> {
+    if subnet.chain_keys.is_empty() {
+        return Ok(None);
+    }
+    let mut key_configs = vec![];
+    for key_id in &subnet.chain_keys {
+        let key_id = MasterPublicKeyId::from_str(key_id).map_err(|e| {
+            anyhow!(
+                "invalid chain key {} of subnet {}: {}",
+                key_id,
+                subnet.index,
+                e
+            )
+        })?;
+        key_configs.push(KeyConfig {
+            key_id,
+            pre_signatures_to_create_in_advance: 5,
+            max_queue_size: DEFAULT_ECDSA_MAX_QUEUE_SIZE,
+        });
+    }
+    Ok(Some(ChainKeyConfig {
+        key_configs,
+        signature_request_timeout_ns: None,
+        idkg_key_rotation_period_ms: None,
+    }))
+}
+
+fn write_replica_config(
+    node_index: NodeIndex,
+    addr: SocketAddr,
+    args: &InitArgs,
+    subnet: &SubnetSettings,
+    node: &NodeSettings,
+    release: Option<&str>,
+) -> Result<()> {
+    let logger_config = LoggerConfig {
+        level: Level::Trace,
+        ..LoggerConfig::default()
+    };
+    let (log, _async_log_guard) = new_replica_logger_from_config(&logger_config);
+    let node_dir = output_dir()?;
+
+    let config_path = node_dir.join(format!("ic-{}.json5", node_index));
+
+    info!(log, "Initialize replica configuration {:?}", config_path);
+
+    let port_offset = namespace::port_offset()?;
+    let replica_config = build_replica_config(node_index, addr, port_offset, args, subnet, node)
+        .with_context(|| format!("failed to build the replica config of node {}", node_index))?;
+
+    write_bitcoin_adapter_config(
+        node_index,
+        subnet,
+        replica_config
+            .adapters_config
+            .as_ref()
+            .and_then(|adapters| adapters.bitcoin_testnet_uds_path.as_deref()),
+    )?;
+
+    // assemble config
+    let config_json = replica_config::to_json(&replica_config, release, node_index)?;
+    std::fs::write(
+        config_path.clone(),
+        format!("{}{}", schema::json5_header(), config_json),
+    )
+    .with_context(|| format!("failed to write {:?}", config_path))?;
+    Ok(())
+}
+
+// Writes the replica config of a node again from the saved config and
+// overrides and its subnet in the topology, keeping the listen address and the
+// transport and tracing setup of its current config. Keys and state stay.
+pub(crate) fn regen_replica_config(node_index: NodeIndex) -> Result<()> {
+    let topology = topology::load()?;
+    topology.node(node_index)?;
+    let current = process::replica_config(node_index)
+        .with_context(|| format!("node {} has no replica config to regenerate", node_index))?;
+    let addr = process::listen_addr(node_index)?;
+    let port_offset = namespace::port_offset()?;
+    let args = InitArgs {
+        transport_proxy: current["transport"]["listening_port"].as_u64()
+            == Some((PROXIED_TRANSPORT_PORT + port_offset) as u64),
+        jaeger_addr: current["tracing"]["jaeger_addr"]
+            .as_str()
+            .map(str::to_string),
+        ..InitArgs::default()
+    };
+    let subnet = topology.subnet_of(node_index).map(|subnet| subnet.index);
+
+    let config = TestnetConfig::saved()?;
+    write_replica_config(
+        node_index,
+        addr,
+        &args,
+        &config.subnet(subnet),
+        &config.node(node_index),
+        config.replica_release.as_deref(),
+    )
+}
+
+fn write_jaeger_compose(node_dir: &Path) -> Result<()> {
+    let compose = match namespace::name() {
+        None => r#"version: "3"
+
+services:
+  jaeger:
+    container_name: jaeger
+    image: jaegertracing/all-in-one:latest
+    environment:
+      - COLLECTOR_OTLP_ENABLED=true
+    ports:
+      - "16686:16686"
+      - "4317:4317"
+    networks:
+      vpcbr:
+        ipv4_address: 10.5.0.5
+"#
+        .to_string(),
+        // the fixed address of the default collector is taken, a named
+        // testnet reaches its own one on the host ports
+        Some(name) => {
+            let offset = namespace::port_offset()?;
+            format!(
+                r#"version: "3"
+
+services:
+  jaeger:
+    container_name: jaeger-{}
+    image: jaegertracing/all-in-one:latest
+    environment:
+      - COLLECTOR_OTLP_ENABLED=true
+    ports:
+      - "{}:16686"
+      - "{}:4317"
+"#,
+                name,
+                16686 + offset,
+                4317 + offset
+            )
+        }
+    };
+    fs::write(node_dir.join("docker-compose.jaeger.yaml"), compose)?;
+    Ok(())
+}
+
+pub(crate) fn run(mut args: InitArgs) -> Result<()> {
+    if args.interactive {
+        args.config = Some(wizard::run()?);
+    }
+    let node_dir = output_dir()?;
+    let mut config = TestnetConfig::load(args.config.as_deref())?;
+    if args.replica_release.is_some() {
+        config.replica_release.clone_from(&args.replica_release);
+    }
+    add_chain_keys(&mut config, &args.chain_keys)?;
+    if let Some(subnet) = args.ckbtc_subnet {
+        add_ckbtc(&mut config, subnet, &args.bitcoind)?;
+    }
+
+    let members = match config.node_addresses() {
+        Some(members) => members,
+        None => nodes().into_iter().map(|node| (node, Some(0))).collect(),
+    };
+    let mut bindings: Vec<(String, String, Option<u64>)> = vec![];
+    for (i, (node, subnet)) in members.into_iter().enumerate() {
+        let node = if args.dfx && i == 0 {
+            dfx::DFX_ADDR.to_string()
+        } else {
+            node
+        };
+        let addr = node_endpoint(&node, args.prefer_ipv6)?;
+        bindings.push((
+            addr.to_string(),
+            SocketAddr::new(addr.ip(), addr.port() + 1).to_string(),
+            subnet,
+        ));
+    }
+
+    let mut unassinged_nodes: BTreeMap<NodeIndex, NodeConfiguration> = BTreeMap::new();
+    let state_dir = output_dir()?.join("state");
+
+    if args.force_reinit {
+        let node_indices: Vec<NodeIndex> = (0..bindings.len())
+            .map(|i| NODE_INDEX + i as NodeIndex)
+            .collect();
+        remove_previous_state(&state_dir, &node_indices)?;
+    }
+    let reused_state_dir = fs::read_dir(&state_dir).map_or(false, |mut dir| dir.next().is_some());
+
+    if !state_dir.is_dir() {
+        fs::create_dir_all(state_dir.clone())
+            .with_context(|| format!("failed to create {:?}", state_dir))?;
+    }
+    config.save()?;
+    replica_config::save_overrides(args.replica_overrides.as_deref())?;
+
+    let mut subnets: BTreeMap<SubnetIndex, BTreeMap<NodeIndex, NodeConfiguration>> =
+        BTreeMap::new();
+
+    for (i, binding) in bindings.iter().enumerate() {
+        let node_index = NODE_INDEX + i as NodeIndex;
+        let addr = binding
+            .0
+            .parse()
+            .with_context(|| format!("invalid address {} of node {}", binding.0, node_index))?;
+        let settings = config.node(node_index);
+        logging::timed("init.replica-config", Some(node_index), || {
+            write_replica_config(
+                node_index,
+                addr,
+                &args,
+                &config.subnet(binding.2),
+                &settings,
+                config.replica_release.as_deref(),
+            )
+        })?;
+
+        // behind NAT the registry records the addresses peers and clients
+        // reach the node on, the replica binds to the NODES address
+        let xnet_bind: SocketAddr = SocketAddr::from_str(&binding.1).unwrap();
+        let public_api = settings.advertised_api(addr)?;
+        let xnet_api = settings.advertised_xnet(public_api, xnet_bind)?;
+
+        match binding.2 {
+            Some(subnet_id) => {
+                let subnet = subnets.entry(subnet_id).or_insert(BTreeMap::new());
+                subnet.insert(
+                    node_index,
+                    NodeConfiguration {
+                        xnet_api,
+                        public_api,
+                        node_operator_principal_id: None,
+                        secret_key_store: None,
+                    },
+                );
+            }
+            None => {
+                unassinged_nodes.insert(
+                    node_index,
+                    NodeConfiguration {
+                        xnet_api,
+                        public_api,
+                        node_operator_principal_id: None,
+                        secret_key_store: None,
+                    },
+                );
+            }
+        }
+    }
+
+    for skew in &args.clock_skews {
+        let (node, offset) = skew.split_once('=').ok_or(anyhow!(
+            "invalid clock skew {}, expected <node>=<offset>",
+            skew
+        ))?;
+        let node: NodeIndex = node
+            .parse()
+            .with_context(|| format!("invalid node in clock skew {}", skew))?;
+        chaos::set_clock_skew(node, offset)
+            .with_context(|| format!("failed to set the clock skew of node {}", node))?;
+    }
+
+    if args.jaeger_addr.is_some() {
+        write_jaeger_compose(&node_dir).context("failed to write the jaeger compose file")?;
+    }
+
+    let replica_version = match &args.replica_version {
+        Some(version) => ReplicaVersion::try_from(version.as_str())
+            .map_err(|e| anyhow!("invalid replica version {}: {:?}", version, e))?,
+        None => ReplicaVersion::default(),
+    };
+
+    if let Some(settings) = config
+        .subnets
+        .iter()
+        .find(|settings| !settings.chain_keys.is_empty() && !subnets.contains_key(&settings.index))
+    {
+        bail!(
+            "chain keys {:?} are configured on subnet {}, which has no nodes",
+            settings.chain_keys,
+            settings.index
+        );
+    }
+
+    let mut topology_config = TopologyConfig::default();
+    for (subnet_id, subnet_nodes) in subnets {
+        let settings = config.subnet(Some(subnet_id));
+        // a single node waits for no peers, so it gets the delays of --fast
+        let fast = args.fast || subnet_nodes.len() == 1;
+        let conf = SubnetConfig::new(
+            subnet_id,
+            subnet_nodes.clone(),
+            replica_version.clone(),
+            None,
+            Some(5000),                                  // max_ingress_messages_per_block
+            Some(constants::MAX_BLOCK_PAYLOAD_SIZE * 5), //max_block_payload_size 5 * 4MB
+            settings
+                .unit_delay_millis
+                .map(Duration::from_millis)
+                .or(fast.then_some(FAST_UNIT_DELAY)),
+            settings
+                .initial_notary_delay_millis
+                .map(Duration::from_millis)
+                .or(fast.then_some(FAST_INITIAL_NOTARY_DELAY)),
+            settings.dkg_interval_length.map(Height::from),
+            None,
+            match subnet_id {
+                // 0 => SubnetType::System,
+                _ => SubnetType::Application,
+            },
+            settings.max_instructions_per_message,
+            settings.max_instructions_per_round,
+            settings.max_instructions_per_install_code,
+            Some(SubnetFeatures::default()),
+            chain_key_config(&settings)?,
+            None,
+            vec![],
+            vec![],
+            SubnetRunningState::default(),
+            Some(settings.initial_height.unwrap_or(0)),
+        );
+
+        #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+        pub struct SubnetConfigJson {
+            schema_version: u32,
+            id: u64,
+            nodes: BTreeMap<NodeIndex, NodeConfiguration>,
+            pub max_ingress_bytes_per_message: u64,
+            pub max_ingress_messages_per_block: u64,
+            pub max_block_payload_size: u64,
+            pub max_instructions_per_message: u64,
+            pub max_instructions_per_round: u64,
+            pub max_instructions_per_install_code: u64,
+            pub max_number_of_canisters: u64,
+            pub initial_height: u64,
+        }
+
+        impl From<SubnetConfig> for SubnetConfigJson {
+            fn from(conf: SubnetConfig) -> Self {
+                SubnetConfigJson {
+                    schema_version: SCHEMA_VERSION,
+                    id: conf.subnet_index,
+                    nodes: conf.membership.clone(),
+                    max_ingress_bytes_per_message: conf.max_ingress_bytes_per_message,
+                    max_ingress_messages_per_block: conf.max_ingress_messages_per_block,
+                    max_block_payload_size: conf.max_block_payload_size,
+                    max_instructions_per_message: conf.max_instructions_per_message,
+                    max_instructions_per_round: conf.max_instructions_per_round,
+                    max_instructions_per_install_code: conf.max_instructions_per_install_code,
+                    max_number_of_canisters: conf.max_number_of_canisters,
+                    initial_height: conf.initial_height,
+                }
+            }
+        }
+
+        let config_path = node_dir.join(format!("subnet-{}.json", subnet_id));
+        let config_json = serde_json::to_string(&SubnetConfigJson::from(conf.clone()))?;
+        std::fs::write(config_path.clone(), config_json.into_bytes()).with_context(|| {
+            format!(
+                "failed to write the config of subnet {} to {:?}",
+                subnet_id, config_path
+            )
+        })?;
+        topology_config.insert_subnet(subnet_id, conf.clone());
+    }
+
+    for (idx, nc) in unassinged_nodes {
+        topology_config.insert_unassigned_node(idx, nc)
+    }
+
+    let version = replica_version.to_string();
+    let mut ic_config = IcConfig::new(
+        /* target_dir= */ state_dir.as_path(),
+        topology_config,
+        replica_version,
+        /* generate_subnet_records= */ true, // see note above
+        /* nns_subnet_index= */ Some(0),
+        /* release_package_url= */ None,
+        /* release_package_sha256_hex */ None,
+        Some(ProvisionalWhitelist::All),
+        None,
+        None,
+        /* ssh_readonly_access_to_unassigned_nodes */ vec![],
+    );
+
+    ic_config.set_use_specified_ids_allocation_range(args.use_specified_ids_allocation_range);
+
+    let initialized = logging::timed("init.registry", None, || {
+        ic_config
+            .initialize()
+            .map_err(|e| anyhow!("ic-prep failed to initialize {:?}: {:?}", state_dir, e))
+    })
+    .map_err(|error| {
+        if reused_state_dir {
+            error.context(format!(
+                "{:?} exists from a previous run, use --force-reinit to start over",
+                state_dir
+            ))
+        } else {
+            error
+        }
+    })?;
+    logging::timed("init.exports", None, || {
+        let topology =
+            topology::write(&initialized, 0).context("failed to write the topology summary")?;
+        routing::assign_specified_ids(&topology, &config)
+            .context("failed to route the specified canister ids")?;
+        checkpoint::record_initial_heights(&topology, &config)
+            .context("failed to record the initial heights of the subnets")?;
+        root_key::export(&topology).context("failed to export the root key")?;
+        env_file::write(&topology).context("failed to write testnet.env")?;
+        registry_export::export(&topology).context("failed to export the registry records")?;
+        metadata::write(&topology, &version).context("failed to write the testnet metadata")?;
+        namespace::register().context("failed to register the testnet in ~/.ic-testnet")?;
+        if args.dfx {
+            dfx::write_network_files().context("failed to write the dfx network files")?;
+        }
+        Ok(())
+    })
+}
+
+// Deletes the registry and the node states so that a new registry does not
+// meet state certified under the previous one.
+fn remove_previous_state(state_dir: &Path, nodes: &[NodeIndex]) -> Result<()> {
+    let mut running = vec![];
+    for node in nodes {
+        if backend::is_running(*node)? {
+            running.push(*node);
+        }
+    }
+    if !running.is_empty() {
+        bail!(
+            "nodes {:?} are still running, stop them before reinitializing",
+            running
+        );
+    }
+
+    let mut dirs = vec![state_dir.to_path_buf()];
+    for node in nodes {
+        dirs.push(node_state_dir(*node)?);
+    }
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        fs::remove_dir_all(dir).with_context(|| format!("failed to remove {:?}", dir))?;
+    }
+
+    Ok(())
+}
+
+// Longest socket path accepted by bind(2), including the terminating nul.
+#[cfg(target_os = "linux")]
+const UDS_PATH_MAX: usize = 108;
+#[cfg(not(target_os = "linux"))]
+const UDS_PATH_MAX: usize = 104;
+
+// Adapters are reached through unix domain sockets, which are left out of the
+// config where they cannot work so that it can still be generated, e.g. on
+// Windows or for deep directories on macOS.
+fn adapter_uds_path(node_index: NodeIndex, path: PathBuf) -> Option<PathBuf> {
+    if !cfg!(unix) {
+        eprintln!(
+            "node {}: adapters are disabled, unix domain sockets are not supported on {}",
+            node_index,
+            env::consts::OS
+        );
+        return None;
+    }
+    if path.as_os_str().len() >= UDS_PATH_MAX {
+        eprintln!(
+            "node {}: adapters are disabled, {:?} is longer than the {} bytes allowed for sockets",
+            node_index,
+            path,
+            UDS_PATH_MAX - 1
+        );
+        return None;
+    }
+
+    Some(path)
+}
+
+fn build_replica_config(
+    node_index: NodeIndex,
+    http_listen_addr: SocketAddr,
+    port_offset: u16,
+    args: &InitArgs,
+    subnet: &SubnetSettings,
+    node: &NodeSettings,
+) -> io::Result<ReplicaConfig> {
+    let state_dir = node_state_dir(node_index)?;
+
+    let node_dir = state_dir.join(format!("node-{}", node_index));
+    let artifact_pool_dir = node_dir.join("ic_consensus_pool");
+    let crypto_root = node_dir.join("crypto");
+    let state_manager_root = node_dir.join("state");
+    let registry_local_store_path = state_dir.join("ic_registry_local_store");
+
+    let state_manager = Some(StateManagerConfig::new(state_manager_root.clone()));
+    let http_handler = Some(HttpHandlerConfig {
+        listen_addr: http_listen_addr,
+        http_max_concurrent_streams: 10000,
+        max_read_state_concurrent_requests: 2000,
+        max_status_concurrent_requests: 2000,
+        max_catch_up_package_concurrent_requests: 2000,
+        max_dashboard_concurrent_requests: 100,
+        max_call_concurrent_requests: 5000,
+        max_query_concurrent_requests: 5000,
+        max_pprof_concurrent_requests: 5,
+        ..Default::default()
+    });
+
+    let mut artifact_pool_cfg = ArtifactPoolTomlConfig::new(artifact_pool_dir.clone(), None);
+    // artifact_pool.rs picks "lmdb" if None here
+    artifact_pool_cfg.consensus_pool_backend.clone_from(&None);
+    let artifact_pool = Some(artifact_pool_cfg);
+
+    let crypto = Some(CryptoConfig::new(crypto_root.clone()));
+    let registry_client = Some(RegistryClientConfig {
+        local_store: registry_local_store_path.clone(),
+    });
+    let logger_config = LoggerConfig {
+        level: ic_config::logger::Level::Info,
+        ..LoggerConfig::default()
+    };
+    let logger = Some(logger_config);
+
+    // behind the proxy every replica binds the transport on its own address,
+    // which the proxy forwards to
+    let (node_ip, listening_port) = if args.transport_proxy {
+        (
+            http_listen_addr.ip().to_string(),
+            PROXIED_TRANSPORT_PORT + port_offset,
+        )
+    } else {
+        ("0.0.0.0".to_string(), HTTP_PORT + port_offset)
+    };
+    let transport = Some(TransportConfig {
+        node_ip,
+        listening_port,
+        send_queue_size: 1024,
+        ..Default::default()
+    });
+
+    let hypervisor = Some(HypervisorConfig {
+        canister_sandboxing_flag: if node.sandboxing {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        },
+        deterministic_time_slicing: if subnet.deterministic_time_slicing {
+            FlagStatus::Enabled
+        } else {
+            FlagStatus::Disabled
+        },
+        create_funds_whitelist: "*".to_string(),
+
+        embedders_config: EmbeddersConfig {
+            feature_flags: FeatureFlags {
+                rate_limiting_of_debug_prints: FlagStatus::Disabled,
+                best_effort_responses: FlagStatus::Enabled,
+                wasm64: FlagStatus::Enabled,
+                ..FeatureFlags::default()
+            },
+            ..EmbeddersConfig::default()
+        },
+        rate_limiting_of_heap_delta: FlagStatus::Disabled,
+        rate_limiting_of_instructions: FlagStatus::Disabled,
+        canister_snapshots: FlagStatus::Enabled,
+        query_stats_aggregation: match subnet.query_stats {
+            Some(false) => FlagStatus::Disabled,
+            _ => FlagStatus::Enabled,
+        },
+        query_stats_epoch_length: subnet.query_stats_epoch_length.unwrap_or(60),
+        default_provisional_cycles_balance: Cycles::new(18_446_744_073_709_551_616),
+
+        ..HypervisorConfig::default()
+    });
+
+    let metrics = Some(MetricsConfig {
+        exporter: Exporter::Http(SocketAddr::new(
+            http_listen_addr.ip(),
+            METRICS_PORT + port_offset,
+        )),
+        ..MetricsConfig::default()
+    });
+
+    let tracing = args
+        .jaeger_addr
+        .as_ref()
+        .filter(|_| args.trace_nodes.is_empty() || args.trace_nodes.contains(&node_index))
+        .map(|addr| TracingConfig {
+            jaeger_addr: Some(addr.clone()),
+        });
+
+    let adapters_config = Some(AdaptersConfig {
+        https_outcalls_uds_path: adapter_uds_path(node_index, node_dir.join("https_outcalls")),
+        bitcoin_testnet_uds_path: match subnet.bitcoind {
+            Some(_) => adapter_uds_path(node_index, node_dir.join("bitcoin")),
+            None => None,
+        },
+        ..AdaptersConfig::default()
+    });
+
+    Ok(ReplicaConfig {
+        registry_client,
+        transport,
+        state_manager,
+        hypervisor,
+        http_handler,
+        metrics,
+        artifact_pool,
+        crypto,
+        logger,
+        adapters_config,
+        tracing,
+        ..ReplicaConfig::default()
+    })
+}
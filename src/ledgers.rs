@@ -0,0 +1,101 @@
+use crate::agent::{connect, create_canister, install_code, read_wasm};
+use crate::icrc::{ledger_arg, IndexArg, IndexInitArg};
+use crate::identities;
+use crate::init::output_dir;
+use anyhow::Result;
+use candid::{CandidType, Encode, Principal};
+use ic_management_canister_types::CanisterInstallMode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub(crate) const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+const INITIAL_BALANCE: u64 = 1_000_000_000_000;
+
+#[derive(CandidType)]
+struct IcpIndexInitArg {
+    ledger_id: Principal,
+}
+
+#[derive(Serialize, Default)]
+struct Ledgers {
+    icp_index: Option<Principal>,
+    icrc: BTreeMap<String, (Principal, Principal)>,
+}
+
+// Installs the ICP index next to the NNS ledger and an ICRC-1 ledger (which
+// also serves the ICRC-3 block log) with its index for every symbol.
+pub(crate) async fn install(
+    url: &str,
+    wasm_dir: &Path,
+    icp_index: bool,
+    icrc_symbols: &[String],
+) -> Result<()> {
+    let agent = connect(url, None).await?;
+    let mut ledgers = Ledgers::default();
+
+    if icp_index {
+        let index = create_canister(&agent, 0, None).await?;
+        let arg = IcpIndexInitArg {
+            ledger_id: Principal::from_text(ICP_LEDGER_CANISTER_ID)?,
+        };
+        install_code(
+            &agent,
+            index,
+            CanisterInstallMode::Install,
+            read_wasm(wasm_dir, "ic-icp-index-canister.wasm.gz")?,
+            Encode!(&arg)?,
+        )
+        .await?;
+        println!("ICP index {}", index);
+        ledgers.icp_index = Some(index);
+    }
+
+    let initial_balances: Vec<(Principal, u64)> = identities::load()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|identity| Ok((Principal::from_text(identity.principal)?, INITIAL_BALANCE)))
+        .collect::<Result<_>>()?;
+
+    for symbol in icrc_symbols {
+        let ledger = create_canister(&agent, 0, None).await?;
+        let index = create_canister(&agent, 0, None).await?;
+
+        let arg = ledger_arg(
+            symbol,
+            symbol,
+            agent.get_principal().map_err(anyhow::Error::msg)?,
+            10_000,
+            initial_balances.clone(),
+        );
+        install_code(
+            &agent,
+            ledger,
+            CanisterInstallMode::Install,
+            read_wasm(wasm_dir, "ic-icrc1-ledger.wasm.gz")?,
+            Encode!(&arg)?,
+        )
+        .await?;
+
+        let arg = IndexArg::Init(IndexInitArg { ledger_id: ledger });
+        install_code(
+            &agent,
+            index,
+            CanisterInstallMode::Install,
+            read_wasm(wasm_dir, "ic-icrc1-index-ng.wasm.gz")?,
+            Encode!(&arg)?,
+        )
+        .await?;
+
+        println!("{} ledger {} index {}", symbol, ledger, index);
+        ledgers.icrc.insert(symbol.clone(), (ledger, index));
+    }
+
+    fs::write(
+        output_dir()?.join("ledgers.json"),
+        serde_json::to_string_pretty(&ledgers)?,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,3 @@
+//! Helpers for using ic-testnet from Rust tests. The testnet itself is driven
+//! through the `ic-testnet` binary.
+pub mod testing;
@@ -0,0 +1,161 @@
+use crate::config::NodeSettings;
+use crate::init::node_state_dir;
+use anyhow::{bail, Context, Result};
+use ic_prep_lib::node::NodeIndex;
+#[cfg(unix)]
+use nix::fcntl::{open, OFlag};
+#[cfg(unix)]
+use nix::sys::stat::{major, minor, stat, Mode};
+#[cfg(unix)]
+use nix::unistd::{close, write as write_fd};
+use std::env;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// cgroup v2 period of the CPU quota in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+// Limits of nodes tagged as slow, unless set explicitly.
+const SLOW_CPUS: f64 = 0.25;
+const SLOW_IOPS: u64 = 200;
+
+// Limited replicas run in a child cgroup of this one, which has to be
+// delegated to the user running ic-testnet.
+fn cgroup_root() -> PathBuf {
+    env::var("IC_TESTNET_CGROUP")
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from("/sys/fs/cgroup/ic-testnet"))
+}
+
+fn write(path: PathBuf, value: &str) -> Result<()> {
+    fs::write(&path, value).with_context(|| format!("failed to write {} to {:?}", value, path))
+}
+
+fn node_cgroup(index: NodeIndex) -> Result<PathBuf> {
+    let root = cgroup_root();
+    if !root.join("cgroup.subtree_control").is_file() {
+        let user = env::var("USER").unwrap_or("$USER".to_string());
+        bail!(
+            "resource limits need the cgroup v2 group {:?}, create it with \
+             `sudo mkdir {} && sudo chown -R {} {}`",
+            root,
+            root.display(),
+            user,
+            root.display()
+        );
+    }
+    write(root.join("cgroup.subtree_control"), "+cpu +memory +io")?;
+
+    let cgroup = root.join(format!("node-{}", index));
+    fs::create_dir_all(&cgroup).with_context(|| format!("failed to create {:?}", cgroup))?;
+    Ok(cgroup)
+}
+
+// io.max takes the whole disk holding the state of the node, not a partition.
+#[cfg(unix)]
+fn state_disk(index: NodeIndex) -> Result<String> {
+    let dev = stat(&node_state_dir(index)?)?.st_dev;
+    // virtual file systems such as overlayfs have no block device
+    let sysfs = PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)));
+    if !sysfs.exists() {
+        bail!("the state of node {} is not on a block device", index);
+    }
+    let disk = if sysfs.join("partition").is_file() {
+        fs::read_to_string(sysfs.join("../dev"))?
+    } else {
+        fs::read_to_string(sysfs.join("dev"))?
+    };
+    Ok(disk.trim().to_string())
+}
+
+#[cfg(not(unix))]
+fn state_disk(index: NodeIndex) -> Result<String> {
+    bail!(
+        "the disk of node {} cannot be throttled on {}",
+        index,
+        env::consts::OS
+    )
+}
+
+// Sets up the cgroup capping the CPU, memory and, for slow nodes, disk
+// operations of a replica about to start, if the node has any limits.
+pub(crate) fn prepare(node: &NodeSettings) -> Result<Option<PathBuf>> {
+    if node.cpus.is_none() && node.memory_mib.is_none() && !node.slow {
+        return Ok(None);
+    }
+    if !cfg!(target_os = "linux") {
+        bail!(
+            "resource limits use cgroups and are not supported on {}",
+            env::consts::OS
+        );
+    }
+
+    let cgroup = node_cgroup(node.index)?;
+    let cpus = node.cpus.or(node.slow.then_some(SLOW_CPUS));
+    let cpu_max = match cpus {
+        Some(cpus) => format!("{} {}", (cpus * CPU_PERIOD as f64) as u64, CPU_PERIOD),
+        None => format!("max {}", CPU_PERIOD),
+    };
+    write(cgroup.join("cpu.max"), &cpu_max)?;
+    let memory_max = match node.memory_mib {
+        Some(mib) => (mib * 1024 * 1024).to_string(),
+        None => "max".to_string(),
+    };
+    write(cgroup.join("memory.max"), &memory_max)?;
+    // the cgroup of a node that was slow before still caps its disk, a state
+    // off a block device was never capped
+    let disk = if node.slow {
+        Some(state_disk(node.index)?)
+    } else {
+        state_disk(node.index).ok()
+    };
+    if let Some(disk) = disk {
+        let iops = if node.slow {
+            SLOW_IOPS.to_string()
+        } else {
+            "max".to_string()
+        };
+        write(
+            cgroup.join("io.max"),
+            &format!("{} riops={} wiops={}", disk, iops, iops),
+        )?;
+    }
+
+    Ok(Some(cgroup))
+}
+
+// Moves the replica into its cgroup between fork and exec, so it never runs
+// unlimited. The sandbox processes it spawns later inherit the cgroup.
+#[cfg(unix)]
+pub(crate) fn join_on_exec(command: &mut Command, cgroup: &Path) -> Result<()> {
+    let procs = CString::new(cgroup.join("cgroup.procs").as_os_str().as_bytes())?;
+    // SAFETY: the closure allocates nothing and only makes the open, write
+    // and close system calls, which are async-signal-safe. Writing 0 moves
+    // the writing process.
+    unsafe {
+        command.pre_exec(move || {
+            let fd = open(procs.as_c_str(), OFlag::O_WRONLY, Mode::empty())?;
+            let written = write_fd(fd, b"0");
+            let _ = close(fd);
+            written?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn join_on_exec(_command: &mut Command, cgroup: &Path) -> Result<()> {
+    bail!(
+        "cannot move a process into {:?} on {}",
+        cgroup,
+        env::consts::OS
+    )
+}
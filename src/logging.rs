@@ -0,0 +1,72 @@
+use anyhow::Result;
+use clap::ArgEnum;
+use ic_prep_lib::node::NodeIndex;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+pub(crate) enum LogFormat {
+    Text,
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+// With `--log-format json` every phase of the tool is a JSON line on stderr,
+// next to the usual output on stdout, e.g.
+//
+//     {"ts":1700000000123,"phase":"run.start","node":101,"status":"ok","duration_ms":35}
+fn emit(
+    phase: &str,
+    node: Option<NodeIndex>,
+    status: &str,
+    duration: Option<u128>,
+    error: Option<String>,
+) {
+    if !JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |ts| ts.as_millis());
+    let mut line = json!({ "ts": ts, "phase": phase, "status": status });
+    if let Some(node) = node {
+        line["node"] = node.into();
+    }
+    if let Some(duration) = duration {
+        line["duration_ms"] = (duration as u64).into();
+    }
+    if let Some(error) = error {
+        line["error"] = error.into();
+    }
+    eprintln!("{}", line);
+}
+
+// Something that happened outside of a phase, e.g. a replica that exited.
+pub(crate) fn event(phase: &str, node: Option<NodeIndex>, error: Option<String>) {
+    let status = if error.is_some() { "error" } else { "ok" };
+    emit(phase, node, status, None, error);
+}
+
+// Runs a phase, reporting its start and then its duration or its error.
+pub(crate) fn timed<T>(
+    phase: &str,
+    node: Option<NodeIndex>,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    emit(phase, node, "started", None, None);
+    let start = Instant::now();
+    let result = f();
+    let duration = Some(start.elapsed().as_millis());
+    match &result {
+        Ok(_) => emit(phase, node, "ok", duration, None),
+        Err(e) => emit(phase, node, "error", duration, Some(format!("{:#}", e))),
+    }
+
+    result
+}
@@ -1,313 +1,382 @@
-use anyhow::Result;
-use ic_config::artifact_pool::LMDBConfig;
-use ic_config::embedders::FeatureFlags;
-use ic_config::flag_status::FlagStatus;
-use ic_config::logger::Level;
-use ic_config::{
-    adapters::AdaptersConfig, artifact_pool::ArtifactPoolTomlConfig, crypto::CryptoConfig,
-    http_handler::Config as HttpHandlerConfig, logger::Config as LoggerConfig,
-    registry_client::Config as RegistryClientConfig, state_manager::Config as StateManagerConfig,
-    transport::TransportConfig, ConfigOptional as ReplicaConfig,
-};
-use ic_config::{
-    embedders::Config as EmbeddersConfig, execution_environment::Config as HypervisorConfig,
-};
-use ic_logger::{info, new_replica_logger_from_config, no_op_logger};
-use ic_prep_lib::subnet_configuration::{constants, SubnetIndex};
-use ic_prep_lib::{
-    internet_computer::{IcConfig, TopologyConfig},
-    node::{NodeConfiguration, NodeIndex},
-    subnet_configuration::{SubnetConfig, SubnetRunningState},
-};
-use ic_protobuf::types::v1::ConsensusMessage;
-use ic_registry_provisional_whitelist::ProvisionalWhitelist;
-use ic_registry_subnet_features::SubnetFeatures;
-use ic_registry_subnet_type::SubnetType;
-use ic_types::{Cycles, Height, ReplicaVersion};
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::{collections::BTreeMap, net::SocketAddr};
-use std::{env, fs};
-use std::{io, str::FromStr};
-
-const NODE_INDEX: NodeIndex = 100;
-
-fn write_replica_config(node_index: NodeIndex, addr: SocketAddr) -> Result<()> {
-    let logger_config = LoggerConfig {
-        level: Level::Trace,
-        ..LoggerConfig::default()
-    };
-    let (log, _async_log_guard) = new_replica_logger_from_config(&logger_config);
-    let mut node_dir = env::current_dir()?;
-    node_dir.push("tmp");
-
-    let config_path = node_dir.join(format!("ic-{}.json5", node_index));
-
-    info!(log, "Initialize replica configuration {:?}", config_path);
+#[cfg(feature = "nns")]
+mod agent;
+#[cfg(feature = "nns")]
+mod apply;
+mod backend;
+#[cfg(feature = "nns")]
+mod bench;
+#[cfg(feature = "nns")]
+mod call;
+#[cfg(feature = "nns")]
+mod candid_ui;
+#[cfg(feature = "nns")]
+mod canister;
+#[cfg(feature = "nns")]
+mod chainkey;
+mod chaos;
+mod checkpoint;
+#[cfg(feature = "nns")]
+mod ckbtc;
+mod config;
+#[cfg(feature = "lmdb-tools")]
+mod consensus;
+#[cfg(feature = "run")]
+mod dashboard;
+mod dfx;
+#[cfg(feature = "lmdb-tools")]
+mod dkg;
+mod env_file;
+#[cfg(all(feature = "run", feature = "nns"))]
+mod gateway;
+mod generate;
+mod graph;
+#[cfg(all(feature = "run", feature = "nns"))]
+mod harness;
+mod health;
+mod hooks;
+#[cfg(all(feature = "run", feature = "nns"))]
+mod http_gateway;
+#[cfg(feature = "nns")]
+mod icrc;
+#[cfg(feature = "nns")]
+mod identities;
+mod image;
+mod init;
+#[cfg(feature = "nns")]
+mod ledgers;
+mod limits;
+mod logging;
+mod metadata;
+mod metrics;
+mod namespace;
+#[cfg(feature = "run")]
+mod net;
+#[cfg(feature = "nns")]
+mod nns;
+mod node;
+mod plan;
+#[cfg(all(feature = "run", feature = "nns"))]
+mod pocket_ic;
+#[cfg(feature = "lmdb-tools")]
+mod pool;
+#[cfg(feature = "run")]
+mod ports;
+mod process;
+#[cfg(feature = "run")]
+mod progress;
+#[cfg(feature = "nns")]
+mod propose;
+#[cfg(feature = "run")]
+mod proxy;
+#[cfg(feature = "nns")]
+mod query_stats;
+mod registry_export;
+mod replica_config;
+mod root_key;
+mod routing;
+#[cfg(feature = "run")]
+mod run;
+#[cfg(all(feature = "run", feature = "nns"))]
+mod scenario;
+mod schema;
+mod secrets;
+#[cfg(feature = "run")]
+mod serve;
+mod snapshot;
+#[cfg(feature = "nns")]
+mod sns;
+mod starter;
+mod state_sync;
+#[cfg(feature = "run")]
+mod subnet;
+mod tools;
+mod topology;
+#[cfg(feature = "run")]
+mod uds;
+#[cfg(feature = "nns")]
+mod verify_cert;
+#[cfg(feature = "run")]
+mod vm;
+mod watch;
+mod wizard;
+#[cfg(feature = "nns")]
+mod workload;
+#[cfg(feature = "run")]
+mod xnet;
+
+#[cfg(all(feature = "run", not(unix)))]
+compile_error!(
+    "running replicas needs unix, build with --no-default-features for config generation only"
+);
 
-    let replica_config = build_replica_config(node_index, addr)?;
-
-    // assemble config
-    let config_json = serde_json::to_string(&replica_config)?;
-    std::fs::write(config_path.clone(), config_json.into_bytes())?;
-    Ok(())
+use anyhow::Result;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+
+#[derive(Parser)]
+#[clap(name = "ic-testnet", about = "IC testnet with multiple nodes")]
+struct Cli {
+    /// Also report the phases of the command as JSON lines on stderr
+    #[clap(long, global = true, arg_enum, default_value = "text")]
+    log_format: logging::LogFormat,
+    /// Name of an additional testnet with its own files in tmp/<name> and
+    /// its own ports
+    #[clap(long, global = true, env = "IC_TESTNET_NAME")]
+    name: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn lmdb() {
-    let conf = LMDBConfig {
-        persistent_pool_validated_persistent_db_path: PathBuf::from("ic_consensus_pool"),
-    };
-    let log = no_op_logger();
-    use ic_interfaces::consensus_pool::PoolSection;
-
-    let pool = ic_artifact_pool::lmdb_pool::PersistentHeightIndexedPool::new_consensus_pool(
-        conf, true, log,
-    );
-    let range = pool.block_proposal().height_range().unwrap();
-    println!("{range:?}")
+#[derive(Subcommand)]
+enum Command {
+    /// Generate node configs and the initial registry (default)
+    Init(init::InitArgs),
+    /// Run a single node testnet accepting the flags of ic-starter
+    Starter(starter::StarterArgs),
+    /// Upgrade files generated by previous releases
+    Migrate(schema::MigrateArgs),
+    /// Compare an edited config file with the testnet and print the changes
+    /// it takes
+    Plan(plan::PlanArgs),
+    #[cfg(feature = "nns")]
+    /// Converge the running testnet to an edited config file
+    Apply(apply::ApplyArgs),
+    /// Print when, by whom and with which versions the testnet was
+    /// initialized
+    Info(metadata::InfoArgs),
+    /// List the testnets initialized on this machine
+    List,
+    /// Draw the subnets and nodes of the testnet
+    #[clap(subcommand)]
+    Topology(graph::TopologyCommand),
+    #[cfg(feature = "run")]
+    /// Start and supervise the replicas
+    Run(run::RunArgs),
+    #[cfg(feature = "nns")]
+    /// Submit NNS proposals that are adopted by the majority neuron
+    Propose(propose::ProposeArgs),
+    #[cfg(feature = "nns")]
+    /// Generate test identities with ledger accounts and neurons
+    Identities {
+        #[clap(long, default_value = "4")]
+        count: usize,
+    },
+    #[cfg(feature = "nns")]
+    /// Manage the NNS canisters
+    #[clap(subcommand)]
+    Nns(nns::NnsCommand),
+    /// Generate deployment files for the topology
+    #[clap(subcommand)]
+    Generate(generate::GenerateCommand),
+    /// Build a container image with the replica, adapters and node configs
+    BuildImage(image::BuildImageArgs),
+    /// Save and restore the state of all nodes
+    #[clap(subcommand)]
+    Snapshot(snapshot::SnapshotCommand),
+    /// Export checkpoints of a subnet and start fresh testnets from them
+    #[clap(subcommand)]
+    Checkpoint(checkpoint::CheckpointCommand),
+    /// Maintain the files of single nodes
+    #[clap(subcommand)]
+    Node(node::NodeCommand),
+    /// Encrypt the key material of the testnet at rest
+    #[clap(subcommand)]
+    Secrets(secrets::SecretsCommand),
+    #[cfg(feature = "run")]
+    /// Serve an HTTP API to control the testnet
+    Serve(serve::ServeArgs),
+    #[cfg(all(feature = "run", feature = "nns"))]
+    /// Forward API requests for any canister to a node of the subnet the
+    /// registry routes it to and serve canister frontends, like a boundary
+    /// node
+    Gateway(gateway::GatewayArgs),
+    #[cfg(all(feature = "run", feature = "nns"))]
+    /// Serve the PocketIC protocol on top of the testnet
+    PocketIc(pocket_ic::PocketIcArgs),
+    #[cfg(feature = "run")]
+    /// Operate on the replicas of a subnet
+    #[clap(subcommand)]
+    Subnet(subnet::SubnetCommand),
+    #[cfg(feature = "nns")]
+    /// Upload SNS wasms and launch test SNSes
+    #[clap(subcommand)]
+    Sns(sns::SnsCommand),
+    #[cfg(feature = "nns")]
+    /// ckBTC integration on top of a regtest bitcoind
+    #[clap(subcommand)]
+    Ckbtc(ckbtc::CkBtcCommand),
+    #[cfg(feature = "nns")]
+    /// Install test canisters and exercise them, e.g. to validate a fresh
+    /// testnet
+    DeployWorkload(workload::DeployWorkloadArgs),
+    #[cfg(feature = "nns")]
+    /// Measure the performance of a subnet under load
+    #[clap(subcommand)]
+    Bench(bench::BenchCommand),
+    #[cfg(feature = "nns")]
+    /// Install the Candid UI canister
+    CandidUi(candid_ui::CandidUiArgs),
+    #[cfg(feature = "nns")]
+    /// Submit an update call to a canister
+    Call(call::CallArgs),
+    #[cfg(feature = "nns")]
+    /// Submit a query call to a canister
+    Query(call::CallArgs),
+    #[cfg(feature = "nns")]
+    /// Manage the state of canisters
+    #[clap(subcommand)]
+    Canister(canister::CanisterCommand),
+    #[cfg(feature = "nns")]
+    /// Print the aggregated query statistics of a canister
+    QueryStats(query_stats::QueryStatsArgs),
+    #[cfg(feature = "nns")]
+    /// Chain key diagnostics
+    #[clap(subcommand)]
+    Chainkey(chainkey::ChainKeyCommand),
+    #[cfg(feature = "nns")]
+    /// Verify a read_state certificate against the testnet root key
+    VerifyCert(verify_cert::VerifyCertArgs),
+    #[cfg(feature = "nns")]
+    /// Check the NNS delegation in the certificates of the subnets against
+    /// the registry
+    VerifyDelegations(verify_cert::VerifyDelegationsArgs),
+    #[cfg(feature = "run")]
+    /// Check the network between the nodes
+    #[clap(subcommand)]
+    Net(net::NetCommand),
+    #[cfg(feature = "run")]
+    /// Inspect xnet streams between subnets
+    #[clap(subcommand)]
+    Xnet(xnet::XnetCommand),
+    #[cfg(feature = "run")]
+    /// Run nodes as IC-OS virtual machines
+    #[clap(subcommand)]
+    Vm(vm::VmCommand),
+    /// Watch finalization progress of every subnet and alert on stalls
+    Watch(watch::WatchArgs),
+    /// Report the state syncs of every node: durations, bytes and chunks
+    StateSync(state_sync::StateSyncArgs),
+    #[cfg(feature = "run")]
+    /// Live terminal view of node status, heights, resource usage and log errors
+    Dashboard(dashboard::DashboardArgs),
+    /// Manage webhooks notified on testnet events
+    #[clap(subcommand)]
+    Hooks(hooks::HooksCommand),
+    #[cfg(all(feature = "run", feature = "nns"))]
+    /// Run declarative test scenarios
+    #[clap(subcommand)]
+    Scenario(scenario::ScenarioCommand),
+    #[cfg(feature = "lmdb-tools")]
+    /// Inspect the consensus pool of a node
+    #[clap(subcommand)]
+    Pool(pool::PoolCommand),
+    #[cfg(feature = "lmdb-tools")]
+    /// Measure consensus rounds from the consensus pool of a node
+    #[clap(subcommand)]
+    Consensus(consensus::ConsensusCommand),
+    #[cfg(feature = "lmdb-tools")]
+    /// Inspect the distributed key generation of a subnet
+    #[clap(subcommand)]
+    Dkg(dkg::DkgCommand),
+    /// Inject faults into the running testnet
+    #[clap(subcommand)]
+    Chaos(chaos::ChaosCommand),
+    #[cfg(all(feature = "run", feature = "nns"))]
+    /// End-to-end test harnesses
+    #[clap(subcommand)]
+    Test(harness::TestCommand),
 }
 
 fn main() -> Result<()> {
-    let mut node_dir = env::current_dir()?;
-    node_dir.push("tmp");
-
-    let nodes: Vec<String> = option_env!("NODES")
-        .unwrap_or("10.5.0.10 10.5.0.11 10.5.0.12 10.5.0.13")
-        .split(" ")
-        .map(|s| s.to_string())
-        .collect();
-
-    let bindings: Vec<(String, String, Option<u64>)> = nodes
-        .iter()
-        .map(|node| (format!("{}:4100", node), format!("{}:4101", node), Some(0)))
-        .collect::<Vec<_>>();
-
-    let mut unassinged_nodes: BTreeMap<NodeIndex, NodeConfiguration> = BTreeMap::new();
-    let mut state_dir = env::current_dir()?;
-    state_dir.push(format!("tmp"));
-    state_dir.push(format!("state"));
-
-    if !state_dir.is_dir() {
-        fs::create_dir_all(state_dir.clone())?;
-    }
-
-    let mut subnets: BTreeMap<SubnetIndex, BTreeMap<NodeIndex, NodeConfiguration>> =
-        BTreeMap::new();
-
-    for (i, binding) in bindings.iter().enumerate() {
-        let node_index = NODE_INDEX + i as NodeIndex;
-        let addr = binding.0.parse()?;
-        write_replica_config(node_index, addr)?;
-
-        match binding.2 {
-            Some(subnet_id) => {
-                let subnet = subnets.entry(subnet_id).or_insert(BTreeMap::new());
-                subnet.insert(
-                    node_index,
-                    NodeConfiguration {
-                        xnet_api: SocketAddr::from_str(&binding.1).unwrap(),
-                        public_api: addr,
-                        node_operator_principal_id: None,
-                        secret_key_store: None,
-                    },
-                );
-            }
-            None => {
-                unassinged_nodes.insert(
-                    node_index,
-                    NodeConfiguration {
-                        xnet_api: SocketAddr::from_str(&binding.1).unwrap(),
-                        public_api: addr,
-                        node_operator_principal_id: None,
-                        secret_key_store: None,
-                    },
-                );
-            }
-        }
-    }
-
-    let mut topology_config = TopologyConfig::default();
-    for (subnet_id, subnet_nodes) in subnets {
-        let conf = SubnetConfig::new(
-            subnet_id,
-            subnet_nodes.clone(),
-            ReplicaVersion::default(),
-            None,
-            Some(5000),                                  // max_ingress_messages_per_block
-            Some(constants::MAX_BLOCK_PAYLOAD_SIZE * 5), //max_block_payload_size 5 * 4MB
-            None,                                        //config.unit_delay,
-            None,                                        // config.initial_notary_delay,
-            None,                                        // config.dkg_interval_length,
-            None,
-            match subnet_id {
-                // 0 => SubnetType::System,
-                _ => SubnetType::Application,
-            },
-            None,
-            None,
-            None,
-            Some(SubnetFeatures::default()),
-            None, // chain_key_config,
-            None,
-            vec![],
-            vec![],
-            SubnetRunningState::default(),
-            Some(0),
-        );
-
-        #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-        pub struct SubnetConfigJson {
-            id: u64,
-            nodes: BTreeMap<NodeIndex, NodeConfiguration>,
-            pub max_ingress_bytes_per_message: u64,
-            pub max_ingress_messages_per_block: u64,
-            pub max_block_payload_size: u64,
-            pub max_instructions_per_message: u64,
-            pub max_instructions_per_round: u64,
-            pub max_instructions_per_install_code: u64,
-            pub max_number_of_canisters: u64,
-            pub initial_height: u64,
-        }
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    logging::set_format(cli.log_format);
+    namespace::set_name(cli.name.as_deref())?;
 
-        impl From<SubnetConfig> for SubnetConfigJson {
-            fn from(conf: SubnetConfig) -> Self {
-                SubnetConfigJson {
-                    id: conf.subnet_index,
-                    nodes: conf.membership.clone(),
-                    max_ingress_bytes_per_message: conf.max_ingress_bytes_per_message,
-                    max_ingress_messages_per_block: conf.max_ingress_messages_per_block,
-                    max_block_payload_size: conf.max_block_payload_size,
-                    max_instructions_per_message: conf.max_instructions_per_message,
-                    max_instructions_per_round: conf.max_instructions_per_round,
-                    max_instructions_per_install_code: conf.max_instructions_per_install_code,
-                    max_number_of_canisters: conf.max_number_of_canisters,
-                    initial_height: conf.initial_height,
-                }
-            }
-        }
-
-        let config_path = node_dir.join(format!("subnet-{}.json", subnet_id));
-        let config_json = serde_json::to_string(&SubnetConfigJson::from(conf.clone()))?;
-        std::fs::write(config_path.clone(), config_json.into_bytes())?;
-        topology_config.insert_subnet(subnet_id, conf.clone());
-    }
-
-    for (idx, nc) in unassinged_nodes {
-        topology_config.insert_unassigned_node(idx, nc)
-    }
-
-    let mut ic_config = IcConfig::new(
-        /* target_dir= */ state_dir.as_path(),
-        topology_config,
-        ReplicaVersion::default(),
-        /* generate_subnet_records= */ true, // see note above
-        /* nns_subnet_index= */ Some(0),
-        /* release_package_url= */ None,
-        /* release_package_sha256_hex */ None,
-        Some(ProvisionalWhitelist::All),
-        None,
-        None,
-        /* ssh_readonly_access_to_unassigned_nodes */ vec![],
-    );
-
-    ic_config.set_use_specified_ids_allocation_range(false);
-
-    ic_config.initialize()?;
-
-    Ok(())
+    let command = matches.subcommand_name().unwrap_or("init").to_string();
+    logging::timed(&command, None, || dispatch(cli))
 }
 
-fn build_replica_config(
-    node_index: NodeIndex,
-    http_listen_addr: SocketAddr,
-) -> io::Result<ReplicaConfig> {
-    let mut state_dir = match env::var("BASE_DIR") {
-        Ok(dir) => PathBuf::from(dir),
-        _ => env::current_dir()?,
-    };
-    state_dir.push(format!("state-{}", node_index));
-
-    let node_dir = state_dir.join(format!("node-{}", node_index));
-    let artifact_pool_dir = node_dir.join("ic_consensus_pool");
-    let crypto_root = node_dir.join("crypto");
-    let state_manager_root = node_dir.join("state");
-    let registry_local_store_path = state_dir.join("ic_registry_local_store");
-
-    let state_manager = Some(StateManagerConfig::new(state_manager_root.clone()));
-    let http_handler = Some(HttpHandlerConfig {
-        listen_addr: http_listen_addr,
-        http_max_concurrent_streams: 10000,
-        max_read_state_concurrent_requests: 2000,
-        max_status_concurrent_requests: 2000,
-        max_catch_up_package_concurrent_requests: 2000,
-        max_dashboard_concurrent_requests: 100,
-        max_call_concurrent_requests: 5000,
-        max_query_concurrent_requests: 5000,
-        max_pprof_concurrent_requests: 5,
-        ..Default::default()
-    });
-
-    let mut artifact_pool_cfg = ArtifactPoolTomlConfig::new(artifact_pool_dir.clone(), None);
-    // artifact_pool.rs picks "lmdb" if None here
-    artifact_pool_cfg.consensus_pool_backend.clone_from(&None);
-    let artifact_pool = Some(artifact_pool_cfg);
-
-    let crypto = Some(CryptoConfig::new(crypto_root.clone()));
-    let registry_client = Some(RegistryClientConfig {
-        local_store: registry_local_store_path.clone(),
-    });
-    let logger_config = LoggerConfig {
-        level: ic_config::logger::Level::Info,
-        ..LoggerConfig::default()
-    };
-    let logger = Some(logger_config);
-
-    let transport = Some(TransportConfig {
-        node_ip: "0.0.0.0".to_string(),
-        listening_port: 4100,
-        send_queue_size: 1024,
-        ..Default::default()
-    });
-
-    let hypervisor = Some(HypervisorConfig {
-        canister_sandboxing_flag: FlagStatus::Disabled,
-        deterministic_time_slicing: FlagStatus::Disabled,
-        create_funds_whitelist: "*".to_string(),
-
-        embedders_config: EmbeddersConfig {
-            feature_flags: FeatureFlags {
-                rate_limiting_of_debug_prints: FlagStatus::Disabled,
-                best_effort_responses: FlagStatus::Enabled,
-                wasm64: FlagStatus::Enabled,
-                ..FeatureFlags::default()
-            },
-            ..EmbeddersConfig::default()
-        },
-        rate_limiting_of_heap_delta: FlagStatus::Disabled,
-        rate_limiting_of_instructions: FlagStatus::Disabled,
-        canister_snapshots: FlagStatus::Enabled,
-        query_stats_epoch_length: 60,
-        default_provisional_cycles_balance: Cycles::new(18_446_744_073_709_551_616),
-
-        ..HypervisorConfig::default()
-    });
-
-    let adapters_config = Some(AdaptersConfig {
-        https_outcalls_uds_path: Some(node_dir.join("https_outcalls")),
-        ..AdaptersConfig::default()
-    });
-
-    Ok(ReplicaConfig {
-        registry_client,
-        transport,
-        state_manager,
-        hypervisor,
-        http_handler,
-        metrics: None,
-        artifact_pool,
-        crypto,
-        logger,
-        adapters_config,
-        ..ReplicaConfig::default()
-    })
+fn dispatch(cli: Cli) -> Result<()> {
+    match cli
+        .command
+        .unwrap_or(Command::Init(init::InitArgs::default()))
+    {
+        Command::Init(args) => init::run(args),
+        Command::Starter(args) => starter::run(args),
+        Command::Migrate(args) => schema::run(args),
+        Command::Plan(args) => plan::run(args),
+        #[cfg(feature = "nns")]
+        Command::Apply(args) => apply::run(args),
+        Command::Info(args) => metadata::run(args),
+        Command::List => namespace::list(),
+        Command::Topology(cmd) => graph::run(cmd),
+        #[cfg(feature = "run")]
+        Command::Run(args) => run::run(args),
+        #[cfg(feature = "nns")]
+        Command::Propose(cmd) => propose::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::Identities { count } => identities::run(count),
+        #[cfg(feature = "nns")]
+        Command::Nns(cmd) => nns::run(cmd),
+        Command::Generate(cmd) => generate::run(cmd),
+        Command::BuildImage(args) => image::run(args),
+        Command::Snapshot(cmd) => snapshot::run(cmd),
+        Command::Checkpoint(cmd) => checkpoint::run(cmd),
+        Command::Node(cmd) => node::run(cmd),
+        Command::Secrets(cmd) => secrets::run(cmd),
+        #[cfg(feature = "run")]
+        Command::Serve(args) => serve::run(args),
+        #[cfg(all(feature = "run", feature = "nns"))]
+        Command::Gateway(args) => gateway::run(args),
+        #[cfg(all(feature = "run", feature = "nns"))]
+        Command::PocketIc(args) => pocket_ic::run(args),
+        #[cfg(feature = "run")]
+        Command::Subnet(cmd) => subnet::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::Sns(cmd) => sns::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::Ckbtc(cmd) => ckbtc::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::CandidUi(args) => candid_ui::run(args),
+        #[cfg(feature = "nns")]
+        Command::DeployWorkload(args) => workload::run(args),
+        #[cfg(feature = "nns")]
+        Command::Bench(cmd) => bench::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::Call(args) => call::run(args, false),
+        #[cfg(feature = "nns")]
+        Command::Query(args) => call::run(args, true),
+        #[cfg(feature = "nns")]
+        Command::Canister(cmd) => canister::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::QueryStats(args) => query_stats::run(args),
+        #[cfg(feature = "nns")]
+        Command::Chainkey(cmd) => chainkey::run(cmd),
+        #[cfg(feature = "nns")]
+        Command::VerifyCert(args) => verify_cert::run(args),
+        #[cfg(feature = "nns")]
+        Command::VerifyDelegations(args) => verify_cert::run_delegations(args),
+        #[cfg(feature = "run")]
+        Command::Net(cmd) => net::run(cmd),
+        #[cfg(feature = "run")]
+        Command::Xnet(cmd) => xnet::run(cmd),
+        #[cfg(feature = "run")]
+        Command::Vm(cmd) => vm::run(cmd),
+        Command::Watch(args) => watch::run(args),
+        Command::StateSync(args) => state_sync::run(args),
+        #[cfg(feature = "run")]
+        Command::Dashboard(args) => dashboard::run(args),
+        Command::Hooks(cmd) => hooks::run(cmd),
+        #[cfg(all(feature = "run", feature = "nns"))]
+        Command::Scenario(cmd) => scenario::run(cmd),
+        #[cfg(feature = "lmdb-tools")]
+        Command::Pool(cmd) => pool::run(cmd),
+        #[cfg(feature = "lmdb-tools")]
+        Command::Consensus(cmd) => consensus::run(cmd),
+        #[cfg(feature = "lmdb-tools")]
+        Command::Dkg(cmd) => dkg::run(cmd),
+        Command::Chaos(cmd) => chaos::run(cmd),
+        #[cfg(all(feature = "run", feature = "nns"))]
+        Command::Test(cmd) => harness::run(cmd),
+    }
 }
@@ -0,0 +1,244 @@
+use crate::init::output_dir;
+use crate::process;
+use crate::schema::SCHEMA_VERSION;
+use crate::tools::ic_binary;
+use crate::topology::{self, Topology};
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Binaries of IC_BIN_DIR whose hashes `init` records: the replica, the
+// sandbox binaries it ships and the adapters.
+pub(crate) const PINNED_BINARIES: [&str; 6] = [
+    "replica",
+    "canister_sandbox",
+    "sandbox_launcher",
+    "compiler_sandbox",
+    "ic-https-outcalls-adapter",
+    "ic-btc-adapter",
+];
+
+// Provenance of a testnet, written by `init` so that whoever finds a long-lived
+// testnet can tell where it came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Metadata {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub created_at: u64,
+    pub created_by: String,
+    pub tool_version: String,
+    pub replica_version: String,
+    pub nodes: usize,
+    pub subnets: usize,
+    /// SHA-256 of topology.json
+    pub topology_hash: String,
+    /// Last time `apply` changed the topology
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+    /// SHA-256 of the binaries present at `init`, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub binaries: BTreeMap<String, String>,
+}
+
+#[derive(Args)]
+pub(crate) struct InfoArgs {
+    /// Print the metadata as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(output_dir()?.join("testnet.meta.json"))
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn topology_hash() -> Result<String> {
+    let topology = fs::read(output_dir()?.join("topology.json"))?;
+    Ok(hex::encode(Sha256::digest(topology)))
+}
+
+fn binary_hashes() -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    for name in PINNED_BINARIES {
+        let path = ic_binary(name);
+        if path.is_file() {
+            let binary =
+                fs::read(&path).map_err(|e| anyhow!("failed to read {:?}: {}", path, e))?;
+            hashes.insert(name.to_string(), hex::encode(Sha256::digest(binary)));
+        }
+    }
+    Ok(hashes)
+}
+
+// Binaries that differ from the ones recorded at `init`, e.g. a rebuilt
+// replica next to the old sandbox binaries.
+fn skewed_binaries(metadata: &Metadata) -> Result<Vec<String>> {
+    let current = binary_hashes()?;
+    let mut names: Vec<&String> = metadata.binaries.keys().chain(current.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    Ok(names
+        .into_iter()
+        .filter_map(
+            |name| match (metadata.binaries.get(name), current.get(name)) {
+                (Some(pinned), Some(hash)) if pinned == hash => None,
+                (Some(_), Some(_)) => Some(format!("{} changed", name)),
+                (Some(_), None) => Some(format!("{} is gone", name)),
+                _ => Some(format!("{} was added", name)),
+            },
+        )
+        .collect())
+}
+
+// Refuses to start replicas from binaries other than the recorded ones, unless
+// the skew is allowed. Testnets initialized before hashes were recorded are
+// not checked.
+pub(crate) fn check_binaries(allow_version_skew: bool) -> Result<()> {
+    let Ok(metadata) = load() else {
+        return Ok(());
+    };
+    if metadata.binaries.is_empty() {
+        return Ok(());
+    }
+    let skewed = skewed_binaries(&metadata)?;
+    if skewed.is_empty() {
+        return Ok(());
+    }
+    if allow_version_skew {
+        eprintln!(
+            "warning: binaries differ from the ones of init: {}",
+            skewed.join(", ")
+        );
+        return Ok(());
+    }
+    bail!(
+        "binaries in {:?} differ from the ones of init: {}; rebuild the recorded ones, re-run init or pass --allow-version-skew",
+        ic_binary(""),
+        skewed.join(", ")
+    )
+}
+
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    nix::unistd::gethostname()
+        .ok()
+        .map(|host| host.to_string_lossy().to_string())
+}
+
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+fn author() -> String {
+    let user = env::var("USER")
+        .or(env::var("USERNAME"))
+        .unwrap_or("unknown".to_string());
+    match host_name() {
+        Some(host) => format!("{}@{}", user, host),
+        None => user,
+    }
+}
+
+fn save(metadata: &Metadata) -> Result<()> {
+    fs::write(path()?, serde_json::to_string_pretty(metadata)?)?;
+    Ok(())
+}
+
+pub(crate) fn write(topology: &Topology, replica_version: &str) -> Result<Metadata> {
+    let metadata = Metadata {
+        schema_version: SCHEMA_VERSION,
+        created_at: now()?,
+        created_by: author(),
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        replica_version: replica_version.to_string(),
+        nodes: topology.nodes().count(),
+        subnets: topology.subnets.len(),
+        topology_hash: topology_hash()?,
+        updated_at: None,
+        binaries: binary_hashes()?,
+    };
+    save(&metadata)?;
+    Ok(metadata)
+}
+
+pub(crate) fn load() -> Result<Metadata> {
+    let path = path()?;
+    let json = fs::read_to_string(&path).map_err(|e| {
+        anyhow!(
+            "failed to read {:?}: {}, the testnet was initialized before metadata was kept",
+            path,
+            e
+        )
+    })?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+// Records a change of the topology after `init`, e.g. by `apply`.
+pub(crate) fn touch(topology: &Topology) -> Result<()> {
+    let Ok(mut metadata) = load() else {
+        return Ok(());
+    };
+    metadata.nodes = topology.nodes().count();
+    metadata.subnets = topology.subnets.len();
+    metadata.topology_hash = topology_hash()?;
+    metadata.updated_at = Some(now()?);
+    save(&metadata)
+}
+
+pub(crate) fn run(args: InfoArgs) -> Result<()> {
+    let metadata = load()?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+        return Ok(());
+    }
+
+    println!("created at      {} (unix seconds)", metadata.created_at);
+    println!("created by      {}", metadata.created_by);
+    if let Some(updated_at) = metadata.updated_at {
+        println!("updated at      {} (unix seconds)", updated_at);
+    }
+    println!("ic-testnet      {}", metadata.tool_version);
+    println!("replica version {}", metadata.replica_version);
+    println!(
+        "topology        {} nodes in {} subnets, sha256 {}",
+        metadata.nodes, metadata.subnets, metadata.topology_hash
+    );
+    match topology_hash() {
+        Ok(hash) if hash == metadata.topology_hash => {}
+        Ok(_) => println!("                topology.json changed since, it was edited by hand"),
+        Err(e) => println!("                topology.json is unreadable: {}", e),
+    }
+
+    for (name, hash) in &metadata.binaries {
+        println!("{:<15} sha256 {}", name, hash);
+    }
+    match skewed_binaries(&metadata) {
+        Ok(skewed) if skewed.is_empty() => {}
+        Ok(skewed) => println!(
+            "                binaries differ since: {}",
+            skewed.join(", ")
+        ),
+        Err(e) => println!("                binaries are unreadable: {}", e),
+    }
+
+    if let Ok(topology) = topology::load() {
+        let running = topology
+            .nodes()
+            .filter(|node| process::replica_pid(node.index).is_some())
+            .count();
+        println!("running         {} of {} replicas", running, metadata.nodes);
+    }
+
+    Ok(())
+}
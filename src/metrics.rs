@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+pub(crate) fn scrape(url: &str) -> Result<String> {
+    Ok(reqwest::blocking::Client::new()
+        .get(url)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()?
+        .error_for_status()?
+        .text()?)
+}
+
+// Samples in the Prometheus text format whose labels include all of the given
+// pairs.
+fn samples<'a>(
+    metrics: &'a str,
+    name: &'a str,
+    labels: &'a [(&'a str, &'a str)],
+) -> impl Iterator<Item = f64> + 'a {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(move |line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let (metric, series_labels) = match series.split_once('{') {
+                Some((metric, rest)) => (metric, rest.trim_end_matches('}')),
+                None => (series, ""),
+            };
+            if metric != name {
+                return None;
+            }
+            let matches = labels.iter().all(|(key, expected)| {
+                series_labels
+                    .split(',')
+                    .any(|label| label == format!("{}=\"{}\"", key, expected))
+            });
+            if !matches {
+                return None;
+            }
+
+            value.parse().ok()
+        })
+}
+
+// The first matching sample.
+pub(crate) fn value(metrics: &str, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    samples(metrics, name, labels).next()
+}
+
+// The total of the matching samples, e.g. a counter over all values of a
+// label.
+pub(crate) fn sum(metrics: &str, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    samples(metrics, name, labels).reduce(|total, sample| total + sample)
+}
+
+pub(crate) fn finalized_height(metrics_url: &str) -> Result<u64> {
+    let metrics = scrape(metrics_url)?;
+    value(
+        &metrics,
+        "artifact_pool_consensus_height_stat",
+        &[
+            ("pool_type", "validated"),
+            ("stat", "max"),
+            ("type", "finalization"),
+        ],
+    )
+    .map(|height| height as u64)
+    .ok_or(anyhow!(
+        "no finalization height in metrics of {}",
+        metrics_url
+    ))
+}
+
+// Metric families of several expositions in the Prometheus text format, each
+// with its HELP and TYPE once and all of its samples together, as the format
+// requires.
+#[derive(Default)]
+pub(crate) struct Exposition {
+    families: Vec<Family>,
+    index: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Family {
+    comments: Vec<String>,
+    samples: Vec<String>,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str) -> &mut Family {
+        let position = match self.index.get(name) {
+            Some(position) => *position,
+            None => {
+                self.index.insert(name.to_string(), self.families.len());
+                self.families.push(Family::default());
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[position]
+    }
+
+    // Adds the samples of one target with the given labels in front of their
+    // own, unless they have a label of that name already. Samples belong to
+    // the family of the HELP or TYPE line before them, so the buckets, sum
+    // and count of a histogram stay together.
+    pub(crate) fn add(&mut self, metrics: &str, labels: &[(&str, String)]) {
+        let mut current = String::new();
+        for line in metrics.lines().filter(|line| !line.trim().is_empty()) {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.split_whitespace();
+                if let (Some("HELP" | "TYPE"), Some(name)) = (words.next(), words.next()) {
+                    current = name.to_string();
+                    let family = self.family(name);
+                    if !family.comments.iter().any(|known| known == line) {
+                        family.comments.push(line.to_string());
+                    }
+                }
+                continue;
+            }
+
+            let end = line.find(['{', ' ']).unwrap_or(line.len());
+            let (name, rest) = line.split_at(end);
+            if current.is_empty() || !name.starts_with(current.as_str()) {
+                current = name.to_string();
+            }
+            let (own, value) = match rest.strip_prefix('{') {
+                Some(rest) => rest.split_once('}').unwrap_or((rest, "")),
+                None => ("", rest),
+            };
+            let mut all: Vec<String> = labels
+                .iter()
+                .filter(|(key, _)| {
+                    !own.split(',')
+                        .any(|label| label.trim().starts_with(&format!("{}=", key)))
+                })
+                .map(|(key, value)| format!("{}=\"{}\"", key, value))
+                .collect();
+            if !own.is_empty() {
+                all.push(own.to_string());
+            }
+            let sample = format!("{}{{{}}}{}", name, all.join(","), value);
+            self.family(&current).samples.push(sample);
+        }
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut text = String::new();
+        for family in &self.families {
+            for line in family.comments.iter().chain(&family.samples) {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,170 @@
+use crate::init::output_dir;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Set by `--name`, read by every function locating files of the testnet and
+// inherited by the processes ic-testnet starts.
+pub(crate) const NAME_ENV: &str = "IC_TESTNET_NAME";
+
+// Named testnets move their default ports up by a multiple of this.
+const PORT_STEP: u16 = 10;
+const MAX_TESTNETS: u16 = 99;
+
+// Every testnet initialized on the machine, in ~/.ic-testnet/testnets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    name: Option<String>,
+    dir: PathBuf,
+    port_offset: u16,
+    created_at: u64,
+}
+
+pub(crate) fn name() -> Option<String> {
+    env::var(NAME_ENV).ok().filter(|name| !name.is_empty())
+}
+
+pub(crate) fn set_name(name: Option<&str>) -> Result<()> {
+    let Some(name) = name else {
+        return Ok(());
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "invalid testnet name {:?}, use letters, digits, - and _",
+            name
+        );
+    }
+    env::set_var(NAME_ENV, name);
+    Ok(())
+}
+
+fn registry_dir() -> Result<PathBuf> {
+    let home = env::var("HOME").map_err(|_| anyhow!("HOME is not set"))?;
+    Ok(PathBuf::from(home).join(".ic-testnet").join("testnets"))
+}
+
+fn entry_file(dir: &Path) -> Result<PathBuf> {
+    let hash = hex::encode(Sha256::digest(dir.to_string_lossy().as_bytes()));
+    Ok(registry_dir()?.join(format!("{}.json", &hash[..16])))
+}
+
+// Entries of testnets whose directory still exists.
+fn entries() -> Result<Vec<Entry>> {
+    let registry = registry_dir()?;
+    let Ok(files) = fs::read_dir(&registry) else {
+        return Ok(vec![]);
+    };
+    let mut entries = vec![];
+    for file in files {
+        let path = file?.path();
+        let Ok(entry) = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str::<Entry>(&json)?))
+        else {
+            continue;
+        };
+        if entry.dir.is_dir() {
+            entries.push(entry);
+        } else {
+            let _ = fs::remove_file(&path);
+        }
+    }
+    entries.sort_by(|a, b| a.dir.cmp(&b.dir));
+
+    Ok(entries)
+}
+
+// Testnets without a name keep the default ports, named ones get the first
+// offset no other testnet on the machine uses.
+pub(crate) fn port_offset() -> Result<u16> {
+    if name().is_none() {
+        return Ok(0);
+    }
+    let dir = output_dir()?;
+    let entries = entries()?;
+    if let Some(entry) = entries.iter().find(|entry| entry.dir == dir) {
+        return Ok(entry.port_offset);
+    }
+
+    (1..=MAX_TESTNETS)
+        .map(|i| i * PORT_STEP)
+        .find(|offset| !entries.iter().any(|entry| entry.port_offset == *offset))
+        .ok_or(anyhow!(
+            "all {} port ranges are taken by other testnets, see `ic-testnet list`",
+            MAX_TESTNETS
+        ))
+}
+
+pub(crate) fn register() -> Result<()> {
+    let dir = output_dir()?;
+    let entry = Entry {
+        name: name(),
+        port_offset: port_offset()?,
+        dir: dir.clone(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let path = entry_file(&dir)?;
+    if let Some(registry) = path.parent() {
+        fs::create_dir_all(registry)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&entry)?)?;
+    Ok(())
+}
+
+fn running_replicas(dir: &Path) -> usize {
+    let Ok(pids) = fs::read_dir(dir.join("pids")) else {
+        return 0;
+    };
+    pids.filter_map(|pid| fs::read_to_string(pid.ok()?.path()).ok())
+        .filter(|pid| Path::new(&format!("/proc/{}", pid.trim())).exists())
+        .count()
+}
+
+fn node_count(dir: &Path) -> Option<usize> {
+    let json = fs::read_to_string(dir.join("topology.json")).ok()?;
+    let topology: serde_json::Value = serde_json::from_str(&json).ok()?;
+    let assigned: usize = topology["subnets"]
+        .as_array()?
+        .iter()
+        .filter_map(|subnet| subnet["nodes"].as_array().map(|nodes| nodes.len()))
+        .sum();
+    Some(
+        assigned
+            + topology["unassigned_nodes"]
+                .as_array()
+                .map_or(0, |n| n.len()),
+    )
+}
+
+pub(crate) fn list() -> Result<()> {
+    let entries = entries()?;
+    if entries.is_empty() {
+        println!("no testnets on this machine");
+        return Ok(());
+    }
+
+    println!(
+        "{:<16} {:>5} {:>7} {:>11}  DIR",
+        "NAME", "NODES", "RUNNING", "PORT OFFSET"
+    );
+    for entry in entries {
+        println!(
+            "{:<16} {:>5} {:>7} {:>11}  {}",
+            entry.name.as_deref().unwrap_or("-"),
+            node_count(&entry.dir).map_or("?".to_string(), |n| n.to_string()),
+            running_replicas(&entry.dir),
+            entry.port_offset,
+            entry.dir.display()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use crate::init::transport_port;
+use crate::process;
+use crate::topology::{self, NodeSummary, Topology};
+use anyhow::{bail, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Subcommand)]
+pub(crate) enum NetCommand {
+    /// Check that every node reaches the transport, xnet and public API ports
+    /// of every other node and print the results as a matrix
+    Check {
+        /// Milliseconds to wait for each port
+        #[clap(long, default_value = "1000")]
+        timeout_ms: u64,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Port {
+    Transport,
+    Xnet,
+    Api,
+}
+
+impl Port {
+    fn name(&self) -> &'static str {
+        match self {
+            Port::Transport => "transport",
+            Port::Xnet => "xnet",
+            Port::Api => "api",
+        }
+    }
+
+    fn letter(&self) -> char {
+        match self {
+            Port::Transport => 'T',
+            Port::Xnet => 'X',
+            Port::Api => 'A',
+        }
+    }
+}
+
+// Addresses peers and clients dial for a node, as registered.
+fn targets(node: &NodeSummary) -> Result<Vec<(Port, SocketAddr)>> {
+    let api = node.addr()?;
+    Ok(vec![
+        (
+            Port::Transport,
+            SocketAddr::new(api.ip(), transport_port()?),
+        ),
+        (Port::Xnet, node.xnet.parse()?),
+        (Port::Api, api),
+    ])
+}
+
+// Connects from the address of a node, or from any address of the host.
+fn reach_tcp(from: Option<IpAddr>, to: SocketAddr, timeout: Duration) -> Result<(), String> {
+    let socket = Socket::new(Domain::for_address(to), Type::STREAM, Some(Protocol::TCP))
+        .map_err(|e| e.to_string())?;
+    if let Some(from) = from {
+        socket
+            .bind(&SocketAddr::new(from, 0).into())
+            .map_err(|e| format!("cannot send from {}: {}", from, e))?;
+    }
+    match socket.connect_timeout(&to.into(), timeout) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => Err(
+            format!("no answer within {:?}, dropped by a firewall?", timeout),
+        ),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// The transport is QUIC, which ignores a stray datagram. Only a closed port
+// answers, with an ICMP error the connected socket reports as a refused
+// connection, so silence counts as reachable.
+fn reach_udp(from: Option<IpAddr>, to: SocketAddr, timeout: Duration) -> Result<(), String> {
+    let unspecified: IpAddr = if to.is_ipv6() {
+        "::".parse().unwrap()
+    } else {
+        "0.0.0.0".parse().unwrap()
+    };
+    let from = from.unwrap_or(unspecified);
+    let socket = UdpSocket::bind(SocketAddr::new(from, 0))
+        .map_err(|e| format!("cannot send from {}: {}", from, e))?;
+    socket.connect(to).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    socket.send(&[]).map_err(|e| e.to_string())?;
+    let mut buf = [0u8; 1];
+    match socket.recv(&mut buf) {
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Err("port closed".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Nodes whose address belongs to this host can be checked from, the others
+// only from their own host.
+fn local(ip: IpAddr) -> bool {
+    UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+struct Failure {
+    from: String,
+    to: NodeIndex,
+    port: Port,
+    addr: SocketAddr,
+    error: String,
+}
+
+fn check(topology: &Topology, timeout: Duration) -> Result<()> {
+    let nodes: Vec<&NodeSummary> = topology.nodes().collect();
+    let mut targets_of = vec![];
+    for node in &nodes {
+        targets_of.push((node.index, targets(node)?));
+    }
+
+    // the host itself, as a client of the public APIs, and then every node
+    let mut sources: Vec<(String, Option<NodeIndex>, Option<IpAddr>)> =
+        vec![("host".to_string(), None, None)];
+    let mut remote = vec![];
+    for node in &nodes {
+        let ip = match process::listen_addr(node.index) {
+            Ok(addr) => addr.ip(),
+            Err(_) => node.addr()?.ip(),
+        };
+        if local(ip) {
+            sources.push((node.index.to_string(), Some(node.index), Some(ip)));
+        } else {
+            remote.push(node.index);
+        }
+    }
+
+    let rows: Vec<Vec<(NodeIndex, Vec<Failure>)>> = thread::scope(|scope| {
+        let handles: Vec<_> = sources
+            .iter()
+            .map(|(name, index, ip)| {
+                let targets_of = &targets_of;
+                scope.spawn(move || {
+                    targets_of
+                        .iter()
+                        .filter(|(to, _)| Some(*to) != *index)
+                        .map(|(to, targets)| {
+                            let failures = targets
+                                .iter()
+                                .filter_map(|(port, addr)| {
+                                    let reached = match port {
+                                        Port::Transport => reach_udp(*ip, *addr, timeout),
+                                        Port::Xnet | Port::Api => reach_tcp(*ip, *addr, timeout),
+                                    };
+                                    reached.err().map(|error| Failure {
+                                        from: name.clone(),
+                                        to: *to,
+                                        port: *port,
+                                        addr: *addr,
+                                        error,
+                                    })
+                                })
+                                .collect();
+                            (*to, failures)
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    });
+
+    print!("{:<6}", "from");
+    for node in &nodes {
+        print!(" {:>5}", node.index);
+    }
+    println!();
+    for ((name, _, _), row) in sources.iter().zip(&rows) {
+        print!("{:<6}", name);
+        for node in &nodes {
+            let cell = match row.iter().find(|(to, _)| *to == node.index) {
+                None => "-".to_string(),
+                Some((_, failures)) if failures.is_empty() => "ok".to_string(),
+                Some((_, failures)) => failures.iter().map(|f| f.port.letter()).collect(),
+            };
+            print!(" {:>5}", cell);
+        }
+        println!();
+    }
+    println!("T transport (UDP), X xnet, A public API unreachable");
+    if !remote.is_empty() {
+        println!(
+            "nodes {:?} are not on this host, run `net check` on their host as well",
+            remote
+        );
+    }
+
+    let failures: Vec<&Failure> = rows.iter().flatten().flat_map(|(_, f)| f).collect();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    println!();
+    for failure in &failures {
+        println!(
+            "{} -> {} {} {}: {}",
+            failure.from,
+            failure.to,
+            failure.port.name(),
+            failure.addr,
+            failure.error
+        );
+    }
+    bail!("{} ports are unreachable", failures.len())
+}
+
+pub(crate) fn run(cmd: NetCommand) -> Result<()> {
+    match cmd {
+        NetCommand::Check { timeout_ms } => {
+            check(&topology::load()?, Duration::from_millis(timeout_ms))
+        }
+    }
+}
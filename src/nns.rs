@@ -0,0 +1,85 @@
+use crate::agent::block_on;
+use crate::identities;
+use crate::init::{nns_url, output_dir};
+use crate::ledgers;
+use crate::logging;
+use crate::tools::run_ic_binary;
+use anyhow::Result;
+use clap::Subcommand;
+use std::path::PathBuf;
+
+#[derive(Subcommand)]
+pub(crate) enum NnsCommand {
+    /// Install the NNS canisters with ic-nns-init
+    Install {
+        /// URL of an NNS node, defaults to the first configured node
+        #[clap(long)]
+        nns_url: Option<String>,
+        /// Directory containing the NNS canister wasms
+        #[clap(long, env = "NNS_WASM_DIR", default_value = "ic/artifacts/canisters")]
+        wasm_dir: PathBuf,
+        /// Install SNS-W allowing SNS deployments on the given subnet
+        #[clap(long)]
+        sns_subnet: Option<String>,
+        /// Also install the ICP index canister
+        #[clap(long)]
+        icp_index: bool,
+        /// Install an ICRC-1/ICRC-3 ledger and index pair for the token symbol
+        #[clap(long = "icrc-ledger")]
+        icrc_ledgers: Vec<String>,
+    },
+}
+
+pub(crate) fn run(cmd: NnsCommand) -> Result<()> {
+    match cmd {
+        NnsCommand::Install {
+            nns_url: url,
+            wasm_dir,
+            sns_subnet,
+            icp_index,
+            icrc_ledgers,
+        } => {
+            let registry_local_store = output_dir()?.join("state").join("ic_registry_local_store");
+            let url = url.unwrap_or(nns_url());
+            let mut args = vec![
+                "--url".to_string(),
+                url.clone(),
+                "--registry-local-store-dir".to_string(),
+                registry_local_store.display().to_string(),
+                "--wasm-dir".to_string(),
+                wasm_dir.display().to_string(),
+            ];
+
+            // Without generated identities ic-nns-init falls back to its own
+            // test neurons.
+            if let Ok(identities) = identities::load() {
+                args.push("--initial-neurons".to_string());
+                args.push(
+                    identities::identities_dir()?
+                        .join("initial-neurons.csv")
+                        .display()
+                        .to_string(),
+                );
+                args.push("--initialize-ledger-with-test-accounts".to_string());
+                args.extend(identities.into_iter().map(|identity| identity.account_id));
+            }
+
+            if let Some(subnet) = sns_subnet {
+                args.push("--sns-subnet".to_string());
+                args.push(subnet);
+            }
+
+            let output =
+                logging::timed("nns.install", None, || run_ic_binary("ic-nns-init", &args))?;
+            print!("{}", output);
+
+            if icp_index || !icrc_ledgers.is_empty() {
+                logging::timed("nns.ledgers", None, || {
+                    block_on(ledgers::install(&url, &wasm_dir, icp_index, &icrc_ledgers))
+                })?;
+            }
+        }
+    }
+
+    Ok(())
+}
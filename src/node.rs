@@ -0,0 +1,25 @@
+use crate::{backend, init, process};
+use anyhow::Result;
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+
+#[derive(Subcommand)]
+pub(crate) enum NodeCommand {
+    /// Write the replica config of a node again from tmp/testnet.toml and the
+    /// overrides, keeping its keys and state
+    RegenConfig { node: NodeIndex },
+}
+
+pub(crate) fn run(cmd: NodeCommand) -> Result<()> {
+    match cmd {
+        NodeCommand::RegenConfig { node } => {
+            init::regen_replica_config(node)?;
+            println!("wrote {}", process::config_file(node));
+            if backend::for_node(node)?.running(node) {
+                println!("node {} is running, restart it to apply the config", node);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,396 @@
+use crate::config::TestnetConfig;
+use crate::init::node_endpoint;
+use crate::process;
+use crate::topology::{self, Topology};
+use anyhow::{bail, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+// Subnet settings kept in the subnet record, changed by a proposal. The others
+// end up in the replica configs.
+const SUBNET_RECORD_SETTINGS: &[&str] = &[
+    "max_instructions_per_message",
+    "max_instructions_per_round",
+    "max_instructions_per_install_code",
+    "dkg_interval_length",
+    "unit_delay_millis",
+    "initial_notary_delay_millis",
+];
+
+// Node settings registered with the node record, which only the node can
+// update.
+const NODE_RECORD_SETTINGS: &[&str] = &["advertise", "advertise_xnet"];
+
+#[derive(Args)]
+pub(crate) struct PlanArgs {
+    /// Edited copy of tmp/testnet.toml
+    pub(crate) config: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Change {
+    pub setting: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Action {
+    /// A node that is not registered yet
+    AddNode {
+        addr: SocketAddr,
+        subnet: Option<SubnetIndex>,
+    },
+    RemoveNode {
+        node: NodeIndex,
+        subnet: Option<SubnetIndex>,
+    },
+    MoveNode {
+        node: NodeIndex,
+        from: Option<SubnetIndex>,
+        to: Option<SubnetIndex>,
+    },
+    UpdateSubnetRecord {
+        subnet: SubnetIndex,
+        changes: Vec<Change>,
+    },
+    ReconfigureSubnet {
+        subnet: SubnetIndex,
+        nodes: Vec<NodeIndex>,
+        changes: Vec<Change>,
+    },
+    ReconfigureNode {
+        node: NodeIndex,
+        changes: Vec<Change>,
+    },
+    Relabel {
+        node: NodeIndex,
+        changes: Vec<Change>,
+    },
+    /// Changes the registry generated by `init` cannot take
+    Reinit { reason: String },
+}
+
+pub(crate) struct Plan {
+    pub target: TestnetConfig,
+    pub actions: Vec<Action>,
+}
+
+fn place(subnet: Option<SubnetIndex>) -> String {
+    match subnet {
+        Some(subnet) => format!("subnet {}", subnet),
+        None => "unassigned".to_string(),
+    }
+}
+
+fn value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "default".to_string(),
+        value => value.to_string(),
+    }
+}
+
+// Settings that differ between two sections, compared by their TOML names.
+fn changes<T: Serialize>(from: &T, to: &T, skip: &[&str]) -> Result<Vec<Change>> {
+    let (serde_json::Value::Object(from), serde_json::Value::Object(to)) =
+        (serde_json::to_value(from)?, serde_json::to_value(to)?)
+    else {
+        bail!("settings are not a table");
+    };
+    let null = serde_json::Value::Null;
+    let mut settings: Vec<&String> = from.keys().chain(to.keys()).collect();
+    settings.sort();
+    settings.dedup();
+
+    Ok(settings
+        .into_iter()
+        .filter(|setting| !skip.contains(&setting.as_str()))
+        .filter_map(|setting| {
+            let old = from.get(setting).unwrap_or(&null);
+            let new = to.get(setting).unwrap_or(&null);
+            (old != new).then(|| Change {
+                setting: setting.clone(),
+                from: value(old),
+                to: value(new),
+            })
+        })
+        .collect())
+}
+
+struct Member {
+    node: NodeIndex,
+    addr: SocketAddr,
+    subnet: Option<SubnetIndex>,
+}
+
+// Nodes as registered by `init` and changed by `apply` since, with the address
+// their replica binds to.
+fn members(topology: &Topology) -> Result<Vec<Member>> {
+    let assigned = topology.subnets.iter().flat_map(|subnet| {
+        subnet
+            .nodes
+            .iter()
+            .map(move |node| (node, Some(subnet.index)))
+    });
+    let unassigned = topology.unassigned_nodes.iter().map(|node| (node, None));
+
+    assigned
+        .chain(unassigned)
+        .map(|(node, subnet)| {
+            Ok(Member {
+                node: node.index,
+                addr: process::listen_addr(node.index).or(node.addr())?,
+                subnet,
+            })
+        })
+        .collect()
+}
+
+fn print_changes(f: &mut fmt::Formatter, changes: &[Change]) -> fmt::Result {
+    for change in changes {
+        writeln!(f)?;
+        write!(
+            f,
+            "      {}: {} -> {}",
+            change.setting, change.from, change.to
+        )?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::AddNode { addr, subnet } => write!(
+                f,
+                "  + node at {} in {}, has no keys in the registry yet",
+                addr,
+                place(*subnet)
+            ),
+            Action::RemoveNode { node, subnet } => {
+                write!(f, "  - node {} ({})", node, place(*subnet))
+            }
+            Action::MoveNode { node, from, to } => {
+                write!(f, "  ~ node {}: {} -> {}", node, place(*from), place(*to))
+            }
+            Action::UpdateSubnetRecord { subnet, changes } => {
+                write!(f, "  ~ subnet {} record", subnet)?;
+                print_changes(f, changes)
+            }
+            Action::ReconfigureSubnet {
+                subnet,
+                nodes,
+                changes,
+            } => {
+                write!(
+                    f,
+                    "  ~ subnet {} replica config, restarts nodes {:?}",
+                    subnet, nodes
+                )?;
+                print_changes(f, changes)
+            }
+            Action::ReconfigureNode { node, changes } => {
+                write!(f, "  ~ node {} replica, restarts it", node)?;
+                print_changes(f, changes)
+            }
+            Action::Relabel { node, changes } => {
+                write!(f, "  ~ node {} labels", node)?;
+                print_changes(f, changes)
+            }
+            Action::Reinit { reason } => write!(f, "  ! {}", reason),
+        }
+    }
+}
+
+impl Action {
+    // `apply` converges the running testnet through the registry and the
+    // replica configs, everything else starts over with `init`.
+    pub(crate) fn needs_reinit(&self) -> bool {
+        matches!(self, Action::AddNode { .. } | Action::Reinit { .. })
+    }
+}
+
+impl Plan {
+    pub(crate) fn new(target: TestnetConfig) -> Result<Plan> {
+        let topology = topology::load()?;
+        let current = TestnetConfig::saved()?;
+        let members = members(&topology)?;
+        let mut actions = vec![];
+
+        // nodes are matched by address, so that the indices of the nodes
+        // already running do not depend on their order in the file
+        let mut kept: Vec<(NodeIndex, Option<SubnetIndex>)> = vec![];
+        match target.node_addresses() {
+            Some(addresses) => {
+                for (address, subnet) in addresses {
+                    let addr = node_endpoint(&address, false)?;
+                    match members.iter().find(|member| member.addr == addr) {
+                        Some(member) => {
+                            if member.subnet != subnet {
+                                actions.push(Action::MoveNode {
+                                    node: member.node,
+                                    from: member.subnet,
+                                    to: subnet,
+                                });
+                            }
+                            kept.push((member.node, subnet));
+                        }
+                        None => actions.push(Action::AddNode { addr, subnet }),
+                    }
+                }
+                for member in &members {
+                    if !kept.iter().any(|(node, _)| *node == member.node) {
+                        actions.push(Action::RemoveNode {
+                            node: member.node,
+                            subnet: member.subnet,
+                        });
+                    }
+                }
+            }
+            // without addresses the file only changes settings
+            None => kept.extend(members.iter().map(|member| (member.node, member.subnet))),
+        }
+
+        let mut subnets: Vec<SubnetIndex> = kept.iter().filter_map(|(_, subnet)| *subnet).collect();
+        subnets.sort();
+        subnets.dedup();
+        for subnet in subnets {
+            if topology.subnet(subnet).is_err() {
+                actions.push(Action::Reinit {
+                    reason: format!("subnet {} does not exist", subnet),
+                });
+                continue;
+            }
+            let changes = changes(
+                &current.subnet(Some(subnet)),
+                &target.subnet(Some(subnet)),
+                &["index", "nodes"],
+            )?;
+            // the subnet started at its initial height long ago, and made
+            // its chain keys and bitcoin adapter configs then. The routing
+            // table of the specified ids is only written by `init`.
+            let (restart, changes): (Vec<Change>, Vec<Change>) =
+                changes.into_iter().partition(|change| {
+                    [
+                        "initial_height",
+                        "chain_keys",
+                        "bitcoind",
+                        "specified_id_ranges",
+                    ]
+                    .contains(&change.setting.as_str())
+                });
+            for change in restart {
+                let reason = match change.setting.as_str() {
+                    "initial_height" => format!(
+                        "subnet {} starts at height {} instead of {}",
+                        subnet, change.to, change.from
+                    ),
+                    "chain_keys" => format!(
+                        "subnet {} generates the chain keys {} instead of {}",
+                        subnet, change.to, change.from
+                    ),
+                    "specified_id_ranges" => format!(
+                        "subnet {} is routed the specified ids {} instead of {}",
+                        subnet, change.to, change.from
+                    ),
+                    _ => format!(
+                        "subnet {} connects to bitcoind {} instead of {}",
+                        subnet, change.to, change.from
+                    ),
+                };
+                actions.push(Action::Reinit { reason });
+            }
+            let (record, config): (Vec<Change>, Vec<Change>) = changes
+                .into_iter()
+                .partition(|change| SUBNET_RECORD_SETTINGS.contains(&change.setting.as_str()));
+            if !record.is_empty() {
+                actions.push(Action::UpdateSubnetRecord {
+                    subnet,
+                    changes: record,
+                });
+            }
+            if !config.is_empty() {
+                actions.push(Action::ReconfigureSubnet {
+                    subnet,
+                    nodes: kept
+                        .iter()
+                        .filter(|(_, s)| *s == Some(subnet))
+                        .map(|(node, _)| *node)
+                        .collect(),
+                    changes: config,
+                });
+            }
+        }
+
+        for (node, _) in &kept {
+            let changes = changes(&current.node(*node), &target.node(*node), &["index"])?;
+            // labels only matter to ic-testnet
+            let (labels, changes): (Vec<Change>, Vec<Change>) = changes
+                .into_iter()
+                .partition(|change| change.setting == "labels");
+            if !labels.is_empty() {
+                actions.push(Action::Relabel {
+                    node: *node,
+                    changes: labels,
+                });
+            }
+            let (record, config): (Vec<Change>, Vec<Change>) = changes
+                .into_iter()
+                .partition(|change| NODE_RECORD_SETTINGS.contains(&change.setting.as_str()));
+            for change in record {
+                actions.push(Action::Reinit {
+                    reason: format!(
+                        "node {} registers {} {} instead of {}",
+                        node, change.setting, change.to, change.from
+                    ),
+                });
+            }
+            if !config.is_empty() {
+                actions.push(Action::ReconfigureNode {
+                    node: *node,
+                    changes: config,
+                });
+            }
+        }
+
+        Ok(Plan { target, actions })
+    }
+
+    pub(crate) fn print(&self) {
+        if self.actions.is_empty() {
+            println!("No changes, the testnet matches the configuration.");
+            return;
+        }
+        for action in &self.actions {
+            println!("{}", action);
+        }
+
+        let count = |f: fn(&Action) -> bool| self.actions.iter().filter(|a| f(a)).count();
+        let add = count(|a| matches!(a, Action::AddNode { .. }));
+        let remove = count(|a| matches!(a, Action::RemoveNode { .. }));
+        let reinit = count(|a| matches!(a, Action::Reinit { .. }));
+        println!();
+        println!(
+            "Plan: {} to add, {} to change, {} to remove.",
+            add,
+            self.actions.len() - add - remove - reinit,
+            remove
+        );
+        if self.actions.iter().any(Action::needs_reinit) {
+            println!(
+                "Adding new nodes, subnets or node addresses needs `ic-testnet init --force-reinit`."
+            );
+        }
+    }
+}
+
+pub(crate) fn run(args: PlanArgs) -> Result<()> {
+    let target = TestnetConfig::load(Some(&args.config))?;
+    Plan::new(target)?.print();
+    Ok(())
+}
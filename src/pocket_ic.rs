@@ -0,0 +1,503 @@
+// A subset of the PocketIC server protocol (as spoken by the `pocket-ic`
+// 4.x client library against server 5.0) backed by the running testnet, so
+// that PocketIC test suites can run against real consensus. All instances
+// share the testnet: creating one does not reset any state, a tick waits for
+// the next finalized block and time can only move forward.
+use crate::agent::{block_on, connect, effective_canister_id};
+use crate::chaos;
+use crate::health;
+use crate::identities;
+use crate::topology::{self, Topology};
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use candid::Principal;
+use clap::Args;
+use ic_agent::agent::{RejectCode, RejectResponse};
+use ic_agent::AgentError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+const SERVER_VERSION: &str = "pocket-ic-server 5.0.0";
+const TICK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Args)]
+pub(crate) struct PocketIcArgs {
+    /// Print the emulated server version, the client library checks it
+    #[clap(long)]
+    version: bool,
+    /// Write the port to this file once listening, as the client library expects
+    #[clap(long)]
+    port_file: Option<PathBuf>,
+    /// Exit after this many seconds without requests
+    #[clap(long)]
+    ttl: Option<u64>,
+    #[clap(long, default_value = "127.0.0.1:0")]
+    listen: String,
+}
+
+fn bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(bytes))
+}
+
+fn from_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    STANDARD.decode(text).map_err(serde::de::Error::custom)
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawPrincipalId {
+    #[serde(serialize_with = "bytes", deserialize_with = "from_bytes")]
+    principal_id: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawCanisterId {
+    #[serde(serialize_with = "bytes", deserialize_with = "from_bytes")]
+    canister_id: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawSubnetId {
+    #[serde(serialize_with = "bytes", deserialize_with = "from_bytes")]
+    subnet_id: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum RawEffectivePrincipal {
+    None,
+    SubnetId(RawSubnetId),
+    CanisterId(RawCanisterId),
+}
+
+#[derive(Deserialize)]
+struct RawCanisterCall {
+    sender: RawPrincipalId,
+    canister_id: RawCanisterId,
+    effective_principal: RawEffectivePrincipal,
+    method: String,
+    #[serde(deserialize_with = "from_bytes")]
+    payload: Vec<u8>,
+}
+
+#[derive(Clone, Serialize)]
+enum RawWasmResult {
+    Reply(#[serde(serialize_with = "bytes")] Vec<u8>),
+    Reject(String),
+}
+
+#[derive(Clone, Serialize)]
+struct UserError {
+    code: String,
+    description: String,
+}
+
+#[derive(Clone, Serialize)]
+enum RawCanisterResult {
+    Ok(RawWasmResult),
+    Err(UserError),
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawTime {
+    nanos_since_epoch: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawMessageId {
+    effective_principal: RawEffectivePrincipal,
+    #[serde(serialize_with = "bytes", deserialize_with = "from_bytes")]
+    message_id: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    next_instance: u64,
+    instances: BTreeMap<u64, Instance>,
+}
+
+#[derive(Default)]
+struct Instance {
+    // nanoseconds the replicas' clocks were moved forward
+    time_offset: u64,
+    next_message: u64,
+    // submitted ingress messages are executed right away and their results
+    // kept until awaited
+    messages: BTreeMap<Vec<u8>, RawCanisterResult>,
+}
+
+// PocketIC reports failures as 500 with a message, which the client surfaces.
+struct ApiError(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> ApiError {
+        ApiError(e.into())
+    }
+}
+
+// The client library matches errors by name, the numeric codes of the
+// replica are translated for the common cases only.
+fn error_code(code: Option<&str>) -> &'static str {
+    match code {
+        Some("IC0301") => "CanisterNotFound",
+        Some("IC0406") => "CanisterRejectedMessage",
+        Some("IC0502") => "CanisterTrapped",
+        Some("IC0503") => "CanisterCalledTrap",
+        Some("IC0504") => "CanisterOutOfCycles",
+        Some("IC0508") => "CanisterStopped",
+        Some("IC0536") => "CanisterMethodNotFound",
+        Some("IC0537") => "CanisterWasmModuleNotFound",
+        _ => "CanisterError",
+    }
+}
+
+fn reject_result(reject: RejectResponse) -> RawCanisterResult {
+    if reject.reject_code == RejectCode::CanisterReject {
+        return RawCanisterResult::Ok(RawWasmResult::Reject(reject.reject_message));
+    }
+    RawCanisterResult::Err(UserError {
+        code: error_code(reject.error_code.as_deref()).to_string(),
+        description: reject.reject_message,
+    })
+}
+
+// Calls can only be sent as principals whose keys are known, i.e. anonymously
+// or as one of the generated identities.
+fn sender_pem(sender: &RawPrincipalId) -> Result<Option<PathBuf>> {
+    let sender = Principal::try_from_slice(&sender.principal_id)?;
+    if sender == Principal::anonymous() {
+        return Ok(None);
+    }
+
+    identities::load()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|identity| identity.principal == sender.to_text())
+        .map(|identity| Some(identity.pem))
+        .ok_or(anyhow!(
+            "cannot send as {}, only the anonymous principal and generated identities have keys on a real network",
+            sender
+        ))
+}
+
+fn subnet_index(topology: &Topology, subnet_id: &[u8]) -> Result<u64> {
+    let subnet_id = Principal::try_from_slice(subnet_id)?.to_text();
+    topology
+        .subnets
+        .iter()
+        .find(|subnet| subnet.subnet_id == subnet_id)
+        .map(|subnet| subnet.index)
+        .ok_or(anyhow!("unknown subnet {}", subnet_id))
+}
+
+fn canister_call(
+    topology: &Topology,
+    call: &RawCanisterCall,
+    query: bool,
+) -> Result<RawCanisterResult> {
+    let canister_id = Principal::try_from_slice(&call.canister_id.canister_id)?;
+    let effective = match &call.effective_principal {
+        RawEffectivePrincipal::CanisterId(id) => Principal::try_from_slice(&id.canister_id)?,
+        RawEffectivePrincipal::SubnetId(id) => {
+            effective_canister_id(subnet_index(topology, &id.subnet_id)?)
+        }
+        RawEffectivePrincipal::None if canister_id == Principal::management_canister() => {
+            effective_canister_id(topology.nns_subnet)
+        }
+        RawEffectivePrincipal::None => canister_id,
+    };
+    let url = topology.canister_url(&effective)?;
+    let pem = sender_pem(&call.sender)?;
+
+    block_on(async {
+        let agent = connect(&url, pem.as_deref()).await?;
+        let reply = if query {
+            agent
+                .query(&canister_id, &call.method)
+                .with_effective_canister_id(effective)
+                .with_arg(call.payload.clone())
+                .call()
+                .await
+        } else {
+            agent
+                .update(&canister_id, &call.method)
+                .with_effective_canister_id(effective)
+                .with_arg(call.payload.clone())
+                .call_and_wait()
+                .await
+        };
+
+        Ok(match reply {
+            Ok(reply) => RawCanisterResult::Ok(RawWasmResult::Reply(reply)),
+            Err(AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject)) => {
+                reject_result(reject)
+            }
+            Err(e) => return Err(e.into()),
+        })
+    })
+}
+
+fn tick(topology: &Topology) -> Result<()> {
+    for subnet in &topology.subnets {
+        let height = health::heights(subnet)
+            .into_iter()
+            .filter_map(|(_, height)| height)
+            .max()
+            .ok_or(anyhow!(
+                "subnet {} does not report its height",
+                subnet.index
+            ))?;
+        health::wait_height(subnet, height + 1, TICK_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+fn now_nanos() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64)
+}
+
+fn set_time(topology: &Topology, instance: &mut Instance, nanos: u64) -> Result<()> {
+    let now = now_nanos()?;
+    if nanos < now + instance.time_offset {
+        bail!("the time of a replicated testnet cannot move backwards");
+    }
+
+    let offset = nanos - now;
+    for node in topology.nodes() {
+        chaos::set_clock_skew(node.index, &format!("+{}s", offset / 1_000_000_000))?;
+    }
+    instance.time_offset = offset;
+    Ok(())
+}
+
+fn created(topology: &Topology, instance_id: u64) -> Value {
+    let subnets: BTreeMap<String, Value> = topology
+        .subnets
+        .iter()
+        .map(|subnet| {
+            let kind = if subnet.index == topology.nns_subnet {
+                "NNS"
+            } else {
+                "Application"
+            };
+            let start = effective_canister_id(subnet.index);
+            let end = effective_canister_id(subnet.index + 1);
+            let range = json!({
+                "start": RawCanisterId { canister_id: start.as_slice().to_vec() },
+                "end": RawCanisterId { canister_id: end.as_slice().to_vec() },
+            });
+            (
+                subnet.subnet_id.clone(),
+                json!({
+                    "subnet_kind": kind,
+                    "size": subnet.nodes.len(),
+                    "canister_ranges": [range],
+                }),
+            )
+        })
+        .collect();
+
+    json!({ "Created": { "instance_id": instance_id, "topology": subnets } })
+}
+
+fn route(
+    topology: &Topology,
+    state: &Mutex<State>,
+    method: &Method,
+    path: &str,
+    body: &str,
+) -> Result<Value, ApiError> {
+    let segments: Vec<&str> = path
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let instance_id = match segments.as_slice() {
+        ["instances", id, ..] => id.parse::<u64>().ok(),
+        _ => None,
+    };
+    let with_instance = |f: &mut dyn FnMut(&mut Instance) -> Result<Value>| -> Result<Value> {
+        let mut state = state.lock().map_err(|_| anyhow!("poisoned state"))?;
+        let id = instance_id.ok_or(anyhow!("invalid instance"))?;
+        let instance = state
+            .instances
+            .get_mut(&id)
+            .ok_or(anyhow!("instance {} not found", id))?;
+        f(instance)
+    };
+
+    let value = match (method, segments.as_slice()) {
+        (Method::Get, ["status"]) => json!({}),
+        (Method::Get, ["instances"]) => {
+            let state = state.lock().map_err(|_| anyhow!("poisoned state"))?;
+            let instances: Vec<String> = (0..state.next_instance)
+                .map(|id| {
+                    let status = if state.instances.contains_key(&id) {
+                        "Available"
+                    } else {
+                        "Deleted"
+                    };
+                    status.to_string()
+                })
+                .collect();
+            json!(instances)
+        }
+        (Method::Post, ["instances"]) => {
+            let mut state = state.lock().map_err(|_| anyhow!("poisoned state"))?;
+            let id = state.next_instance;
+            state.next_instance += 1;
+            state.instances.insert(id, Instance::default());
+            created(topology, id)
+        }
+        (Method::Delete, ["instances", _]) => {
+            let mut state = state.lock().map_err(|_| anyhow!("poisoned state"))?;
+            if let Some(id) = instance_id {
+                state.instances.remove(&id);
+            }
+            json!(null)
+        }
+        (Method::Post, ["instances", _, "update", "tick"]) => {
+            with_instance(&mut |_| Ok(json!(null)))?;
+            tick(topology)?;
+            json!(null)
+        }
+        (Method::Get, ["instances", _, "read", "get_time"]) => with_instance(&mut |instance| {
+            Ok(serde_json::to_value(RawTime {
+                nanos_since_epoch: now_nanos()? + instance.time_offset,
+            })?)
+        })?,
+        (Method::Post, ["instances", _, "update", "set_time"]) => {
+            let time: RawTime = serde_json::from_str(body)?;
+            with_instance(&mut |instance| {
+                set_time(topology, instance, time.nanos_since_epoch)?;
+                Ok(json!(null))
+            })?
+        }
+        (Method::Post, ["instances", _, "read", "query"]) => {
+            with_instance(&mut |_| Ok(json!(null)))?;
+            let call: RawCanisterCall = serde_json::from_str(body)?;
+            serde_json::to_value(canister_call(topology, &call, true)?)?
+        }
+        (Method::Post, ["instances", _, "update", "execute_ingress_message"]) => {
+            with_instance(&mut |_| Ok(json!(null)))?;
+            let call: RawCanisterCall = serde_json::from_str(body)?;
+            serde_json::to_value(canister_call(topology, &call, false)?)?
+        }
+        (Method::Post, ["instances", _, "update", "submit_ingress_message"]) => {
+            let call: RawCanisterCall = serde_json::from_str(body)?;
+            let result = canister_call(topology, &call, false)?;
+            with_instance(&mut |instance| {
+                let message_id = instance.next_message.to_be_bytes().to_vec();
+                instance.next_message += 1;
+                instance.messages.insert(message_id.clone(), result.clone());
+                let id = RawMessageId {
+                    effective_principal: RawEffectivePrincipal::None,
+                    message_id,
+                };
+                Ok(json!({ "Ok": id }))
+            })?
+        }
+        (Method::Post, ["instances", _, "update", "await_ingress_message"]) => {
+            let id: RawMessageId = serde_json::from_str(body)?;
+            with_instance(&mut |instance| {
+                let result = instance
+                    .messages
+                    .remove(&id.message_id)
+                    .ok_or(anyhow!("unknown message"))?;
+                Ok(serde_json::to_value(result)?)
+            })?
+        }
+        (Method::Post, ["instances", _, "read", "get_subnet"]) => {
+            let canister: RawCanisterId = serde_json::from_str(body)?;
+            let canister_id = Principal::try_from_slice(&canister.canister_id)?;
+            let index = topology
+                .subnets
+                .iter()
+                .find(|subnet| {
+                    canister_id >= effective_canister_id(subnet.index)
+                        && canister_id < effective_canister_id(subnet.index + 1)
+                })
+                .map(|subnet| subnet.subnet_id.clone());
+            match index {
+                Some(subnet_id) => serde_json::to_value(RawSubnetId {
+                    subnet_id: Principal::from_text(subnet_id)?.as_slice().to_vec(),
+                })?,
+                None => json!(null),
+            }
+        }
+        _ => bail!("{} {} is not supported by ic-testnet", method, path),
+    };
+
+    Ok(value)
+}
+
+fn respond(topology: &Topology, state: &Mutex<State>, mut request: Request) -> Result<()> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+
+    let (status, body) = match route(topology, state, request.method(), request.url(), &body) {
+        Ok(body) => (200, body),
+        Err(ApiError(e)) => (500, json!({ "message": format!("{:#}", e) })),
+    };
+
+    let header = Header::from_bytes("Content-Type", "application/json")
+        .map_err(|_| anyhow!("invalid header"))?;
+    request.respond(
+        Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(header),
+    )?;
+    Ok(())
+}
+
+pub(crate) fn run(args: PocketIcArgs) -> Result<()> {
+    if args.version {
+        println!("{}", SERVER_VERSION);
+        return Ok(());
+    }
+
+    let topology = Arc::new(topology::load()?);
+    let state = Arc::new(Mutex::new(State::default()));
+    let server = Server::http(&args.listen).map_err(|e| anyhow!("{}: {}", args.listen, e))?;
+    let addr = server
+        .server_addr()
+        .to_ip()
+        .ok_or(anyhow!("not listening on an IP address"))?;
+    if let Some(port_file) = &args.port_file {
+        fs::write(port_file, addr.port().to_string())?;
+    }
+    eprintln!("PocketIC interface listening on http://{}", addr);
+
+    let ttl = args.ttl.map(Duration::from_secs);
+    let mut last_request = Instant::now();
+    loop {
+        let request = match ttl {
+            Some(ttl) => match server.recv_timeout(Duration::from_secs(1))? {
+                Some(request) => request,
+                None if last_request.elapsed() > ttl => return Ok(()),
+                None => continue,
+            },
+            None => server.recv()?,
+        };
+        last_request = Instant::now();
+
+        let topology = Arc::clone(&topology);
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            if let Err(e) = respond(&topology, &state, request) {
+                eprintln!("failed to respond: {}", e);
+            }
+        });
+    }
+}
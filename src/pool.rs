@@ -0,0 +1,758 @@
+use crate::init::{node_state_dir, NODE_INDEX};
+use crate::topology;
+use crate::{backend, process};
+use anyhow::{anyhow, bail, Result};
+use clap::Subcommand;
+use ic_artifact_pool::lmdb_pool::PersistentHeightIndexedPool;
+use ic_config::artifact_pool::LMDBConfig;
+use ic_interfaces::consensus_pool::{HeightIndexedPool, PoolSection};
+use ic_logger::no_op_logger;
+use ic_prep_lib::node::NodeIndex;
+use ic_protobuf::types::v1 as pb;
+#[cfg(feature = "run")]
+use ic_state_manager::stream_encoding::decode_stream_slice;
+use ic_types::consensus::certification::CertificationMessage;
+use ic_types::consensus::{BlockProposal, ConsensusMessage, ConsensusMessageHashable, HasHeight};
+use lmdb::{Cursor, Environment, EnvironmentFlags, Transaction};
+use prost::Message;
+#[cfg(feature = "run")]
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub(crate) enum PoolCommand {
+    /// Count artifacts and bytes per type and height in a node's consensus pool
+    Stats {
+        #[clap(long, default_value_t = NODE_INDEX)]
+        node: NodeIndex,
+        /// Number of heights with the most artifacts to list
+        #[clap(long, default_value = "5")]
+        top: usize,
+    },
+    /// Find the blocks in a node's consensus pool that include an ingress
+    /// message
+    FindIngress {
+        #[clap(long, default_value_t = NODE_INDEX)]
+        node: NodeIndex,
+        /// Hex encoded request id of the message
+        #[clap(long)]
+        message_id: String,
+    },
+    /// Decode every artifact in a node's consensus pool and report corrupt ones
+    Fsck {
+        #[clap(long, default_value_t = NODE_INDEX)]
+        node: NodeIndex,
+        /// Delete corrupt artifacts and index entries without an artifact,
+        /// the node has to be stopped
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Copy the validated pools of a node into a stopped node of the same
+    /// subnet
+    Clone {
+        /// Source node, e.g. node-100 or 100, may keep running
+        #[clap(long, parse(try_from_str = parse_node))]
+        from: NodeIndex,
+        #[clap(long, parse(try_from_str = parse_node))]
+        to: NodeIndex,
+        /// Replace the pools the target node already has
+        #[clap(long)]
+        force: bool,
+    },
+    #[cfg(feature = "run")]
+    /// Print the finalized blocks of a node with decoded payloads as JSON
+    /// lines
+    Blocks {
+        #[clap(long, default_value_t = NODE_INDEX)]
+        node: NodeIndex,
+        /// First height, the lowest in the pool by default
+        #[clap(long)]
+        from: Option<u64>,
+        /// Last height, the highest finalized by default
+        #[clap(long)]
+        to: Option<u64>,
+    },
+    /// Show the finalized and certified heights of nodes and flag nodes whose
+    /// certification lags behind
+    Certification {
+        /// Nodes to inspect, all assigned nodes by default
+        #[clap(long)]
+        node: Vec<NodeIndex>,
+        /// Heights certification may trail finalization
+        #[clap(long, default_value = "10")]
+        max_lag: u64,
+    },
+}
+
+fn parse_node(node: &str) -> Result<NodeIndex> {
+    Ok(node.trim_start_matches("node-").parse()?)
+}
+
+pub(crate) fn pool_dir(index: NodeIndex) -> Result<PathBuf> {
+    Ok(node_state_dir(index)?
+        .join(format!("node-{}", index))
+        .join("ic_consensus_pool"))
+}
+
+// Opens the validated section read-only, which is safe next to a running
+// replica.
+pub(crate) fn open(index: NodeIndex) -> Result<PersistentHeightIndexedPool<ConsensusMessage>> {
+    let dir = pool_dir(index)?;
+    if !dir.is_dir() {
+        bail!("node {} has no consensus pool at {:?}", index, dir);
+    }
+    let conf = LMDBConfig {
+        persistent_pool_validated_persistent_db_path: dir,
+    };
+
+    Ok(PersistentHeightIndexedPool::new_consensus_pool(
+        conf,
+        true,
+        no_op_logger(),
+    ))
+}
+
+pub(crate) fn open_certification(
+    index: NodeIndex,
+) -> Result<PersistentHeightIndexedPool<CertificationMessage>> {
+    let dir = pool_dir(index)?;
+    if !dir.is_dir() {
+        bail!("node {} has no consensus pool at {:?}", index, dir);
+    }
+    let conf = LMDBConfig {
+        persistent_pool_validated_persistent_db_path: dir,
+    };
+
+    Ok(PersistentHeightIndexedPool::new_certification_pool(
+        conf,
+        true,
+        no_op_logger(),
+    ))
+}
+
+// The highest certification in the pool of a node: its height and the hash of
+// the certified state.
+#[cfg(feature = "run")]
+pub(crate) fn certified_state(index: NodeIndex) -> Result<Option<(u64, Vec<u8>)>> {
+    let pool = open_certification(index)?;
+    Ok(pool
+        .certifications()
+        .get_highest()
+        .ok()
+        .map(|certification| {
+            (
+                certification.height.get(),
+                certification.signed.content.hash.get().0,
+            )
+        }))
+}
+
+#[derive(Default)]
+struct SectionStats {
+    count: usize,
+    bytes: usize,
+    min_height: Option<u64>,
+    max_height: Option<u64>,
+}
+
+fn section<T: ConsensusMessageHashable + HasHeight>(
+    pool: &dyn HeightIndexedPool<T>,
+    per_height: &mut BTreeMap<u64, usize>,
+) -> SectionStats {
+    let mut stats = SectionStats::default();
+    for artifact in pool.get_all() {
+        let height = artifact.height().get();
+        stats.count += 1;
+        stats.bytes += pb::ConsensusMessage::from(artifact.into_message()).encoded_len();
+        stats.min_height = Some(stats.min_height.map_or(height, |min| min.min(height)));
+        stats.max_height = Some(stats.max_height.map_or(height, |max| max.max(height)));
+        *per_height.entry(height).or_default() += 1;
+    }
+    stats
+}
+
+// Raw LMDB environment of the validated consensus pool, for checks below the
+// typed pool API. Read-only transactions still take a slot in the lock file,
+// so a live replica keeps the pages they read instead of reusing them.
+fn open_env(index: NodeIndex, read_only: bool) -> Result<Environment> {
+    let flags = if read_only {
+        EnvironmentFlags::READ_ONLY
+    } else {
+        EnvironmentFlags::empty()
+    };
+    Ok(Environment::new()
+        .set_flags(flags)
+        .set_max_dbs(64)
+        .open(&pool_dir(index)?.join("consensus"))?)
+}
+
+// Pages of the LMDB file that hold no data of any database: the free list
+// plus space never handed out since the file grew.
+fn free_pages(index: NodeIndex) -> Result<(usize, usize)> {
+    let env = open_env(index, true)?;
+
+    let mut info = lmdb_sys::MDB_envinfo {
+        me_mapaddr: std::ptr::null_mut(),
+        me_mapsize: 0,
+        me_last_pgno: 0,
+        me_last_txnid: 0,
+        me_maxreaders: 0,
+        me_numreaders: 0,
+    };
+    // SAFETY: env is a valid open environment
+    if unsafe { lmdb_sys::mdb_env_info(env.env(), &mut info) } != 0 {
+        bail!("failed to read the LMDB environment info");
+    }
+    let page_size = env.stat()?.page_size() as usize;
+
+    // named databases are keys of the main database
+    let main = env.open_db(None)?;
+    let mut names = vec![];
+    {
+        let txn = env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(main)?;
+        for item in cursor.iter_start() {
+            let (name, _) = item?;
+            names.push(String::from_utf8_lossy(name).to_string());
+        }
+    }
+    let mut dbs = vec![main];
+    for name in &names {
+        dbs.push(env.open_db(Some(name))?);
+    }
+
+    let txn = env.begin_ro_txn()?;
+    let mut used = 0;
+    for db in dbs {
+        let mut stat = lmdb_sys::MDB_stat {
+            ms_psize: 0,
+            ms_depth: 0,
+            ms_branch_pages: 0,
+            ms_leaf_pages: 0,
+            ms_overflow_pages: 0,
+            ms_entries: 0,
+        };
+        // SAFETY: txn and db belong to env
+        if unsafe { lmdb_sys::mdb_stat(txn.txn(), db.dbi(), &mut stat) } != 0 {
+            bail!("failed to read the stats of an LMDB database");
+        }
+        used += stat.ms_branch_pages + stat.ms_leaf_pages + stat.ms_overflow_pages;
+    }
+
+    // the first two pages are the meta pages
+    let total = info.me_last_pgno + 1;
+    Ok((total.saturating_sub(used + 2), page_size))
+}
+
+fn stats(index: NodeIndex, top: usize) -> Result<()> {
+    let pool = open(index)?;
+    let mut per_height = BTreeMap::new();
+    let sections = [
+        (
+            "random beacon",
+            section(pool.random_beacon(), &mut per_height),
+        ),
+        (
+            "random beacon share",
+            section(pool.random_beacon_share(), &mut per_height),
+        ),
+        (
+            "block proposal",
+            section(pool.block_proposal(), &mut per_height),
+        ),
+        (
+            "notarization",
+            section(pool.notarization(), &mut per_height),
+        ),
+        (
+            "notarization share",
+            section(pool.notarization_share(), &mut per_height),
+        ),
+        (
+            "finalization",
+            section(pool.finalization(), &mut per_height),
+        ),
+        (
+            "finalization share",
+            section(pool.finalization_share(), &mut per_height),
+        ),
+        ("random tape", section(pool.random_tape(), &mut per_height)),
+        (
+            "random tape share",
+            section(pool.random_tape_share(), &mut per_height),
+        ),
+        (
+            "catch-up package",
+            section(pool.catch_up_package(), &mut per_height),
+        ),
+        (
+            "catch-up package share",
+            section(pool.catch_up_package_share(), &mut per_height),
+        ),
+    ];
+
+    println!(
+        "{:<24} {:>10} {:>14} {:>21}",
+        "artifact", "count", "bytes", "heights"
+    );
+    for (name, stats) in &sections {
+        let heights = match (stats.min_height, stats.max_height) {
+            (Some(min), Some(max)) => format!("{}..={}", min, max),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:<24} {:>10} {:>14} {:>21}",
+            name, stats.count, stats.bytes, heights
+        );
+    }
+    let count: usize = sections.iter().map(|(_, stats)| stats.count).sum();
+    let bytes: usize = sections.iter().map(|(_, stats)| stats.bytes).sum();
+    println!("{:<24} {:>10} {:>14}", "total", count, bytes);
+
+    if !per_height.is_empty() {
+        let mut counts: Vec<usize> = per_height.values().copied().collect();
+        counts.sort_unstable();
+        println!(
+            "\n{} heights, artifacts per height min {} median {} max {}",
+            counts.len(),
+            counts[0],
+            counts[counts.len() / 2],
+            counts[counts.len() - 1]
+        );
+        let mut heaviest: Vec<(&u64, &usize)> = per_height.iter().collect();
+        heaviest.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (height, count) in heaviest.into_iter().take(top) {
+            println!("  height {} {} artifacts", height, count);
+        }
+    }
+
+    let (free, page_size) =
+        free_pages(index).map_err(|e| anyhow!("failed to inspect the LMDB file: {}", e))?;
+    println!(
+        "\nLMDB free pages {} ({} bytes of {} byte pages)",
+        free,
+        free * page_size,
+        page_size
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "run")]
+fn block_json(proposal: &BlockProposal) -> Result<serde_json::Value> {
+    let block = proposal.as_ref();
+    let mut json = json!({
+        "height": block.height.get(),
+        "hash": proposal.content.get_hash().get_ref().to_string(),
+        "rank": block.rank.0,
+        "proposer": proposal.signature.signer.to_string(),
+        "time_ns": block.context.time.as_nanos_since_unix_epoch(),
+        "registry_version": block.context.registry_version.get(),
+        "certified_height": block.context.certified_height.get(),
+    });
+    if block.payload.is_summary() {
+        json["summary"] = json!(true);
+        return Ok(json);
+    }
+
+    let batch = &block.payload.as_ref().as_data().batch;
+    let mut ingress = vec![];
+    for i in 0..batch.ingress.message_count() {
+        let (id, message) = batch
+            .ingress
+            .get(i)
+            .map_err(|e| anyhow!("undecodable ingress message {}: {:?}", i, e))?;
+        let content = message.content();
+        ingress.push(json!({
+            "message_id": id.message_id.to_string(),
+            "sender": content.sender().to_string(),
+            "canister_id": content.canister_id().to_string(),
+            "method_name": content.method_name(),
+            "arg_bytes": content.arg().len(),
+        }));
+    }
+    json["ingress"] = json!(ingress);
+
+    let mut xnet = vec![];
+    for (subnet_id, slice) in &batch.xnet.stream_slices {
+        let (_, decoded) = decode_stream_slice(&slice.payload).map_err(|e| anyhow!("{:?}", e))?;
+        let header = decoded.header();
+        xnet.push(json!({
+            "from_subnet": subnet_id.to_string(),
+            "begin": header.begin().get(),
+            "end": header.end().get(),
+            "signals_end": header.signals_end().get(),
+            "messages": decoded.messages().map_or(0, |messages| messages.len()),
+            "bytes": slice.payload.len(),
+        }));
+    }
+    json["xnet"] = json!(xnet);
+    json["self_validating"] = json!({
+        "bitcoin_responses": batch.self_validating.get().len(),
+    });
+
+    Ok(json)
+}
+
+#[cfg(feature = "run")]
+fn blocks(index: NodeIndex, from: Option<u64>, to: Option<u64>) -> Result<()> {
+    let pool = open(index)?;
+    let finalized: BTreeMap<u64, String> = pool
+        .finalization()
+        .get_all()
+        .map(|finalization| {
+            (
+                finalization.height().get(),
+                finalization.content.block.get_ref().to_string(),
+            )
+        })
+        .collect();
+
+    let mut proposals: Vec<BlockProposal> = pool
+        .block_proposal()
+        .get_all()
+        .filter(|proposal| {
+            let height = proposal.height().get();
+            from.map_or(true, |from| height >= from)
+                && to.map_or(true, |to| height <= to)
+                && finalized.get(&height)
+                    == Some(&proposal.content.get_hash().get_ref().to_string())
+        })
+        .collect();
+    proposals.sort_by_key(|proposal| proposal.height());
+    for proposal in &proposals {
+        println!("{}", block_json(proposal)?);
+    }
+
+    Ok(())
+}
+
+fn find_ingress(index: NodeIndex, message_id: &str) -> Result<()> {
+    let message_id = message_id.trim_start_matches("0x").to_lowercase();
+    let pool = open(index)?;
+    let finalized: BTreeMap<u64, String> = pool
+        .finalization()
+        .get_all()
+        .map(|finalization| {
+            (
+                finalization.height().get(),
+                finalization.content.block.get_ref().to_string(),
+            )
+        })
+        .collect();
+
+    let mut found = false;
+    for proposal in pool.block_proposal().get_all() {
+        let block = proposal.as_ref();
+        if block.payload.is_summary() {
+            continue;
+        }
+        let ingress = &block.payload.as_ref().as_data().batch.ingress;
+        if !ingress
+            .message_ids()
+            .into_iter()
+            .any(|id| id.message_id.to_string() == message_id)
+        {
+            continue;
+        }
+
+        found = true;
+        let height = proposal.height().get();
+        let hash = proposal.content.get_hash().get_ref().to_string();
+        let status = if finalized.get(&height) == Some(&hash) {
+            "finalized"
+        } else {
+            "not finalized"
+        };
+        println!(
+            "height {} block {} by {} ({})",
+            height, hash, proposal.signature.signer, status
+        );
+    }
+
+    if !found {
+        let range = pool.block_proposal().height_range();
+        bail!(
+            "message {} is in no block of node {} at heights {}, older heights are purged",
+            message_id,
+            index,
+            range.map_or("-".to_string(), |range| format!(
+                "{}..={}",
+                range.min, range.max
+            ))
+        );
+    }
+
+    Ok(())
+}
+
+fn certification(nodes: Vec<NodeIndex>, max_lag: u64) -> Result<()> {
+    let topology = topology::load()?;
+    let mut lagging = vec![];
+    for subnet in &topology.subnets {
+        for node in subnet
+            .nodes
+            .iter()
+            .filter(|node| nodes.is_empty() || nodes.contains(&node.index))
+        {
+            let finalized = open(node.index)?
+                .finalization()
+                .max_height()
+                .map_or(0, |height| height.get());
+            let pool = open_certification(node.index)?;
+            let certified = pool
+                .certifications()
+                .max_height()
+                .map_or(0, |height| height.get());
+            let shared = pool
+                .certification_shares()
+                .max_height()
+                .map_or(0, |height| height.get());
+
+            let lag = finalized.saturating_sub(certified);
+            let flag = if lag > max_lag {
+                lagging.push(node.index);
+                " lagging"
+            } else {
+                ""
+            };
+            println!(
+                "subnet {} node {} finalized {} certified {} (lag {}) highest share {}{}",
+                subnet.index, node.index, finalized, certified, lag, shared, flag
+            );
+        }
+    }
+
+    if !lagging.is_empty() {
+        bail!(
+            "certification of nodes {:?} lags finalization by more than {} heights",
+            lagging,
+            max_lag
+        );
+    }
+
+    Ok(())
+}
+
+// The pool keeps encoded artifacts in ARTIFACTS, keyed by id, and one
+// height indexed database of ids per artifact type next to META.
+const ARTIFACTS_DB: &str = "ARTIFACTS";
+const META_DB: &str = "META";
+
+fn decode_artifact(bytes: &[u8]) -> Result<ConsensusMessage> {
+    let artifact = pb::ValidatedConsensusArtifact::decode(bytes)?;
+    let msg = artifact.msg.ok_or(anyhow!("artifact without message"))?;
+    Ok(ConsensusMessage::try_from(msg)?)
+}
+
+fn fsck(index: NodeIndex, repair: bool) -> Result<()> {
+    if repair && backend::is_running(index)? {
+        bail!("node {} is running, stop it to repair its pool", index);
+    }
+    let env = open_env(index, !repair)?;
+
+    let main = env.open_db(None)?;
+    let mut indices = vec![];
+    {
+        let txn = env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(main)?;
+        for item in cursor.iter_start() {
+            let name = String::from_utf8_lossy(item?.0).to_string();
+            if name != ARTIFACTS_DB && name != META_DB {
+                indices.push(name);
+            }
+        }
+    }
+    let artifacts = env.open_db(Some(ARTIFACTS_DB))?;
+    // databases are opened up front, opening takes a transaction of its own
+    let mut index_dbs = BTreeMap::new();
+    for name in indices {
+        let db = env.open_db(Some(&name))?;
+        index_dbs.insert(name, db);
+    }
+
+    let mut checked = 0;
+    let mut corrupt: Vec<Vec<u8>> = vec![];
+    let mut dangling: Vec<(String, Vec<u8>, Vec<u8>)> = vec![];
+    {
+        let txn = env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(artifacts)?;
+        for item in cursor.iter_start() {
+            let (key, value) = item?;
+            checked += 1;
+            if let Err(e) = decode_artifact(value) {
+                println!("corrupt artifact {}: {}", hex::encode(key), e);
+                corrupt.push(key.to_vec());
+            }
+        }
+
+        for (name, db) in &index_dbs {
+            let mut cursor = txn.open_ro_cursor(*db)?;
+            for item in cursor.iter_start() {
+                let (height, id) = item?;
+                let missing = match txn.get(artifacts, &id) {
+                    Ok(_) => false,
+                    Err(lmdb::Error::NotFound) => true,
+                    Err(e) => return Err(e.into()),
+                };
+                if missing || corrupt.iter().any(|key| *key == id) {
+                    if missing {
+                        println!(
+                            "index {} entry {} points to missing artifact {}",
+                            name,
+                            hex::encode(height),
+                            hex::encode(id)
+                        );
+                    }
+                    dangling.push((name.clone(), height.to_vec(), id.to_vec()));
+                }
+            }
+        }
+    }
+    println!(
+        "checked {} artifacts of node {}: {} corrupt, {} index entries to drop",
+        checked,
+        index,
+        corrupt.len(),
+        dangling.len()
+    );
+
+    if corrupt.is_empty() && dangling.is_empty() {
+        return Ok(());
+    }
+    if !repair {
+        bail!("the pool of node {} is damaged, rerun with --repair", index);
+    }
+
+    let mut txn = env.begin_rw_txn()?;
+    for key in &corrupt {
+        txn.del(artifacts, key, None)?;
+    }
+    for (name, height, id) in &dangling {
+        txn.del(index_dbs[name], height, Some(id.as_slice()))?;
+    }
+    txn.commit()?;
+    println!(
+        "dropped {} artifacts and {} index entries",
+        corrupt.len(),
+        dangling.len()
+    );
+
+    Ok(())
+}
+
+// Sections of the artifact pool with an LMDB environment each.
+const POOL_SECTIONS: [&str; 2] = ["consensus", "certification"];
+
+// mdb_env_copy2 writes a consistent, compacted snapshot even while the
+// source replica keeps writing.
+fn copy_env(source: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    let env = Environment::new()
+        .set_flags(EnvironmentFlags::READ_ONLY)
+        .set_max_dbs(64)
+        .open(source)?;
+    #[cfg(unix)]
+    let path = CString::new(target.as_os_str().as_bytes())?;
+    #[cfg(not(unix))]
+    let path = CString::new(
+        target
+            .to_str()
+            .ok_or(anyhow!("{:?} is no UTF-8 path", target))?,
+    )?;
+    // SAFETY: env is a valid open environment, path a NUL terminated string
+    let rc = unsafe { lmdb_sys::mdb_env_copy2(env.env(), path.as_ptr(), lmdb_sys::MDB_CP_COMPACT) };
+    if rc != 0 {
+        bail!(
+            "failed to copy {:?} to {:?}: {}",
+            source,
+            target,
+            lmdb::Error::from_err_code(rc)
+        );
+    }
+
+    Ok(())
+}
+
+// Bytes of the consensus pool file, which LMDB never shrinks by itself.
+pub(crate) fn size(index: NodeIndex) -> Result<u64> {
+    Ok(fs::metadata(pool_dir(index)?.join("consensus").join("data.mdb"))?.len())
+}
+
+// Rewrites the consensus pool of a stopped node without its free pages.
+pub(crate) fn compact(index: NodeIndex) -> Result<()> {
+    if backend::is_running(index)? {
+        bail!("node {} is running, stop it first", index);
+    }
+    let dir = pool_dir(index)?.join("consensus");
+    let compacted = pool_dir(index)?.join("consensus.compact");
+    if compacted.exists() {
+        fs::remove_dir_all(&compacted)?;
+    }
+    copy_env(&dir, &compacted)?;
+    fs::remove_dir_all(&dir)?;
+    fs::rename(&compacted, &dir)?;
+
+    Ok(())
+}
+
+fn clone(from: NodeIndex, to: NodeIndex, force: bool) -> Result<()> {
+    let topology = topology::load()?;
+    topology.node(from)?;
+    topology.node(to)?;
+    if from == to {
+        bail!("cannot clone the pool of node {} onto itself", from);
+    }
+    let subnet_index = |node| topology.subnet_of(node).map(|subnet| subnet.index);
+    if subnet_index(from) != subnet_index(to) {
+        bail!("nodes {} and {} are not in the same subnet", from, to);
+    }
+    if backend::is_running(to)? {
+        bail!("node {} is running, stop it first", to);
+    }
+
+    let source = pool_dir(from)?;
+    process::seed_state_dir(to)?;
+    let target = pool_dir(to)?;
+    for section in POOL_SECTIONS {
+        let source = source.join(section);
+        if !source.join("data.mdb").is_file() {
+            continue;
+        }
+        let target = target.join(section);
+        if target.join("data.mdb").is_file() {
+            if !force {
+                bail!(
+                    "node {} already has a {} pool, pass --force to replace it",
+                    to,
+                    section
+                );
+            }
+            fs::remove_dir_all(&target)?;
+        }
+        copy_env(&source, &target)?;
+        println!(
+            "copied the {} pool of node {} to node {}",
+            section, from, to
+        );
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: PoolCommand) -> Result<()> {
+    match cmd {
+        PoolCommand::Stats { node, top } => stats(node, top),
+        PoolCommand::FindIngress { node, message_id } => find_ingress(node, &message_id),
+        PoolCommand::Fsck { node, repair } => fsck(node, repair),
+        PoolCommand::Clone { from, to, force } => clone(from, to, force),
+        #[cfg(feature = "run")]
+        PoolCommand::Blocks { node, from, to } => blocks(node, from, to),
+        PoolCommand::Certification { node, max_lag } => certification(node, max_lag),
+    }
+}
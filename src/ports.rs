@@ -0,0 +1,128 @@
+use crate::process;
+use crate::topology::Topology;
+use anyhow::{bail, Result};
+use ic_prep_lib::node::NodeIndex;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener};
+
+// TCP state of listening sockets in /proc/net/tcp.
+const TCP_LISTEN: &str = "0A";
+
+// Ports a replica binds to, as configured by `init`.
+fn node_ports(index: NodeIndex) -> Result<Vec<(&'static str, SocketAddr)>> {
+    let config = process::replica_config(index)?;
+    let http = process::listen_addr(index)?;
+    let mut ports = vec![
+        ("http", http),
+        ("xnet", SocketAddr::new(http.ip(), http.port() + 1)),
+    ];
+    let exporter = &config["metrics"]["exporter"];
+    if let Some(metrics) = exporter["http"].as_str().or(exporter["Http"].as_str()) {
+        ports.push(("metrics", metrics.parse()?));
+    }
+    Ok(ports)
+}
+
+// Inodes of the sockets listening on a port, on any address.
+fn listening_inodes(port: u16) -> Vec<String> {
+    let mut inodes = vec![];
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(table) = fs::read_to_string(table) else {
+            continue;
+        };
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[3] != TCP_LISTEN {
+                continue;
+            }
+            let local_port = fields[1]
+                .rsplit_once(':')
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if local_port == Some(port) {
+                inodes.push(fields[9].to_string());
+            }
+        }
+    }
+    inodes
+}
+
+// The process holding a port, when /proc lets us look at its descriptors.
+fn owner(port: u16) -> Option<(i32, String)> {
+    let sockets: Vec<String> = listening_inodes(port)
+        .iter()
+        .map(|inode| format!("socket:[{}]", inode))
+        .collect();
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<i32>() else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let holds = fds.flatten().any(|fd| {
+            fs::read_link(fd.path()).map_or(false, |link| {
+                sockets.contains(&link.to_string_lossy().to_string())
+            })
+        });
+        if holds {
+            let name = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            return Some((pid, name.trim().to_string()));
+        }
+    }
+    None
+}
+
+// Binds every port of the nodes about to start once, so that a port taken by
+// another process is reported up front rather than as a bind error in the
+// replica log.
+pub(crate) fn check(topology: &Topology, nodes: &[NodeIndex]) -> Result<()> {
+    let mut conflicts = vec![];
+    let mut claimed: Vec<(NodeIndex, SocketAddr)> = vec![];
+    for node in nodes {
+        let ports = match node_ports(*node) {
+            Ok(ports) => ports,
+            // no replica config, the start reports it
+            Err(_) => continue,
+        };
+        for (name, addr) in ports {
+            // e.g. two nodes on 127.0.0.1 sharing the metrics port
+            if let Some((other, _)) = claimed.iter().find(|(_, claimed)| *claimed == addr) {
+                conflicts.push(format!(
+                    "  node {} {} {}: also used by node {}",
+                    node, name, addr, other
+                ));
+                continue;
+            }
+            claimed.push((*node, addr));
+            let error = match TcpListener::bind(addr) {
+                Ok(_) => continue,
+                Err(e) => e,
+            };
+            let reason = match error.kind() {
+                ErrorKind::AddrInUse => match owner(addr.port()) {
+                    Some((pid, command)) => {
+                        let holder = topology
+                            .nodes()
+                            .find(|other| process::replica_pid(other.index) == Some(pid))
+                            .map(|other| format!(", the replica of node {}", other.index))
+                            .unwrap_or_default();
+                        format!("in use by pid {} ({}{})", pid, command, holder)
+                    }
+                    None => "in use by a process of another user".to_string(),
+                },
+                ErrorKind::AddrNotAvailable => "not an address of this host".to_string(),
+                _ => error.to_string(),
+            };
+            conflicts.push(format!("  node {} {} {}: {}", node, name, addr, reason));
+        }
+    }
+
+    if !conflicts.is_empty() {
+        bail!(
+            "replicas cannot bind their ports:\n{}",
+            conflicts.join("\n")
+        );
+    }
+    Ok(())
+}
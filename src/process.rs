@@ -0,0 +1,491 @@
+use crate::chaos::clock_file;
+use crate::config::TestnetConfig;
+use crate::init::{node_state_dir, output_dir, proxied_transport_port};
+use crate::limits;
+use crate::schema;
+use crate::secrets;
+use crate::tools::ic_binary;
+use anyhow::{anyhow, bail, Context, Result};
+use ic_prep_lib::node::NodeIndex;
+#[cfg(unix)]
+use nix::sys::signal::kill;
+#[cfg(unix)]
+pub(crate) use nix::sys::signal::Signal;
+#[cfg(unix)]
+use nix::unistd::Pid;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The signals ic-testnet sends, which only unix delivers.
+#[cfg(not(unix))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Signal {
+    SIGTERM,
+    SIGKILL,
+}
+
+#[cfg(unix)]
+fn send_signal(pid: i32, signal: Signal) -> Result<()> {
+    kill(Pid::from_raw(pid), signal)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_signal(pid: i32, signal: Signal) -> Result<()> {
+    bail!(
+        "cannot send {:?} to pid {}, signals are not supported on {}",
+        signal,
+        pid,
+        env::consts::OS
+    )
+}
+
+// Keeps a child out of the terminal's process group so that Ctrl-C reaches
+// the supervisor only, which then shuts it down in order.
+fn detached(program: &Path) -> Command {
+    let mut command = Command::new(program);
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+}
+
+pub(crate) fn replica_version() -> String {
+    env::var("REPLICA_VERSION").unwrap_or("0.9.0".to_string())
+}
+
+fn libfaketime() -> String {
+    env::var("LIBFAKETIME")
+        .unwrap_or("/usr/lib/x86_64-linux-gnu/faketime/libfaketime.so.1".to_string())
+}
+
+pub(crate) fn config_file(index: NodeIndex) -> String {
+    format!("ic-{}.json5", index)
+}
+
+pub(crate) fn bitcoin_adapter_config_file(index: NodeIndex) -> String {
+    format!("bitcoin-adapter-{}.json", index)
+}
+
+pub(crate) fn pid_file(index: NodeIndex) -> Result<PathBuf> {
+    Ok(output_dir()?
+        .join("pids")
+        .join(format!("node-{}.pid", index)))
+}
+
+// Written by `subnet rolling-restart` with its pid while it has the replica of
+// a node down, so that a supervising `run` neither gives up on the node nor
+// starts a second replica on its state.
+fn restarting_file(index: NodeIndex) -> Result<PathBuf> {
+    Ok(output_dir()?
+        .join("pids")
+        .join(format!("node-{}.restarting", index)))
+}
+
+// Removes the restarting marker of a node when dropped.
+pub(crate) struct Restarting(PathBuf);
+
+pub(crate) fn mark_restarting(index: NodeIndex) -> Result<Restarting> {
+    let path = restarting_file(index)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, std::process::id().to_string())?;
+    Ok(Restarting(path))
+}
+
+impl Drop for Restarting {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Markers left behind by a rolling restart that died do not count.
+pub(crate) fn is_restarting(index: NodeIndex) -> bool {
+    restarting_file(index)
+        .ok()
+        .and_then(|file| fs::read_to_string(file).ok())
+        .and_then(|pid| pid.trim().parse::<i32>().ok())
+        .map_or(false, |pid| Path::new(&format!("/proc/{}", pid)).exists())
+}
+
+fn is_replica_of(pid: i32, index: NodeIndex) -> bool {
+    let config = config_file(index);
+    let suffix = format!("/{}", config);
+    let Ok(cmdline) = fs::read_to_string(format!("/proc/{}/cmdline", pid)) else {
+        return false;
+    };
+    let args: Vec<&str> = cmdline.split('\0').collect();
+
+    args.first().map_or(false, |arg| arg.ends_with("replica"))
+        && args
+            .iter()
+            .any(|arg| *arg == config || arg.ends_with(&suffix))
+}
+
+// Replicas are looked up by their pid file and otherwise by their config file
+// argument, so processes started by the Makefile or by hand are found as well.
+pub(crate) fn replica_pid(index: NodeIndex) -> Option<i32> {
+    let recorded = pid_file(index)
+        .ok()
+        .and_then(|file| fs::read_to_string(file).ok())
+        .and_then(|pid| pid.trim().parse().ok())
+        .filter(|pid| is_replica_of(*pid, index));
+    if recorded.is_some() {
+        return recorded;
+    }
+
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .find(|pid| is_replica_of(*pid, index))
+}
+
+// The bitcoin adapter of a node is found by its config file argument.
+fn bitcoin_adapter_pid(index: NodeIndex) -> Option<i32> {
+    let suffix = format!("/{}", bitcoin_adapter_config_file(index));
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .find(|pid: &i32| {
+            let Ok(cmdline) = fs::read_to_string(format!("/proc/{}/cmdline", pid)) else {
+                return false;
+            };
+            let args: Vec<&str> = cmdline.split('\0').collect();
+            args.first()
+                .map_or(false, |arg| arg.ends_with("ic-btc-adapter"))
+                && args.iter().any(|arg| arg.ends_with(&suffix))
+        })
+}
+
+// Nodes of a subnet with a bitcoind reach it through an adapter, which keeps
+// running while the replica restarts and stops with the node.
+fn start_bitcoin_adapter(index: NodeIndex) -> Result<()> {
+    let config = output_dir()?.join(bitcoin_adapter_config_file(index));
+    if !config.is_file() || bitcoin_adapter_pid(index).is_some() {
+        return Ok(());
+    }
+
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file(index)?.with_extension("btc-adapter.log"))?;
+    let adapter = ic_binary("ic-btc-adapter");
+    detached(&adapter)
+        .arg(&config)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .with_context(|| format!("failed to start {:?} for node {}", adapter, index))?;
+    Ok(())
+}
+
+fn stop_bitcoin_adapter(index: NodeIndex) -> Result<()> {
+    if let Some(pid) = bitcoin_adapter_pid(index) {
+        send_signal(pid, Signal::SIGTERM)?;
+    }
+    Ok(())
+}
+
+// CPU time in clock ticks and resident memory in KiB of a process.
+pub(crate) fn resource_usage(pid: i32) -> Option<(u64, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name may contain spaces, fields are counted after it
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let rss = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap_or(0);
+
+    Some((utime + stime, rss))
+}
+
+pub(crate) fn log_file(index: NodeIndex) -> Result<PathBuf> {
+    Ok(env::current_dir()?
+        .join("logs")
+        .join(format!("node-{}.log", index)))
+}
+
+// Processes a replica with canister sandboxing spawns, looked up next to the
+// replica binary.
+const SANDBOX_BINARIES: [&str; 3] = ["canister_sandbox", "sandbox_launcher", "compiler_sandbox"];
+
+pub(crate) fn replica_config(index: NodeIndex) -> Result<serde_json::Value> {
+    let json5 = fs::read_to_string(output_dir()?.join(config_file(index)))?;
+    // skip the schema comment, the config itself is plain JSON
+    let json: String = json5
+        .lines()
+        .filter(|line| !line.starts_with("//"))
+        .collect();
+    Ok(serde_json::from_str(&json)?)
+}
+
+pub(crate) fn write_replica_config(index: NodeIndex, config: &serde_json::Value) -> Result<()> {
+    let path = output_dir()?.join(config_file(index));
+    fs::write(
+        &path,
+        format!(
+            "{}{}",
+            schema::json5_header(),
+            serde_json::to_string(config)?
+        ),
+    )
+    .with_context(|| format!("failed to write {:?}", path))
+}
+
+// Address the HTTP handler of a node binds to.
+pub(crate) fn listen_addr(index: NodeIndex) -> Result<SocketAddr> {
+    let config = replica_config(index)?;
+    let addr = config["http_handler"]["listen_addr"]
+        .as_str()
+        .ok_or(anyhow!(
+            "the config of node {} has no listen address",
+            index
+        ))?;
+    Ok(addr.parse()?)
+}
+
+// Address a replica initialized with --transport-proxy binds its transport
+// to, peers reach it through the proxy of `run`.
+pub(crate) fn proxied_transport(index: NodeIndex) -> Result<Option<SocketAddr>> {
+    let config = replica_config(index)?;
+    let transport = &config["transport"];
+    let port = proxied_transport_port()?;
+    if transport["listening_port"].as_u64() != Some(port as u64) {
+        return Ok(None);
+    }
+    let ip: IpAddr = transport["node_ip"]
+        .as_str()
+        .unwrap_or_default()
+        .parse()
+        .with_context(|| format!("invalid transport address of node {}", index))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn sandboxing_enabled(index: NodeIndex) -> Result<bool> {
+    let config = replica_config(index)?;
+    Ok(config["hypervisor"]["canister_sandboxing_flag"] == "enabled")
+}
+
+// Copies missing sandbox binaries from IC_BIN_DIR next to the replica.
+fn ship_sandbox_binaries(replica: &Path) -> Result<()> {
+    let replica_dir = replica
+        .parent()
+        .ok_or(anyhow!("{:?} has no parent directory", replica))?;
+    for name in SANDBOX_BINARIES {
+        let target = replica_dir.join(name);
+        if target.is_file() {
+            continue;
+        }
+        let source = ic_binary(name);
+        if !source.is_file() {
+            bail!(
+                "canister sandboxing needs {}, build it with `cd ic && cargo build --bin {}`",
+                name,
+                name
+            );
+        }
+        fs::copy(&source, &target)?;
+    }
+
+    Ok(())
+}
+
+// Nodes start from a copy of the initial state written by `init`.
+pub(crate) fn seed_state_dir(index: NodeIndex) -> Result<PathBuf> {
+    let state_dir = node_state_dir(index)?;
+    if !state_dir.is_dir() {
+        let status = Command::new("cp")
+            .arg("-rf")
+            .arg(output_dir()?.join("state"))
+            .arg(&state_dir)
+            .status()?;
+        if !status.success() {
+            bail!("failed to create {:?}", state_dir);
+        }
+    }
+
+    Ok(state_dir)
+}
+
+pub(crate) fn start_node(index: NodeIndex) -> Result<Child> {
+    start_node_with(index, &ic_binary("replica"), &replica_version())
+}
+
+pub(crate) fn start_node_with(index: NodeIndex, replica: &Path, version: &str) -> Result<Child> {
+    if let Some(pid) = replica_pid(index) {
+        bail!("node {} is already running as pid {}", index, pid);
+    }
+
+    seed_state_dir(index)?;
+    if secrets::is_sealed(&secrets::node_crypto_dir(index)?) {
+        bail!(
+            "the keys of node {} are sealed, start it with `ic-testnet run` or unseal them with `ic-testnet secrets unseal`",
+            index
+        );
+    }
+
+    if sandboxing_enabled(index)? {
+        ship_sandbox_binaries(replica)?;
+    }
+
+    let log_file = log_file(index)?;
+    if let Some(dir) = log_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    start_bitcoin_adapter(index)?;
+
+    let settings = TestnetConfig::saved()?.node(index);
+    let mut command = detached(replica);
+    command
+        .arg("--replica-version")
+        .arg(version)
+        .arg("--config-file")
+        .arg(output_dir()?.join(config_file(index)))
+        .args(&settings.args)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    let clock = clock_file(index)?;
+    if clock.is_file() {
+        command
+            .env("LD_PRELOAD", libfaketime())
+            .env("FAKETIME_TIMESTAMP_FILE", clock)
+            .env("FAKETIME_NO_CACHE", "1");
+    }
+    command.envs(&settings.env);
+    if let Some(cgroup) = limits::prepare(&settings)
+        .with_context(|| format!("failed to limit the resources of node {}", index))?
+    {
+        limits::join_on_exec(&mut command, &cgroup)?;
+    }
+
+    let child = command
+        .spawn()
+        .with_context(|| format!("failed to start node {}", index))?;
+
+    let pid_file = pid_file(index)?;
+    if let Some(dir) = pid_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(pid_file, child.id().to_string())?;
+
+    Ok(child)
+}
+
+pub(crate) fn signal_node(index: NodeIndex, signal: Signal) -> Result<()> {
+    let pid = replica_pid(index).ok_or(anyhow!("node {} is not running", index))?;
+    send_signal(pid, signal)
+}
+
+fn state_root(index: NodeIndex) -> io::Result<PathBuf> {
+    Ok(node_state_dir(index)?
+        .join(format!("node-{}", index))
+        .join("state"))
+}
+
+// Deletes the replicated state and the consensus pool of a stopped node, e.g.
+// before it joins another subnet, and keeps its keys.
+pub(crate) fn reset_replicated_state(index: NodeIndex) -> Result<()> {
+    let node_dir = node_state_dir(index)?.join(format!("node-{}", index));
+    for dir in [node_dir.join("state"), node_dir.join("ic_consensus_pool")] {
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir).with_context(|| format!("failed to remove {:?}", dir))?;
+        }
+    }
+    Ok(())
+}
+
+// Checkpoints are written to `fs_tmp` first and renamed into `checkpoints`
+// once complete.
+fn checkpoint_in_progress(index: NodeIndex) -> bool {
+    state_root(index)
+        .and_then(|root| fs::read_dir(root.join("fs_tmp")))
+        .map_or(false, |mut entries| entries.next().is_some())
+}
+
+// Heights of the checkpoints of a node, oldest first.
+pub(crate) fn checkpoints(index: NodeIndex) -> Vec<u64> {
+    let Ok(entries) = state_root(index).and_then(|root| fs::read_dir(root.join("checkpoints")))
+    else {
+        return vec![];
+    };
+    let mut heights: Vec<u64> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| u64::from_str_radix(entry.file_name().to_str()?, 16).ok())
+        .collect();
+    heights.sort_unstable();
+    heights
+}
+
+pub(crate) fn latest_checkpoint(index: NodeIndex) -> Option<u64> {
+    checkpoints(index).last().copied()
+}
+
+pub(crate) fn checkpoint_dir(index: NodeIndex, height: u64) -> Result<PathBuf> {
+    Ok(state_root(index)?
+        .join("checkpoints")
+        .join(format!("{:016x}", height)))
+}
+
+// Deletes the checkpoints of a node but the newest `keep`, unless one is being
+// written. Returns the heights of the deleted checkpoints.
+pub(crate) fn prune_checkpoints(index: NodeIndex, keep: usize) -> Result<Vec<u64>> {
+    if checkpoint_in_progress(index) {
+        return Ok(vec![]);
+    }
+    let heights = checkpoints(index);
+    let prune = &heights[..heights.len().saturating_sub(keep)];
+    for height in prune {
+        fs::remove_dir_all(checkpoint_dir(index, *height)?)?;
+    }
+
+    Ok(prune.to_vec())
+}
+
+// Asks the replica to terminate, giving it until the timeout to finish a
+// checkpoint that is being flushed, and kills it otherwise. Returns whether the
+// replica exited on its own.
+pub(crate) fn stop_node(index: NodeIndex, timeout: Duration) -> Result<bool> {
+    signal_node(index, Signal::SIGTERM)?;
+
+    let deadline = Instant::now() + timeout;
+    while replica_pid(index).is_some() {
+        if Instant::now() > deadline {
+            if checkpoint_in_progress(index) {
+                eprintln!(
+                    "node {} is killed while flushing a checkpoint, it will catch up from height {:?}",
+                    index,
+                    latest_checkpoint(index)
+                );
+            }
+            signal_node(index, Signal::SIGKILL)?;
+            stop_bitcoin_adapter(index)?;
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    stop_bitcoin_adapter(index)?;
+    Ok(true)
+}
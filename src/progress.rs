@@ -0,0 +1,122 @@
+use crate::health;
+#[cfg(feature = "lmdb-tools")]
+use crate::pool;
+use crate::topology::{SubnetSummary, Topology};
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Comments sent on an idle event stream, so that closed connections are
+// noticed and their threads end.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, PartialEq, Serialize)]
+pub(crate) struct SubnetProgress {
+    pub subnet: SubnetIndex,
+    /// Highest finalized height among the nodes
+    pub height: Option<u64>,
+    /// Highest certified height among the nodes and the hash of the certified
+    /// state there
+    pub certified_height: Option<u64>,
+    pub state_hash: Option<String>,
+}
+
+// The latest progress of every subnet, with a sequence number that grows on
+// every change.
+#[derive(Default)]
+pub(crate) struct Progress {
+    state: Mutex<(u64, Vec<SubnetProgress>)>,
+    changed: Condvar,
+}
+
+fn subnet_progress(subnet: &SubnetSummary) -> SubnetProgress {
+    let height = health::heights(subnet)
+        .into_iter()
+        .filter_map(|(_, height)| height)
+        .max();
+    #[cfg(feature = "lmdb-tools")]
+    let certified = subnet
+        .nodes
+        .iter()
+        .filter_map(|node| pool::certified_state(node.index).ok().flatten())
+        .max_by_key(|(height, _)| *height);
+    #[cfg(not(feature = "lmdb-tools"))]
+    let certified: Option<(u64, Vec<u8>)> = None;
+
+    SubnetProgress {
+        subnet: subnet.index,
+        height,
+        certified_height: certified.as_ref().map(|(height, _)| *height),
+        state_hash: certified.map(|(_, hash)| hex::encode(hash)),
+    }
+}
+
+impl Progress {
+    // Polls the subnets in a thread of its own and wakes up waiting readers
+    // when anything changed.
+    pub(crate) fn watch(topology: Arc<Topology>) -> Arc<Progress> {
+        let progress = Arc::new(Progress::default());
+        let watcher = Arc::clone(&progress);
+        thread::spawn(move || loop {
+            let subnets: Vec<SubnetProgress> =
+                topology.subnets.iter().map(subnet_progress).collect();
+            let mut state = watcher.state.lock().unwrap();
+            if state.1 != subnets {
+                *state = (state.0 + 1, subnets);
+                watcher.changed.notify_all();
+            }
+            drop(state);
+            thread::sleep(POLL_INTERVAL);
+        });
+        progress
+    }
+
+    // The progress once its sequence number passes `since`, or the current one
+    // after `timeout`.
+    pub(crate) fn wait(&self, since: u64, timeout: Duration) -> (u64, Vec<SubnetProgress>) {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.0 <= since {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        state.clone()
+    }
+}
+
+// Streams server-sent events to a client, an event per subnet whenever its
+// progress changed, until the client goes away.
+pub(crate) fn stream(progress: &Progress, writer: &mut dyn Write) -> io::Result<()> {
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    writer.flush()?;
+
+    let mut seq = 0;
+    let mut sent: Vec<SubnetProgress> = vec![];
+    loop {
+        let (next, subnets) = progress.wait(seq, KEEPALIVE_INTERVAL);
+        if next == seq {
+            writer.write_all(b": keepalive\n\n")?;
+        }
+        for subnet in subnets.iter().filter(|subnet| !sent.contains(subnet)) {
+            let event = format!(
+                "id: {}\nevent: progress\ndata: {}\n\n",
+                next,
+                serde_json::to_string(subnet)?
+            );
+            writer.write_all(event.as_bytes())?;
+        }
+        writer.flush()?;
+        seq = next;
+        sent = subnets;
+    }
+}
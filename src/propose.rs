@@ -0,0 +1,198 @@
+use crate::identities;
+use crate::init::nns_url;
+use crate::tools::run_ic_binary;
+use anyhow::Result;
+use clap::{Args, Subcommand};
+
+#[derive(Args)]
+pub(crate) struct ProposeArgs {
+    /// URL of an NNS node, defaults to the first configured node
+    #[clap(long)]
+    nns_url: Option<String>,
+    /// Propose with the neuron of a generated identity, the majority neuron by
+    /// default
+    #[clap(long)]
+    identity: Option<String>,
+    /// Summary attached to the proposal
+    #[clap(long, default_value = "Proposal submitted by ic-testnet")]
+    summary: String,
+    #[clap(subcommand)]
+    command: ProposeCommand,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum ProposeCommand {
+    /// Elect a new replica version
+    BlessReplicaVersion {
+        version: String,
+        #[clap(long)]
+        release_package_url: String,
+        #[clap(long)]
+        release_package_sha256_hex: String,
+    },
+    /// Upgrade all nodes of a subnet to an elected replica version
+    DeployReplicaVersion {
+        /// Subnet id or index
+        subnet: String,
+        version: String,
+    },
+    /// Change parameters of an existing subnet record
+    UpdateSubnet {
+        /// Subnet id or index
+        #[clap(long)]
+        subnet: String,
+        #[clap(long)]
+        max_ingress_bytes_per_message: Option<u64>,
+        #[clap(long)]
+        max_ingress_messages_per_block: Option<u64>,
+        #[clap(long)]
+        max_block_payload_size: Option<u64>,
+        #[clap(long)]
+        unit_delay_millis: Option<u64>,
+        #[clap(long)]
+        initial_notary_delay_millis: Option<u64>,
+        #[clap(long)]
+        dkg_interval_length: Option<u64>,
+        #[clap(long)]
+        is_halted: Option<bool>,
+    },
+    /// Add unassigned nodes to a subnet
+    AddNodeToSubnet {
+        /// Subnet id or index
+        #[clap(long)]
+        subnet: String,
+        /// Node ids to add
+        #[clap(required = true)]
+        nodes: Vec<String>,
+    },
+}
+
+fn push_opt<T: ToString>(args: &mut Vec<String>, flag: &str, value: Option<T>) {
+    if let Some(value) = value {
+        args.push(flag.to_string());
+        args.push(value.to_string());
+    }
+}
+
+// A single neuron holds the majority of the voting power: the first generated
+// neuron when `identities --count` ran before `nns install`, the test neuron
+// of ic-nns-init otherwise. The proposer votes yes on submission, so proposals
+// go out from that neuron unless an identity is given, and are adopted right
+// away.
+pub(crate) fn submit(
+    nns_url: &str,
+    identity: Option<&str>,
+    summary: &str,
+    command: Vec<String>,
+) -> Result<String> {
+    let mut ic_admin_args = vec![];
+    let mut proposer = vec![];
+    let identity = match identity {
+        Some(name) => Some(identities::find(name)?),
+        None => identities::majority()?,
+    };
+    match identity {
+        Some(identity) => {
+            // --secret-key-pem is a global ic-admin flag
+            ic_admin_args.extend([
+                "--secret-key-pem".to_string(),
+                identity.pem.display().to_string(),
+            ]);
+            proposer.extend(["--proposer".to_string(), identity.neuron_id.to_string()]);
+        }
+        None => proposer.push("--test-neuron-proposer".to_string()),
+    }
+
+    ic_admin_args.extend(["--nns-url".to_string(), nns_url.to_string()]);
+    ic_admin_args.extend(command);
+    ic_admin_args.extend(proposer);
+    ic_admin_args.extend(["--summary".to_string(), summary.to_string()]);
+
+    run_ic_binary("ic-admin", &ic_admin_args)
+}
+
+pub(crate) fn run(args: ProposeArgs) -> Result<()> {
+    let mut command = vec![];
+
+    match args.command {
+        ProposeCommand::BlessReplicaVersion {
+            version,
+            release_package_url,
+            release_package_sha256_hex,
+        } => {
+            command.extend([
+                "propose-to-revise-elected-guestos-versions".to_string(),
+                "--replica-version-to-elect".to_string(),
+                version,
+                "--release-package-urls".to_string(),
+                release_package_url,
+                "--release-package-sha256-hex".to_string(),
+                release_package_sha256_hex,
+            ]);
+        }
+        ProposeCommand::DeployReplicaVersion { subnet, version } => {
+            command.extend([
+                "propose-to-deploy-guestos-to-all-subnet-nodes".to_string(),
+                subnet,
+                version,
+            ]);
+        }
+        ProposeCommand::UpdateSubnet {
+            subnet,
+            max_ingress_bytes_per_message,
+            max_ingress_messages_per_block,
+            max_block_payload_size,
+            unit_delay_millis,
+            initial_notary_delay_millis,
+            dkg_interval_length,
+            is_halted,
+        } => {
+            command.extend([
+                "propose-to-update-subnet".to_string(),
+                "--subnet".to_string(),
+                subnet,
+            ]);
+            push_opt(
+                &mut command,
+                "--max-ingress-bytes-per-message",
+                max_ingress_bytes_per_message,
+            );
+            push_opt(
+                &mut command,
+                "--max-ingress-messages-per-block",
+                max_ingress_messages_per_block,
+            );
+            push_opt(
+                &mut command,
+                "--max-block-payload-size",
+                max_block_payload_size,
+            );
+            push_opt(&mut command, "--unit-delay-millis", unit_delay_millis);
+            push_opt(
+                &mut command,
+                "--initial-notary-delay-millis",
+                initial_notary_delay_millis,
+            );
+            push_opt(&mut command, "--dkg-interval-length", dkg_interval_length);
+            push_opt(&mut command, "--is-halted", is_halted);
+        }
+        ProposeCommand::AddNodeToSubnet { subnet, nodes } => {
+            command.extend([
+                "propose-to-add-nodes-to-subnet".to_string(),
+                "--subnet".to_string(),
+                subnet,
+            ]);
+            command.extend(nodes);
+        }
+    }
+
+    let output = submit(
+        &args.nns_url.unwrap_or(nns_url()),
+        args.identity.as_deref(),
+        &args.summary,
+        command,
+    )?;
+    print!("{}", output);
+
+    Ok(())
+}
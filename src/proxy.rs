@@ -0,0 +1,203 @@
+use crate::chaos::{self, Shaping};
+use crate::init::transport_port;
+use crate::process;
+use crate::topology::Topology;
+use anyhow::{Context, Result};
+use ic_prep_lib::node::NodeIndex;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_DATAGRAM: usize = 65_536;
+// How often the proxies pick up changes of `chaos latency` and `partition`.
+const RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+// Peers that sent nothing back for this long get a new upstream socket.
+const SESSION_IDLE: Duration = Duration::from_secs(60);
+
+struct Datagram {
+    due: Instant,
+    socket: Arc<UdpSocket>,
+    to: SocketAddr,
+    data: Vec<u8>,
+}
+
+// Sends datagrams once their delay passed, in order like netem does, so that
+// a lowered latency holds packets back rather than reordering them. Every
+// peer has one for each direction, so a delayed peer holds back no other.
+fn delayed_sender() -> Sender<Datagram> {
+    let (sender, receiver) = mpsc::channel::<Datagram>();
+    thread::spawn(move || {
+        for datagram in receiver {
+            let now = Instant::now();
+            if datagram.due > now {
+                thread::sleep(datagram.due - now);
+            }
+            let _ = datagram.socket.send_to(&datagram.data, datagram.to);
+        }
+    });
+    sender
+}
+
+struct Session {
+    upstream: Arc<UdpSocket>,
+    alive: Arc<AtomicBool>,
+    // datagrams of the peer to the replica
+    sender: Sender<Datagram>,
+}
+
+// Listens on the transport address peers dial for a node and relays every
+// peer through an upstream socket of its own to the transport the replica
+// binds behind it.
+struct Relay {
+    node: NodeIndex,
+    public: Arc<UdpSocket>,
+    backend: SocketAddr,
+    peers: Vec<(IpAddr, NodeIndex)>,
+    shaping: Arc<RwLock<Shaping>>,
+}
+
+impl Relay {
+    fn peer(&self, addr: SocketAddr) -> Option<NodeIndex> {
+        self.peers
+            .iter()
+            .find(|(ip, _)| *ip == addr.ip())
+            .map(|(_, node)| *node)
+    }
+
+    fn delay(&self, peer: Option<NodeIndex>) -> Option<Duration> {
+        match self.shaping.read() {
+            Ok(shaping) => shaping.delay(self.node, peer),
+            Err(_) => Some(Duration::ZERO),
+        }
+    }
+
+    fn connect(
+        self: &Arc<Self>,
+        peer_addr: SocketAddr,
+        peer: Option<NodeIndex>,
+    ) -> Result<Session> {
+        let upstream = Arc::new(UdpSocket::bind(SocketAddr::new(self.backend.ip(), 0))?);
+        upstream.set_read_timeout(Some(SESSION_IDLE))?;
+        let alive = Arc::new(AtomicBool::new(true));
+
+        let relay = Arc::clone(self);
+        let socket = Arc::clone(&upstream);
+        let session_alive = Arc::clone(&alive);
+        let replies = delayed_sender();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            // replies of the replica go back to the peer from the address it dialed
+            while let Ok((len, _)) = socket.recv_from(&mut buf) {
+                if let Some(delay) = relay.delay(peer) {
+                    let _ = replies.send(Datagram {
+                        due: Instant::now() + delay,
+                        socket: Arc::clone(&relay.public),
+                        to: peer_addr,
+                        data: buf[..len].to_vec(),
+                    });
+                }
+            }
+            session_alive.store(false, Ordering::Relaxed);
+        });
+
+        Ok(Session {
+            upstream,
+            alive,
+            sender: delayed_sender(),
+        })
+    }
+
+    fn serve(self: Arc<Self>) {
+        let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let Ok((len, from)) = self.public.recv_from(&mut buf) else {
+                continue;
+            };
+            let peer = self.peer(from);
+            // partitioned
+            let Some(delay) = self.delay(peer) else {
+                continue;
+            };
+
+            sessions.retain(|_, session| session.alive.load(Ordering::Relaxed));
+            if !sessions.contains_key(&from) {
+                match self.connect(from, peer) {
+                    Ok(session) => {
+                        sessions.insert(from, session);
+                    }
+                    Err(e) => {
+                        eprintln!("node {} proxy cannot relay {}: {}", self.node, from, e);
+                        continue;
+                    }
+                }
+            }
+            let session = &sessions[&from];
+            let _ = session.sender.send(Datagram {
+                due: Instant::now() + delay,
+                socket: Arc::clone(&session.upstream),
+                to: self.backend,
+                data: buf[..len].to_vec(),
+            });
+        }
+    }
+}
+
+// Replicas send their transport packets from the address they bind, which
+// tells the proxies which node a packet comes from.
+fn peers(topology: &Topology) -> Vec<(IpAddr, NodeIndex)> {
+    topology
+        .nodes()
+        .filter_map(|node| {
+            let addr = process::listen_addr(node.index).or_else(|_| node.addr());
+            addr.ok().map(|addr| (addr.ip(), node.index))
+        })
+        .collect()
+}
+
+// Starts the transport proxies of the nodes initialized with
+// --transport-proxy, which run as long as the supervising process.
+pub(crate) fn start(topology: &Topology, nodes: &[NodeIndex]) -> Result<Vec<NodeIndex>> {
+    let shaping = Arc::new(RwLock::new(chaos::load_shaping()?));
+    let peers = peers(topology);
+    let port = transport_port()?;
+
+    let mut proxied = vec![];
+    for node in nodes {
+        let Some(backend) = process::proxied_transport(*node)? else {
+            continue;
+        };
+        let listen = SocketAddr::new(backend.ip(), port);
+        let public = UdpSocket::bind(listen)
+            .with_context(|| format!("failed to bind the transport proxy of node {}", node))?;
+        let relay = Arc::new(Relay {
+            node: *node,
+            public: Arc::new(public),
+            backend,
+            peers: peers.clone(),
+            shaping: Arc::clone(&shaping),
+        });
+        thread::spawn(move || relay.serve());
+        proxied.push(*node);
+    }
+
+    if !proxied.is_empty() {
+        thread::spawn(move || loop {
+            thread::sleep(RELOAD_INTERVAL);
+            match chaos::load_shaping() {
+                Ok(current) => {
+                    if let Ok(mut shaping) = shaping.write() {
+                        *shaping = current;
+                    }
+                }
+                Err(e) => eprintln!("failed to read the latencies and partitions: {}", e),
+            }
+        });
+    }
+
+    Ok(proxied)
+}
@@ -0,0 +1,72 @@
+use crate::agent::{block_on, connect};
+use crate::identities;
+use crate::topology;
+use anyhow::Result;
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use clap::Args;
+use ic_management_canister_types::Method;
+
+#[derive(Args)]
+pub(crate) struct QueryStatsArgs {
+    canister: String,
+    /// Controller of the canister, anonymous by default
+    #[clap(long)]
+    identity: Option<String>,
+}
+
+#[derive(CandidType)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct QueryStats {
+    num_calls_total: Nat,
+    num_instructions_total: Nat,
+    request_payload_bytes_total: Nat,
+    response_payload_bytes_total: Nat,
+}
+
+// the fields of `canister_status` that are of interest here
+#[derive(CandidType, Deserialize)]
+struct CanisterStatus {
+    query_stats: QueryStats,
+}
+
+pub(crate) fn run(args: QueryStatsArgs) -> Result<()> {
+    let canister_id = Principal::from_text(&args.canister)?;
+    let url = topology::load()?.canister_url(&canister_id)?;
+    let pem = match args.identity {
+        Some(name) => Some(identities::find(&name)?.pem),
+        None => None,
+    };
+
+    let reply = block_on(async {
+        let agent = connect(&url, pem.as_deref()).await?;
+        Ok(agent
+            .update(
+                &Principal::management_canister(),
+                Method::CanisterStatus.to_string(),
+            )
+            .with_effective_canister_id(canister_id)
+            .with_arg(Encode!(&CanisterIdRecord { canister_id })?)
+            .call_and_wait()
+            .await?)
+    })?;
+    let status = Decode!(&reply, CanisterStatus)?;
+
+    // aggregated once per epoch, so counts lag the calls by up to an epoch
+    let stats = status.query_stats;
+    println!("calls                  {}", stats.num_calls_total);
+    println!("instructions           {}", stats.num_instructions_total);
+    println!(
+        "request payload bytes  {}",
+        stats.request_payload_bytes_total
+    );
+    println!(
+        "response payload bytes {}",
+        stats.response_payload_bytes_total
+    );
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use crate::init::output_dir;
+use crate::topology::Topology;
+use anyhow::{anyhow, Result};
+use ic_registry_keys::{make_node_record_key, make_subnet_list_record_key, make_subnet_record_key};
+use ic_registry_local_store::{LocalStoreImpl, LocalStoreReader};
+use ic_types::{NodeId, PrincipalId, RegistryVersion, SubnetId};
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+
+fn principal(text: &str) -> Result<PrincipalId> {
+    PrincipalId::from_str(text).map_err(|e| anyhow!("invalid principal {}: {}", text, e))
+}
+
+// Copies the protobuf encoded `SubnetRecord`s, `NodeRecord`s and the
+// `SubnetListRecord` from the registry written by ic-prep to tmp/registry, as
+// the latest value of each key.
+pub(crate) fn export(topology: &Topology) -> Result<()> {
+    let store = LocalStoreImpl::new(output_dir()?.join("state").join("ic_registry_local_store"));
+    let mut records: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for entry in store.get_changelog_since_version(RegistryVersion::from(0))? {
+        for mutation in entry {
+            match mutation.value {
+                Some(value) => records.insert(mutation.key, value),
+                None => records.remove(&mutation.key),
+            };
+        }
+    }
+
+    let dir = output_dir()?.join("registry");
+    fs::create_dir_all(&dir)?;
+    let mut export = |key: String, file: String| -> Result<()> {
+        let record = records
+            .get(&key)
+            .ok_or(anyhow!("registry has no record {}", key))?;
+        fs::write(dir.join(file), record)?;
+        Ok(())
+    };
+
+    export(make_subnet_list_record_key(), "subnet_list.pb".to_string())?;
+    for subnet in &topology.subnets {
+        let subnet_id = SubnetId::from(principal(&subnet.subnet_id)?);
+        export(
+            make_subnet_record_key(subnet_id),
+            format!("subnet-{}.pb", subnet.index),
+        )?;
+    }
+    for node in topology.nodes() {
+        let node_id = NodeId::from(principal(&node.node_id)?);
+        export(
+            make_node_record_key(node_id),
+            format!("node-{}.pb", node.index),
+        )?;
+    }
+
+    Ok(())
+}
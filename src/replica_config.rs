@@ -0,0 +1,263 @@
+use crate::init::output_dir;
+use anyhow::{anyhow, bail, Context, Result};
+use ic_config::ConfigOptional as ReplicaConfig;
+use ic_prep_lib::node::NodeIndex;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Removes the value at a JSON pointer, e.g. /hypervisor/canister_snapshots.
+fn remove(config: &mut Value, pointer: &str) {
+    let (parent, key) = pointer.rsplit_once('/').unwrap_or(("", pointer));
+    if let Some(Value::Object(parent)) = config.pointer_mut(parent) {
+        parent.remove(key);
+    }
+}
+
+fn before_2024_08_08(config: &mut Value) {
+    remove(config, "/hypervisor/embedders_config/feature_flags/wasm64");
+}
+
+fn before_2024_07_25(config: &mut Value) {
+    remove(
+        config,
+        "/hypervisor/embedders_config/feature_flags/best_effort_responses",
+    );
+}
+
+fn before_2024_01_31(config: &mut Value) {
+    remove(config, "/hypervisor/canister_snapshots");
+}
+
+// WRITERS turn the config of the ic submodule into the shape replicas released
+// before a date read, newest first. A config for a release goes through all
+// writers dated after it, so each only has to know the change of its date.
+const WRITERS: &[(&str, fn(&mut Value))] = &[
+    ("2024-08-08", before_2024_08_08),
+    ("2024-07-25", before_2024_07_25),
+    ("2024-01-31", before_2024_01_31),
+];
+
+// The date of a replica release given as a date or a release name like
+// rc--2024-06-12_23-01.
+fn release_date(release: &str) -> Result<&str> {
+    let is_date = |candidate: &str| {
+        candidate.len() == 10
+            && candidate.char_indices().all(|(i, c)| match i {
+                4 | 7 => c == '-',
+                _ => c.is_ascii_digit(),
+            })
+    };
+    (0..release.len().saturating_sub(9))
+        .filter_map(|start| release.get(start..start + 10))
+        .find(|candidate| is_date(candidate))
+        .ok_or(anyhow!(
+            "replica release {} is neither a date like 2024-06-12 nor a release name containing one",
+            release
+        ))
+}
+
+// The copy of `init --replica-overrides` the configs are regenerated with.
+fn overrides_file() -> Result<PathBuf> {
+    Ok(output_dir()?.join("replica-overrides.json5"))
+}
+
+// Overrides are JSON with comments on lines of their own, like the configs.
+fn parse_overrides(path: &Path) -> Result<Value> {
+    let json5 = fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    let json: String = json5
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("//"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let overrides: Value =
+        serde_json::from_str(&json).with_context(|| format!("invalid overrides {:?}", path))?;
+    if !overrides.is_object() || !overrides.get("nodes").map_or(true, Value::is_object) {
+        bail!(
+            "{:?} has to be an object with config fields and an optional \"nodes\" object",
+            path
+        );
+    }
+    Ok(overrides)
+}
+
+// Keeps the overrides of `init` for later config writes, or drops the ones of
+// a previous init.
+pub(crate) fn save_overrides(path: Option<&Path>) -> Result<()> {
+    let saved = overrides_file()?;
+    match path {
+        Some(path) => {
+            parse_overrides(path)?;
+            fs::copy(path, &saved).with_context(|| format!("failed to copy {:?}", path))?;
+        }
+        None if saved.is_file() => fs::remove_file(&saved)?,
+        None => {}
+    }
+    Ok(())
+}
+
+// Objects are merged field by field, anything else replaces the generated
+// value, and null removes it.
+fn merge(config: &mut Value, overrides: &Value) {
+    match (config, overrides) {
+        (Value::Object(config), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match value {
+                    Value::Null => {
+                        config.remove(key);
+                    }
+                    _ => merge(config.entry(key.clone()).or_insert(Value::Null), value),
+                }
+            }
+        }
+        (config, overrides) => *config = overrides.clone(),
+    }
+}
+
+// Fields of the written config the parsed one lost, which the replica does
+// not know and ignores, e.g. misspelled overrides.
+fn ignored_fields(written: &Value, parsed: &Value, path: &str, ignored: &mut Vec<String>) {
+    let (Value::Object(written), Value::Object(parsed)) = (written, parsed) else {
+        return;
+    };
+    for (key, value) in written {
+        let field = format!("{}/{}", path, key);
+        match parsed.get(key) {
+            Some(parsed) => ignored_fields(value, parsed, &field, ignored),
+            None => ignored.push(field),
+        }
+    }
+}
+
+// Parses the config the way the replica does, so configs it would reject at
+// startup fail here. Configs for another release are only checked for JSON
+// the replica of the ic submodule reads, their fields may differ.
+fn validate(json: &str, release: Option<&str>, node: NodeIndex) -> Result<()> {
+    let parsed: ReplicaConfig = serde_json::from_str(json)
+        .with_context(|| format!("the replica would reject the config of node {}", node))?;
+    let mut ignored = vec![];
+    ignored_fields(
+        &serde_json::from_str(json)?,
+        &serde_json::to_value(&parsed)?,
+        "",
+        &mut ignored,
+    );
+    if ignored.is_empty() {
+        return Ok(());
+    }
+    if release.is_some() {
+        eprintln!(
+            "node {}: the replica of the ic submodule does not know the config fields {}",
+            node,
+            ignored.join(", ")
+        );
+        return Ok(());
+    }
+    bail!(
+        "the replica would ignore the config fields {} of node {}",
+        ignored.join(", "),
+        node
+    )
+}
+
+// The replica config of a node as JSON for a release, the shape of the ic
+// submodule without one, with the saved overrides on top: the fields for all
+// nodes first, then those under "nodes" for the node, e.g.
+//
+//     {
+//       "hypervisor": { "max_canister_memory_size": 2147483648 },
+//       "nodes": { "101": { "logger": { "level": "debug" } } }
+//     }
+pub(crate) fn to_json(
+    config: &ReplicaConfig,
+    release: Option<&str>,
+    node: NodeIndex,
+) -> Result<String> {
+    let mut value = serde_json::to_value(config)?;
+    if let Some(release) = release {
+        let date = release_date(release)?;
+        for (since, writer) in WRITERS {
+            if date < *since {
+                writer(&mut value);
+            }
+        }
+    }
+
+    let saved = overrides_file()?;
+    if saved.is_file() {
+        let mut overrides = parse_overrides(&saved)?;
+        let per_node = overrides
+            .as_object_mut()
+            .and_then(|overrides| overrides.remove("nodes"));
+        merge(&mut value, &overrides);
+        if let Some(own) = per_node
+            .as_ref()
+            .and_then(|nodes| nodes.get(node.to_string()))
+        {
+            merge(&mut value, own);
+        }
+    }
+
+    let json = serde_json::to_string(&value)?;
+    validate(&json, release, node)?;
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merges_objects_field_by_field() {
+        let mut config = json!({
+            "hypervisor": { "max_canister_memory_size": 1, "canister_snapshots": "enabled" },
+            "logger": { "level": "info" },
+        });
+        merge(
+            &mut config,
+            &json!({
+                "hypervisor": { "max_canister_memory_size": 2, "canister_snapshots": null },
+                "logger": "debug",
+                "tracing": { "jaeger_addr": "127.0.0.1:4317" },
+            }),
+        );
+        assert_eq!(
+            config,
+            json!({
+                "hypervisor": { "max_canister_memory_size": 2 },
+                "logger": "debug",
+                "tracing": { "jaeger_addr": "127.0.0.1:4317" },
+            })
+        );
+    }
+
+    #[test]
+    fn finds_the_date_of_a_release() {
+        assert_eq!(release_date("2024-06-12").unwrap(), "2024-06-12");
+        assert_eq!(release_date("rc--2024-06-12_23-01").unwrap(), "2024-06-12");
+        assert!(release_date("2024-6-12").is_err());
+        assert!(release_date("rc--latest").is_err());
+        assert!(release_date("").is_err());
+    }
+
+    #[test]
+    fn reports_fields_the_parsed_config_lost() {
+        let written = json!({
+            "hypervisor": { "max_canister_memory_size": 1, "max_canister_memroy_size": 2 },
+            "no_such_section": {},
+            "logger": "debug",
+        });
+        let parsed = json!({
+            "hypervisor": { "max_canister_memory_size": 1 },
+            "logger": { "level": "debug" },
+        });
+        let mut ignored = vec![];
+        ignored_fields(&written, &parsed, "", &mut ignored);
+        ignored.sort();
+        assert_eq!(
+            ignored,
+            vec!["/hypervisor/max_canister_memroy_size", "/no_such_section"]
+        );
+    }
+}
@@ -0,0 +1,36 @@
+use crate::init::output_dir;
+use crate::topology::Topology;
+use anyhow::Result;
+use std::fs;
+
+pub(crate) fn export(topology: &Topology) -> Result<()> {
+    let dir = output_dir()?;
+    let der = topology.root_key_der()?;
+    let root_key = hex::encode(&der);
+    let url = topology.subnet_url(topology.nns_subnet)?;
+
+    fs::copy(&topology.root_key, dir.join("root_key.pem"))?;
+    fs::write(dir.join("root_key.der"), &der)?;
+
+    let agent_rs = format!(
+        r#"let agent = ic_agent::Agent::builder()
+    .with_url("{url}")
+    .build()?;
+agent.set_root_key(hex::decode("{root_key}")?);
+"#
+    );
+    fs::write(dir.join("agent-rs.rs"), agent_rs)?;
+
+    let agent_js = format!(
+        r#"import {{ HttpAgent }} from "@dfinity/agent";
+
+const agent = await HttpAgent.create({{ host: "{url}", shouldFetchRootKey: false }});
+agent.rootKey = Uint8Array.from(
+  "{root_key}".match(/../g).map((byte) => parseInt(byte, 16)),
+).buffer;
+"#
+    );
+    fs::write(dir.join("agent-js.mjs"), agent_js)?;
+
+    Ok(())
+}
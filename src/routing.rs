@@ -0,0 +1,131 @@
+use crate::config::{IdRange, TestnetConfig};
+use crate::init::output_dir;
+use crate::topology::Topology;
+use anyhow::{anyhow, bail, Result};
+use candid::Principal;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_protobuf::registry::routing_table::v1 as pb;
+use ic_registry_keys::make_routing_table_record_key;
+use ic_registry_local_store::{KeyMutation, LocalStoreImpl, LocalStoreReader, LocalStoreWriter};
+use ic_registry_routing_table::{CanisterIdRange, RoutingTable};
+use ic_types::{CanisterId, PrincipalId, RegistryVersion, SubnetId};
+use prost::Message;
+use std::path::Path;
+use std::str::FromStr;
+
+// Canister ids are a big endian number followed by 0x01 0x01.
+pub(crate) fn canister_number(canister_id: &Principal) -> Result<u64> {
+    let bytes = canister_id.as_slice();
+    if bytes.len() != 10 || bytes[8..] != [1, 1] {
+        bail!("{} is not a canister id", canister_id);
+    }
+    Ok(u64::from_be_bytes(bytes[..8].try_into()?))
+}
+
+fn bounds(range: &IdRange) -> Result<(u64, u64)> {
+    let number = |text: &str| -> Result<u64> {
+        let principal = Principal::from_text(text)
+            .map_err(|e| anyhow!("invalid canister id {}: {}", text, e))?;
+        canister_number(&principal)
+    };
+    let (start, end) = (number(&range.start)?, number(&range.end)?);
+    if start > end {
+        bail!(
+            "specified id range {} to {} is empty",
+            range.start,
+            range.end
+        );
+    }
+    Ok((start, end))
+}
+
+// The subnet a `specified_id_ranges` entry of the config routes a canister to.
+pub(crate) fn specified_subnet(config: &TestnetConfig, id: u64) -> Result<Option<SubnetIndex>> {
+    for subnet in &config.subnets {
+        for range in &subnet.specified_id_ranges {
+            let (start, end) = bounds(range)?;
+            if (start..=end).contains(&id) {
+                return Ok(Some(subnet.index));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// The latest value of a registry key in a local store, with the version of
+// the store.
+pub(crate) fn latest_record(store: &Path, key: &str) -> Result<(RegistryVersion, Option<Vec<u8>>)> {
+    let changelog =
+        LocalStoreImpl::new(store).get_changelog_since_version(RegistryVersion::from(0))?;
+    let value = changelog
+        .iter()
+        .flatten()
+        .filter(|mutation| mutation.key == key)
+        .last()
+        .and_then(|mutation| mutation.value.clone());
+
+    Ok((RegistryVersion::from(changelog.len() as u64), value))
+}
+
+// The latest routing table in a registry local store and its version.
+pub(crate) fn read_routing_table(store: &Path) -> Result<(RegistryVersion, RoutingTable)> {
+    let (version, record) = latest_record(store, &make_routing_table_record_key())?;
+    let record = record.ok_or(anyhow!("registry {:?} has no routing table", store))?;
+    let routing_table = RoutingTable::try_from(pb::RoutingTable::decode(record.as_slice())?)
+        .map_err(|e| anyhow!("invalid routing table: {:?}", e))?;
+
+    Ok((version, routing_table))
+}
+
+// Routes the `specified_id_ranges` of each subnet to it, with a registry
+// version on top of the one ic-prep wrote. ic-prep only knows the routing
+// table where each subnet gets the range it allocates canister ids from.
+pub(crate) fn assign_specified_ids(topology: &Topology, config: &TestnetConfig) -> Result<()> {
+    let mut ranges = vec![];
+    for subnet in &config.subnets {
+        for range in &subnet.specified_id_ranges {
+            ranges.push((subnet.index, range, bounds(range)?));
+        }
+    }
+    if ranges.is_empty() {
+        return Ok(());
+    }
+
+    let store_path = output_dir()?.join("state").join("ic_registry_local_store");
+    let (version, mut routing_table) = read_routing_table(&store_path)?;
+
+    for (index, range, (start, end)) in ranges {
+        let subnet = topology.subnet(index)?;
+        let subnet_id = SubnetId::from(
+            PrincipalId::from_str(&subnet.subnet_id)
+                .map_err(|e| anyhow!("invalid subnet id {}: {}", subnet.subnet_id, e))?,
+        );
+        routing_table
+            .insert(
+                CanisterIdRange {
+                    start: CanisterId::from_u64(start),
+                    end: CanisterId::from_u64(end),
+                },
+                subnet_id,
+            )
+            .map_err(|e| {
+                anyhow!(
+                    "specified ids {} to {} of subnet {} overlap other canister ids: {:?}",
+                    range.start,
+                    range.end,
+                    index,
+                    e
+                )
+            })?;
+    }
+
+    LocalStoreImpl::new(store_path).store(
+        version.increment(),
+        vec![KeyMutation {
+            key: make_routing_table_record_key(),
+            value: Some(pb::RoutingTable::from(routing_table).encode_to_vec()),
+        }],
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,528 @@
+use crate::backend::{self, NodeBackend};
+use crate::config::{Backend, Selector, TestnetConfig};
+use crate::health;
+use crate::hooks::{self, Event};
+use crate::init::output_dir;
+use crate::logging;
+use crate::metadata;
+#[cfg(feature = "lmdb-tools")]
+use crate::pool;
+use crate::ports;
+use crate::process;
+use crate::proxy;
+use crate::secrets;
+use crate::topology::{self, Topology};
+use crate::uds;
+use anyhow::{bail, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self as std_process, Child};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Args)]
+pub(crate) struct RunArgs {
+    /// Nodes to run, all nodes when omitted
+    #[clap(long = "node")]
+    nodes: Vec<NodeIndex>,
+    /// Run the nodes matching a label selector, e.g. zone=b, in addition to
+    /// the --node ones
+    #[clap(long)]
+    select: Option<Selector>,
+    /// Supervise replicas that are already running, e.g. after ic-testnet restarted
+    #[clap(long)]
+    attach: bool,
+    /// Restart replicas that exit
+    #[clap(long)]
+    restart: bool,
+    /// Seconds replicas get to flush their state on SIGINT/SIGTERM before they
+    /// are killed
+    #[clap(long, default_value = "60")]
+    shutdown_timeout: u64,
+    /// Also serve the public API of every node on tmp/sockets/node-<idx>.sock
+    #[clap(long)]
+    uds: bool,
+    /// Start this many replicas at a time and wait for them to catch up with
+    /// their subnet before starting the next ones
+    #[clap(long)]
+    stage: Option<usize>,
+    /// Seconds a stage gets to catch up
+    #[clap(long, default_value = "300")]
+    stage_timeout: u64,
+    /// Start replicas even when the binaries differ from the ones recorded at
+    /// init
+    #[clap(long)]
+    allow_version_skew: bool,
+    /// Delete all but the newest N checkpoints of every node, at least 2
+    #[clap(long)]
+    keep_checkpoints: Option<usize>,
+    #[cfg(feature = "lmdb-tools")]
+    /// Restart nodes whose consensus pool file grew beyond this many MiB with
+    /// a compacted pool
+    #[clap(long)]
+    max_pool_size: Option<u64>,
+}
+
+// Seconds between checks of the retention limits.
+const RETENTION_INTERVAL: u64 = 60;
+
+#[derive(Serialize, Deserialize)]
+struct LockInfo {
+    pid: u32,
+    started_at: u64,
+    nodes: Vec<NodeIndex>,
+}
+
+// Held by the supervising ic-testnet process, removed when it exits.
+struct Lock(PathBuf);
+
+impl Lock {
+    fn acquire(nodes: &[NodeIndex]) -> Result<Lock> {
+        let path = output_dir()?.join("testnet.lock");
+        if let Ok(json) = fs::read_to_string(&path) {
+            if let Ok(info) = serde_json::from_str::<LockInfo>(&json) {
+                if is_alive(info.pid as i32) {
+                    bail!(
+                        "testnet is already supervised by pid {} ({:?})",
+                        info.pid,
+                        path
+                    );
+                }
+            }
+        }
+
+        let info = LockInfo {
+            pid: std_process::id(),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            nodes: nodes.to_vec(),
+        };
+        fs::write(&path, serde_json::to_string_pretty(&info)?)?;
+
+        Ok(Lock(path))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn is_alive(pid: i32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+enum Replica {
+    Child(Child),
+    // started by a previous ic-testnet process
+    Attached(i32),
+    // a container, VM or remote service, watched through its backend
+    Remote(NodeIndex, Box<dyn NodeBackend>),
+}
+
+impl Replica {
+    fn describe(&self) -> String {
+        match self {
+            Replica::Child(child) => format!("pid {}", child.id()),
+            Replica::Attached(pid) => format!("pid {}", pid),
+            Replica::Remote(_, backend) => format!("{} backend", backend.name()),
+        }
+    }
+
+    fn exited(&mut self) -> bool {
+        match self {
+            Replica::Child(child) => child.try_wait().map_or(true, |status| status.is_some()),
+            Replica::Attached(pid) => !is_alive(*pid),
+            Replica::Remote(node, backend) => !backend.running(*node),
+        }
+    }
+}
+
+fn start(node: NodeIndex) -> Result<Replica> {
+    let backend = backend::for_node(node)?;
+    Ok(match backend.start(node)? {
+        Some(child) => Replica::Child(child),
+        None => Replica::Remote(node, backend),
+    })
+}
+
+// A replica that is already running, e.g. started by a previous ic-testnet
+// process or `subnet rolling-restart`.
+fn attach(node: NodeIndex) -> Result<Option<Replica>> {
+    if TestnetConfig::saved()?.node(node).backend == Backend::Process {
+        let Some(pid) = process::replica_pid(node) else {
+            return Ok(None);
+        };
+        fs::write(process::pid_file(node)?, pid.to_string())?;
+        return Ok(Some(Replica::Attached(pid)));
+    }
+    let backend = backend::for_node(node)?;
+    Ok(backend
+        .running(node)
+        .then(|| Replica::Remote(node, backend)))
+}
+
+pub(crate) fn run(args: RunArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let mut nodes: Vec<NodeIndex> = args.nodes.clone();
+    if let Some(select) = &args.select {
+        nodes.extend(select.select(&topology)?);
+        nodes.sort();
+        nodes.dedup();
+    }
+    if nodes.is_empty() {
+        nodes = topology.nodes().map(|node| node.index).collect();
+    }
+
+    let mut running: Vec<NodeIndex> = vec![];
+    for node in &nodes {
+        if backend::for_node(*node)?.running(*node) {
+            running.push(*node);
+        }
+    }
+    if !running.is_empty() && !args.attach {
+        bail!(
+            "nodes {:?} are already running, use --attach to supervise them",
+            running
+        );
+    }
+
+    if let Some(keep) = args.keep_checkpoints {
+        // the state of the last catch-up package may not be the newest one
+        if keep < 2 {
+            bail!("--keep-checkpoints has to keep at least 2 checkpoints");
+        }
+    }
+
+    let starting: Vec<NodeIndex> = nodes
+        .iter()
+        .filter(|node| !running.contains(node))
+        .copied()
+        .collect();
+    // ssh and vm nodes bind their ports on other hosts
+    let config = TestnetConfig::saved()?;
+    let local: Vec<NodeIndex> = starting
+        .iter()
+        .filter(|node| {
+            matches!(
+                config.node(**node).backend,
+                Backend::Process | Backend::Docker
+            )
+        })
+        .copied()
+        .collect();
+    ports::check(&topology, &local)?;
+    if !starting.is_empty() {
+        metadata::check_binaries(args.allow_version_skew)?;
+    }
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&shutdown))?;
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&shutdown))?;
+
+    let _lock = Lock::acquire(&nodes)?;
+    let _unsealed = secrets::unseal_nodes(&starting)?;
+    let proxied = proxy::start(&topology, &nodes)?;
+    if !proxied.is_empty() {
+        println!("transport of nodes {:?} behind proxies", proxied);
+    }
+
+    // replicas started before an error are stopped again, unsupervised
+    // replicas would keep running after the lock is gone
+    let mut replicas: BTreeMap<NodeIndex, Replica> = BTreeMap::new();
+    let supervised = supervise(&args, &topology, &nodes, &shutdown, &mut replicas);
+    if !replicas.is_empty() {
+        stop(replicas, Duration::from_secs(args.shutdown_timeout))?;
+    }
+    supervised
+}
+
+// Time between attempts to start a replica that failed to start again.
+const RESTART_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Starts the replicas and watches them until all exited or a shutdown is
+// requested, leaving the replicas in `replicas` for the caller to stop.
+fn supervise(
+    args: &RunArgs,
+    topology: &Topology,
+    nodes: &[NodeIndex],
+    shutdown: &Arc<AtomicBool>,
+    replicas: &mut BTreeMap<NodeIndex, Replica>,
+) -> Result<()> {
+    let stage = args.stage.unwrap_or(nodes.len()).max(1);
+    for batch in nodes.chunks(stage) {
+        for node in batch {
+            let replica = match attach(*node)? {
+                Some(replica) => replica,
+                None => logging::timed("run.start", Some(*node), || start(*node))?,
+            };
+            println!("node {} running as {}", node, replica.describe());
+            replicas.insert(*node, replica);
+        }
+        if args.stage.is_some() {
+            logging::timed("run.stage", None, || {
+                wait_caught_up(
+                    topology,
+                    batch,
+                    replicas,
+                    shutdown,
+                    Duration::from_secs(args.stage_timeout),
+                )
+            })?;
+        }
+    }
+
+    if args.uds {
+        for node in nodes {
+            let path = uds::serve(*node, topology.node(*node)?.addr()?)?;
+            println!("node {} public API on {:?}", node, path);
+        }
+    }
+    if let Some(keep) = args.keep_checkpoints {
+        let nodes = nodes.to_vec();
+        let shutdown = Arc::clone(shutdown);
+        thread::spawn(move || prune_checkpoints(&nodes, keep, &shutdown));
+    }
+    #[cfg(feature = "lmdb-tools")]
+    let mut pool_checked_at = Instant::now();
+    // nodes to start again once their retry time came
+    let mut retries: BTreeMap<NodeIndex, Instant> = BTreeMap::new();
+    let mut restarting: BTreeSet<NodeIndex> = BTreeSet::new();
+
+    while !replicas.is_empty() || !retries.is_empty() {
+        if shutdown.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(1));
+
+        #[cfg(feature = "lmdb-tools")]
+        if let Some(max) = args.max_pool_size {
+            if pool_checked_at.elapsed() >= Duration::from_secs(RETENTION_INTERVAL) {
+                pool_checked_at = Instant::now();
+                for node in compact_pools(
+                    replicas,
+                    max * 1024 * 1024,
+                    Duration::from_secs(args.shutdown_timeout),
+                )? {
+                    retries.insert(node, Instant::now() + RESTART_RETRY_INTERVAL);
+                }
+            }
+        }
+
+        let exited: Vec<NodeIndex> = replicas
+            .iter_mut()
+            .filter_map(|(node, replica)| replica.exited().then_some(*node))
+            .collect();
+        for node in exited {
+            // the exited replica stays until `subnet rolling-restart` started
+            // the node again
+            if process::is_restarting(node) {
+                if restarting.insert(node) {
+                    println!("node {} is restarted by subnet rolling-restart", node);
+                }
+                continue;
+            }
+            restarting.remove(&node);
+            replicas.remove(&node);
+
+            // started again meanwhile, e.g. by `subnet rolling-restart`
+            if let Some(replica) = attach(node)? {
+                println!("node {} restarted as {}", node, replica.describe());
+                replicas.insert(node, replica);
+                continue;
+            }
+            let _ = fs::remove_file(process::pid_file(node)?);
+
+            let message = format!("replica of node {} exited", node);
+            eprintln!("{}", message);
+            logging::event("run.exit", Some(node), Some(message.clone()));
+            hooks::emit(Event::NodeCrash, &message, json!({ "node": node }));
+
+            if args.restart {
+                retries.insert(node, Instant::now());
+            }
+        }
+
+        let due: Vec<NodeIndex> = retries
+            .iter()
+            .filter(|(_, at)| **at <= Instant::now())
+            .map(|(node, _)| *node)
+            .collect();
+        for node in due {
+            retries.remove(&node);
+            match start(node) {
+                Ok(replica) => {
+                    println!("node {} restarted as {}", node, replica.describe());
+                    replicas.insert(node, replica);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "failed to restart node {}, retrying in {:?}: {:#}",
+                        node, RESTART_RETRY_INTERVAL, e
+                    );
+                    retries.insert(node, Instant::now() + RESTART_RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Heights a node may trail the highest node of its subnet and count as caught
+// up.
+const CAUGHT_UP_LAG: u64 = 10;
+
+// Subnets make no blocks before two thirds of their nodes are up, so early
+// stages only have to report a height and later ones to get close to the
+// height of the subnet.
+fn wait_caught_up(
+    topology: &Topology,
+    batch: &[NodeIndex],
+    replicas: &mut BTreeMap<NodeIndex, Replica>,
+    shutdown: &AtomicBool,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut waiting = vec![];
+        for node in batch {
+            if replicas
+                .get_mut(node)
+                .map_or(true, |replica| replica.exited())
+            {
+                bail!(
+                    "node {} exited during start, see logs/node-{}.log",
+                    node,
+                    node
+                );
+            }
+            let Some(subnet) = topology.subnet_of(*node) else {
+                // unassigned nodes make no blocks
+                continue;
+            };
+            let heights = health::heights(subnet);
+            let highest = heights
+                .iter()
+                .filter_map(|(_, height)| *height)
+                .max()
+                .unwrap_or(0);
+            match heights
+                .iter()
+                .find(|(index, _)| index == node)
+                .and_then(|(_, height)| *height)
+            {
+                Some(height) if height + CAUGHT_UP_LAG >= highest => {}
+                _ => waiting.push(*node),
+            }
+        }
+        if waiting.is_empty() {
+            return Ok(());
+        }
+        if shutdown.load(Ordering::Relaxed) || Instant::now() > deadline {
+            bail!("nodes {:?} did not catch up within {:?}", waiting, timeout);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn prune_checkpoints(nodes: &[NodeIndex], keep: usize, shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::Relaxed) {
+        for node in nodes {
+            match process::prune_checkpoints(*node, keep) {
+                Ok(pruned) if !pruned.is_empty() => {
+                    println!("node {} deleted checkpoints at heights {:?}", node, pruned)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("failed to delete checkpoints of node {}: {}", node, e),
+            }
+        }
+        thread::sleep(Duration::from_secs(RETENTION_INTERVAL));
+    }
+}
+
+// Replicas keep their pool open, so oversized pools are compacted while the
+// node is stopped and the node started again right after. Returns the nodes
+// that did not start again.
+#[cfg(feature = "lmdb-tools")]
+fn compact_pools(
+    replicas: &mut BTreeMap<NodeIndex, Replica>,
+    max_bytes: u64,
+    timeout: Duration,
+) -> Result<Vec<NodeIndex>> {
+    let oversized: Vec<NodeIndex> = replicas
+        .keys()
+        .filter(|node| pool::size(**node).map_or(false, |size| size > max_bytes))
+        .copied()
+        .collect();
+    let mut failed = vec![];
+    for node in oversized {
+        let size = pool::size(node)?;
+        let backend = backend::for_node(node)?;
+        backend.stop(node, timeout)?;
+        if let Some(Replica::Child(mut child)) = replicas.remove(&node) {
+            let _ = child.wait();
+        }
+        let _ = fs::remove_file(process::pid_file(node)?);
+
+        if let Err(e) = pool::compact(node) {
+            eprintln!("failed to compact the pool of node {}: {}", node, e);
+        }
+        let replica = match start(node) {
+            Ok(replica) => replica,
+            Err(e) => {
+                eprintln!(
+                    "failed to start node {} after compacting its pool: {:#}",
+                    node, e
+                );
+                failed.push(node);
+                continue;
+            }
+        };
+        println!(
+            "node {} restarted as {} after compacting its pool from {} to {} MiB",
+            node,
+            replica.describe(),
+            size / 1024 / 1024,
+            pool::size(node).unwrap_or(0) / 1024 / 1024
+        );
+        replicas.insert(node, replica);
+    }
+
+    Ok(failed)
+}
+
+// Stops all replicas concurrently so that the timeout bounds the whole
+// shutdown.
+fn stop(replicas: BTreeMap<NodeIndex, Replica>, timeout: Duration) -> Result<()> {
+    println!("stopping {} replicas", replicas.len());
+    thread::scope(|scope| {
+        for (node, replica) in replicas {
+            scope.spawn(move || {
+                match backend::for_node(node).and_then(|backend| backend.stop(node, timeout)) {
+                    Ok(true) => println!(
+                        "node {} stopped, last checkpoint at height {:?}",
+                        node,
+                        process::latest_checkpoint(node)
+                    ),
+                    Ok(false) => eprintln!("node {} killed after {:?}", node, timeout),
+                    Err(e) => eprintln!("failed to stop node {}: {}", node, e),
+                }
+                if let Replica::Child(mut child) = replica {
+                    let _ = child.wait();
+                }
+                let _ = process::pid_file(node).map(fs::remove_file);
+                let _ = uds::socket_path(node).map(fs::remove_file);
+            });
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use crate::agent::{block_on, connect, create_canister, install_code};
+use crate::topology::{self, Topology};
+use crate::{backend, chaos, health};
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use ic_management_canister_types::CanisterInstallMode;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Subcommand)]
+pub(crate) enum ScenarioCommand {
+    /// Execute the steps of a scenario file in order
+    Run { file: PathBuf },
+}
+
+#[derive(Debug, Deserialize)]
+struct Scenario {
+    #[serde(default)]
+    name: String,
+    steps: Vec<Step>,
+}
+
+fn default_timeout() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Step {
+    /// Start replicas, all nodes when `nodes` is empty
+    Start {
+        #[serde(default)]
+        nodes: Vec<NodeIndex>,
+    },
+    KillNode {
+        node: NodeIndex,
+    },
+    WaitHeight {
+        height: u64,
+        #[serde(default)]
+        subnet: SubnetIndex,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    Partition {
+        groups: Vec<Vec<NodeIndex>>,
+    },
+    Heal {},
+    Latency {
+        node: NodeIndex,
+        ms: u64,
+    },
+    InstallCanister {
+        wasm: PathBuf,
+        #[serde(default)]
+        subnet: SubnetIndex,
+        #[serde(default)]
+        arg_hex: String,
+    },
+    AssertHealthy {
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    Sleep {
+        seconds: u64,
+    },
+    ClockSkew {
+        node: NodeIndex,
+        offset: String,
+    },
+    KillAtHeight {
+        node: NodeIndex,
+        height: u64,
+    },
+}
+
+struct Runner {
+    topology: Topology,
+    // nodes killed by the scenario are expected to be unresponsive
+    killed: Vec<NodeIndex>,
+}
+
+impl Runner {
+    fn step(&mut self, step: &Step) -> Result<()> {
+        match step {
+            Step::Start { nodes } => {
+                let nodes: Vec<NodeIndex> = if nodes.is_empty() {
+                    self.topology.nodes().map(|node| node.index).collect()
+                } else {
+                    nodes.clone()
+                };
+                for node in nodes {
+                    let backend = backend::for_node(node)?;
+                    match backend.start(node)? {
+                        Some(replica) => println!("  started node {} (pid {})", node, replica.id()),
+                        None => println!("  started node {} ({} backend)", node, backend.name()),
+                    }
+                    self.killed.retain(|killed| *killed != node);
+                }
+            }
+            Step::KillNode { node } => {
+                backend::for_node(*node)?.kill(*node)?;
+                self.killed.push(*node);
+            }
+            Step::WaitHeight {
+                height,
+                subnet,
+                timeout,
+            } => health::wait_height(
+                self.topology.subnet(*subnet)?,
+                *height,
+                Duration::from_secs(*timeout),
+            )?,
+            Step::Partition { groups } => chaos::partition(&self.topology, groups)?,
+            Step::Heal {} => chaos::heal(&self.topology)?,
+            Step::Latency { node, ms } => chaos::set_latency(&self.topology, *node, *ms)?,
+            Step::InstallCanister {
+                wasm,
+                subnet,
+                arg_hex,
+            } => {
+                let wasm = fs::read(wasm)?;
+                let arg = if arg_hex.is_empty() {
+                    candid::Encode!()?
+                } else {
+                    hex::decode(arg_hex)?
+                };
+                let url = self.topology.subnet_url(*subnet)?;
+                let canister_id = block_on(async {
+                    let agent = connect(&url, None).await?;
+                    let canister_id = create_canister(&agent, *subnet, None).await?;
+                    install_code(&agent, canister_id, CanisterInstallMode::Install, wasm, arg)
+                        .await?;
+                    Ok(canister_id)
+                })?;
+                println!("  installed {}", canister_id);
+            }
+            Step::AssertHealthy { timeout } => {
+                for subnet in &self.topology.subnets {
+                    health::wait_healthy(subnet, &self.killed, Duration::from_secs(*timeout))?;
+                }
+            }
+            Step::Sleep { seconds } => thread::sleep(Duration::from_secs(*seconds)),
+            Step::ClockSkew { node, offset } => chaos::set_clock_skew(*node, offset)?,
+            Step::KillAtHeight { node, height } => {
+                let killed_at = chaos::kill_at_height(
+                    &self.topology,
+                    *node,
+                    *height,
+                    Duration::from_millis(20),
+                )?;
+                println!("  killed node {} at height {}", node, killed_at);
+                self.killed.push(*node);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn run(cmd: ScenarioCommand) -> Result<()> {
+    match cmd {
+        ScenarioCommand::Run { file } => {
+            let scenario: Scenario = serde_yaml::from_str(&fs::read_to_string(&file)?)?;
+            let mut runner = Runner {
+                topology: topology::load()?,
+                killed: vec![],
+            };
+
+            println!("scenario {}", scenario.name);
+            let total = scenario.steps.len();
+            for (i, step) in scenario.steps.iter().enumerate() {
+                println!("step {}/{}: {:?}", i + 1, total, step);
+                runner
+                    .step(step)
+                    .map_err(|e| anyhow!("step {} failed: {}", i + 1, e))?;
+            }
+            println!("scenario passed");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,467 @@
+use crate::init::{metrics_port, output_dir};
+use crate::routing::latest_record;
+use crate::topology::{NodeSummary, SubnetSummary, Topology};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use ic_protobuf::registry::crypto::v1::NodePublicKeys;
+use ic_protobuf::registry::node::v1::{ConnectionEndpoint, NodeRecord};
+use ic_protobuf::registry::subnet::v1::{SubnetListRecord, SubnetRecord};
+use ic_registry_keys::{make_node_record_key, make_subnet_list_record_key, make_subnet_record_key};
+use ic_types::{NodeId, PrincipalId, SubnetId};
+use prost::Message;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+// Version of the files written to `tmp/`, bumped whenever their layout changes
+// in a way older files have to be migrated for.
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+// Files of the first releases, which wrote the replica and subnet configs but
+// no topology summary.
+const BASELINE: u32 = 0;
+
+// Files written before versioning was introduced.
+const UNVERSIONED: u32 = 1;
+
+// Node ids are derived from the DER encoding of the ed25519 signing key.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+// JSON5 allows comments, so replica configs carry the version in their first
+// line without the replica having to know about it.
+pub(crate) fn json5_header() -> String {
+    format!("// ic-testnet schema {}\n", SCHEMA_VERSION)
+}
+
+#[derive(Args)]
+pub(crate) struct MigrateArgs {
+    /// Only report the schema version of the files in tmp/
+    #[clap(long)]
+    check: bool,
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+pub(crate) fn version(dir: &Path) -> Result<u32> {
+    let topology = dir.join("topology.json");
+    if !topology.is_file() {
+        if !generated_files(dir, "ic-", ".json5")?.is_empty()
+            && !generated_files(dir, "subnet-", ".json")?.is_empty()
+        {
+            return Ok(BASELINE);
+        }
+        bail!(
+            "{:?} is missing and there are no replica and subnet configs to regenerate it from, run `ic-testnet init` again",
+            topology
+        );
+    }
+
+    let version = read_json(&topology)?
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .map_or(UNVERSIONED, |version| version as u32);
+    if version < UNVERSIONED {
+        bail!(
+            "{:?} has schema {}, which only files without a topology summary have",
+            topology,
+            version
+        );
+    }
+    Ok(version)
+}
+
+fn generated_files(dir: &Path, prefix: &str, suffix: &str) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| {
+                    name.starts_with(prefix) && name.ends_with(suffix)
+                })
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// The node index in the name of a generated file, e.g. 100 in ic-100.json5.
+fn file_index(path: &Path, prefix: &str, suffix: &str) -> Result<u64> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(prefix))
+        .and_then(|name| name.strip_suffix(suffix))
+        .and_then(|index| index.parse().ok())
+        .ok_or(anyhow!("{:?} has no index in its name", path))
+}
+
+fn endpoint(endpoint: Option<ConnectionEndpoint>, index: NodeIndex) -> Result<SocketAddr> {
+    let endpoint = endpoint.ok_or(anyhow!("the record of node {} has no endpoint", index))?;
+    Ok(SocketAddr::new(
+        endpoint.ip_addr.parse::<IpAddr>()?,
+        endpoint.port as u16,
+    ))
+}
+
+// The summary of a node from the keys and the registry ic-prep wrote to the
+// state dir.
+fn baseline_node(state: &Path, index: NodeIndex, metrics_port: u16) -> Result<NodeSummary> {
+    let keys = state
+        .join(format!("node-{}", index))
+        .join("crypto")
+        .join("public_keys.pb");
+    let public_keys = NodePublicKeys::decode(
+        fs::read(&keys)
+            .with_context(|| format!("node {} has no public keys in {:?}", index, keys))?
+            .as_slice(),
+    )?;
+    let signing_key = public_keys
+        .node_signing_pk
+        .ok_or(anyhow!("node {} has no signing key", index))?;
+    let node_id = NodeId::from(PrincipalId::new_self_authenticating(
+        &[ED25519_DER_PREFIX.as_slice(), &signing_key.key_value].concat(),
+    ));
+
+    let store = state.join("ic_registry_local_store");
+    let (_, record) = latest_record(&store, &make_node_record_key(node_id))?;
+    let record = NodeRecord::decode(
+        record
+            .ok_or(anyhow!(
+                "registry {:?} has no record of node {}",
+                store,
+                index
+            ))?
+            .as_slice(),
+    )?;
+    let http = endpoint(record.http, index)?;
+    Ok(NodeSummary {
+        index,
+        node_id: node_id.to_string(),
+        url: format!("http://{}", http),
+        xnet: endpoint(record.xnet, index)?.to_string(),
+        metrics: format!("http://{}", SocketAddr::new(http.ip(), metrics_port)),
+    })
+}
+
+// The members of each subnet in the registry ic-prep wrote to the state dir.
+fn baseline_subnets(state: &Path) -> Result<Vec<(SubnetId, Vec<String>)>> {
+    let store = state.join("ic_registry_local_store");
+    let (_, list) = latest_record(&store, &make_subnet_list_record_key())?;
+    let list = SubnetListRecord::decode(
+        list.ok_or(anyhow!("registry {:?} has no subnet list", store))?
+            .as_slice(),
+    )?;
+
+    let mut subnets = vec![];
+    for subnet_id in list.subnets {
+        let subnet_id = SubnetId::from(
+            PrincipalId::try_from(subnet_id.as_slice())
+                .map_err(|e| anyhow!("invalid subnet id in {:?}: {}", store, e))?,
+        );
+        let (_, record) = latest_record(&store, &make_subnet_record_key(subnet_id))?;
+        let record = SubnetRecord::decode(
+            record
+                .ok_or(anyhow!(
+                    "registry {:?} has no record of subnet {}",
+                    store,
+                    subnet_id
+                ))?
+                .as_slice(),
+        )?;
+        let members = record
+            .membership
+            .iter()
+            .map(|node_id| {
+                PrincipalId::try_from(node_id.as_slice())
+                    .map(|node_id| node_id.to_string())
+                    .map_err(|e| anyhow!("invalid node id in {:?}: {}", store, e))
+            })
+            .collect::<Result<_>>()?;
+        subnets.push((subnet_id, members));
+    }
+    Ok(subnets)
+}
+
+// Regenerates the topology summary the first releases never wrote, from the
+// replica and subnet configs and the state ic-prep initialized.
+fn v0_to_v1(dir: &Path) -> Result<()> {
+    let state = dir.join("state");
+    let metrics_port = metrics_port()?;
+    let mut nodes = BTreeMap::new();
+    for config in generated_files(dir, "ic-", ".json5")? {
+        let index = file_index(&config, "ic-", ".json5")?;
+        nodes.insert(index, baseline_node(&state, index, metrics_port)?);
+    }
+
+    let registry_subnets = baseline_subnets(&state)?;
+    let mut subnets = vec![];
+    for config in generated_files(dir, "subnet-", ".json")? {
+        let index = file_index(&config, "subnet-", ".json")?;
+        let members = read_json(&config)?["nodes"]
+            .as_object()
+            .map_or(vec![], |nodes| nodes.keys().cloned().collect());
+        let members = members
+            .iter()
+            .map(|member| {
+                member
+                    .parse()
+                    .ok()
+                    .and_then(|member| nodes.remove(&member))
+                    .ok_or(anyhow!(
+                        "node {} of subnet {} has no replica config",
+                        member,
+                        index
+                    ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (subnet_id, _) = registry_subnets
+            .iter()
+            .find(|(_, registry_members)| {
+                !members.is_empty()
+                    && members
+                        .iter()
+                        .all(|member| registry_members.contains(&member.node_id))
+            })
+            .ok_or(anyhow!(
+                "the registry has no subnet with the nodes of subnet {}",
+                index
+            ))?;
+        subnets.push(SubnetSummary {
+            index,
+            subnet_id: subnet_id.to_string(),
+            nodes: members,
+        });
+    }
+
+    let topology = Topology {
+        schema_version: UNVERSIONED,
+        root_key: state.join("nns_public_key.pem"),
+        // the first releases always made subnet 0 the NNS
+        nns_subnet: 0,
+        subnets,
+        unassigned_nodes: nodes.into_values().collect(),
+    };
+    write_json(
+        &dir.join("topology.json"),
+        &serde_json::to_value(&topology)?,
+    )
+}
+
+// Stamps the topology summary, subnet configs and replica configs.
+fn v1_to_v2(dir: &Path) -> Result<()> {
+    let topology = dir.join("topology.json");
+    let mut value = read_json(&topology)?;
+    value["schema_version"] = Value::from(2);
+    write_json(&topology, &value)?;
+
+    for subnet in generated_files(dir, "subnet-", ".json")? {
+        let mut value = read_json(&subnet)?;
+        value["schema_version"] = Value::from(2);
+        write_json(&subnet, &value)?;
+    }
+
+    for config in generated_files(dir, "ic-", ".json5")? {
+        let json5 = fs::read_to_string(&config)?;
+        if !json5.starts_with("// ic-testnet schema") {
+            fs::write(&config, format!("// ic-testnet schema 2\n{}", json5))?;
+        }
+    }
+
+    Ok(())
+}
+
+// MIGRATIONS[i] upgrades files of version i to i + 1.
+const MIGRATIONS: &[fn(&Path) -> Result<()>] = &[v0_to_v1, v1_to_v2];
+
+fn migrate(dir: &Path, version: u32) -> Result<()> {
+    for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migrate(dir)?;
+        println!("migrated schema {} to {}", from, from + 1);
+    }
+    Ok(())
+}
+
+pub(crate) fn run(args: MigrateArgs) -> Result<()> {
+    let dir = output_dir()?;
+    let version = version(&dir)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "{:?} has schema {}, newer than the {} supported by this release",
+            dir,
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    if args.check || version == SCHEMA_VERSION {
+        println!("schema {} (current {})", version, SCHEMA_VERSION);
+        return Ok(());
+    }
+
+    migrate(&dir, version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_protobuf::registry::crypto::v1::PublicKey;
+    use ic_registry_local_store::{KeyMutation, LocalStoreImpl, LocalStoreWriter};
+    use ic_types::RegistryVersion;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    fn node_id(index: NodeIndex) -> NodeId {
+        NodeId::from(PrincipalId::new_self_authenticating(
+            &[ED25519_DER_PREFIX.as_slice(), &[index as u8; 32]].concat(),
+        ))
+    }
+
+    fn mutation(key: String, value: impl Message) -> KeyMutation {
+        KeyMutation {
+            key,
+            value: Some(value.encode_to_vec()),
+        }
+    }
+
+    fn node_record(index: NodeIndex) -> NodeRecord {
+        let endpoint = |port| ConnectionEndpoint {
+            ip_addr: format!("127.0.1.{}", index - 99),
+            port,
+        };
+        NodeRecord {
+            http: Some(endpoint(8080)),
+            xnet: Some(endpoint(2497)),
+            ..Default::default()
+        }
+    }
+
+    // The files of the first releases: node 100 in subnet 0 and node 101
+    // unassigned.
+    fn baseline() -> Result<TempDir> {
+        let dir = tempfile::tempdir()?;
+        let state = dir.path().join("state");
+        let subnet_id = SubnetId::from(PrincipalId::new_subnet_test_id(0));
+        let mut mutations = vec![
+            mutation(
+                make_subnet_list_record_key(),
+                SubnetListRecord {
+                    subnets: vec![subnet_id.get().to_vec()],
+                },
+            ),
+            mutation(
+                make_subnet_record_key(subnet_id),
+                SubnetRecord {
+                    membership: vec![node_id(100).get().to_vec()],
+                    ..Default::default()
+                },
+            ),
+        ];
+        for index in [100, 101] {
+            fs::write(dir.path().join(format!("ic-{}.json5", index)), "{}")?;
+            let crypto = state.join(format!("node-{}", index)).join("crypto");
+            fs::create_dir_all(&crypto)?;
+            let public_keys = NodePublicKeys {
+                node_signing_pk: Some(PublicKey {
+                    key_value: vec![index as u8; 32],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            fs::write(crypto.join("public_keys.pb"), public_keys.encode_to_vec())?;
+            mutations.push(mutation(
+                make_node_record_key(node_id(index)),
+                node_record(index),
+            ));
+        }
+        write_json(
+            &dir.path().join("subnet-0.json"),
+            &json!({ "id": 0, "nodes": { "100": {} } }),
+        )?;
+        LocalStoreImpl::new(state.join("ic_registry_local_store"))
+            .store(RegistryVersion::from(1), mutations)?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn detects_the_baseline_layout() -> Result<()> {
+        let dir = baseline()?;
+        assert_eq!(version(dir.path())?, BASELINE);
+
+        fs::remove_file(dir.path().join("subnet-0.json"))?;
+        assert!(version(dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_versions_below_unversioned() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let topology = dir.path().join("topology.json");
+        write_json(&topology, &json!({ "schema_version": 0 }))?;
+        assert!(version(dir.path()).is_err());
+
+        write_json(&topology, &json!({}))?;
+        assert_eq!(version(dir.path())?, UNVERSIONED);
+        Ok(())
+    }
+
+    #[test]
+    fn regenerates_the_topology_of_the_baseline() -> Result<()> {
+        let dir = baseline()?;
+        v0_to_v1(dir.path())?;
+        assert_eq!(version(dir.path())?, UNVERSIONED);
+
+        let topology: Topology =
+            serde_json::from_value(read_json(&dir.path().join("topology.json"))?)?;
+        assert_eq!(topology.subnets.len(), 1);
+        let subnet = &topology.subnets[0];
+        assert_eq!(subnet.index, 0);
+        assert_eq!(
+            subnet.subnet_id,
+            PrincipalId::new_subnet_test_id(0).to_string()
+        );
+        assert_eq!(subnet.nodes.len(), 1);
+        assert_eq!(subnet.nodes[0].node_id, node_id(100).to_string());
+        assert_eq!(subnet.nodes[0].url, "http://127.0.1.1:8080");
+        assert_eq!(subnet.nodes[0].xnet, "127.0.1.1:2497");
+        assert_eq!(topology.unassigned_nodes.len(), 1);
+        assert_eq!(topology.unassigned_nodes[0].index, 101);
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_the_baseline_to_the_current_schema() -> Result<()> {
+        let dir = baseline()?;
+        migrate(dir.path(), version(dir.path())?)?;
+        assert_eq!(version(dir.path())?, SCHEMA_VERSION);
+
+        let subnet = read_json(&dir.path().join("subnet-0.json"))?;
+        assert_eq!(subnet["schema_version"], SCHEMA_VERSION);
+        let config = fs::read_to_string(dir.path().join("ic-100.json5"))?;
+        assert!(config.starts_with(&json5_header()));
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_unversioned_files_once() -> Result<()> {
+        let dir = baseline()?;
+        v0_to_v1(dir.path())?;
+        migrate(dir.path(), UNVERSIONED)?;
+        migrate(dir.path(), version(dir.path())?)?;
+
+        let config = fs::read_to_string(dir.path().join("ic-100.json5"))?;
+        assert_eq!(config.matches("// ic-testnet schema").count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+#[cfg(feature = "nns")]
+use crate::identities;
+use crate::init::{node_state_dir, output_dir};
+use crate::process;
+use crate::topology;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::num::NonZeroU32;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"ic-testnet sealed v1\n";
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+#[derive(Subcommand)]
+pub(crate) enum SecretsCommand {
+    /// Encrypt the secret key stores of all nodes and the identities with the
+    /// passphrase in IC_TESTNET_PASSPHRASE or IC_TESTNET_PASSPHRASE_FILE
+    Seal,
+    /// Decrypt everything `seal` encrypted
+    Unseal,
+    /// List the key material and whether it is sealed
+    Status,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    path: String,
+    mode: u32,
+    data: Vec<u8>,
+}
+
+pub(crate) fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var("IC_TESTNET_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    if let Ok(file) = env::var("IC_TESTNET_PASSPHRASE_FILE") {
+        let passphrase = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read the passphrase from {}", file))?;
+        return Ok(passphrase.trim_end_matches('\n').to_string());
+    }
+    bail!("sealed keys need IC_TESTNET_PASSPHRASE or IC_TESTNET_PASSPHRASE_FILE")
+}
+
+fn key(passphrase: &str, salt: &[u8]) -> Result<LessSafeKey> {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| anyhow!("invalid key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn sealed_path(dir: &Path) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".sealed");
+    dir.with_file_name(name)
+}
+
+// File modes are kept on unix, elsewhere files get the default permissions.
+#[cfg(unix)]
+fn mode(path: &Path) -> Result<u32> {
+    Ok(fs::metadata(path)?.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode(_path: &Path) -> Result<u32> {
+    Ok(0o600)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    Ok(fs::set_permissions(path, fs::Permissions::from_mode(mode))?)
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+fn collect(root: &Path, dir: &Path, files: &mut Vec<SealedFile>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(root, &path, files)?;
+        } else {
+            files.push(SealedFile {
+                path: path.strip_prefix(root)?.to_string_lossy().to_string(),
+                mode: mode(&path)?,
+                data: fs::read(&path)?,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Replaces a directory with `<dir>.sealed`: a random salt and nonce followed
+// by the files of the directory, encrypted with ChaCha20-Poly1305 under a key
+// derived from the passphrase with PBKDF2.
+pub(crate) fn seal(dir: &Path, passphrase: &str) -> Result<()> {
+    let mut files = vec![];
+    collect(dir, dir, &mut files)?;
+    let mut data = serde_cbor::to_vec(&files)?;
+
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| anyhow!("no randomness"))?;
+    rng.fill(&mut nonce).map_err(|_| anyhow!("no randomness"))?;
+    key(passphrase, &salt)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| anyhow!("failed to encrypt {:?}", dir))?;
+
+    let sealed = sealed_path(dir);
+    let parts: [&[u8]; 4] = [MAGIC, &salt, &nonce, &data];
+    fs::write(&sealed, parts.concat()).with_context(|| format!("failed to write {:?}", sealed))?;
+    set_mode(&sealed, 0o600)?;
+    fs::remove_dir_all(dir).with_context(|| format!("failed to remove {:?}", dir))
+}
+
+pub(crate) fn unseal(dir: &Path, passphrase: &str) -> Result<()> {
+    let sealed = sealed_path(dir);
+    let bytes = fs::read(&sealed).with_context(|| format!("failed to read {:?}", sealed))?;
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        bail!("{:?} is not sealed by ic-testnet", sealed);
+    };
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        bail!("{:?} is truncated", sealed);
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let mut data = ciphertext.to_vec();
+    let plaintext = key(passphrase, salt)?
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("invalid nonce"))?,
+            Aad::empty(),
+            &mut data,
+        )
+        .map_err(|_| anyhow!("wrong passphrase for {:?}", sealed))?;
+    let files: Vec<SealedFile> = serde_cbor::from_slice(plaintext)?;
+
+    for file in files {
+        let path = dir.join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, &file.data)?;
+        set_mode(&path, file.mode)?;
+    }
+    fs::remove_file(&sealed)?;
+    Ok(())
+}
+
+pub(crate) fn is_sealed(dir: &Path) -> bool {
+    sealed_path(dir).is_file()
+}
+
+// Secret key stores of the nodes in a state directory. Every state directory
+// starts as a copy of tmp/state and holds the keys of all nodes.
+fn crypto_dirs(state_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(state_dir) else {
+        return vec![];
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("node-"))
+        .map(|entry| entry.path().join("crypto"))
+        .filter(|dir| dir.is_dir() || is_sealed(dir))
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+fn key_material() -> Result<Vec<PathBuf>> {
+    let mut dirs = crypto_dirs(&output_dir()?.join("state"));
+    for node in topology::load()?.nodes() {
+        dirs.extend(crypto_dirs(&node_state_dir(node.index)?));
+    }
+    #[cfg(feature = "nns")]
+    {
+        let dir = identities::identities_dir()?;
+        if dir.is_dir() || is_sealed(&dir) {
+            dirs.push(dir);
+        }
+    }
+    Ok(dirs)
+}
+
+// Key store a node's replica reads, in its own state directory.
+pub(crate) fn node_crypto_dir(index: NodeIndex) -> Result<PathBuf> {
+    Ok(node_state_dir(index)?
+        .join(format!("node-{}", index))
+        .join("crypto"))
+}
+
+// Key stores `run` decrypted for its replicas, sealed again when it exits.
+pub(crate) struct Unsealed {
+    passphrase: String,
+    dirs: Vec<(NodeIndex, PathBuf)>,
+}
+
+pub(crate) fn unseal_nodes(nodes: &[NodeIndex]) -> Result<Unsealed> {
+    let mut unsealed = Unsealed {
+        passphrase: String::new(),
+        dirs: vec![],
+    };
+    for node in nodes {
+        // the copy of tmp/state carries the sealed key stores along
+        process::seed_state_dir(*node)?;
+        let dir = node_crypto_dir(*node)?;
+        if !is_sealed(&dir) {
+            continue;
+        }
+        if unsealed.passphrase.is_empty() {
+            unsealed.passphrase = passphrase()?;
+        }
+        unseal(&dir, &unsealed.passphrase)?;
+        unsealed.dirs.push((*node, dir));
+    }
+    Ok(unsealed)
+}
+
+// A replica that is still running, e.g. one `run` failed to stop, keeps
+// reading its key store, so that one stays decrypted.
+impl Drop for Unsealed {
+    fn drop(&mut self) {
+        for (node, dir) in &self.dirs {
+            if let Some(pid) = process::replica_pid(*node) {
+                eprintln!(
+                    "warning: the replica of node {} still runs as pid {}, {:?} stays unsealed, seal it with `ic-testnet secrets seal` once it stopped",
+                    node, pid, dir
+                );
+                continue;
+            }
+            match seal(dir, &self.passphrase) {
+                Ok(()) => println!("sealed {:?} again", dir),
+                Err(e) => eprintln!("failed to seal {:?}: {:#}", dir, e),
+            }
+        }
+    }
+}
+
+pub(crate) fn run(cmd: SecretsCommand) -> Result<()> {
+    let dirs = key_material()?;
+    match cmd {
+        SecretsCommand::Seal => {
+            let passphrase = passphrase()?;
+            for dir in dirs.iter().filter(|dir| !is_sealed(dir)) {
+                seal(dir, &passphrase)?;
+                println!("sealed {:?}", dir);
+            }
+        }
+        SecretsCommand::Unseal => {
+            let passphrase = passphrase()?;
+            for dir in dirs.iter().filter(|dir| is_sealed(dir)) {
+                unseal(dir, &passphrase)?;
+                println!("unsealed {:?}", dir);
+            }
+        }
+        SecretsCommand::Status => {
+            for dir in &dirs {
+                let state = if is_sealed(dir) {
+                    "sealed"
+                } else {
+                    "plaintext"
+                };
+                println!("{:<9} {}", state, dir.display());
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,382 @@
+use crate::backend;
+use crate::chaos;
+use crate::health;
+use crate::metrics::{self, Exposition};
+use crate::namespace;
+use crate::progress::{self, Progress};
+use crate::snapshot;
+use crate::topology::{self, Topology};
+use anyhow::{anyhow, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+const CONTROL_PORT: u16 = 4900;
+const METRICS_PORT: u16 = 4901;
+// Longest a /progress request waits for a change.
+const MAX_POLL_SECS: u64 = 60;
+
+#[derive(Args)]
+pub(crate) struct ServeArgs {
+    /// Address the control API listens on, 127.0.0.1:4900 plus the port
+    /// offset of a named testnet by default
+    #[clap(long)]
+    listen: Option<String>,
+    /// Address the metrics of all replicas are served on for Prometheus,
+    /// 127.0.0.1:4901 plus the port offset of a named testnet by default
+    #[clap(long)]
+    metrics_listen: Option<String>,
+}
+
+// Client errors are reported with 400, everything else with 500.
+enum ApiError {
+    BadRequest(String),
+    NotFound,
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> ApiError {
+        ApiError::Internal(e)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError {
+        ApiError::BadRequest(e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct StopRequest {
+    #[serde(default = "default_stop_timeout")]
+    timeout_secs: u64,
+}
+
+fn default_stop_timeout() -> u64 {
+    60
+}
+
+#[derive(Deserialize)]
+struct PartitionRequest {
+    groups: Vec<Vec<NodeIndex>>,
+}
+
+#[derive(Deserialize)]
+struct LatencyRequest {
+    node: NodeIndex,
+    ms: u64,
+}
+
+#[derive(Deserialize)]
+struct ClockSkewRequest {
+    node: NodeIndex,
+    offset: String,
+}
+
+#[derive(Deserialize)]
+struct KillAtHeightRequest {
+    node: NodeIndex,
+    height: u64,
+}
+
+fn node(topology: &Topology, index: &str) -> Result<NodeIndex, ApiError> {
+    let index = index
+        .parse()
+        .map_err(|_| ApiError::BadRequest(format!("invalid node index {}", index)))?;
+    topology
+        .node(index)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    Ok(index)
+}
+
+fn body<T: for<'de> Deserialize<'de>>(body: &str) -> Result<T, ApiError> {
+    // an empty body takes the defaults
+    let body = if body.trim().is_empty() { "{}" } else { body };
+    Ok(serde_json::from_str(body)?)
+}
+
+// A query parameter, e.g. `since` of /progress?since=12.
+fn param<T: FromStr>(path: &str, name: &str) -> Result<Option<T>, ApiError> {
+    let query = path.split_once('?').map_or("", |(_, query)| query);
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| {
+            value
+                .parse()
+                .map_err(|_| ApiError::BadRequest(format!("invalid {} {}", name, value)))
+        })
+        .transpose()
+}
+
+fn health(topology: &Topology) -> Value {
+    let subnets: Vec<Value> = topology
+        .subnets
+        .iter()
+        .map(|subnet| {
+            let nodes: Vec<Value> = health::heights(subnet)
+                .into_iter()
+                .map(|(node, height)| {
+                    json!({
+                        "node": node,
+                        "running": backend::for_node(node)
+                            .map_or(false, |backend| backend.running(node)),
+                        "height": height,
+                    })
+                })
+                .collect();
+            json!({ "subnet": subnet.index, "nodes": nodes })
+        })
+        .collect();
+    json!({ "subnets": subnets })
+}
+
+fn route(
+    topology: &Topology,
+    progress: &Progress,
+    method: &Method,
+    path: &str,
+    body_text: &str,
+) -> Result<Value, ApiError> {
+    let segments: Vec<&str> = path
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match (method, segments.as_slice()) {
+        (Method::Get, ["topology"]) => Ok(serde_json::to_value(topology)?),
+        (Method::Get, ["health"]) => Ok(health(topology)),
+        (Method::Get, ["progress"]) => {
+            let since = param(path, "since")?.unwrap_or(0);
+            let timeout = param(path, "timeout_secs")?
+                .unwrap_or(30)
+                .min(MAX_POLL_SECS);
+            let (seq, subnets) = progress.wait(since, Duration::from_secs(timeout));
+            Ok(json!({ "seq": seq, "subnets": subnets }))
+        }
+        (Method::Post, ["nodes", index, "start"]) => {
+            let index = node(topology, index)?;
+            let backend = backend::for_node(index)?;
+            match backend.start(index)? {
+                Some(replica) => Ok(json!({ "node": index, "pid": replica.id() })),
+                None => Ok(json!({ "node": index, "backend": backend.name() })),
+            }
+        }
+        (Method::Post, ["nodes", index, "stop"]) => {
+            let index = node(topology, index)?;
+            let request: StopRequest = body(body_text)?;
+            let graceful =
+                backend::for_node(index)?.stop(index, Duration::from_secs(request.timeout_secs))?;
+            Ok(json!({ "node": index, "graceful": graceful }))
+        }
+        (Method::Post, ["nodes", index, "kill"]) => {
+            let index = node(topology, index)?;
+            backend::for_node(index)?.kill(index)?;
+            Ok(json!({ "node": index }))
+        }
+        (Method::Get, ["snapshots"]) => Ok(json!({ "snapshots": snapshot::list()? })),
+        (Method::Post, ["snapshots", name]) => {
+            snapshot::save(topology, name)?;
+            Ok(json!({ "snapshot": name }))
+        }
+        (Method::Post, ["snapshots", name, "restore"]) => {
+            snapshot::restore(topology, name)?;
+            Ok(json!({ "snapshot": name }))
+        }
+        (Method::Post, ["chaos", "partition"]) => {
+            let request: PartitionRequest = body(body_text)?;
+            chaos::partition(topology, &request.groups)?;
+            Ok(json!({}))
+        }
+        (Method::Post, ["chaos", "heal"]) => {
+            chaos::heal(topology)?;
+            Ok(json!({}))
+        }
+        (Method::Post, ["chaos", "latency"]) => {
+            let request: LatencyRequest = body(body_text)?;
+            node(topology, &request.node.to_string())?;
+            chaos::set_latency(topology, request.node, request.ms)?;
+            Ok(json!({}))
+        }
+        (Method::Post, ["chaos", "clock-skew"]) => {
+            let request: ClockSkewRequest = body(body_text)?;
+            node(topology, &request.node.to_string())?;
+            chaos::set_clock_skew(request.node, &request.offset)?;
+            Ok(json!({}))
+        }
+        (Method::Post, ["chaos", "kill-at-height"]) => {
+            let request: KillAtHeightRequest = body(body_text)?;
+            let height = chaos::kill_at_height(
+                topology,
+                request.node,
+                request.height,
+                Duration::from_millis(20),
+            )?;
+            Ok(json!({ "node": request.node, "height": height }))
+        }
+        _ => Err(ApiError::NotFound),
+    }
+}
+
+fn respond(topology: &Topology, progress: &Progress, mut request: Request) -> Result<()> {
+    if request.method() == &Method::Get && request.url().split('?').next() == Some("/events") {
+        println!("{} {} stream", request.method(), request.url());
+        // ends with an error once the client disconnects
+        let _ = progress::stream(progress, &mut request.into_writer());
+        return Ok(());
+    }
+
+    let mut body_text = String::new();
+    request.as_reader().read_to_string(&mut body_text)?;
+
+    let (status, body) = match route(
+        topology,
+        progress,
+        request.method(),
+        request.url(),
+        &body_text,
+    ) {
+        Ok(body) => (200, body),
+        Err(ApiError::BadRequest(e)) => (400, json!({ "error": e })),
+        Err(ApiError::NotFound) => (404, json!({ "error": "not found" })),
+        Err(ApiError::Internal(e)) => (500, json!({ "error": format!("{:#}", e) })),
+    };
+    println!("{} {} {}", request.method(), request.url(), status);
+
+    let header = Header::from_bytes("Content-Type", "application/json")
+        .map_err(|_| anyhow!("invalid header"))?;
+    request.respond(
+        Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(header),
+    )?;
+    Ok(())
+}
+
+// The metrics of every node scraped at once, labelled with its node index and
+// subnet, and ic_testnet_scrape_up telling which nodes answered.
+fn aggregate_metrics(topology: &Topology) -> String {
+    let mut targets: Vec<(String, Option<String>, &str)> = vec![];
+    for subnet in &topology.subnets {
+        for node in &subnet.nodes {
+            targets.push((
+                node.index.to_string(),
+                Some(subnet.index.to_string()),
+                &node.metrics,
+            ));
+        }
+    }
+    for node in &topology.unassigned_nodes {
+        targets.push((node.index.to_string(), None, &node.metrics));
+    }
+
+    let scraped: Vec<Result<String>> = thread::scope(|scope| {
+        let scrapes: Vec<_> = targets
+            .iter()
+            .map(|(_, _, url)| scope.spawn(move || metrics::scrape(url)))
+            .collect();
+        scrapes
+            .into_iter()
+            .map(|scrape| {
+                scrape
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("scrape panicked")))
+            })
+            .collect()
+    });
+
+    let mut exposition = Exposition::default();
+    let mut up = String::from(
+        "# HELP ic_testnet_scrape_up Whether the metrics of the node could be scraped\n# TYPE ic_testnet_scrape_up gauge\n",
+    );
+    for ((node, subnet, _), metrics) in targets.iter().zip(scraped) {
+        let mut labels = vec![("node_index", node.clone())];
+        if let Some(subnet) = subnet {
+            labels.push(("subnet", subnet.clone()));
+        }
+        let value = match metrics {
+            Ok(metrics) => {
+                exposition.add(&metrics, &labels);
+                1
+            }
+            Err(_) => 0,
+        };
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, value))
+            .collect();
+        up.push_str(&format!(
+            "ic_testnet_scrape_up{{{}}} {}\n",
+            labels.join(","),
+            value
+        ));
+    }
+    exposition.add(&up, &[]);
+
+    exposition.render()
+}
+
+// Scrapes the nodes anew for every request, so Prometheus needs a single
+// target for the whole testnet.
+fn serve_metrics(server: Server, topology: Arc<Topology>) {
+    for request in server.incoming_requests() {
+        let topology = Arc::clone(&topology);
+        thread::spawn(move || {
+            let header = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                .expect("valid header");
+            let response = Response::from_string(aggregate_metrics(&topology)).with_header(header);
+            if let Err(e) = request.respond(response) {
+                eprintln!("failed to respond: {}", e);
+            }
+        });
+    }
+}
+
+pub(crate) fn run(args: ServeArgs) -> Result<()> {
+    let topology = Arc::new(topology::load()?);
+    let progress = Progress::watch(Arc::clone(&topology));
+    let listen = match args.listen {
+        Some(listen) => listen,
+        None => format!("127.0.0.1:{}", CONTROL_PORT + namespace::port_offset()?),
+    };
+    let server = Server::http(&listen).map_err(|e| anyhow!("{}: {}", listen, e))?;
+    println!("control API listening on http://{}", listen);
+
+    let metrics_listen = match args.metrics_listen {
+        Some(listen) => listen,
+        None => format!("127.0.0.1:{}", METRICS_PORT + namespace::port_offset()?),
+    };
+    let metrics_server =
+        Server::http(&metrics_listen).map_err(|e| anyhow!("{}: {}", metrics_listen, e))?;
+    println!(
+        "replica metrics served on http://{}/metrics",
+        metrics_listen
+    );
+    let metrics_topology = Arc::clone(&topology);
+    thread::spawn(move || serve_metrics(metrics_server, metrics_topology));
+
+    // requests such as kill-at-height and event streams block, so each one gets its own thread
+    for request in server.incoming_requests() {
+        let topology = Arc::clone(&topology);
+        let progress = Arc::clone(&progress);
+        thread::spawn(move || {
+            if let Err(e) = respond(&topology, &progress, request) {
+                eprintln!("failed to respond: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use crate::backend;
+use crate::hooks::{self, Event};
+use crate::init::{node_state_dir, output_dir};
+use crate::topology::Topology;
+use anyhow::{bail, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Subcommand)]
+pub(crate) enum SnapshotCommand {
+    /// Copy the state of every stopped node under a name
+    Save { name: String },
+    /// Replace the state of every stopped node with a saved snapshot
+    Restore { name: String },
+    /// List the saved snapshots
+    List,
+}
+
+fn snapshots_dir() -> Result<PathBuf> {
+    Ok(output_dir()?.join("snapshots"))
+}
+
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    let status = Command::new("cp").arg("-a").arg(from).arg(to).status()?;
+    if !status.success() {
+        bail!("failed to copy {:?} to {:?}", from, to);
+    }
+    Ok(())
+}
+
+// Replicas keep writing their state, so snapshots are only consistent while
+// all of them are stopped.
+fn ensure_stopped(topology: &Topology) -> Result<Vec<NodeIndex>> {
+    let nodes: Vec<NodeIndex> = topology.nodes().map(|node| node.index).collect();
+    let mut running = vec![];
+    for node in &nodes {
+        if backend::is_running(*node)? {
+            running.push(*node);
+        }
+    }
+    if !running.is_empty() {
+        bail!("nodes {:?} are running, stop them first", running);
+    }
+    Ok(nodes)
+}
+
+pub(crate) fn save(topology: &Topology, name: &str) -> Result<()> {
+    let nodes = ensure_stopped(topology)?;
+    let dir = snapshots_dir()?.join(name);
+    if dir.exists() {
+        bail!("snapshot {} already exists", name);
+    }
+    fs::create_dir_all(&dir)?;
+
+    for node in nodes {
+        let state_dir = node_state_dir(node)?;
+        if state_dir.is_dir() {
+            copy(&state_dir, &dir.join(format!("state-{}", node)))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn restore(topology: &Topology, name: &str) -> Result<()> {
+    let nodes = ensure_stopped(topology)?;
+    let dir = snapshots_dir()?.join(name);
+    if !dir.is_dir() {
+        bail!("no snapshot {}", name);
+    }
+
+    for node in nodes {
+        let state_dir = node_state_dir(node)?;
+        if state_dir.is_dir() {
+            fs::remove_dir_all(&state_dir)?;
+        }
+        let saved = dir.join(format!("state-{}", node));
+        // nodes without saved state start from the initial state again
+        if saved.is_dir() {
+            copy(&saved, &state_dir)?;
+        }
+    }
+
+    hooks::emit(
+        Event::SnapshotRestore,
+        &format!("restored snapshot {}", name),
+        json!({ "snapshot": name }),
+    );
+    Ok(())
+}
+
+pub(crate) fn list() -> Result<Vec<String>> {
+    let dir = snapshots_dir()?;
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+pub(crate) fn run(cmd: SnapshotCommand) -> Result<()> {
+    let topology = crate::topology::load()?;
+    match cmd {
+        SnapshotCommand::Save { name } => {
+            save(&topology, &name)?;
+            println!("saved snapshot {}", name);
+        }
+        SnapshotCommand::Restore { name } => {
+            restore(&topology, &name)?;
+            println!("restored snapshot {}", name);
+        }
+        SnapshotCommand::List => {
+            for name in list()? {
+                println!("{}", name);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::identities;
+use crate::init::nns_url;
+use crate::propose::submit;
+use crate::tools::run_ic_binary_with_env;
+use anyhow::{anyhow, bail, Context, Result};
+use candid::Principal;
+use clap::Subcommand;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+const SNS_WASMS: [(&str, &str); 6] = [
+    ("root", "sns-root-canister.wasm.gz"),
+    ("governance", "sns-governance-canister.wasm.gz"),
+    ("ledger", "ic-icrc1-ledger.wasm.gz"),
+    ("swap", "sns-swap-canister.wasm.gz"),
+    ("archive", "ic-icrc1-archive.wasm.gz"),
+    ("index", "ic-icrc1-index-ng.wasm.gz"),
+];
+
+// The part of sns_init.yaml checked before proposing.
+#[derive(Deserialize)]
+struct SnsInitConfig {
+    #[serde(default)]
+    dapp_canisters: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum SnsCommand {
+    /// Upload the SNS canister wasms to SNS-W
+    UploadWasms {
+        /// URL of an NNS node, defaults to the first configured node
+        #[clap(long)]
+        nns_url: Option<String>,
+        /// Directory containing the SNS canister wasms
+        #[clap(long, env = "SNS_WASM_DIR", default_value = "ic/artifacts/canisters")]
+        wasm_dir: PathBuf,
+    },
+    /// Propose the creation of a test SNS for a deployed dapp canister
+    Launch {
+        /// URL of an NNS node, defaults to the first configured node
+        #[clap(long)]
+        nns_url: Option<String>,
+        /// Dapp canister controlled by the SNS, NNS root must be one of its controllers
+        #[clap(long)]
+        dapp: String,
+        /// SNS init config (sns_init.yaml)
+        #[clap(long)]
+        init_config: PathBuf,
+    },
+}
+
+pub(crate) fn run(cmd: SnsCommand) -> Result<()> {
+    match cmd {
+        SnsCommand::UploadWasms {
+            nns_url: url,
+            wasm_dir,
+        } => {
+            let url = url.unwrap_or(nns_url());
+
+            for (canister_type, file) in SNS_WASMS {
+                let wasm = wasm_dir.join(file);
+                if !wasm.is_file() {
+                    bail!("missing SNS {} wasm {:?}", canister_type, wasm);
+                }
+
+                let output = submit(
+                    &url,
+                    None,
+                    &format!("Add SNS {} wasm", canister_type),
+                    vec![
+                        "propose-to-add-wasm-to-sns-wasm".to_string(),
+                        "--wasm-module-path".to_string(),
+                        wasm.display().to_string(),
+                        "--canister-type".to_string(),
+                        canister_type.to_string(),
+                    ],
+                )?;
+                print!("{}", output);
+            }
+        }
+        SnsCommand::Launch {
+            nns_url: url,
+            dapp,
+            init_config,
+        } => {
+            let dapp_id = Principal::from_text(&dapp)
+                .map_err(|e| anyhow!("invalid dapp canister id {}: {}", dapp, e))?;
+            let config: SnsInitConfig = serde_yaml::from_str(&fs::read_to_string(&init_config)?)
+                .with_context(|| format!("invalid SNS init config {:?}", init_config))?;
+            if !config
+                .dapp_canisters
+                .iter()
+                .any(|canister| Principal::from_text(canister.trim()).ok() == Some(dapp_id))
+            {
+                bail!(
+                    "{} is not listed in the dapp_canisters of {:?}",
+                    dapp,
+                    init_config
+                );
+            }
+
+            // the sns tool proposes as the selected dfx identity, the one of
+            // the majority neuron when identities were generated
+            let mut args = vec![
+                "propose".to_string(),
+                "--network".to_string(),
+                url.unwrap_or(nns_url()),
+            ];
+            let mut envs = vec![];
+            match identities::majority()? {
+                Some(identity) => {
+                    args.extend(["--neuron-id".to_string(), identity.neuron_id.to_string()]);
+                    envs.push(("DFX_CONFIG_ROOT", identities::dfx_config_root(&identity)?));
+                }
+                None => args.push("--test-neuron-proposer".to_string()),
+            }
+            args.push(init_config.display().to_string());
+            let output = run_ic_binary_with_env("sns", &args, &envs)?;
+            print!("{}", output);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use crate::config::{SubnetSettings, TestnetConfig};
+use crate::health;
+use crate::init::{self, InitArgs, NODE_INDEX};
+use crate::process;
+use crate::tools::ic_binary;
+use crate::topology;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+// Finalized height proving that the replica makes progress.
+const PROGRESS_HEIGHT: u64 = 3;
+
+// The commonly used flags of ic-starter. Like ic-starter this runs a single
+// node subnet in the foreground. Flags this tool has no equivalent for are
+// accepted and reported as ignored.
+#[derive(Args)]
+pub(crate) struct StarterArgs {
+    /// Replica binary, defaults to the one in IC_BIN_DIR
+    #[clap(long)]
+    replica_path: Option<PathBuf>,
+    /// Directory for the generated configs and state
+    #[clap(long)]
+    state_dir: Option<PathBuf>,
+    #[clap(long, default_value = "8080")]
+    http_port: u16,
+    /// Written once the replica serves HTTP requests
+    #[clap(long)]
+    http_port_file: Option<PathBuf>,
+    #[clap(long)]
+    replica_version: Option<String>,
+    /// Only `application` is supported
+    #[clap(long)]
+    subnet_type: Option<String>,
+
+    #[clap(long)]
+    metrics_addr: Option<String>,
+    #[clap(long)]
+    log_level: Option<String>,
+    #[clap(long)]
+    subnet_features: Vec<String>,
+    #[clap(long)]
+    initial_notary_delay_millis: Option<u64>,
+    #[clap(long)]
+    unit_delay_millis: Option<u64>,
+    #[clap(long)]
+    dkg_interval_length: Option<u64>,
+    #[clap(long)]
+    consensus_pool_backend: Option<String>,
+    #[clap(long)]
+    provisional_whitelist: Option<String>,
+    #[clap(long)]
+    create_funds_whitelist: Option<String>,
+    #[clap(long)]
+    https_outcalls_uds_path: Option<PathBuf>,
+    #[clap(long)]
+    bitcoin_testnet_uds_path: Option<PathBuf>,
+    #[clap(long)]
+    ecdsa_keyid: Option<String>,
+    #[clap(long)]
+    chain_key_ids: Vec<String>,
+    #[clap(long)]
+    use_specified_ids_allocation_range: bool,
+}
+
+impl StarterArgs {
+    fn ignored(&self) -> Vec<&'static str> {
+        let flags = [
+            ("--metrics-addr", self.metrics_addr.is_some()),
+            ("--log-level", self.log_level.is_some()),
+            ("--subnet-features", !self.subnet_features.is_empty()),
+            (
+                "--consensus-pool-backend",
+                self.consensus_pool_backend.is_some(),
+            ),
+            // every principal is whitelisted already
+            (
+                "--provisional-whitelist",
+                self.provisional_whitelist.is_some(),
+            ),
+            (
+                "--create-funds-whitelist",
+                self.create_funds_whitelist.is_some(),
+            ),
+            (
+                "--https-outcalls-uds-path",
+                self.https_outcalls_uds_path.is_some(),
+            ),
+            (
+                "--bitcoin-testnet-uds-path",
+                self.bitcoin_testnet_uds_path.is_some(),
+            ),
+        ];
+        flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(flag, _)| flag)
+            .collect()
+    }
+
+    // The settings of the single subnet, --ecdsa-keyid names the curve and
+    // key like ecdsa chain key ids without the scheme.
+    fn subnet_settings(&self) -> SubnetSettings {
+        let mut chain_keys = self.chain_key_ids.clone();
+        if let Some(key_id) = &self.ecdsa_keyid {
+            chain_keys.push(format!("ecdsa:{}", key_id));
+        }
+        SubnetSettings {
+            index: 0,
+            dkg_interval_length: self.dkg_interval_length,
+            unit_delay_millis: self.unit_delay_millis,
+            initial_notary_delay_millis: self.initial_notary_delay_millis,
+            chain_keys,
+            ..SubnetSettings::default()
+        }
+    }
+}
+
+fn serves_http(port: u16) -> bool {
+    reqwest::blocking::Client::new()
+        .get(format!("http://127.0.0.1:{}/api/v2/status", port))
+        .timeout(Duration::from_secs(2))
+        .send()
+        .map_or(false, |response| response.status().is_success())
+}
+
+pub(crate) fn run(args: StarterArgs) -> Result<()> {
+    match args.subnet_type.as_deref() {
+        None | Some("application") => {}
+        Some(subnet_type) => bail!("subnet type {} is not supported", subnet_type),
+    }
+    for flag in args.ignored() {
+        eprintln!("ignoring {}, it has no equivalent in ic-testnet", flag);
+    }
+
+    // resolve paths before moving into the state directory
+    let replica = match &args.replica_path {
+        Some(path) => {
+            fs::canonicalize(path).with_context(|| format!("replica {:?} not found", path))?
+        }
+        None => env::current_dir()?.join(ic_binary("replica")),
+    };
+    if let Some(state_dir) = &args.state_dir {
+        fs::create_dir_all(state_dir)?;
+        let state_dir = fs::canonicalize(state_dir)?;
+        env::set_current_dir(&state_dir)?;
+        env::set_var("BASE_DIR", &state_dir);
+    }
+    env::set_var("NODES", format!("127.0.0.1:{}", args.http_port));
+
+    let output_dir = init::output_dir()?;
+    fs::create_dir_all(&output_dir)?;
+    let config = output_dir.join("starter.toml");
+    let testnet = TestnetConfig {
+        subnets: vec![args.subnet_settings()],
+        ..TestnetConfig::default()
+    };
+    fs::write(&config, toml::to_string(&testnet)?)?;
+
+    init::run(InitArgs {
+        config: Some(config),
+        replica_version: args.replica_version.clone(),
+        use_specified_ids_allocation_range: args.use_specified_ids_allocation_range,
+        ..InitArgs::default()
+    })?;
+
+    let version = args
+        .replica_version
+        .clone()
+        .unwrap_or_else(process::replica_version);
+    let mut replica = process::start_node_with(NODE_INDEX, &replica, &version)?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !serves_http(args.http_port) {
+        if let Some(status) = replica.try_wait()? {
+            bail!(
+                "replica exited with {}, see logs/node-{}.log",
+                status,
+                NODE_INDEX
+            );
+        }
+        if Instant::now() > deadline {
+            bail!("replica does not serve HTTP after {:?}", STARTUP_TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    // the port file tells callers the replica is ready, which it is once it
+    // makes blocks
+    let topology = topology::load()?;
+    health::wait_height(topology.subnet(0)?, PROGRESS_HEIGHT, STARTUP_TIMEOUT)
+        .context("the replica serves HTTP but makes no blocks")?;
+    if let Some(file) = &args.http_port_file {
+        fs::write(file, args.http_port.to_string())?;
+    }
+    println!("replica listening on http://127.0.0.1:{}", args.http_port);
+
+    let status = replica.wait()?;
+    if !status.success() {
+        bail!("replica exited with {}", status);
+    }
+    Ok(())
+}
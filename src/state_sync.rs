@@ -0,0 +1,137 @@
+use crate::metrics;
+use crate::topology;
+use anyhow::Result;
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::Serialize;
+
+#[derive(Args)]
+pub(crate) struct StateSyncArgs {
+    /// Nodes to report on, all nodes when omitted
+    #[clap(long = "node")]
+    nodes: Vec<NodeIndex>,
+    /// Print JSON lines instead of a table
+    #[clap(long)]
+    json: bool,
+}
+
+// State syncs of a node since its replica started, from the counters of the
+// state manager and the state sync manager.
+#[derive(Serialize)]
+struct StateSyncReport {
+    node: NodeIndex,
+    subnet: Option<SubnetIndex>,
+    completed: u64,
+    aborted: u64,
+    /// Seconds of the completed state syncs
+    duration_secs: f64,
+    /// Bytes fetched from peers and copied from the local checkpoints
+    fetched_bytes: u64,
+    copied_bytes: u64,
+    chunks_downloaded: u64,
+    chunks_failed: u64,
+    /// Chunks the running state sync still misses
+    remaining_chunks: u64,
+}
+
+impl StateSyncReport {
+    fn scrape(node: NodeIndex, subnet: Option<SubnetIndex>, url: &str) -> Result<StateSyncReport> {
+        let metrics = metrics::scrape(url)?;
+        let sum = |name: &str, labels: &[(&str, &str)]| {
+            metrics::sum(&metrics, name, labels).unwrap_or(0.0)
+        };
+        let completed = sum("state_sync_duration_seconds_count", &[("status", "ok")]);
+        let total = sum("state_sync_duration_seconds_count", &[]);
+        let downloaded = sum("state_sync_manager_chunk_download_results_total", &[]);
+        let succeeded = sum(
+            "state_sync_manager_chunk_download_results_total",
+            &[("result", "success")],
+        );
+
+        Ok(StateSyncReport {
+            node,
+            subnet,
+            completed: completed as u64,
+            aborted: (total - completed) as u64,
+            duration_secs: sum("state_sync_duration_seconds_sum", &[("status", "ok")]),
+            fetched_bytes: sum("state_sync_size_bytes_total", &[("op", "fetch")]) as u64,
+            copied_bytes: sum("state_sync_size_bytes_total", &[("op", "copy")]) as u64,
+            chunks_downloaded: succeeded as u64,
+            chunks_failed: (downloaded - succeeded) as u64,
+            remaining_chunks: sum("state_sync_remaining_chunks", &[]) as u64,
+        })
+    }
+
+    // Average rate of the completed syncs.
+    fn bandwidth_mib(&self) -> Option<f64> {
+        (self.duration_secs > 0.0)
+            .then(|| self.fetched_bytes as f64 / 1024.0 / 1024.0 / self.duration_secs)
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1}", bytes as f64 / 1024.0 / 1024.0)
+}
+
+pub(crate) fn run(args: StateSyncArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let nodes: Vec<_> = topology
+        .nodes()
+        .filter(|node| args.nodes.is_empty() || args.nodes.contains(&node.index))
+        .collect();
+
+    if !args.json {
+        println!(
+            "{:>5} {:>6} {:>5} {:>7} {:>9} {:>9} {:>11} {:>10} {:>8} {:>8} {:>9}",
+            "node",
+            "subnet",
+            "syncs",
+            "aborted",
+            "secs",
+            "MiB/s",
+            "fetched MiB",
+            "copied MiB",
+            "chunks",
+            "failed",
+            "remaining"
+        );
+    }
+    for node in nodes {
+        let report = match StateSyncReport::scrape(
+            node.index,
+            topology.subnet_of(node.index).map(|subnet| subnet.index),
+            &node.metrics,
+        ) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("node {}: {}", node.index, e);
+                continue;
+            }
+        };
+        if args.json {
+            println!("{}", serde_json::to_string(&report)?);
+            continue;
+        }
+        println!(
+            "{:>5} {:>6} {:>5} {:>7} {:>9.1} {:>9} {:>11} {:>10} {:>8} {:>8} {:>9}",
+            report.node,
+            report
+                .subnet
+                .map_or("-".to_string(), |subnet| subnet.to_string()),
+            report.completed,
+            report.aborted,
+            report.duration_secs,
+            report
+                .bandwidth_mib()
+                .map_or("-".to_string(), |rate| format!("{:.1}", rate)),
+            mib(report.fetched_bytes),
+            mib(report.copied_bytes),
+            report.chunks_downloaded,
+            report.chunks_failed,
+            report.remaining_chunks
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use crate::backend;
+use crate::chaos;
+use crate::health;
+use crate::process;
+use crate::topology::{self, SubnetSummary};
+use anyhow::{bail, Result};
+use clap::Subcommand;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use std::time::Duration;
+
+#[derive(Subcommand)]
+pub(crate) enum SubnetCommand {
+    /// Restart the replicas of a subnet a batch at a time, checking that the
+    /// subnet keeps finalizing while each batch is down
+    RollingRestart {
+        subnet: SubnetIndex,
+        /// Replicas restarted at once, at most a third of the subnet
+        #[clap(long, default_value = "1")]
+        batch: usize,
+        /// Wait for the restarted replicas to report again before moving on to
+        /// the next batch
+        #[clap(long)]
+        wait_healthy: bool,
+        /// Seconds to wait for each health check and replica shutdown
+        #[clap(long, default_value = "120")]
+        timeout: u64,
+    },
+    /// Move the clock of every replica of a subnet forward, so that batch
+    /// time, timers and e.g. neuron ages jump ahead
+    AdvanceTime {
+        subnet: SubnetIndex,
+        /// How far, e.g. 90s, 6h or 30d
+        #[clap(long)]
+        by: String,
+        /// Seconds to wait for the subnet to finalize at the new time
+        #[clap(long, default_value = "120")]
+        timeout: u64,
+    },
+}
+
+// The replicas of a subnet validate block times against their own clock, so
+// all of them move together and keep running under libfaketime.
+fn advance_time(subnet: &SubnetSummary, secs: f64, timeout: Duration) -> Result<()> {
+    let without: Vec<_> = subnet
+        .nodes
+        .iter()
+        .filter(|node| {
+            process::replica_pid(node.index).map_or(false, |pid| !chaos::runs_faketime(pid))
+        })
+        .map(|node| node.index)
+        .collect();
+    if !without.is_empty() {
+        for node in &without {
+            if !chaos::clock_file(*node)?.is_file() {
+                chaos::set_clock_skew(*node, "+0s")?;
+            }
+        }
+        bail!(
+            "nodes {:?} run without a clock file, restart them first, e.g. with `subnet rolling-restart {}`",
+            without,
+            subnet.index
+        );
+    }
+
+    let height = health::heights(subnet)
+        .into_iter()
+        .filter_map(|(_, height)| height)
+        .max();
+    for node in &subnet.nodes {
+        let offset = chaos::clock_offset(node.index)? + secs;
+        chaos::set_clock_skew(node.index, &format!("{:+}s", offset.round() as i64))?;
+    }
+    if let Some(height) = height {
+        health::wait_height(subnet, height + 2, timeout)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run(cmd: SubnetCommand) -> Result<()> {
+    match cmd {
+        SubnetCommand::RollingRestart {
+            subnet,
+            batch,
+            wait_healthy,
+            timeout,
+        } => {
+            let topology = topology::load()?;
+            let subnet = topology.subnet(subnet)?;
+            let timeout = Duration::from_secs(timeout);
+
+            // consensus needs more than two thirds of the replicas
+            let tolerated = (subnet.nodes.len() - 1) / 3;
+            if batch == 0 || batch > tolerated {
+                bail!(
+                    "subnet {} with {} nodes tolerates batches of at most {} replicas",
+                    subnet.index,
+                    subnet.nodes.len(),
+                    tolerated
+                );
+            }
+
+            let nodes: Vec<_> = subnet.nodes.iter().map(|node| node.index).collect();
+            for nodes in nodes.chunks(batch) {
+                println!("restarting nodes {:?}", nodes);
+                // a supervising `run` waits for the replicas started here
+                // until the markers are gone
+                let restarting = nodes
+                    .iter()
+                    .map(|node| process::mark_restarting(*node))
+                    .collect::<Result<Vec<_>>>()?;
+                for node in nodes {
+                    backend::for_node(*node)?.stop(*node, timeout)?;
+                }
+                health::wait_healthy(subnet, nodes, timeout)?;
+
+                for node in nodes {
+                    backend::for_node(*node)?.start(*node)?;
+                }
+                drop(restarting);
+                if wait_healthy {
+                    health::wait_healthy(subnet, &[], timeout)?;
+                }
+            }
+            println!(
+                "restarted all {} nodes of subnet {}",
+                nodes.len(),
+                subnet.index
+            );
+        }
+        SubnetCommand::AdvanceTime {
+            subnet,
+            by,
+            timeout,
+        } => {
+            let secs = chaos::offset_secs(&by)?;
+            if secs <= 0.0 {
+                bail!("the time of a subnet only moves forward");
+            }
+            let topology = topology::load()?;
+            let subnet = topology.subnet(subnet)?;
+            advance_time(subnet, secs, Duration::from_secs(timeout))?;
+            let offset = subnet
+                .nodes
+                .first()
+                .map_or(Ok(0.0), |node| chaos::clock_offset(node.index))?;
+            println!(
+                "subnet {} is {:.0}s ahead of the wall clock",
+                subnet.index, offset
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,358 @@
+//! A single subnet testnet for `cargo test`:
+//!
+//! ```no_run
+//! let testnet = ic_testnet::testing::Testnet::start_for_test().unwrap();
+//! let agent = testnet.agent().unwrap();
+//! let subnet_id = testnet.topology().subnets[0].id;
+//! // the testnet is stopped and its directory removed when `testnet` is dropped
+//! ```
+//!
+//! The `ic-testnet` binary is taken from `IC_TESTNET_BIN` or `PATH`, the
+//! replica from `IC_BIN_DIR`.
+//!
+//! Every testnet is a named one, so that tests running side by side get
+//! ports of their own, see `ic-testnet list`.
+use anyhow::{anyhow, bail, Result};
+use candid::Principal;
+#[cfg(feature = "nns")]
+use ic_agent::Agent;
+use ic_types::{NodeId, PrincipalId, SubnetId};
+#[cfg(unix)]
+use nix::sys::signal::{kill, Signal};
+#[cfg(unix)]
+use nix::unistd::Pid;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_cbor::Value;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+// `run` gets less time to stop the replica than the guard waits for `run`.
+const REPLICA_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// `init` takes the first port offset no registered testnet uses and registers
+// its own when it is done, so testnets of the same test binary initialize one
+// at a time.
+static INIT: Mutex<()> = Mutex::new(());
+
+pub struct Testnet {
+    dir: TempDir,
+    supervisor: Child,
+    topology: Topology,
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub index: u64,
+    pub id: NodeId,
+    pub url: Url,
+    pub xnet: SocketAddr,
+    pub metrics: Url,
+}
+
+#[derive(Debug, Clone)]
+pub struct Subnet {
+    pub index: u64,
+    pub id: SubnetId,
+    pub nodes: Vec<Node>,
+}
+
+/// The nodes and subnets of a started testnet.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    /// DER encoded root public key
+    pub root_key: Vec<u8>,
+    pub nns_subnet: SubnetId,
+    pub subnets: Vec<Subnet>,
+    pub unassigned_nodes: Vec<Node>,
+}
+
+// topology.json as written by `ic-testnet init`
+#[derive(Deserialize)]
+struct NodeJson {
+    index: u64,
+    node_id: String,
+    url: String,
+    xnet: String,
+    metrics: String,
+}
+
+#[derive(Deserialize)]
+struct SubnetJson {
+    index: u64,
+    subnet_id: String,
+    nodes: Vec<NodeJson>,
+}
+
+#[derive(Deserialize)]
+struct TopologyJson {
+    nns_subnet: u64,
+    subnets: Vec<SubnetJson>,
+    unassigned_nodes: Vec<NodeJson>,
+}
+
+fn principal(text: &str) -> Result<PrincipalId> {
+    PrincipalId::from_str(text).map_err(|e| anyhow!("invalid principal {}: {}", text, e))
+}
+
+impl TryFrom<NodeJson> for Node {
+    type Error = anyhow::Error;
+
+    fn try_from(node: NodeJson) -> Result<Node> {
+        Ok(Node {
+            index: node.index,
+            id: NodeId::from(principal(&node.node_id)?),
+            url: Url::parse(&node.url)?,
+            xnet: node.xnet.parse()?,
+            metrics: Url::parse(&node.metrics)?,
+        })
+    }
+}
+
+impl Topology {
+    fn load(dir: &Path, name: &str) -> Result<Topology> {
+        let output_dir = dir.join("tmp").join(name);
+        let json: TopologyJson =
+            serde_json::from_str(&fs::read_to_string(output_dir.join("topology.json"))?)?;
+
+        let mut subnets = vec![];
+        for subnet in json.subnets {
+            subnets.push(Subnet {
+                index: subnet.index,
+                id: SubnetId::from(principal(&subnet.subnet_id)?),
+                nodes: subnet
+                    .nodes
+                    .into_iter()
+                    .map(Node::try_from)
+                    .collect::<Result<_>>()?,
+            });
+        }
+        let nns_subnet = subnets
+            .iter()
+            .find(|subnet| subnet.index == json.nns_subnet)
+            .map(|subnet| subnet.id)
+            .ok_or(anyhow!("no NNS subnet {}", json.nns_subnet))?;
+
+        Ok(Topology {
+            root_key: fs::read(output_dir.join("root_key.der"))?,
+            nns_subnet,
+            subnets,
+            unassigned_nodes: json
+                .unassigned_nodes
+                .into_iter()
+                .map(Node::try_from)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.subnets
+            .iter()
+            .flat_map(|subnet| subnet.nodes.iter())
+            .chain(self.unassigned_nodes.iter())
+    }
+}
+
+impl Subnet {
+    pub fn principal(&self) -> Principal {
+        self.id.get().0
+    }
+}
+
+/// Configures the testnet started by [`TestnetBuilder::start`].
+pub struct TestnetBuilder {
+    nodes: usize,
+}
+
+impl Default for TestnetBuilder {
+    fn default() -> TestnetBuilder {
+        TestnetBuilder { nodes: 1 }
+    }
+}
+
+fn testnet_binary() -> PathBuf {
+    env::var("IC_TESTNET_BIN")
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from("ic-testnet"))
+}
+
+// The testnet runs from its own directory, so a relative IC_BIN_DIR has to be
+// resolved against the current one first.
+fn ic_bin_dir() -> Result<PathBuf> {
+    let dir = PathBuf::from(env::var("IC_BIN_DIR").unwrap_or("ic/target/debug".to_string()));
+    Ok(env::current_dir()?.join(dir))
+}
+
+fn testnet_command(dir: &Path, name: &str, nodes: &str) -> Result<Command> {
+    let mut command = Command::new(testnet_binary());
+    command
+        .current_dir(dir)
+        .env("IC_TESTNET_NAME", name)
+        .env("NODES", nodes)
+        .env("BASE_DIR", dir)
+        .env("IC_BIN_DIR", ic_bin_dir()?);
+    Ok(command)
+}
+
+fn healthy(url: &Url) -> bool {
+    let status = reqwest::blocking::Client::new()
+        .get(format!("{}api/v2/status", url))
+        .timeout(Duration::from_secs(2))
+        .send()
+        .and_then(|response| response.bytes());
+    let Ok(status) = status else {
+        return false;
+    };
+
+    // the status is a CBOR map, possibly wrapped in the self-describing tag
+    let status = match serde_cbor::from_slice::<Value>(&status) {
+        Ok(Value::Tag(_, status)) => *status,
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    match status {
+        Value::Map(map) => map.iter().any(|(key, value)| {
+            *key == Value::Text("replica_health_status".to_string())
+                && *value == Value::Text("healthy".to_string())
+        }),
+        _ => false,
+    }
+}
+
+impl TestnetBuilder {
+    /// Number of nodes of the subnet. Each one listens on its own loopback
+    /// address 127.0.<n>.1.
+    pub fn nodes(mut self, nodes: usize) -> TestnetBuilder {
+        self.nodes = nodes;
+        self
+    }
+
+    /// Generates the testnet in a temporary directory and starts it,
+    /// returning once every replica reports healthy. The testnet is named
+    /// after the directory and listens on the ports of its own offset.
+    pub fn start(self) -> Result<Testnet> {
+        if self.nodes == 0 {
+            bail!("a testnet needs at least one node");
+        }
+        let nodes: Vec<String> = (1..=self.nodes).map(|i| format!("127.0.{}.1", i)).collect();
+        let nodes = nodes.join(" ");
+        let dir = tempfile::Builder::new().prefix("ic-testnet-").tempdir()?;
+        let name = dir
+            .path()
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(anyhow!("{:?} has no name", dir.path()))?
+            .to_string();
+
+        let output = {
+            let _init = INIT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            testnet_command(dir.path(), &name, &nodes)?
+                .arg("init")
+                .output()?
+        };
+        if !output.status.success() {
+            bail!(
+                "ic-testnet init failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let topology = Topology::load(dir.path(), &name)?;
+
+        let logs = fs::File::create(dir.path().join("run.log"))?;
+        let supervisor = testnet_command(dir.path(), &name, &nodes)?
+            .arg("run")
+            .arg("--shutdown-timeout")
+            .arg(REPLICA_SHUTDOWN_TIMEOUT.as_secs().to_string())
+            .stdin(Stdio::null())
+            .stdout(logs.try_clone()?)
+            .stderr(logs)
+            .spawn()?;
+        let mut testnet = Testnet {
+            dir,
+            supervisor,
+            topology,
+        };
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while !testnet.topology.nodes().all(|node| healthy(&node.url)) {
+            if let Some(status) = testnet.supervisor.try_wait()? {
+                bail!(
+                    "ic-testnet run exited with {}, see {:?}",
+                    status,
+                    testnet.dir.path().join("run.log")
+                );
+            }
+            if Instant::now() > deadline {
+                bail!("testnet not healthy after {:?}", STARTUP_TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+
+        Ok(testnet)
+    }
+}
+
+impl Testnet {
+    pub fn builder() -> TestnetBuilder {
+        TestnetBuilder::default()
+    }
+
+    /// Starts a testnet with a single node.
+    pub fn start_for_test() -> Result<Testnet> {
+        Testnet::builder().start()
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// URL of the first node.
+    pub fn url(&self) -> &Url {
+        &self.topology.subnets[0].nodes[0].url
+    }
+
+    /// Directory holding the generated configs, state and logs.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// An anonymous agent trusting the testnet's root key.
+    #[cfg(feature = "nns")]
+    pub fn agent(&self) -> Result<Agent> {
+        let agent = Agent::builder().with_url(self.url().as_str()).build()?;
+        agent.set_root_key(self.topology.root_key.clone());
+        Ok(agent)
+    }
+}
+
+impl Drop for Testnet {
+    fn drop(&mut self) {
+        // `run` stops the replica when it is terminated, elsewhere the
+        // supervisor can only be killed
+        #[cfg(unix)]
+        let terminated = kill(Pid::from_raw(self.supervisor.id() as i32), Signal::SIGTERM).is_ok();
+        #[cfg(not(unix))]
+        let terminated = self.supervisor.kill().is_ok();
+        if terminated {
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            while let Ok(None) = self.supervisor.try_wait() {
+                if Instant::now() > deadline {
+                    let _ = self.supervisor.kill();
+                    break;
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+        let _ = self.supervisor.wait();
+    }
+}
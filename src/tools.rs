@@ -0,0 +1,38 @@
+use anyhow::{anyhow, bail, Result};
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Binaries built from the `ic` submodule, e.g. `ic-admin` or `ic-nns-init`.
+pub(crate) fn ic_binary(name: &str) -> PathBuf {
+    let dir = env::var("IC_BIN_DIR").unwrap_or("ic/target/debug".to_string());
+    PathBuf::from(dir).join(name)
+}
+
+pub(crate) fn run_ic_binary(name: &str, args: &[String]) -> Result<String> {
+    run_ic_binary_with_env(name, args, &[])
+}
+
+pub(crate) fn run_ic_binary_with_env(
+    name: &str,
+    args: &[String],
+    envs: &[(&str, PathBuf)],
+) -> Result<String> {
+    let bin = ic_binary(name);
+    let output = Command::new(&bin)
+        .args(args)
+        .envs(envs.iter().map(|(key, value)| (*key, value)))
+        .output()
+        .map_err(|e| anyhow!("failed to run {:?}: {}", bin, e))?;
+
+    if !output.status.success() {
+        bail!(
+            "{} exited with {}: {}",
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -0,0 +1,170 @@
+use crate::config::TestnetConfig;
+use crate::init::{metrics_port, output_dir};
+use crate::routing;
+use crate::schema::SCHEMA_VERSION;
+use anyhow::{anyhow, bail, Result};
+use candid::Principal;
+use ic_prep_lib::internet_computer::InitializedIc;
+use ic_prep_lib::node::{InitializedNode, NodeIndex};
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+const CANISTER_IDS_PER_SUBNET_LOG2: u32 = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NodeSummary {
+    pub index: NodeIndex,
+    pub node_id: String,
+    pub url: String,
+    pub xnet: String,
+    pub metrics: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SubnetSummary {
+    pub index: SubnetIndex,
+    pub subnet_id: String,
+    pub nodes: Vec<NodeSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Topology {
+    // missing in files written before versioning
+    #[serde(default)]
+    pub schema_version: u32,
+    pub root_key: PathBuf,
+    pub nns_subnet: SubnetIndex,
+    pub subnets: Vec<SubnetSummary>,
+    pub unassigned_nodes: Vec<NodeSummary>,
+}
+
+fn node_summary(index: NodeIndex, node: &InitializedNode, metrics_port: u16) -> NodeSummary {
+    NodeSummary {
+        index,
+        node_id: node.node_id.to_string(),
+        url: format!("http://{}", node.node_config.public_api),
+        xnet: node.node_config.xnet_api.to_string(),
+        metrics: format!(
+            "http://{}",
+            SocketAddr::new(node.node_config.public_api.ip(), metrics_port)
+        ),
+    }
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(output_dir()?.join("topology.json"))
+}
+
+pub(crate) fn write(ic: &InitializedIc, nns_subnet: SubnetIndex) -> Result<Topology> {
+    let metrics_port = metrics_port()?;
+    let topology = Topology {
+        schema_version: SCHEMA_VERSION,
+        root_key: ic.target_dir.join("nns_public_key.pem"),
+        nns_subnet,
+        subnets: ic
+            .initialized_topology
+            .iter()
+            .map(|(index, subnet)| SubnetSummary {
+                index: *index,
+                subnet_id: subnet.subnet_id.to_string(),
+                nodes: subnet
+                    .initialized_nodes
+                    .iter()
+                    .map(|(index, node)| node_summary(*index, node, metrics_port))
+                    .collect(),
+            })
+            .collect(),
+        unassigned_nodes: ic
+            .unassigned_nodes
+            .iter()
+            .map(|(index, node)| node_summary(*index, node, metrics_port))
+            .collect(),
+    };
+
+    topology.save()?;
+    Ok(topology)
+}
+
+pub(crate) fn load() -> Result<Topology> {
+    let path = path()?;
+    let json =
+        fs::read_to_string(&path).map_err(|e| anyhow!("failed to read {:?}: {}", path, e))?;
+    let topology: Topology = serde_json::from_str(&json)?;
+    if topology.schema_version != SCHEMA_VERSION {
+        bail!(
+            "{:?} has schema {} instead of {}, run `ic-testnet migrate`",
+            path,
+            topology.schema_version,
+            SCHEMA_VERSION
+        );
+    }
+
+    Ok(topology)
+}
+
+impl NodeSummary {
+    pub(crate) fn addr(&self) -> Result<SocketAddr> {
+        Ok(self.url.trim_start_matches("http://").parse()?)
+    }
+}
+
+impl Topology {
+    pub(crate) fn save(&self) -> Result<()> {
+        fs::write(path()?, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn nodes(&self) -> impl Iterator<Item = &NodeSummary> {
+        self.subnets
+            .iter()
+            .flat_map(|subnet| subnet.nodes.iter())
+            .chain(self.unassigned_nodes.iter())
+    }
+
+    pub(crate) fn node(&self, index: NodeIndex) -> Result<&NodeSummary> {
+        self.nodes()
+            .find(|node| node.index == index)
+            .ok_or(anyhow!("unknown node {}", index))
+    }
+
+    pub(crate) fn root_key_der(&self) -> Result<Vec<u8>> {
+        let pem = pem::parse(fs::read(&self.root_key)?)?;
+        Ok(pem.contents().to_vec())
+    }
+
+    pub(crate) fn subnet(&self, index: SubnetIndex) -> Result<&SubnetSummary> {
+        self.subnets
+            .iter()
+            .find(|subnet| subnet.index == index)
+            .ok_or(anyhow!("unknown subnet {}", index))
+    }
+
+    // None for unassigned nodes.
+    pub(crate) fn subnet_of(&self, node: NodeIndex) -> Option<&SubnetSummary> {
+        self.subnets
+            .iter()
+            .find(|subnet| subnet.nodes.iter().any(|member| member.index == node))
+    }
+
+    pub(crate) fn subnet_url(&self, index: SubnetIndex) -> Result<String> {
+        self.subnet(index)?
+            .nodes
+            .first()
+            .map(|node| node.url.clone())
+            .ok_or(anyhow!("subnet {} has no nodes", index))
+    }
+
+    // Canister ids allocated by ic-prep encode the subnet index in their
+    // upper bits, specified ids are routed by the config.
+    pub(crate) fn canister_url(&self, canister_id: &Principal) -> Result<String> {
+        let id = routing::canister_number(canister_id)?;
+        if let Some(subnet) = routing::specified_subnet(&TestnetConfig::saved()?, id)? {
+            return self.subnet_url(subnet);
+        }
+
+        self.subnet_url(id >> CANISTER_IDS_PER_SUBNET_LOG2)
+    }
+}
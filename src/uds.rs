@@ -0,0 +1,55 @@
+use crate::init::output_dir;
+use anyhow::{Context, Result};
+use ic_prep_lib::node::NodeIndex;
+use std::fs;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+
+pub(crate) fn socket_path(index: NodeIndex) -> Result<PathBuf> {
+    Ok(output_dir()?
+        .join("sockets")
+        .join(format!("node-{}.sock", index)))
+}
+
+fn forward(mut from: UnixStream, mut to: TcpStream) {
+    let (Ok(mut from_reader), Ok(mut to_writer)) = (from.try_clone(), to.try_clone()) else {
+        return;
+    };
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut from_reader, &mut to_writer);
+        let _ = to_writer.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut to, &mut from);
+    let _ = from.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+}
+
+// The replica's HTTP handler only listens on TCP, so the public API of a node
+// is made available on a Unix socket by forwarding every connection to it.
+pub(crate) fn serve(index: NodeIndex, addr: SocketAddr) -> Result<PathBuf> {
+    let path = socket_path(index)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // left behind by a supervisor that was killed
+    let _ = fs::remove_file(&path);
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("failed to bind {:?}", path))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            thread::spawn(move || match TcpStream::connect(addr) {
+                Ok(node) => forward(stream, node),
+                Err(e) => eprintln!("node {} is not reachable at {}: {}", index, addr, e),
+            });
+        }
+    });
+
+    Ok(path)
+}
@@ -0,0 +1,278 @@
+use crate::agent::{block_on, connect};
+use crate::init::output_dir;
+use crate::routing::{latest_record, read_routing_table};
+use crate::topology::{self, SubnetSummary, Topology};
+use anyhow::{anyhow, bail, Result};
+use candid::Principal;
+use clap::Args;
+use ic_agent::hash_tree::LookupResult;
+use ic_agent::{Agent, Certificate};
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_protobuf::registry::crypto::v1::PublicKey;
+use ic_registry_keys::make_crypto_threshold_signing_pubkey_key;
+use ic_registry_routing_table::RoutingTable;
+use ic_types::{PrincipalId, SubnetId};
+use prost::Message;
+use serde_cbor::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Args)]
+pub(crate) struct VerifyCertArgs {
+    /// Effective canister id the certificate was requested for
+    #[clap(long)]
+    canister: String,
+    /// CBOR encoded certificate, a fresh one for the `time` path is fetched when omitted
+    #[clap(long)]
+    file: Option<PathBuf>,
+    /// The file contains the certificate hex encoded
+    #[clap(long)]
+    hex: bool,
+}
+
+#[derive(Args)]
+pub(crate) struct VerifyDelegationsArgs {
+    /// Check only this subnet, all subnets but the NNS by default
+    #[clap(long)]
+    subnet: Option<SubnetIndex>,
+}
+
+fn lookup<'a>(certificate: &'a Certificate, path: &[&[u8]]) -> Option<&'a [u8]> {
+    match certificate.tree.lookup_path(path) {
+        LookupResult::Found(value) => Some(value),
+        _ => None,
+    }
+}
+
+// (start, end) canister id pairs, as in the canister_ranges of a certificate.
+type Ranges = BTreeSet<(Vec<u8>, Vec<u8>)>;
+
+fn certified_ranges(cbor: &[u8]) -> Result<Ranges> {
+    let Value::Array(ranges) = serde_cbor::from_slice(cbor)? else {
+        bail!("canister_ranges is not a list");
+    };
+    ranges
+        .into_iter()
+        .map(|range| match range {
+            Value::Array(bounds) => match bounds.as_slice() {
+                [Value::Bytes(start), Value::Bytes(end)] => Ok((start.clone(), end.clone())),
+                _ => bail!("a canister range is not a pair of ids"),
+            },
+            _ => bail!("a canister range is not a pair of ids"),
+        })
+        .collect()
+}
+
+fn registry_ranges(routing_table: &RoutingTable, subnet_id: SubnetId) -> Ranges {
+    routing_table
+        .ranges(subnet_id)
+        .iter()
+        .map(|range| {
+            (
+                range.start.get().as_slice().to_vec(),
+                range.end.get().as_slice().to_vec(),
+            )
+        })
+        .collect()
+}
+
+// The threshold public key ic-prep registered for a subnet, raw BLS bytes.
+fn registry_public_key(subnet_id: SubnetId) -> Result<Vec<u8>> {
+    let store = output_dir()?.join("state").join("ic_registry_local_store");
+    let key = make_crypto_threshold_signing_pubkey_key(subnet_id);
+    let record = latest_record(&store, &key)?
+        .1
+        .ok_or(anyhow!("registry has no {}", key))?;
+    Ok(PublicKey::decode(record.as_slice())?.key_value)
+}
+
+// Mismatches between the certificate of a subnet, its NNS delegation and the
+// registry ic-prep generated.
+async fn check_subnet(
+    agent: &Agent,
+    topology: &Topology,
+    routing_table: &RoutingTable,
+    subnet: &SubnetSummary,
+) -> Result<Vec<String>> {
+    let subnet_id = SubnetId::from(
+        PrincipalId::from_str(&subnet.subnet_id)
+            .map_err(|e| anyhow!("invalid subnet id {}: {}", subnet.subnet_id, e))?,
+    );
+    let expected_ranges = registry_ranges(routing_table, subnet_id);
+    let Some((start, _)) = expected_ranges.iter().next() else {
+        return Ok(vec!["no canister ids are routed to the subnet".to_string()]);
+    };
+    let canister_id = Principal::from_slice(start);
+
+    let certificate = agent
+        .read_state_raw(vec![vec!["time".into()]], canister_id)
+        .await?;
+    let mut problems = vec![];
+    if let Err(e) = agent.verify(&certificate, canister_id) {
+        problems.push(format!("certificate verification failed: {}", e));
+    }
+
+    let is_nns = subnet.index == topology.nns_subnet;
+    let delegation = match (&certificate.delegation, is_nns) {
+        (None, true) => return Ok(problems),
+        (Some(_), true) => {
+            problems.push("the NNS subnet signs with a delegation".to_string());
+            return Ok(problems);
+        }
+        (None, false) => {
+            problems.push("the certificate has no NNS delegation".to_string());
+            return Ok(problems);
+        }
+        (Some(delegation), false) => delegation,
+    };
+
+    let delegated = Principal::from_slice(&delegation.subnet_id);
+    if delegated.to_string() != subnet.subnet_id {
+        problems.push(format!(
+            "delegation is for subnet {} instead of {}",
+            delegated, subnet.subnet_id
+        ));
+    }
+    let nns_certificate: Certificate = serde_cbor::from_slice(&delegation.certificate)?;
+    if nns_certificate.delegation.is_some() {
+        problems.push("the delegation is itself delegated".to_string());
+    }
+
+    let id = delegation.subnet_id.as_slice();
+    // the certificate carries the key DER encoded, the registry the raw key
+    match lookup(&nns_certificate, &[b"subnet", id, b"public_key"]) {
+        Some(der) => {
+            let registered = registry_public_key(subnet_id)?;
+            if !der.ends_with(&registered) {
+                problems.push(format!(
+                    "delegated public key {} is not the registered {}",
+                    hex::encode(der),
+                    hex::encode(&registered)
+                ));
+            }
+        }
+        None => problems.push("the delegation has no public key of the subnet".to_string()),
+    }
+    match lookup(&nns_certificate, &[b"subnet", id, b"canister_ranges"]) {
+        Some(cbor) => {
+            let certified = certified_ranges(cbor)?;
+            if certified != expected_ranges {
+                let show = |ranges: &Ranges| {
+                    ranges
+                        .iter()
+                        .map(|(start, end)| {
+                            format!(
+                                "{}..{}",
+                                Principal::from_slice(start),
+                                Principal::from_slice(end)
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                problems.push(format!(
+                    "delegated canister ranges [{}] differ from the routing table [{}]",
+                    show(&certified),
+                    show(&expected_ranges)
+                ));
+            }
+        }
+        None => problems.push("the delegation has no canister ranges".to_string()),
+    }
+
+    Ok(problems)
+}
+
+pub(crate) fn run_delegations(args: VerifyDelegationsArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let (_, routing_table) =
+        read_routing_table(&output_dir()?.join("state").join("ic_registry_local_store"))?;
+    let subnets: Vec<&SubnetSummary> = match args.subnet {
+        Some(index) => vec![topology.subnet(index)?],
+        None => topology
+            .subnets
+            .iter()
+            .filter(|subnet| subnet.index != topology.nns_subnet)
+            .collect(),
+    };
+
+    let mut failed = 0;
+    for subnet in subnets {
+        let problems = block_on(async {
+            let agent = connect(&topology.subnet_url(subnet.index)?, None).await?;
+            check_subnet(&agent, &topology, &routing_table, subnet).await
+        })
+        .unwrap_or_else(|e| vec![format!("{:#}", e)]);
+        if problems.is_empty() {
+            println!("subnet {} ok", subnet.index);
+            continue;
+        }
+        failed += 1;
+        println!("subnet {}:", subnet.index);
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
+    if failed > 0 {
+        bail!("{} subnets have delegation problems", failed);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run(args: VerifyCertArgs) -> Result<()> {
+    let canister_id = Principal::from_text(&args.canister)?;
+    let topology = topology::load()?;
+    let url = topology.canister_url(&canister_id)?;
+
+    let certificate = match &args.file {
+        Some(file) => {
+            let bytes = fs::read(file)?;
+            let bytes = if args.hex {
+                hex::decode(String::from_utf8(bytes)?.trim())?
+            } else {
+                bytes
+            };
+            Some(serde_cbor::from_slice::<Certificate>(&bytes)?)
+        }
+        None => None,
+    };
+
+    let certificate = block_on(async {
+        let agent = connect(&url, None).await?;
+        let certificate = match certificate {
+            Some(certificate) => certificate,
+            None => {
+                agent
+                    .read_state_raw(vec![vec!["time".into()]], canister_id)
+                    .await?
+            }
+        };
+        agent
+            .verify(&certificate, canister_id)
+            .map_err(|e| anyhow!("certificate verification failed: {}", e))?;
+
+        Ok(certificate)
+    })?;
+
+    match &certificate.delegation {
+        Some(delegation) => {
+            let subnet_id = Principal::from_slice(&delegation.subnet_id);
+            let known = topology
+                .subnets
+                .iter()
+                .any(|subnet| subnet.subnet_id == subnet_id.to_string());
+            println!(
+                "delegated by subnet {}{}",
+                subnet_id,
+                if known { "" } else { " (not in topology)" }
+            );
+        }
+        None => println!("signed by the NNS subnet without delegation"),
+    }
+    println!("certificate is valid for {}", canister_id);
+
+    Ok(())
+}
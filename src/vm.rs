@@ -0,0 +1,333 @@
+use crate::backend::NodeBackend;
+use crate::config::{TestnetConfig, VmSettings};
+use crate::init::{node_state_dir, output_dir};
+use crate::secrets;
+use crate::topology::{self, NodeSummary, Topology};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Ports GuestOS binds, which the registry has to record for its nodes.
+const GUESTOS_PUBLIC_PORT: u16 = 8080;
+const GUESTOS_XNET_PORT: u16 = 2497;
+// Room for the file system around ic-bootstrap.tar on the config media.
+const MEDIA_HEADROOM: u64 = 16 * 1024 * 1024;
+
+#[derive(Subcommand)]
+pub(crate) enum VmCommand {
+    /// Write the bootstrap config media of a node to tmp/vms/node-<idx>/config.img
+    Media { node: NodeIndex },
+    /// Boot nodes as IC-OS virtual machines
+    Start {
+        #[clap(required = true)]
+        nodes: Vec<NodeIndex>,
+    },
+    /// Shut down the virtual machines of nodes, killing them after --timeout
+    /// seconds
+    Stop {
+        #[clap(required = true)]
+        nodes: Vec<NodeIndex>,
+        #[clap(long, default_value = "60")]
+        timeout: u64,
+    },
+    /// List the virtual machines and whether they run
+    List,
+}
+
+fn vm_dir(index: NodeIndex) -> Result<PathBuf> {
+    Ok(output_dir()?.join("vms").join(format!("node-{}", index)))
+}
+
+fn settings() -> Result<VmSettings> {
+    TestnetConfig::saved()?.vm.ok_or(anyhow!(
+        "the testnet config has no [vm] section with the GuestOS image"
+    ))
+}
+
+pub(crate) fn vm_pid(index: NodeIndex) -> Option<i32> {
+    let pid_file = vm_dir(index).ok()?.join("qemu.pid");
+    let pid: i32 = fs::read_to_string(pid_file).ok()?.trim().parse().ok()?;
+    Path::new(&format!("/proc/{}", pid)).exists().then_some(pid)
+}
+
+fn tool(command: &mut Command) -> Result<()> {
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {:?}", command.get_program()))?;
+    if !output.status.success() {
+        bail!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+// The network settings GuestOS reads at boot.
+fn config_ini(settings: &VmSettings, node: &NodeSummary) -> Result<String> {
+    let addr = node.addr()?;
+    let xnet: SocketAddr = node.xnet.parse()?;
+    if addr.port() != GUESTOS_PUBLIC_PORT || xnet.port() != GUESTOS_XNET_PORT {
+        bail!(
+            "node {} is registered with ports {} and {}, IC-OS binds {} and {}; advertise these in the config before init",
+            node.index,
+            addr.port(),
+            xnet.port(),
+            GUESTOS_PUBLIC_PORT,
+            GUESTOS_XNET_PORT
+        );
+    }
+
+    let mut ini = String::new();
+    let (family, default_prefix) = match addr.ip() {
+        IpAddr::V6(_) => ("ipv6", 64),
+        IpAddr::V4(_) => ("ipv4", 24),
+    };
+    ini.push_str(&format!(
+        "{}_address={}/{}\n",
+        family,
+        addr.ip(),
+        settings.prefix_length.unwrap_or(default_prefix)
+    ));
+    if let Some(gateway) = &settings.gateway {
+        ini.push_str(&format!("{}_gateway={}\n", family, gateway));
+    }
+    ini.push_str(&format!("hostname=ic-testnet-node-{}\n", node.index));
+    Ok(ini)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    tool(Command::new("cp").arg("-a").arg(from).arg(to))
+}
+
+// The config media IC-OS bootstraps from: a FAT image labelled CONFIG with
+// ic-bootstrap.tar, holding the network settings, the keys of the node, the
+// registry and how to reach the NNS.
+pub(crate) fn build_media(topology: &Topology, index: NodeIndex) -> Result<PathBuf> {
+    let settings = settings()?;
+    let node = topology.node(index)?;
+    let crypto_dir = secrets::node_crypto_dir(index)?;
+    if secrets::is_sealed(&crypto_dir) {
+        bail!(
+            "the keys of node {} are sealed, unseal them with `ic-testnet secrets unseal`",
+            index
+        );
+    }
+    let mut registry = node_state_dir(index)?.join("ic_registry_local_store");
+    if !registry.is_dir() {
+        registry = output_dir()?.join("state").join("ic_registry_local_store");
+    }
+
+    let dir = vm_dir(index)?;
+    let bootstrap = dir.join("bootstrap");
+    if bootstrap.is_dir() {
+        fs::remove_dir_all(&bootstrap)?;
+    }
+    fs::create_dir_all(&bootstrap)?;
+
+    fs::write(bootstrap.join("config.ini"), config_ini(&settings, node)?)?;
+    copy_dir(&crypto_dir, &bootstrap.join("ic_crypto"))?;
+    copy_dir(&registry, &bootstrap.join("ic_registry_local_store"))?;
+    fs::copy(&topology.root_key, bootstrap.join("nns_public_key.pem"))?;
+    let nns_urls: Vec<&str> = topology
+        .subnet(topology.nns_subnet)?
+        .nodes
+        .iter()
+        .map(|node| node.url.as_str())
+        .collect();
+    fs::write(
+        bootstrap.join("nns.conf"),
+        format!("nns_url={}\n", nns_urls.join(",")),
+    )?;
+    if let Some(keys) = &settings.ssh_authorized_keys {
+        let accounts = bootstrap.join("accounts_ssh_authorized_keys");
+        fs::create_dir_all(&accounts)?;
+        fs::copy(keys, accounts.join("admin"))
+            .with_context(|| format!("failed to read {:?}", keys))?;
+    }
+
+    let tar = dir.join("ic-bootstrap.tar");
+    tool(
+        Command::new("tar")
+            .arg("-cf")
+            .arg(&tar)
+            .arg("-C")
+            .arg(&bootstrap)
+            .arg("."),
+    )?;
+
+    let media = dir.join("config.img");
+    File::create(&media)?.set_len(fs::metadata(&tar)?.len() + MEDIA_HEADROOM)?;
+    tool(
+        Command::new("mkfs.vfat")
+            .arg("-n")
+            .arg("CONFIG")
+            .arg(&media),
+    )?;
+    tool(
+        Command::new("mcopy")
+            .arg("-i")
+            .arg(&media)
+            .arg(&tar)
+            .arg("::"),
+    )?;
+
+    Ok(media)
+}
+
+// Boots a node from an overlay of the GuestOS image, which keeps what the VM
+// writes, and fresh config media.
+pub(crate) fn start(topology: &Topology, index: NodeIndex) -> Result<i32> {
+    if let Some(pid) = vm_pid(index) {
+        bail!("the VM of node {} is already running as pid {}", index, pid);
+    }
+    let settings = settings()?;
+    let limits = TestnetConfig::saved()?.node(index);
+    let media = build_media(topology, index)?;
+    let dir = vm_dir(index)?;
+
+    let disk = dir.join("disk.qcow2");
+    if !disk.is_file() {
+        let image = fs::canonicalize(&settings.image)
+            .with_context(|| format!("failed to find the GuestOS image {:?}", settings.image))?;
+        tool(
+            Command::new("qemu-img")
+                .args(["create", "-f", "qcow2", "-F", "raw", "-b"])
+                .arg(image)
+                .arg(&disk),
+        )?;
+    }
+
+    let cpus = limits.cpus.map_or(2, |cpus| cpus.ceil().max(1.0) as u64);
+    let memory_mib = limits.memory_mib.unwrap_or(4096);
+    let mac = format!(
+        "52:54:00:1c:{:02x}:{:02x}",
+        (index >> 8) & 0xff,
+        index & 0xff
+    );
+    tool(
+        Command::new("qemu-system-x86_64")
+            .args(["-machine", "q35,accel=kvm", "-cpu", "host", "-smp"])
+            .arg(cpus.to_string())
+            .arg("-m")
+            .arg(format!("{}M", memory_mib))
+            .arg("-drive")
+            .arg(format!("file={},format=qcow2,if=virtio", disk.display()))
+            .arg("-drive")
+            .arg(format!("file={},format=raw,if=virtio", media.display()))
+            .arg("-netdev")
+            .arg(format!("bridge,id=net0,br={}", settings.bridge))
+            .arg("-device")
+            .arg(format!("virtio-net-pci,netdev=net0,mac={}", mac))
+            .arg("-monitor")
+            .arg(format!(
+                "unix:{},server,nowait",
+                dir.join("monitor.sock").display()
+            ))
+            .arg("-serial")
+            .arg(format!("file:{}", dir.join("console.log").display()))
+            .args(["-display", "none", "-daemonize", "-pidfile"])
+            .arg(dir.join("qemu.pid")),
+    )?;
+
+    vm_pid(index).ok_or(anyhow!("the VM of node {} exited right away", index))
+}
+
+// Asks the guest to power off as if its power button was pressed, and kills
+// qemu when it is still running after the timeout. Returns whether the guest
+// shut down by itself.
+pub(crate) fn stop(index: NodeIndex, timeout: Duration) -> Result<bool> {
+    let Some(pid) = vm_pid(index) else {
+        bail!("the VM of node {} is not running", index);
+    };
+    let monitor = vm_dir(index)?.join("monitor.sock");
+    if let Ok(mut monitor) = UnixStream::connect(&monitor) {
+        monitor.write_all(b"system_powerdown\n")?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if vm_pid(index).is_none() {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    kill(Pid::from_raw(pid), Signal::SIGKILL)?;
+    Ok(false)
+}
+
+pub(crate) fn run(cmd: VmCommand) -> Result<()> {
+    let topology = topology::load()?;
+    match cmd {
+        VmCommand::Media { node } => {
+            println!("{}", build_media(&topology, node)?.display());
+        }
+        VmCommand::Start { nodes } => {
+            for node in nodes {
+                let pid = start(&topology, node)?;
+                println!("node {} VM pid {}", node, pid);
+            }
+        }
+        VmCommand::Stop { nodes, timeout } => {
+            for node in nodes {
+                let graceful = stop(node, Duration::from_secs(timeout))?;
+                println!(
+                    "node {} VM {}",
+                    node,
+                    if graceful { "shut down" } else { "killed" }
+                );
+            }
+        }
+        VmCommand::List => {
+            for node in topology.nodes() {
+                if !vm_dir(node.index)?.is_dir() {
+                    continue;
+                }
+                match vm_pid(node.index) {
+                    Some(pid) => println!("node {} running as pid {}", node.index, pid),
+                    None => println!("node {} stopped", node.index),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Lifecycle of a node in a VM, for the commands going through its backend.
+pub(crate) struct VmBackend;
+
+impl NodeBackend for VmBackend {
+    fn name(&self) -> &'static str {
+        "vm"
+    }
+
+    fn start(&self, index: NodeIndex) -> Result<Option<Child>> {
+        start(&topology::load()?, index)?;
+        Ok(None)
+    }
+
+    fn running(&self, index: NodeIndex) -> bool {
+        vm_pid(index).is_some()
+    }
+
+    fn stop(&self, index: NodeIndex, timeout: Duration) -> Result<bool> {
+        stop(index, timeout)
+    }
+
+    fn kill(&self, index: NodeIndex) -> Result<()> {
+        let pid = vm_pid(index).ok_or(anyhow!("the VM of node {} is not running", index))?;
+        kill(Pid::from_raw(pid), Signal::SIGKILL)?;
+        Ok(())
+    }
+}
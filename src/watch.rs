@@ -0,0 +1,125 @@
+use crate::hooks::{self, Event};
+use crate::metrics::finalized_height;
+use crate::topology;
+use anyhow::{bail, Result};
+use clap::Args;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Args)]
+pub(crate) struct WatchArgs {
+    /// Seconds between samples
+    #[clap(long, default_value = "5")]
+    interval: u64,
+    /// Alert when a subnet does not finalize a block for this many seconds
+    #[clap(long, default_value = "60")]
+    stall_after: u64,
+    /// Post alerts to this URL and keep watching instead of exiting, which
+    /// also happens when `hooks` are registered for subnet stalls
+    #[clap(long)]
+    webhook: Option<String>,
+}
+
+struct SubnetProgress {
+    height: u64,
+    sampled_at: Instant,
+    progressed_at: Instant,
+    alerted: bool,
+}
+
+pub(crate) fn run(args: WatchArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let mut progress: BTreeMap<SubnetIndex, SubnetProgress> = BTreeMap::new();
+    let mut reachable: BTreeMap<NodeIndex, bool> = BTreeMap::new();
+
+    loop {
+        for subnet in &topology.subnets {
+            let mut heights = vec![];
+            for node in &subnet.nodes {
+                let height = finalized_height(&node.metrics).ok();
+                let was_reachable = reachable.insert(node.index, height.is_some());
+                if height.is_none() && was_reachable == Some(true) {
+                    let message = format!("node {} stopped responding", node.index);
+                    eprintln!("{}", message);
+                    hooks::emit(
+                        Event::NodeCrash,
+                        &message,
+                        json!({ "node": node.index, "subnet": subnet.index }),
+                    );
+                }
+                heights.extend(height);
+            }
+            let now = Instant::now();
+            let (max, min) = match (heights.iter().max(), heights.iter().min()) {
+                (Some(max), Some(min)) => (*max, *min),
+                _ => (0, 0),
+            };
+
+            let state = progress.entry(subnet.index).or_insert(SubnetProgress {
+                height: max,
+                sampled_at: now,
+                progressed_at: now,
+                alerted: false,
+            });
+            let elapsed = now.duration_since(state.sampled_at).as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                max.saturating_sub(state.height) as f64 / elapsed
+            } else {
+                0.0
+            };
+            if max > state.height {
+                state.progressed_at = now;
+                state.alerted = false;
+            }
+            state.height = max;
+            state.sampled_at = now;
+
+            println!(
+                "subnet {} height {} {:.2} blocks/s lag {} ({}/{} nodes reporting)",
+                subnet.index,
+                max,
+                rate,
+                max - min,
+                heights.len(),
+                subnet.nodes.len()
+            );
+
+            let stalled = now.duration_since(state.progressed_at);
+            if stalled >= Duration::from_secs(args.stall_after) && !state.alerted {
+                let message = format!(
+                    "subnet {} has not finalized a block for {}s, stuck at height {}",
+                    subnet.index,
+                    stalled.as_secs(),
+                    max
+                );
+                let alert = json!({
+                    "subnet": subnet.index,
+                    "height": max,
+                    "stalled_secs": stalled.as_secs(),
+                    "text": message,
+                });
+                let notified = hooks::emit(Event::SubnetStall, &message, alert.clone());
+                if let Some(url) = &args.webhook {
+                    if let Err(e) = reqwest::blocking::Client::new()
+                        .post(url)
+                        .json(&alert)
+                        .send()
+                    {
+                        eprintln!("failed to post alert to {}: {}", url, e);
+                    }
+                }
+                if !notified && args.webhook.is_none() {
+                    bail!(message);
+                }
+                eprintln!("{}", message);
+                state.alerted = true;
+            }
+        }
+
+        thread::sleep(Duration::from_secs(args.interval));
+    }
+}
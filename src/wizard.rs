@@ -0,0 +1,103 @@
+use crate::config::{NodeSettings, SubnetSettings, TestnetConfig};
+use crate::init::NODE_INDEX;
+use anyhow::{bail, Context, Result};
+use ic_prep_lib::node::NodeIndex;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+fn ask<T: FromStr>(question: &str, default: &str) -> Result<T> {
+    loop {
+        print!("{} [{}]: ", question, default);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer)? == 0 {
+            bail!("no answer to \"{}\"", question);
+        }
+        let answer = match answer.trim() {
+            "" => default,
+            answer => answer,
+        };
+        match answer.parse() {
+            Ok(value) => return Ok(value),
+            Err(_) => println!("invalid answer {}", answer),
+        }
+    }
+}
+
+pub(crate) fn yes(question: &str, default: bool) -> Result<bool> {
+    loop {
+        let answer: String = ask(question, if default { "y" } else { "n" })?;
+        match answer.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("answer y or n"),
+        }
+    }
+}
+
+// Asks for the topology and the most common settings and writes the TOML file
+// `init --config` reads. Returns its path.
+pub(crate) fn run() -> Result<PathBuf> {
+    println!("Every node gets its own IP, all replicas of a host may use loopback addresses.");
+    let subnets: u64 = ask("Subnets, the first one is the NNS subnet", "1")?;
+    let nodes_per_subnet: usize = ask("Nodes per subnet", "4")?;
+    let unassigned: usize = ask("Unassigned nodes", "0")?;
+    let first_ip: Ipv4Addr = ask(
+        "IP of the first node, the next nodes count up",
+        "127.0.0.10",
+    )?;
+    let port: u16 = ask("HTTP port of every node, xnet uses the next one", "4100")?;
+    let nns = yes("Install the NNS canisters after starting the testnet", true)?;
+    let query_stats = yes("Aggregate query statistics", true)?;
+    let dts = yes("Deterministic time slicing for long messages", false)?;
+    let sandboxing = yes("Canister sandboxing (needs the sandbox binaries)", false)?;
+    let path: PathBuf = ask("Write the configuration to", "testnet.toml")?;
+    if path.exists() && !yes(&format!("Overwrite {:?}", path), false)? {
+        bail!("kept {:?}", path);
+    }
+
+    let total = subnets as usize * nodes_per_subnet + unassigned;
+    let mut addresses = (0..total).map(|i| {
+        let ip = Ipv4Addr::from(u32::from(first_ip) + i as u32);
+        format!("{}:{}", ip, port)
+    });
+    let mut config = TestnetConfig::default();
+    for index in 0..subnets {
+        config.subnets.push(SubnetSettings {
+            index,
+            nodes: addresses.by_ref().take(nodes_per_subnet).collect(),
+            query_stats: Some(query_stats),
+            deterministic_time_slicing: dts,
+            ..SubnetSettings::default()
+        });
+    }
+    config.unassigned_nodes = addresses.collect();
+    if sandboxing {
+        for i in 0..total {
+            config.nodes.push(NodeSettings {
+                index: NODE_INDEX + i as NodeIndex,
+                sandboxing: true,
+                ..NodeSettings::default()
+            });
+        }
+    }
+
+    fs::write(&path, toml::to_string(&config)?)
+        .with_context(|| format!("failed to write {:?}", path))?;
+    println!(
+        "wrote {:?}, `ic-testnet init --config {}` repeats this setup",
+        path,
+        path.display()
+    );
+
+    println!("next steps:");
+    println!("  ic-testnet run");
+    if nns {
+        println!("  ic-testnet nns install");
+    }
+
+    Ok(path)
+}
@@ -0,0 +1,291 @@
+use crate::agent::{block_on, connect, create_canister, install_code};
+use crate::init::output_dir;
+use crate::topology::{self, Topology};
+use anyhow::{bail, Result};
+use candid::Principal;
+use clap::{ArgEnum, Args};
+use ic_agent::Agent;
+use ic_management_canister_types::CanisterInstallMode;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_universal_canister::{call_args, wasm, UNIVERSAL_CANISTER_WASM};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Stable memory pages of 64 KiB in a MiB.
+const PAGES_PER_MIB: u64 = 16;
+
+// The workloads are universal canisters, a prebuilt wasm shipped with
+// ic-testnet that executes the program each call carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+pub(crate) enum Workload {
+    /// Update calls writing a counter to stable memory, then read back
+    Counter,
+    /// Grows stable memory by --memory-mib and touches every MiB
+    MemoryHog,
+    /// Counts heartbeats, one per round of the subnet
+    Heartbeat,
+    /// Calls another canister, on another subnet when there is one
+    InterCanister,
+}
+
+impl Workload {
+    fn name(&self) -> &'static str {
+        match self {
+            Workload::Counter => "counter",
+            Workload::MemoryHog => "memory-hog",
+            Workload::Heartbeat => "heartbeat",
+            Workload::InterCanister => "inter-canister",
+        }
+    }
+}
+
+#[derive(Args)]
+pub(crate) struct DeployWorkloadArgs {
+    /// Workloads to deploy, all of them when omitted
+    #[clap(long = "workload", arg_enum)]
+    workloads: Vec<Workload>,
+    #[clap(long, default_value = "0")]
+    subnet: SubnetIndex,
+    /// Update calls of the counter
+    #[clap(long, default_value = "100")]
+    calls: u64,
+    #[clap(long, default_value = "256")]
+    memory_mib: u64,
+    /// Seconds the heartbeat counts for
+    #[clap(long, default_value = "10")]
+    heartbeat_secs: u64,
+}
+
+// Canisters of the last `deploy-workload`, by workload.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Deployment {
+    pub subnet: SubnetIndex,
+    pub canisters: BTreeMap<String, String>,
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(output_dir()?.join("workload.json"))
+}
+
+pub(crate) async fn deploy(agent: &Agent, subnet: SubnetIndex) -> Result<Principal> {
+    let canister_id = create_canister(agent, subnet, None).await?;
+    install_code(
+        agent,
+        canister_id,
+        CanisterInstallMode::Install,
+        UNIVERSAL_CANISTER_WASM.to_vec(),
+        vec![],
+    )
+    .await?;
+    Ok(canister_id)
+}
+
+pub(crate) async fn update(
+    agent: &Agent,
+    canister_id: Principal,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    Ok(agent
+        .update(&canister_id, "update")
+        .with_arg(payload)
+        .call_and_wait()
+        .await?)
+}
+
+async fn query(agent: &Agent, canister_id: Principal, payload: Vec<u8>) -> Result<Vec<u8>> {
+    Ok(agent
+        .query(&canister_id, "query")
+        .with_arg(payload)
+        .call()
+        .await?)
+}
+
+fn int64(bytes: &[u8]) -> Result<u64> {
+    if bytes.len() < 8 {
+        bail!("unexpected reply {}", hex::encode(bytes));
+    }
+    Ok(u64::from_le_bytes(bytes[..8].try_into()?))
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() * p / 100).min(sorted.len() - 1)]
+}
+
+async fn counter(agent: &Agent, canister_id: Principal, calls: u64) -> Result<String> {
+    update(agent, canister_id, wasm().stable_grow(1).reply().build()).await?;
+    let mut latencies = vec![];
+    for value in 1..=calls {
+        let start = Instant::now();
+        let payload = wasm().stable_write(0, &value.to_le_bytes()).reply().build();
+        update(agent, canister_id, payload).await?;
+        latencies.push(start.elapsed());
+    }
+    let value = int64(
+        &query(
+            agent,
+            canister_id,
+            wasm().stable_read(0, 8).append_and_reply().build(),
+        )
+        .await?,
+    )?;
+    if value != calls {
+        bail!("counter is {} after {} calls", value, calls);
+    }
+    if latencies.is_empty() {
+        return Ok("no calls".to_string());
+    }
+
+    latencies.sort();
+    Ok(format!(
+        "{} update calls, latency p50 {:?} p95 {:?} max {:?}",
+        calls,
+        percentile(&latencies, 50),
+        percentile(&latencies, 95),
+        latencies[latencies.len() - 1]
+    ))
+}
+
+async fn memory_hog(agent: &Agent, canister_id: Principal, mib: u64) -> Result<String> {
+    update(
+        agent,
+        canister_id,
+        wasm().stable64_grow(mib * PAGES_PER_MIB).reply().build(),
+    )
+    .await?;
+    // one write per MiB keeps the payload well below the ingress limit
+    let mut payload = wasm();
+    for i in 0..mib {
+        payload = payload.stable64_write(i * 1024 * 1024, &[0xff]);
+    }
+    update(agent, canister_id, payload.reply().build()).await?;
+
+    let pages = int64(
+        &query(
+            agent,
+            canister_id,
+            wasm().stable64_size().reply_int64().build(),
+        )
+        .await?,
+    )?;
+    if pages < mib * PAGES_PER_MIB {
+        bail!(
+            "stable memory has {} pages instead of {}",
+            pages,
+            mib * PAGES_PER_MIB
+        );
+    }
+    Ok(format!("{} MiB of stable memory", pages / PAGES_PER_MIB))
+}
+
+async fn global_counter(agent: &Agent, canister_id: Principal) -> Result<u64> {
+    let payload = wasm().get_global_counter().reply_int64().build();
+    int64(&query(agent, canister_id, payload).await?)
+}
+
+async fn heartbeat(agent: &Agent, canister_id: Principal, secs: u64) -> Result<String> {
+    let tick = wasm().inc_global_counter().build();
+    update(
+        agent,
+        canister_id,
+        wasm().set_heartbeat(tick).reply().build(),
+    )
+    .await?;
+    let before = global_counter(agent, canister_id).await?;
+    thread::sleep(Duration::from_secs(secs));
+    let beats = global_counter(agent, canister_id).await? - before;
+    if beats == 0 {
+        bail!(
+            "no heartbeat in {} seconds, the subnet makes no blocks",
+            secs
+        );
+    }
+    Ok(format!(
+        "{} heartbeats in {} seconds, {:.1} rounds per second",
+        beats,
+        secs,
+        beats as f64 / secs as f64
+    ))
+}
+
+async fn inter_canister(agent: &Agent, caller: Principal, callee: Principal) -> Result<String> {
+    let start = Instant::now();
+    let payload = wasm()
+        .call_simple(
+            callee.as_slice(),
+            "update",
+            call_args().other_side(wasm().reply_data(b"pong").build()),
+        )
+        .build();
+    let reply = update(agent, caller, payload).await?;
+    if reply != b"pong" {
+        bail!("unexpected reply {} from {}", hex::encode(reply), callee);
+    }
+    Ok(format!("{} -> {} in {:?}", caller, callee, start.elapsed()))
+}
+
+// A subnet other than the one of the workloads, for xnet calls.
+fn other_subnet(topology: &Topology, subnet: SubnetIndex) -> SubnetIndex {
+    topology
+        .subnets
+        .iter()
+        .map(|other| other.index)
+        .find(|other| *other != subnet)
+        .unwrap_or(subnet)
+}
+
+pub(crate) fn run(args: DeployWorkloadArgs) -> Result<()> {
+    let topology = topology::load()?;
+    let mut workloads = args.workloads.clone();
+    if workloads.is_empty() {
+        workloads = vec![
+            Workload::Counter,
+            Workload::MemoryHog,
+            Workload::Heartbeat,
+            Workload::InterCanister,
+        ];
+    }
+    workloads.sort();
+    workloads.dedup();
+
+    let url = topology.subnet_url(args.subnet)?;
+    let callee_subnet = other_subnet(&topology, args.subnet);
+    let callee_url = topology.subnet_url(callee_subnet)?;
+    let mut deployment = Deployment {
+        subnet: args.subnet,
+        canisters: BTreeMap::new(),
+    };
+
+    block_on(async {
+        let agent = connect(&url, None).await?;
+        for workload in &workloads {
+            let canister_id = deploy(&agent, args.subnet).await?;
+            println!("{} canister {}", workload.name(), canister_id);
+            deployment
+                .canisters
+                .insert(workload.name().to_string(), canister_id.to_string());
+
+            let summary = match workload {
+                Workload::Counter => counter(&agent, canister_id, args.calls).await?,
+                Workload::MemoryHog => memory_hog(&agent, canister_id, args.memory_mib).await?,
+                Workload::Heartbeat => heartbeat(&agent, canister_id, args.heartbeat_secs).await?,
+                Workload::InterCanister => {
+                    let callee_agent = connect(&callee_url, None).await?;
+                    let callee = deploy(&callee_agent, callee_subnet).await?;
+                    deployment
+                        .canisters
+                        .insert("inter-canister-callee".to_string(), callee.to_string());
+                    inter_canister(&agent, canister_id, callee).await?
+                }
+            };
+            println!("  {}", summary);
+        }
+        Ok(())
+    })?;
+
+    fs::write(path()?, serde_json::to_string_pretty(&deployment)?)?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::secrets;
+use crate::topology::{self, Topology};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Subcommand;
+use ic_prep_lib::node::NodeIndex;
+use ic_prep_lib::subnet_configuration::SubnetIndex;
+use ic_protobuf::messaging::xnet::v1::CertifiedStreamSlice;
+use ic_protobuf::registry::crypto::v1::NodePublicKeys;
+use ic_state_manager::stream_encoding::decode_stream_slice;
+use pem::Pem;
+use prost::Message;
+use reqwest::blocking::Client;
+use reqwest::Identity;
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Subcommand)]
+pub(crate) enum XnetCommand {
+    /// Show stream indices and backlogs of a subnet's outgoing streams
+    Streams {
+        /// Subnet hosting the streams
+        #[clap(long)]
+        subnet: SubnetIndex,
+        /// Remote subnet, all streams are listed when omitted
+        #[clap(long)]
+        to: Option<SubnetIndex>,
+    },
+}
+
+// sks_data.pb, the secret key store of a node as ic-crypto writes it.
+#[derive(Clone, PartialEq, Message)]
+struct SecretKeyStore {
+    #[prost(uint32, tag = "1")]
+    version: u32,
+    #[prost(map = "string, message", tag = "2")]
+    key_id_to_secret_key_v1: HashMap<String, SecretKeyV1>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SecretKeyV1 {
+    /// CBOR of the CspSecretKey enum
+    #[prost(bytes = "vec", tag = "1")]
+    csp_secret_key: Vec<u8>,
+    #[prost(string, tag = "2")]
+    scope: String,
+}
+
+// The PKCS#8 DER of a CspSecretKey::TlsEd25519, None for other keys.
+fn tls_secret_key(csp_secret_key: &[u8]) -> Option<Vec<u8>> {
+    use serde_cbor::Value;
+    let Ok(Value::Map(key)) = serde_cbor::from_slice(csp_secret_key) else {
+        return None;
+    };
+    let Some(Value::Map(der)) = key.get(&Value::Text("TlsEd25519".to_string())) else {
+        return None;
+    };
+    match der.get(&Value::Text("bytes".to_string()))? {
+        Value::Bytes(bytes) => Some(bytes.clone()),
+        Value::Array(bytes) => bytes
+            .iter()
+            .map(|byte| match byte {
+                Value::Integer(byte) => u8::try_from(*byte).ok(),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+// Replicas only serve xnet streams to the nodes in the registry, so the
+// client authenticates with the TLS certificate and key of a node.
+fn node_identity(node: NodeIndex) -> Result<Identity> {
+    let dir = secrets::node_crypto_dir(node)?;
+    if secrets::is_sealed(&dir) {
+        bail!(
+            "the keys of node {} are sealed, unseal them with `ic-testnet secrets unseal`",
+            node
+        );
+    }
+    let public_keys = NodePublicKeys::decode(
+        fs::read(dir.join("public_keys.pb"))
+            .with_context(|| format!("node {} has no public keys in {:?}", node, dir))?
+            .as_slice(),
+    )?;
+    let cert = public_keys
+        .tls_certificate
+        .ok_or(anyhow!("node {} has no TLS certificate", node))?
+        .certificate_der;
+    let store = SecretKeyStore::decode(
+        fs::read(dir.join("sks_data.pb"))
+            .with_context(|| format!("node {} has no secret key store in {:?}", node, dir))?
+            .as_slice(),
+    )?;
+    let key = store
+        .key_id_to_secret_key_v1
+        .values()
+        .find_map(|key| tls_secret_key(&key.csp_secret_key))
+        .ok_or(anyhow!("node {} has no TLS secret key", node))?;
+
+    let pem =
+        pem::encode(&Pem::new("CERTIFICATE", cert)) + &pem::encode(&Pem::new("PRIVATE KEY", key));
+    Ok(Identity::from_pem(pem.as_bytes())?)
+}
+
+fn subnet_name(topology: &Topology, subnet_id: &str) -> String {
+    match topology
+        .subnets
+        .iter()
+        .find(|subnet| subnet.subnet_id == subnet_id)
+    {
+        Some(subnet) => format!("subnet {} ({})", subnet.index, subnet_id),
+        None => subnet_id.to_string(),
+    }
+}
+
+pub(crate) fn run(cmd: XnetCommand) -> Result<()> {
+    match cmd {
+        XnetCommand::Streams { subnet, to } => {
+            let topology = topology::load()?;
+            let node = topology
+                .subnet(subnet)?
+                .nodes
+                .first()
+                .ok_or(anyhow!("subnet {} has no nodes", subnet))?;
+
+            // the xnet endpoint serves TLS with self-signed node certificates
+            // and asks for one of the client, the node connects as itself
+            let client = Client::builder()
+                .use_rustls_tls()
+                .identity(node_identity(node.index)?)
+                .danger_accept_invalid_certs(true)
+                .build()?;
+            let base = format!("https://{}/api/v1", node.xnet);
+
+            let remotes: Vec<String> = match to {
+                Some(to) => vec![topology.subnet(to)?.subnet_id.clone()],
+                None => client
+                    .get(format!("{}/streams", base))
+                    .send()?
+                    .error_for_status()?
+                    .json()?,
+            };
+
+            for remote in remotes {
+                let bytes = client
+                    .get(format!("{}/stream/{}", base, remote))
+                    .send()?
+                    .error_for_status()?
+                    .bytes()?;
+                let slice = CertifiedStreamSlice::decode(bytes)?;
+                let (_, slice) =
+                    decode_stream_slice(&slice.payload).map_err(|e| anyhow!("{:?}", e))?;
+                let header = slice.header();
+
+                println!(
+                    "subnet {} -> {}: begin {} end {} signals_end {} backlog {} messages",
+                    subnet,
+                    subnet_name(&topology, &remote),
+                    header.begin(),
+                    header.end(),
+                    header.signals_end(),
+                    header.end().get() - header.begin().get(),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}